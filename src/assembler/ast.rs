@@ -0,0 +1,507 @@
+//! Syntax tree of a source file
+//! A file is a list of sections, each holding the lines written under
+//! it. Comments and blank lines are kept so the source can be printed
+//! back out (e.g. by a formatter).
+//! ```text
+//! Ast
+//!   preamble: comments and blank lines before the first section
+//!   sections: SectionNode
+//!     items: DataLine | CodeLine | LabelLine | ConstantLine | LinkageLine
+//!            | MacroDef | Expansion | Include | Comment | Blank
+//! ```
+//!
+//! A line may define any number of labels. Labels on a line of their own
+//! (`LabelLine`) name the address of the next data cell or instruction,
+//! and labels on a data line name its first byte (after any padding for
+//! `.align`).
+//!
+//! Values and operands are `Expr` trees. They are evaluated once every
+//! label has an address, so they may refer to labels defined later on.
+//! The exception is the counts of `.space`, `.fill` and `.align`, which
+//! decide addresses themselves and so can only use constants.
+//!
+//! A macro definition keeps its body as text. Each use of a macro is an
+//! `Expansion` holding both the line as written and the items the body
+//! expanded to, so tools can work with either. In the same way an
+//! `Include` holds the tree of the file it includes.
+
+use super::lexer::Span;
+use super::Section;
+use crate::cpu::instructions::Opcode;
+
+/// A parsed source file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ast {
+    /// Comments and blank lines before the first section (for an included
+    /// file, the lines continuing the section it was included in)
+    pub preamble: Vec<Item>,
+    pub sections: Vec<SectionNode>,
+    /// Paths of the main file and every file it includes, which spans
+    /// refer to by index (only set on the main file's tree)
+    pub files: Vec<String>,
+}
+
+/// A `.data` or `.code` directive and the lines under it
+#[derive(Clone, Debug, PartialEq)]
+pub struct SectionNode {
+    pub section: Section,
+    /// The directive
+    pub span: Span,
+    /// Comment after the directive
+    pub comment: Option<Comment>,
+    pub items: Vec<Item>,
+}
+
+/// A line inside a section
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Data(DataLine),
+    Code(CodeLine),
+    /// A line holding only labels
+    Labels(LabelLine),
+    /// `.equ` or `.set`
+    Constant(ConstantLine),
+    /// `.global` or `.extern`
+    Linkage(LinkageLine),
+    /// `.macro` to `.endm`
+    Macro(MacroDef),
+    /// A use of a macro
+    Expansion(Expansion),
+    /// `.include "PATH"`
+    Include(Include),
+    /// A line holding only a comment
+    Comment(Comment),
+    /// An empty line, with its line number
+    Blank(usize),
+}
+
+/// `[LABEL:]* DAT VALUE` or `[LABEL:]* .DIRECTIVE ARGUMENTS`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataLine {
+    /// Line number in the source file (starting at 1)
+    pub line: usize,
+    pub labels: Vec<Label>,
+    /// `DAT` or the directive
+    pub keyword_span: Span,
+    pub value: DataValue,
+    pub comment: Option<Comment>,
+}
+
+/// Contents of a data line
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataValue {
+    /// `DAT [VALUE]` (0 if left out)
+    Dat(Option<Expr>),
+    /// `.byte VALUE, ...`
+    Bytes(Vec<Expr>),
+    /// `.word VALUE, ...`, each stored as 2 bytes, high byte first
+    Words(Vec<Expr>),
+    /// `.ascii "TEXT"`, or `.asciz "TEXT"` which adds a 0 byte
+    Ascii {
+        bytes: Vec<u8>,
+        /// The string as written, including its quotes
+        text: String,
+        zero_terminated: bool,
+    },
+    /// `.space COUNT` or `.fill COUNT, VALUE` (0 if left out)
+    Fill { count: Expr, value: Option<Expr> },
+    /// `.align BOUNDARY`: 0s up to the next multiple of BOUNDARY
+    Align(Expr),
+}
+
+/// `[LABEL:]* OPCODE [OPERAND]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeLine {
+    /// Line number in the source file (starting at 1)
+    pub line: usize,
+    pub labels: Vec<Label>,
+    pub opcode: Opcode,
+    pub opcode_span: Span,
+    pub operand: Option<Expr>,
+    pub comment: Option<Comment>,
+}
+
+/// `[LABEL:]+` on a line of its own
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelLine {
+    /// Line number in the source file (starting at 1)
+    pub line: usize,
+    pub labels: Vec<Label>,
+    pub comment: Option<Comment>,
+}
+
+/// `.equ NAME VALUE` (defined once) or `.set NAME VALUE` (may be
+/// redefined; each use sees the latest definition before it)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConstantLine {
+    /// Line number in the source file (starting at 1)
+    pub line: usize,
+    /// The directive
+    pub span: Span,
+    pub redefinable: bool,
+    pub name: Label,
+    pub value: Expr,
+    pub comment: Option<Comment>,
+}
+
+/// `.global NAME, ...` (labels other objects may use) or `.extern NAME,
+/// ...` (labels defined in another object)
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkageLine {
+    /// Line number in the source file (starting at 1)
+    pub line: usize,
+    /// The directive
+    pub span: Span,
+    pub linkage: Linkage,
+    pub names: Vec<Label>,
+    pub comment: Option<Comment>,
+}
+
+/// Whether a `LinkageLine` exports or imports labels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Linkage {
+    Global,
+    Extern,
+}
+
+/// `.macro NAME [PARAM, ...]` then the body lines, then `.endm`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacroDef {
+    /// Line number of `.macro` in the source file (starting at 1)
+    pub line: usize,
+    /// The `.macro` directive
+    pub span: Span,
+    pub name: Label,
+    pub params: Vec<Label>,
+    pub comment: Option<Comment>,
+    /// Lines between `.macro` and `.endm`, as written
+    pub body: Vec<String>,
+    /// Line number of `.endm` (`None` if it is missing)
+    pub end_line: Option<usize>,
+    /// Comment after `.endm`
+    pub end_comment: Option<Comment>,
+}
+
+/// `[LABEL:]* NAME [ARGUMENT, ...]` where `NAME` is a macro
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expansion {
+    /// Line number of the use (starting at 1)
+    pub line: usize,
+    pub labels: Vec<Label>,
+    pub name: String,
+    pub name_span: Span,
+    /// Arguments as written
+    pub arguments: Vec<String>,
+    pub comment: Option<Comment>,
+    /// The items the body expanded to
+    pub items: Vec<Item>,
+    /// Names given to the body's own labels for this expansion
+    pub locals: Vec<String>,
+    /// Where the expansion came from, e.g. ` (in expansion of B at line 3,
+    /// in expansion of A at line 9)`, to add to diagnostics
+    pub trace: String,
+}
+
+/// `.include "PATH"`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Include {
+    /// Line number in the source file (starting at 1)
+    pub line: usize,
+    /// The directive
+    pub span: Span,
+    /// The path as written (without quotes)
+    pub path: String,
+    pub comment: Option<Comment>,
+    /// Index of the included file in `Ast::files` (`None` if it could not
+    /// be read)
+    pub file: Option<usize>,
+    /// The included file
+    pub ast: Ast,
+}
+
+/// An item with where it is in the program
+#[derive(Clone, Copy, Debug)]
+pub struct Placed<'a> {
+    pub item: &'a Item,
+    /// `None` before the first section
+    pub section: Option<Section>,
+    /// The innermost macro expansion the item came from
+    pub expansion: Option<&'a Expansion>,
+    /// Line of the main file the item belongs to: its own, or that of the
+    /// outermost macro use or `.include` it came from
+    pub origin: usize,
+}
+
+impl Placed<'_> {
+    /// Text to add to diagnostics about the item
+    pub fn trace(&self) -> &str {
+        self.expansion.map_or("", |expansion| expansion.trace.as_str())
+    }
+}
+
+/// A label definition
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    pub name: String,
+    pub span: Span,
+    /// Whether the label was written with a trailing `:`
+    pub colon: bool,
+}
+
+/// A constant expression
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// The whole expression, including any parentheses around it
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprKind {
+    /// A literal, with its source text (e.g. `0x2A` or `'*'`)
+    Number(i64, String),
+    /// A label or constant
+    Symbol(String),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// An expression in parentheses
+    Group(Box<Expr>),
+}
+
+/// Binary operators, from tightest to loosest binding:
+/// `* / %`, `+ -`, `<< >>`, `&`, `^`, `|`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    And,
+    Xor,
+    Or,
+}
+
+/// A `;` or `//` comment, including the marker
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
+}
+
+impl Ast {
+    /// Iterate over every data line in source order
+    pub fn data_lines(&self) -> impl Iterator<Item = &DataLine> {
+        self.items().filter_map(|item| match item {
+            Item::Data(line) => Some(line),
+            _ => None,
+        })
+    }
+
+    /// Iterate over every code line in source order
+    pub fn code_lines(&self) -> impl Iterator<Item = &CodeLine> {
+        self.items().filter_map(|item| match item {
+            Item::Code(line) => Some(line),
+            _ => None,
+        })
+    }
+
+    /// Iterate over every `.equ` and `.set` in source order
+    pub fn constants(&self) -> impl Iterator<Item = &ConstantLine> {
+        self.items().filter_map(|item| match item {
+            Item::Constant(line) => Some(line),
+            _ => None,
+        })
+    }
+
+    /// Iterate over every item in source order, with the items of a macro
+    /// expansion or included file following the expansion or `.include`
+    pub fn items(&self) -> impl Iterator<Item = &Item> {
+        self.placed().into_iter().map(|placed| placed.item)
+    }
+
+    /// Every item in source order (expanding macros and included files),
+    /// with where it is
+    pub fn placed(&self) -> Vec<Placed<'_>> {
+        let mut out = Vec::new();
+        place_ast(self, None, None, &mut out);
+        out
+    }
+
+    /// Iterate over every `.global` and `.extern` in source order
+    pub fn linkages(&self) -> impl Iterator<Item = &LinkageLine> {
+        self.items().filter_map(|item| match item {
+            Item::Linkage(line) => Some(line),
+            _ => None,
+        })
+    }
+
+    /// Find the `.include` in this file that led to another file being read
+    pub fn include_of(&self, file: usize) -> Option<&Include> {
+        let items = self.preamble.iter().chain(self.sections.iter().flat_map(|node| node.items.iter()));
+        items
+            .filter_map(|item| match item {
+                Item::Include(include) => Some(include),
+                _ => None,
+            })
+            .find(|include| include.file == Some(file) || include.ast.include_of(file).is_some())
+    }
+}
+
+/// Add the items of a tree to a list, in a section
+fn place_ast<'a>(ast: &'a Ast, section: Option<Section>, origin: Option<usize>, out: &mut Vec<Placed<'a>>) {
+    place(&ast.preamble, section, None, origin, out);
+    for node in &ast.sections {
+        place(&node.items, Some(node.section), None, origin, out);
+    }
+}
+
+/// Add items to a list, followed by what they expand to
+fn place<'a>(
+    items: &'a [Item],
+    section: Option<Section>,
+    expansion: Option<&'a Expansion>,
+    origin: Option<usize>,
+    out: &mut Vec<Placed<'a>>,
+) {
+    for item in items {
+        out.push(Placed {
+            item,
+            section,
+            expansion,
+            origin: origin.unwrap_or(item.line()),
+        });
+
+        let origin = Some(origin.unwrap_or(item.line()));
+        match item {
+            Item::Expansion(inner) => place(&inner.items, section, Some(inner), origin, out),
+            Item::Include(include) => place_ast(&include.ast, section, origin, out),
+            _ => {},
+        }
+    }
+}
+
+impl DataValue {
+    /// Every expression in the value
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            DataValue::Dat(value) => value.iter().collect(),
+            DataValue::Bytes(values) | DataValue::Words(values) => values.iter().collect(),
+            DataValue::Ascii { .. } => Vec::new(),
+            DataValue::Fill { count, value } => std::iter::once(count).chain(value).collect(),
+            DataValue::Align(boundary) => vec![boundary],
+        }
+    }
+}
+
+impl Expr {
+    /// Call a function with the name and span of every symbol used
+    pub fn for_each_symbol(&self, f: &mut impl FnMut(&str, Span)) {
+        match &self.kind {
+            ExprKind::Number(..) => {},
+            ExprKind::Symbol(name) => f(name, self.span),
+            ExprKind::Negate(inner) | ExprKind::Group(inner) => inner.for_each_symbol(f),
+            ExprKind::Binary(_, left, right) => {
+                left.for_each_symbol(f);
+                right.for_each_symbol(f);
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    /// Write the expression with a space around each operator
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.kind {
+            ExprKind::Number(_, text) => write!(f, "{}", text),
+            ExprKind::Symbol(name) => write!(f, "{}", name),
+            ExprKind::Negate(inner) => write!(f, "-{}", inner),
+            ExprKind::Binary(op, left, right) => write!(f, "{} {} {}", left, op.symbol(), right),
+            ExprKind::Group(inner) => write!(f, "({})", inner),
+        }
+    }
+}
+
+impl BinaryOp {
+    /// Get the operator for a token, with its binding power (higher binds
+    /// tighter)
+    pub fn from_token(text: &str) -> Option<(BinaryOp, u8)> {
+        let op = match text {
+            "*" => (BinaryOp::Mul, 6),
+            "/" => (BinaryOp::Div, 6),
+            "%" => (BinaryOp::Rem, 6),
+            "+" => (BinaryOp::Add, 5),
+            "-" => (BinaryOp::Sub, 5),
+            "<<" => (BinaryOp::Shl, 4),
+            ">>" => (BinaryOp::Shr, 4),
+            "&" => (BinaryOp::And, 3),
+            "^" => (BinaryOp::Xor, 2),
+            "|" => (BinaryOp::Or, 1),
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// The operator as written in source
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::And => "&",
+            BinaryOp::Xor => "^",
+            BinaryOp::Or => "|",
+        }
+    }
+}
+
+impl Item {
+    /// Labels defined by the item
+    pub fn labels(&self) -> &[Label] {
+        match self {
+            Item::Data(line) => &line.labels,
+            Item::Code(line) => &line.labels,
+            Item::Labels(line) => &line.labels,
+            Item::Expansion(expansion) => &expansion.labels,
+            Item::Constant(_)
+            | Item::Linkage(_)
+            | Item::Macro(_)
+            | Item::Include(_)
+            | Item::Comment(_)
+            | Item::Blank(_) => &[],
+        }
+    }
+
+    /// Line the item starts on
+    pub fn line(&self) -> usize {
+        match self {
+            Item::Data(line) => line.line,
+            Item::Code(line) => line.line,
+            Item::Labels(line) => line.line,
+            Item::Constant(line) => line.line,
+            Item::Linkage(line) => line.line,
+            Item::Macro(definition) => definition.line,
+            Item::Expansion(expansion) => expansion.line,
+            Item::Include(include) => include.line,
+            Item::Comment(comment) => comment.span.line,
+            Item::Blank(line) => *line,
+        }
+    }
+}
+
+impl SectionNode {
+    /// Last line belonging to the section
+    pub fn last_line(&self) -> usize {
+        let last = self.items.last().map(|item| match item {
+            Item::Macro(definition) => definition.end_line.unwrap_or(definition.line + definition.body.len()),
+            item => item.line(),
+        });
+        last.unwrap_or(self.span.line)
+    }
+}
@@ -0,0 +1,295 @@
+//! Evaluates constant expressions
+//! Expressions are evaluated once every label has an address, using 64
+//! bit arithmetic. Only the final value has to fit in a word, so
+//! `(SIZE * 100) / 50` works even if `SIZE * 100` does not.
+//!
+//! A symbol is either a label (its address) or an `.equ`/`.set`
+//! constant. Each use of a `.set` constant sees the latest definition
+//! before it, or the first one if it is used before being defined.
+//!
+//! In a relocatable object labels have no final address yet, so a value
+//! may only be a label plus or minus a number (or the distance between
+//! two labels in the same section), which the linker finishes off.
+
+use std::collections::HashMap;
+
+use super::ast::{Ast, BinaryOp, ConstantLine, Expr, ExprKind, Linkage};
+use super::lexer::Span;
+use super::{literal, Diagnostic, Severity, SymbolTable};
+
+/// Labels and constants that expressions can refer to
+pub struct Scope<'a> {
+    /// `None` while labels are still being given addresses
+    symbols: Option<&'a SymbolTable>,
+    /// Definitions of each constant in source order
+    constants: HashMap<&'a str, Vec<&'a ConstantLine>>,
+    /// Labels declared `.extern`
+    externs: Vec<&'a str>,
+}
+
+/// Value of an expression in a relocatable object: a number, or the
+/// address of a label plus a number
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relocatable {
+    /// Label whose address is added when linking
+    pub symbol: Option<String>,
+    pub addend: i64,
+}
+
+impl<'a> Scope<'a> {
+    /// Create a scope from resolved labels and the constants in a tree
+    pub fn new(symbols: &'a SymbolTable, ast: &'a Ast) -> Scope<'a> {
+        Scope {
+            symbols: Some(symbols),
+            ..Scope::constants_only(ast)
+        }
+    }
+
+    /// Create a scope of only the constants in a tree, for values needed
+    /// to work out addresses
+    pub fn constants_only(ast: &'a Ast) -> Scope<'a> {
+        let mut constants: HashMap<&str, Vec<&ConstantLine>> = HashMap::new();
+        for constant in ast.constants() {
+            constants.entry(constant.name.name.as_str()).or_default().push(constant);
+        }
+
+        let externs = ast
+            .linkages()
+            .filter(|line| line.linkage == Linkage::Extern)
+            .flat_map(|line| line.names.iter().map(|name| name.name.as_str()))
+            .collect();
+
+        Scope {
+            symbols: None,
+            constants,
+            externs,
+        }
+    }
+
+    /// Evaluate an expression used on a line
+    pub fn eval(&self, expr: &Expr, line: usize) -> Result<i64, Diagnostic> {
+        self.eval_in(expr, line, false, &mut Vec::new()).map(|value| value.addend)
+    }
+
+    /// Evaluate an expression used on a line of a relocatable object,
+    /// leaving the addresses of labels to the linker
+    pub fn relocatable(&self, expr: &Expr, line: usize) -> Result<Relocatable, Diagnostic> {
+        self.eval_in(expr, line, true, &mut Vec::new())
+    }
+
+    /// Evaluate an expression used on a line and check it fits in a
+    /// number of bits
+    pub fn bits(&self, expr: &Expr, line: usize, bits: u32) -> Result<u64, Diagnostic> {
+        let value = self.eval(expr, line)?;
+        literal::fit(value, bits).map_err(|message| error(expr.span, message))
+    }
+
+    /// Value of every constant, using its last definition
+    pub fn constants(&self) -> Vec<(String, i64)> {
+        let mut values: Vec<(String, i64)> = self
+            .constants
+            .iter()
+            .filter_map(|(name, definitions)| {
+                let last = definitions.last().unwrap();
+                self.eval(&last.value, last.line).ok().map(|value| (name.to_string(), value))
+            })
+            .collect();
+        values.sort();
+        values
+    }
+
+    /// Evaluate an expression, keeping track of the constant definitions
+    /// being evaluated to catch ones that depend on themselves
+    fn eval_in(
+        &self,
+        expr: &Expr,
+        line: usize,
+        relocate: bool,
+        stack: &mut Vec<&'a ConstantLine>,
+    ) -> Result<Relocatable, Diagnostic> {
+        let number = |addend| Relocatable { symbol: None, addend };
+
+        match &expr.kind {
+            ExprKind::Number(value, _) => Ok(number(*value)),
+            ExprKind::Symbol(name) => {
+                if let Some(definition) = self.constant(name, line) {
+                    if stack.iter().any(|other| std::ptr::eq(*other, definition)) {
+                        return Err(error(expr.span, format!("'{}' depends on itself", name)));
+                    }
+
+                    stack.push(definition);
+                    let value = self.eval_in(&definition.value, definition.line, relocate, stack);
+                    stack.pop();
+                    return value;
+                }
+
+                if self.externs.contains(&name.as_str()) {
+                    if relocate {
+                        return Ok(Relocatable {
+                            symbol: Some(name.clone()),
+                            addend: 0,
+                        });
+                    }
+                    let message = format!("'{}' is defined in another object (assemble this file as an object)", name);
+                    return Err(error(expr.span, message));
+                }
+
+                let symbols = match self.symbols {
+                    Some(symbols) => symbols,
+                    None => {
+                        let message = format!("'{}' is not a constant (this value decides addresses)", name);
+                        return Err(error(expr.span, message));
+                    },
+                };
+                match symbols.address_of(name) {
+                    Some(_) if relocate => Ok(Relocatable {
+                        symbol: Some(name.clone()),
+                        addend: 0,
+                    }),
+                    Some(address) => Ok(number(address as i64)),
+                    None => Err(error(expr.span, format!("Undefined label '{}'", name))),
+                }
+            },
+            ExprKind::Negate(inner) => {
+                let value = self.eval_in(inner, line, relocate, stack)?;
+                match value.symbol {
+                    None => Ok(number(-value.addend)),
+                    Some(symbol) => Err(error(expr.span, not_relocatable(&symbol))),
+                }
+            },
+            ExprKind::Group(inner) => self.eval_in(inner, line, relocate, stack),
+            ExprKind::Binary(op, left, right) => {
+                let a = self.eval_in(left, line, relocate, stack)?;
+                let b = self.eval_in(right, line, relocate, stack)?;
+                self.combine(*op, a, b).map_err(|message| error(expr.span, message))
+            },
+        }
+    }
+
+    /// Apply a binary operator to values that may be relative to labels
+    fn combine(&self, op: BinaryOp, a: Relocatable, b: Relocatable) -> Result<Relocatable, String> {
+        let symbol = match (op, a.symbol, b.symbol) {
+            (_, None, None) => None,
+            (BinaryOp::Add, Some(symbol), None) | (BinaryOp::Add, None, Some(symbol)) => Some(symbol),
+            (BinaryOp::Sub, Some(symbol), None) => Some(symbol),
+            // The distance between two labels in the same section is fixed
+            (BinaryOp::Sub, Some(x), Some(y)) => {
+                let symbols = self.symbols.unwrap();
+                match (symbols.get(&x), symbols.get(&y)) {
+                    (Some(first), Some(second)) if first.section == second.section => {
+                        let distance = first.address as i64 - second.address as i64;
+                        let value = binary(op, a.addend, b.addend)?;
+                        return Ok(Relocatable {
+                            symbol: None,
+                            addend: distance + value,
+                        });
+                    },
+                    (Some(_), _) => return Err(not_relocatable(&y)),
+                    _ => return Err(not_relocatable(&x)),
+                }
+            },
+            (_, Some(symbol), _) | (_, _, Some(symbol)) => return Err(not_relocatable(&symbol)),
+        };
+
+        Ok(Relocatable {
+            symbol,
+            addend: binary(op, a.addend, b.addend)?,
+        })
+    }
+
+    /// Find the definition of a constant seen from a line
+    fn constant(&self, name: &str, line: usize) -> Option<&'a ConstantLine> {
+        let definitions = self.constants.get(name)?;
+        definitions
+            .iter()
+            .rev()
+            .find(|definition| definition.line < line)
+            .or(definitions.first())
+            .copied()
+    }
+}
+
+/// Apply a binary operator
+fn binary(op: BinaryOp, a: i64, b: i64) -> Result<i64, String> {
+    let shift = || match u32::try_from(b) {
+        Ok(shift) if shift < 64 => Ok(shift),
+        _ => Err(format!("Cannot shift by {}", b)),
+    };
+
+    let value = match op {
+        BinaryOp::Mul => a.checked_mul(b),
+        BinaryOp::Div | BinaryOp::Rem if b == 0 => return Err("Division by zero".to_string()),
+        BinaryOp::Div => a.checked_div(b),
+        BinaryOp::Rem => a.checked_rem(b),
+        BinaryOp::Add => a.checked_add(b),
+        BinaryOp::Sub => a.checked_sub(b),
+        BinaryOp::Shl => a.checked_shl(shift()?),
+        BinaryOp::Shr => a.checked_shr(shift()?),
+        BinaryOp::And => Some(a & b),
+        BinaryOp::Xor => Some(a ^ b),
+        BinaryOp::Or => Some(a | b),
+    };
+
+    value.ok_or_else(|| format!("Overflow in {} {} {}", a, op.symbol(), b))
+}
+
+/// Message for a label used in a way the linker cannot finish off
+fn not_relocatable(symbol: &str) -> String {
+    format!(
+        "The address of '{}' is decided when linking, so only a number can be added to or subtracted from it",
+        symbol
+    )
+}
+
+fn error(span: Span, message: String) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        span,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_source;
+
+    /// Data assembled from source, or its error messages
+    fn data(source: &str) -> Result<Vec<u8>, Vec<String>> {
+        let assembly = assemble_source(source);
+        match assembly.has_errors() {
+            true => Err(assembly.diagnostics.iter().map(|d| d.message.clone()).collect()),
+            false => Ok(assembly.program.data),
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(data(".data\nDAT 2 + 3 * 4\nDAT (2 + 3) * 4\nDAT 7 % 4 << 2\nDAT -1\n"), Ok(vec![14, 20, 12, 255]));
+    }
+
+    #[test]
+    fn only_the_result_must_fit() {
+        assert_eq!(data(".equ SIZE 100\n.data\nDAT (SIZE * 100) / 50\n"), Ok(vec![200]));
+        assert_eq!(
+            data(".data\nDAT 256\n"),
+            Err(vec!["Value 256 does not fit in 8 bits (expected -128 to 255)".to_string()])
+        );
+    }
+
+    #[test]
+    fn labels_and_constants() {
+        assert_eq!(data(".equ N 2\n.data\nA: DAT 1\nB: DAT B - A + N\n"), Ok(vec![1, 3]));
+        assert_eq!(data(".data\nDAT X\n.set X 1\nDAT X\n.set X 2\nDAT X\n"), Ok(vec![1, 1, 2]));
+    }
+
+    #[test]
+    fn constants_that_depend_on_themselves() {
+        let errors = data(".equ A B\n.equ B A\n.data\nDAT A\n").unwrap_err();
+        assert!(errors.iter().any(|error| error.contains("depends on itself")), "{:?}", errors);
+    }
+
+    #[test]
+    fn undefined_labels() {
+        assert_eq!(data(".data\nDAT NOPE\n"), Err(vec!["Undefined label 'NOPE'".to_string()]));
+    }
+}
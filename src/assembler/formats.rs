@@ -0,0 +1,456 @@
+//! File formats a program can be saved in and loaded from
+//! ```text
+//! bin      data length (2 bytes, big endian), data, code (see `assembler`)
+//! ihex     Intel HEX, data at 0x00000 and code at 0x10000
+//! srec     Motorola S-records (S2, 24 bit addresses), data at 0x000000
+//!          and code at 0x010000
+//! text     plain hex text: `data` followed by its bytes, then `code`
+//!          followed by its bytes (`#` starts a comment)
+//! logisim  Logisim/Digital `v2.0 raw` memory images, one file per
+//!          memory: `prog.lgs` is saved as `prog.data.lgs` and
+//!          `prog.code.lgs` (and `prog.logisim` the same way, so it can
+//!          be loaded back as `prog.lgs`)
+//! ```
+//! Both memories hold bytes, so a ROM in a simulator should have 8 bit
+//! addresses and 8 bit data. Memories are written in full up to their
+//! last byte; anything after it starts as 0. A file holding more than a
+//! memory's 256 bytes is rejected.
+//!
+//! The format of a file is chosen by its extension (see
+//! `Format::ALL`), unless given by name. (Digital also reads `v2.0 raw`
+//! images but calls them `.hex`, which here means Intel HEX.)
+
+use std::path::Path;
+
+use super::Program;
+
+/// Bytes per record or row
+const ROW_SIZE: usize = 16;
+
+/// Address code starts at in Intel HEX and S-record files
+const CODE_BASE: usize = 0x10000;
+
+/// Bytes in each memory of the machine
+const MEMORY_SIZE: usize = 256;
+
+/// A file format for programs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Binary,
+    IntelHex,
+    SRecord,
+    HexText,
+    Logisim,
+}
+
+impl Format {
+    /// Every format with its name and file extensions
+    pub const ALL: [(Format, &'static str, &'static [&'static str]); 5] = [
+        (Format::Binary, "bin", &["bin"]),
+        (Format::IntelHex, "ihex", &["hex", "ihex", "ihx"]),
+        (Format::SRecord, "srec", &["srec", "s28", "mot"]),
+        (Format::HexText, "text", &["txt"]),
+        (Format::Logisim, "logisim", &["lgs", "img"]),
+    ];
+
+    /// Get the format of a file from its extension, if it is a program
+    /// file
+    pub fn from_path(path: &str) -> Option<Format> {
+        let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+        Format::ALL
+            .iter()
+            .find(|(_, _, extensions)| extensions.contains(&extension.as_str()))
+            .map(|(format, _, _)| *format)
+    }
+
+    /// Name of the format, as accepted by `from_str`
+    pub fn name(&self) -> &'static str {
+        Format::ALL.iter().find(|(format, _, _)| format == self).unwrap().1
+    }
+}
+
+/// Save a program to a file (two files for `Format::Logisim`)
+pub fn save(program: &Program, path: &str, format: Format) -> std::io::Result<()> {
+    match format {
+        Format::Binary => std::fs::write(path, program.to_binary()),
+        Format::IntelHex => std::fs::write(path, intel_hex(program)),
+        Format::SRecord => std::fs::write(path, s_records(program)),
+        Format::HexText => std::fs::write(path, hex_text(program)),
+        Format::Logisim => {
+            let (data_path, code_path) = logisim_paths(path);
+            std::fs::write(data_path, logisim(&program.data))?;
+            std::fs::write(code_path, logisim(&program.code))
+        },
+    }
+}
+
+/// Load a program from a file (two files for `Format::Logisim`)
+pub fn load(path: &str, format: Format) -> Result<Program, String> {
+    let read = |path: &str| std::fs::read(path).map_err(|e| format!("{}: {}", path, e));
+    let text = |path: &str| read(path).map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+
+    let program = match format {
+        Format::Binary => {
+            let binary = read(path)?;
+            Program::from_binary(&binary).map_err(|e| format!("{}: {}", path, e))
+        },
+        Format::IntelHex => parse_intel_hex(&text(path)?),
+        Format::SRecord => parse_s_records(&text(path)?),
+        Format::HexText => parse_hex_text(&text(path)?),
+        Format::Logisim => {
+            let (data_path, code_path) = logisim_paths(path);
+            let data = parse_logisim(&text(&data_path)?).map_err(|e| format!("{}: {}", data_path, e))?;
+            let code = parse_logisim(&text(&code_path)?).map_err(|e| format!("{}: {}", code_path, e))?;
+            Ok(Program {
+                data,
+                code,
+                ..Program::default()
+            })
+        },
+    };
+
+    let program = program.map_err(|e| format!("{}: {}", path, e))?;
+    for (name, memory) in [("data", &program.data), ("code", &program.code)] {
+        if memory.len() > MEMORY_SIZE {
+            return Err(format!("{}: {} is {} bytes, more than the {} in memory", path, name, memory.len(), MEMORY_SIZE));
+        }
+    }
+    Ok(program)
+}
+
+/// Paths of the data and code images for a Logisim file name, which
+/// always end in a Logisim extension (`.lgs` unless the name has one)
+pub fn logisim_paths(path: &str) -> (String, String) {
+    let extension = match Format::from_path(path) {
+        Some(Format::Logisim) => Path::new(path).extension().unwrap().to_string_lossy().into_owned(),
+        _ => "lgs".to_string(),
+    };
+    let path = Path::new(path);
+    let with = |memory: &str| path.with_extension(format!("{}.{}", memory, extension)).display().to_string();
+    (with("data"), with("code"))
+}
+
+/// Write a program as Intel HEX
+fn intel_hex(program: &Program) -> String {
+    let mut out = String::new();
+    let mut record = |kind: u8, address: usize, bytes: &[u8]| {
+        let mut record = vec![bytes.len() as u8, (address >> 8) as u8, address as u8, kind];
+        record.extend_from_slice(bytes);
+        let checksum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+        record.push(checksum);
+        out.push(':');
+        out.push_str(&hex(&record, ""));
+        out.push('\n');
+    };
+
+    for (row, bytes) in program.data.chunks(ROW_SIZE).enumerate() {
+        record(0x00, row * ROW_SIZE, bytes);
+    }
+    // Extended linear address: the upper 16 bits of what follows
+    record(0x04, 0, &[(CODE_BASE >> 24) as u8, (CODE_BASE >> 16) as u8]);
+    for (row, bytes) in program.code.chunks(ROW_SIZE).enumerate() {
+        record(0x00, row * ROW_SIZE, bytes);
+    }
+    record(0x01, 0, &[]);
+    out
+}
+
+/// Read a program from Intel HEX
+fn parse_intel_hex(text: &str) -> Result<Program, String> {
+    let mut program = Program::default();
+    let mut base = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| format!("line {}: {}", index + 1, message);
+
+        let hex_digits = line.strip_prefix(':').ok_or_else(|| error("record does not start with ':'".to_string()))?;
+        let record = parse_hex(hex_digits).map_err(error)?;
+        if record.len() < 5 || record.len() != 5 + record[0] as usize {
+            return Err(error("record length does not match its byte count".to_string()));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(error("checksum does not match".to_string()));
+        }
+
+        let address = u16::from_be_bytes([record[1], record[2]]) as usize;
+        let bytes = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => place(&mut program, base + address, bytes).map_err(error)?,
+            0x01 => break,
+            0x02 | 0x04 if bytes.len() != 2 => return Err(error("address record must hold 2 bytes".to_string())),
+            0x02 => base = (u16::from_be_bytes([bytes[0], bytes[1]]) as usize) << 4,
+            0x04 => base = (u16::from_be_bytes([bytes[0], bytes[1]]) as usize) << 16,
+            // Start addresses mean nothing here
+            0x03 | 0x05 => {},
+            kind => return Err(error(format!("unknown record type {:02X}", kind))),
+        }
+    }
+    Ok(program)
+}
+
+/// Write a program as S-records
+fn s_records(program: &Program) -> String {
+    let mut out = String::new();
+    let mut record = |kind: char, address: &[u8], bytes: &[u8]| {
+        let mut record = vec![(address.len() + bytes.len() + 1) as u8];
+        record.extend_from_slice(address);
+        record.extend_from_slice(bytes);
+        let checksum = !record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        record.push(checksum);
+        out.push('S');
+        out.push(kind);
+        out.push_str(&hex(&record, ""));
+        out.push('\n');
+    };
+    let address = |address: usize| [(address >> 16) as u8, (address >> 8) as u8, address as u8];
+
+    record('0', &[0, 0], b"vnc");
+    for (row, bytes) in program.data.chunks(ROW_SIZE).enumerate() {
+        record('2', &address(row * ROW_SIZE), bytes);
+    }
+    for (row, bytes) in program.code.chunks(ROW_SIZE).enumerate() {
+        record('2', &address(CODE_BASE + row * ROW_SIZE), bytes);
+    }
+    record('8', &address(CODE_BASE), &[]);
+    out
+}
+
+/// Read a program from S-records
+fn parse_s_records(text: &str) -> Result<Program, String> {
+    let mut program = Program::default();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| format!("line {}: {}", index + 1, message);
+
+        let (kind, hex_digits) = match line.strip_prefix('S').and_then(|rest| rest.split_at_checked(1)) {
+            Some(split) => split,
+            None => return Err(error("record does not start with 'S'".to_string())),
+        };
+        let record = parse_hex(hex_digits).map_err(error)?;
+        if record.is_empty() || record.len() != 1 + record[0] as usize {
+            return Err(error("record length does not match its byte count".to_string()));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF {
+            return Err(error("checksum does not match".to_string()));
+        }
+
+        let address_size = match kind {
+            "1" => 2,
+            "2" => 3,
+            "3" => 4,
+            // Header, count and start address records
+            "0" | "5" | "6" | "7" | "8" | "9" => continue,
+            _ => return Err(error(format!("unknown record type S{}", kind))),
+        };
+        if record.len() < 2 + address_size {
+            return Err(error("record is too short for its address".to_string()));
+        }
+        let address = record[1..1 + address_size].iter().fold(0, |address, byte| address << 8 | *byte as usize);
+        place(&mut program, address, &record[1 + address_size..record.len() - 1]).map_err(error)?;
+    }
+    Ok(program)
+}
+
+/// Write a program as plain hex text
+fn hex_text(program: &Program) -> String {
+    let mut out = String::from("# virtual nanocomputer program\n");
+    for (name, bytes) in [("data", &program.data), ("code", &program.code)] {
+        out.push_str(name);
+        out.push('\n');
+        for row in bytes.chunks(ROW_SIZE) {
+            out.push_str(&hex(row, " "));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Read a program from plain hex text
+fn parse_hex_text(text: &str) -> Result<Program, String> {
+    let mut program = Program::default();
+    let mut memory = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        let error = |message: String| format!("line {}: {}", index + 1, message);
+        match line {
+            "" => {},
+            "data" => memory = Some(&mut program.data),
+            "code" => memory = Some(&mut program.code),
+            _ => {
+                let memory = memory.as_mut().ok_or_else(|| error("bytes before 'data' or 'code'".to_string()))?;
+                for byte in line.split_whitespace() {
+                    if memory.len() == MEMORY_SIZE {
+                        return Err(error(format!("more than {} bytes", MEMORY_SIZE)));
+                    }
+                    memory.push(u8::from_str_radix(byte, 16).map_err(|_| error(format!("invalid byte '{}'", byte)))?);
+                }
+            },
+        }
+    }
+    Ok(program)
+}
+
+/// Write a memory as a Logisim `v2.0 raw` image
+fn logisim(bytes: &[u8]) -> String {
+    let mut out = String::from("v2.0 raw\n");
+    for row in bytes.chunks(ROW_SIZE) {
+        let row: Vec<String> = row.iter().map(|byte| format!("{:x}", byte)).collect();
+        out.push_str(&row.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+/// Read a Logisim `v2.0 raw` image, which may repeat a value with
+/// `COUNT*VALUE`
+fn parse_logisim(text: &str) -> Result<Vec<u8>, String> {
+    let mut lines = text.lines();
+    if lines.next().map(str::trim) != Some("v2.0 raw") {
+        return Err("not a Logisim image (missing 'v2.0 raw')".to_string());
+    }
+
+    let mut bytes = Vec::new();
+    for (index, line) in lines.enumerate() {
+        let line = line.split('#').next().unwrap();
+        for word in line.split_whitespace() {
+            let error = || format!("line {}: invalid value '{}'", index + 2, word);
+            let (count, value) = match word.split_once('*') {
+                Some((count, value)) => (count.parse::<usize>().map_err(|_| error())?, value),
+                None => (1, word),
+            };
+            let value = u8::from_str_radix(value, 16).map_err(|_| error())?;
+            if count > MEMORY_SIZE - bytes.len() {
+                return Err(format!("line {}: image is more than {} bytes", index + 2, MEMORY_SIZE));
+            }
+            bytes.extend(std::iter::repeat_n(value, count));
+        }
+    }
+    Ok(bytes)
+}
+
+/// Put bytes at an address of the combined address space of Intel HEX and
+/// S-record files
+fn place(program: &mut Program, address: usize, bytes: &[u8]) -> Result<(), String> {
+    let (memory, start) = match address {
+        address if address < CODE_BASE => (&mut program.data, address),
+        address => (&mut program.code, address - CODE_BASE),
+    };
+    if start + bytes.len() > CODE_BASE {
+        return Err(format!("address 0x{:X} is outside both memories", address + bytes.len() - 1));
+    }
+
+    if memory.len() < start + bytes.len() {
+        memory.resize(start + bytes.len(), 0);
+    }
+    memory[start..start + bytes.len()].copy_from_slice(bytes);
+    Ok(())
+}
+
+/// Parse a run of hex digit pairs
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(format!("invalid hex '{}'", text));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| format!("invalid hex '{}'", text)))
+        .collect()
+}
+
+/// Format bytes as upper case hex
+fn hex(bytes: &[u8], separator: &str) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(separator)
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Format::ALL.iter().find(|(_, name, _)| *name == s) {
+            Some((format, _, _)) => Ok(*format),
+            None => {
+                let names: Vec<&str> = Format::ALL.iter().map(|(_, name, _)| *name).collect();
+                Err(format!("unknown format '{}' (expected one of {})", s, names.join(", ")))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble_with, Options};
+    use std::path::PathBuf;
+
+    /// A fresh directory for the files of one test
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("vnc-formats-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn program() -> Program {
+        let source = ".data\nA: DAT 3\nB: DAT 0xFF\n.space 20\nC: DAT 7\n.code\nLDA A\nADD B\nSTA C\nOUT\nHLT\n";
+        assemble_with(source, &Options::default()).program
+    }
+
+    #[test]
+    fn round_trips() {
+        let directory = directory("round-trips");
+        let program = program();
+        for (format, name, extensions) in Format::ALL {
+            let path = directory.join(format!("prog.{}", extensions[0])).display().to_string();
+            assert_eq!(Format::from_path(&path), Some(format));
+            assert_eq!(name.parse::<Format>(), Ok(format));
+
+            save(&program, &path, format).unwrap();
+            let loaded = load(&path, format).unwrap();
+            assert_eq!((loaded.data, loaded.code), (program.data.clone(), program.code.clone()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn logisim_without_its_extension() {
+        let directory = directory("logisim-name");
+        let path = directory.join("out.logisim").display().to_string();
+        save(&program(), &path, Format::Logisim).unwrap();
+
+        assert!(directory.join("out.data.lgs").exists());
+        assert!(directory.join("out.code.lgs").exists());
+        let loaded = load(&directory.join("out.lgs").display().to_string(), Format::Logisim).unwrap();
+        assert_eq!(loaded.code, program().code);
+    }
+
+    #[test]
+    fn images_larger_than_memory() {
+        assert!(parse_logisim("v2.0 raw\n100*1\n").is_ok());
+        assert!(parse_logisim("v2.0 raw\n300*1\n").is_err());
+        assert!(parse_logisim("v2.0 raw\n99999999999*1\n").is_err());
+        assert!(parse_logisim("v2.0 raw\n200*1 57*0\n").is_err());
+
+        let text = format!("code\n{}\n", "00 ".repeat(257));
+        assert!(parse_hex_text(&text).is_err());
+
+        let directory = directory("too-large");
+        for (name, format) in [("prog.bin", Format::Binary), ("prog.ihex", Format::IntelHex)] {
+            let path = directory.join(name).display().to_string();
+            let large = Program {
+                code: vec![0; 300],
+                ..Program::default()
+            };
+            save(&large, &path, format).unwrap();
+            let error = load(&path, format).unwrap_err();
+            assert!(error.contains("more than the 256"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Source formatter
+//! Prints a parsed file back out in one layout, so that files written by
+//! different people (or editors) look the same:
+//! ```text
+//! ; counts down from 3
+//! .equ START 3
+//!
+//! .data
+//! A:      DAT START       ; the counter
+//! ONE:    DAT 1
+//!
+//! .code
+//! loop:   LDA A
+//!         SUB ONE
+//!         STA A
+//!         JNZ loop
+//!         HLT
+//! ```
+//! Labels start each line and the mnemonic (or directive) comes after
+//! them, in a column wide enough for the longest labels of the file
+//! (other than those on a line of their own).
+//! Mnemonics are written in upper case and directives in lower case.
+//! Operands follow the mnemonic, with one space around each operator and
+//! after each comma. Section directives and anything before the first
+//! section start at the beginning of the line, with a blank line before
+//! each section. Comments after a line are lined up in one column for the
+//! whole file, after the longest line with such a comment, and comments on
+//! a line of their own keep to the start of the line or the mnemonic
+//! column, whichever is nearer to where they were. Runs of blank lines
+//! become one.
+//!
+//! Only the text of the file itself is changed: included files are left
+//! alone and the lines of a macro body are only re-indented (and their
+//! mnemonics and directives re-cased). A file that
+//! does not parse is not formatted, and formatting is checked to assemble
+//! to the same bytes as before.
+
+use super::ast::{Ast, Comment, DataValue, Expr, Item, Label};
+use super::lexer::{self, Span, Token, TokenKind};
+use super::{assemble_with, parser, Options, Section};
+
+/// Narrowest column for the mnemonic
+const MIN_LABEL_WIDTH: usize = 8;
+
+/// Columns are rounded up to multiples of this
+const TAB_WIDTH: usize = 4;
+
+/// Width of a mnemonic (operands of shorter ones line up with the rest)
+const MNEMONIC_WIDTH: usize = 3;
+
+/// Format source text, or return the problems that stop it being
+/// formatted
+pub fn format(source: &str, options: &Options) -> Result<String, Vec<String>> {
+    let (ast, diagnostics) = parser::parse_with(source, options);
+    // A line with a problem is left out of the tree, so would be lost
+    if !diagnostics.is_empty() {
+        let name = |span: Span| ast.files.get(span.file).map_or("", String::as_str);
+        return Err(diagnostics.iter().map(|d| format!("{}:{}", name(d.span), d)).collect());
+    }
+
+    let mut formatter = Formatter {
+        lines: source.split('\n').collect(),
+        label_width: MIN_LABEL_WIDTH,
+        rows: Vec::new(),
+    };
+    formatter.label_width = formatter.label_width(&ast);
+    formatter.ast(&ast);
+    let text = formatter.finish();
+
+    let before = assemble_with(source, options);
+    if !before.has_errors() {
+        let after = assemble_with(&text, options);
+        if after.program.data != before.program.data || after.program.code != before.program.code {
+            return Err(vec!["Formatting would change the assembled program, so the file was left as it is".to_string()]);
+        }
+    }
+
+    Ok(text)
+}
+
+/// A line of output
+#[derive(Clone, Debug, PartialEq)]
+enum Row {
+    Blank,
+    /// A comment on a line of its own, with its indentation
+    Comment(String),
+    /// A line and the comment after it
+    Line(String, Option<String>),
+}
+
+/// A line of a macro body, split into columns
+struct BodyLine<'a> {
+    labels: String,
+    keyword: &'a str,
+    arguments: &'a str,
+    comment: Option<Token<'a>>,
+}
+
+struct Formatter<'a> {
+    /// Text of each line of the file
+    lines: Vec<&'a str>,
+    /// Column of the mnemonic
+    label_width: usize,
+    rows: Vec<Row>,
+}
+
+impl<'a> Formatter<'a> {
+    /// Work out the mnemonic column from the longest labels in the file
+    fn label_width(&self, ast: &Ast) -> usize {
+        let mut widest = 0;
+        // Labels on a line of their own do not need to leave room
+        for item in all_items(ast).filter(|item| !matches!(item, Item::Labels(_))) {
+            widest = widest.max(labels_text(item.labels()).len());
+            if let Item::Macro(definition) = item {
+                for text in &definition.body {
+                    let mut tokens = Vec::new();
+                    lexer::lex_line(text, 0, &mut tokens);
+                    widest = widest.max(body_line(text, &tokens).map_or(0, |line| line.labels.len()));
+                }
+            }
+        }
+        round_up(widest + 1).max(MIN_LABEL_WIDTH)
+    }
+
+    /// Add the rows for a whole file
+    fn ast(&mut self, ast: &Ast) {
+        for item in &ast.preamble {
+            self.item(item, 0);
+        }
+        for node in &ast.sections {
+            self.blank();
+            let directive = match node.section {
+                Section::Data => ".data",
+                Section::Code => ".code",
+            };
+            self.rows.push(Row::Line(directive.to_string(), comment_text(&node.comment)));
+
+            // The section starts straight after its directive
+            for item in node.items.iter().skip_while(|item| matches!(item, Item::Blank(_))) {
+                self.item(item, self.label_width);
+            }
+        }
+    }
+
+    /// Add the rows for an item, with its mnemonic at a column
+    fn item(&mut self, item: &Item, column: usize) {
+        let comment = |comment: &Option<Comment>| comment_text(comment);
+        match item {
+            Item::Data(line) => {
+                let keyword = self.keyword_at(line.keyword_span);
+                let arguments = match &line.value {
+                    DataValue::Dat(value) => value.as_ref().map_or(String::new(), Expr::to_string),
+                    DataValue::Ascii { text, .. } => text.clone(),
+                    value => join(value.exprs()),
+                };
+                self.line(&line.labels, keyword, &arguments, comment(&line.comment), column);
+            },
+            Item::Code(line) => {
+                let operand = line.operand.as_ref().map_or(String::new(), Expr::to_string);
+                self.line(&line.labels, &line.opcode.to_string(), &operand, comment(&line.comment), column);
+            },
+            Item::Labels(line) => self.line(&line.labels, "", "", comment(&line.comment), column),
+            Item::Constant(line) => {
+                let keyword = if line.redefinable { ".set" } else { ".equ" };
+                let arguments = format!("{} {}", line.name.name, line.value);
+                self.line(&[], keyword, &arguments, comment(&line.comment), column);
+            },
+            Item::Linkage(line) => {
+                let keyword = self.keyword_at(line.span);
+                let names: Vec<&str> = line.names.iter().map(|name| name.name.as_str()).collect();
+                self.line(&[], keyword, &names.join(", "), comment(&line.comment), column);
+            },
+            Item::Macro(definition) => {
+                let mut arguments = definition.name.name.clone();
+                if !definition.params.is_empty() {
+                    let params: Vec<&str> = definition.params.iter().map(|param| param.name.as_str()).collect();
+                    arguments = format!("{} {}", arguments, params.join(", "));
+                }
+                self.line(&[], ".macro", &arguments, comment(&definition.comment), column);
+                for text in &definition.body {
+                    self.body_line(text);
+                }
+                self.line(&[], ".endm", "", comment(&definition.end_comment), column);
+            },
+            Item::Expansion(expansion) => {
+                let arguments = expansion.arguments.join(", ");
+                self.line(&expansion.labels, &expansion.name, &arguments, comment(&expansion.comment), column);
+            },
+            Item::Include(include) => {
+                let path = format!("\"{}\"", include.path);
+                self.line(&[], ".include", &path, comment(&include.comment), column);
+            },
+            Item::Comment(line) => self.comment(line.span.column, &line.text, column),
+            Item::Blank(_) => self.blank(),
+        }
+    }
+
+    /// Add a line of a macro body, which is kept as text
+    fn body_line(&mut self, text: &str) {
+        let column = self.label_width;
+        let mut tokens = Vec::new();
+        lexer::lex_line(text, 0, &mut tokens);
+        match body_line(text, &tokens) {
+            None => match tokens.first() {
+                Some(comment) => self.comment(comment.span.column, comment.text, column),
+                None => self.blank(),
+            },
+            Some(line) => {
+                let mut text = format!("{:<width$}", line.labels, width = column);
+                text.push_str(&columns(line.keyword, line.arguments));
+                let comment = line.comment.map(|comment| comment.text.trim_end().to_string());
+                self.rows.push(Row::Line(text.trim_end().to_string(), comment));
+            },
+        }
+    }
+
+    /// Add a line of labels, a keyword and its arguments
+    fn line(&mut self, labels: &[Label], keyword: &str, arguments: &str, comment: Option<String>, column: usize) {
+        let mut text = format!("{:<width$}", labels_text(labels), width = column);
+        text.push_str(&columns(keyword, arguments));
+        self.rows.push(Row::Line(text.trim_end().to_string(), comment));
+    }
+
+    /// Add a comment on a line of its own, at the start of the line if it
+    /// was there, otherwise at a column
+    fn comment(&mut self, written_at: usize, text: &str, column: usize) {
+        let indent = if written_at == 0 { 0 } else { column };
+        self.rows.push(Row::Comment(format!("{}{}", " ".repeat(indent), text.trim_end())));
+    }
+
+    /// Add a blank line, unless there is one already or nothing before it
+    fn blank(&mut self) {
+        if !matches!(self.rows.last(), None | Some(Row::Blank)) {
+            self.rows.push(Row::Blank);
+        }
+    }
+
+    /// Mnemonic or directive at a span of the file, in its usual case
+    fn keyword_at(&self, span: Span) -> &'a str {
+        let text = self.lines[span.line - 1].get(span.column..span.end()).unwrap_or("");
+        lexer::keyword(text).unwrap_or(text)
+    }
+
+    /// Line up the comments after lines and join the rows into text
+    fn finish(mut self) -> String {
+        if self.rows.last() == Some(&Row::Blank) {
+            self.rows.pop();
+        }
+
+        let widest = self
+            .rows
+            .iter()
+            .filter_map(|row| match row {
+                Row::Line(text, Some(_)) => Some(text.len()),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        let mut out = String::new();
+        for row in &self.rows {
+            match row {
+                Row::Blank => {},
+                Row::Comment(text) | Row::Line(text, None) => out.push_str(text),
+                Row::Line(text, Some(comment)) => {
+                    out.push_str(&format!("{:<width$}{}", text, comment, width = round_up(widest + 1)));
+                },
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Split a line of a macro body into columns (`None` if it is blank or
+/// only a comment)
+fn body_line<'a>(text: &'a str, tokens: &[Token<'a>]) -> Option<BodyLine<'a>> {
+    let (tokens, comment) = match tokens.split_last() {
+        Some((last, rest)) if last.kind == TokenKind::Comment => (rest, Some(*last)),
+        _ => (tokens, None),
+    };
+    if tokens.is_empty() {
+        return None;
+    }
+
+    // Labels with a colon, or one without that is followed by a mnemonic
+    let mut labels = Vec::new();
+    let mut rest = tokens;
+    while let [name, colon, tail @ ..] = rest {
+        if colon.kind != TokenKind::Colon {
+            break;
+        }
+        labels.push(format!("{}:", name.text));
+        rest = tail;
+    }
+    let is_keyword = |token: &Token| {
+        token.text == "DAT" || token.text.parse::<crate::cpu::instructions::Opcode>().is_ok() || token.kind == TokenKind::Directive
+    };
+    if let [label, keyword, ..] = rest {
+        if label.kind == TokenKind::Identifier && !is_keyword(label) && is_keyword(keyword) {
+            labels.push(label.text.to_string());
+            rest = &rest[1..];
+        }
+    }
+
+    let arguments = match (rest.get(1), rest.last()) {
+        (Some(first), Some(last)) => &text[first.span.column..last.span.end()],
+        _ => "",
+    };
+    Some(BodyLine {
+        labels: labels.join(" "),
+        keyword: rest.first().map_or("", |keyword| keyword.text),
+        arguments,
+        comment,
+    })
+}
+
+/// Every item of a file (not those of macro expansions or included files)
+fn all_items(ast: &Ast) -> impl Iterator<Item = &Item> {
+    ast.preamble.iter().chain(ast.sections.iter().flat_map(|node| node.items.iter()))
+}
+
+/// Labels as written at the start of a line
+fn labels_text(labels: &[Label]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|label| match label.colon {
+            true => format!("{}:", label.name),
+            false => label.name.clone(),
+        })
+        .collect();
+    labels.join(" ")
+}
+
+/// A keyword with its arguments in the column after it
+fn columns(keyword: &str, arguments: &str) -> String {
+    match arguments.is_empty() {
+        true => keyword.to_string(),
+        false => format!("{:<width$} {}", keyword, arguments, width = MNEMONIC_WIDTH),
+    }
+}
+
+/// Expressions separated by commas
+fn join(exprs: Vec<&Expr>) -> String {
+    exprs.iter().map(|expr| expr.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Text of a comment after a line
+fn comment_text(comment: &Option<Comment>) -> Option<String> {
+    comment.as_ref().map(|comment| comment.text.trim_end().to_string())
+}
+
+/// Round a column up to the next tab stop
+fn round_up(column: usize) -> usize {
+    column.div_ceil(TAB_WIDTH) * TAB_WIDTH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = "; counts down from 3
+.EQU START 3
+.DATA
+a:   dat START ; the counter
+ONE: DAT 1      ; one
+.macro dec  x
+  lda x   ; load
+  sub ONE
+  sta x
+.endm
+.Code
+loop:     dec a      ; count
+   jnz loop
+ ; done
+  hlt
+";
+
+    const FORMATTED: &str = "; counts down from 3
+.equ START 3
+
+.data
+a:      DAT START   ; the counter
+ONE:    DAT 1       ; one
+        .macro dec x
+        LDA x       ; load
+        SUB ONE
+        STA x
+        .endm
+
+.code
+loop:   dec a       ; count
+        JNZ loop
+        ; done
+        HLT
+";
+
+    #[test]
+    fn formats_any_case() {
+        assert_eq!(format(MESSY, &Options::default()), Ok(FORMATTED.to_string()));
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let once = format(MESSY, &Options::default()).unwrap();
+        assert_eq!(format(&once, &Options::default()), Ok(once));
+    }
+
+    #[test]
+    fn formatted_text_is_kept_byte_for_byte() {
+        assert_eq!(format(FORMATTED, &Options::default()), Ok(FORMATTED.to_string()));
+    }
+
+    #[test]
+    fn formatting_keeps_the_program() {
+        let before = assemble_with(MESSY, &Options::default());
+        let after = assemble_with(FORMATTED, &Options::default());
+        assert!(!before.has_errors());
+        assert_eq!(after.program.data, before.program.data);
+        assert_eq!(after.program.code, before.program.code);
+    }
+
+    #[test]
+    fn comments_line_up_across_blocks() {
+        let text = format(".data\nA: DAT 1 ; a\n\n.code\nLDA A ; a long line\nHLT ; stop\n", &Options::default()).unwrap();
+        let columns: Vec<usize> = text.lines().filter_map(|line| line.find(';')).collect();
+        assert_eq!(columns.len(), 3);
+        assert!(columns.iter().all(|column| *column == columns[0]));
+    }
+
+    #[test]
+    fn unparsable_files_are_not_formatted() {
+        assert!(format(".code\n        FOO 1\n", &Options::default()).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Splits source text into tokens
+//! Every token records where it came from so problems can be reported at
+//! the right place. Whitespace (spaces, tabs, `\r`) separates tokens and
+//! is otherwise ignored; line ends are kept as `Newline` tokens because
+//! the language is line based.
+//!
+//! Comments start with `;` or `//` and run to the end of the line. They
+//! are kept as tokens so tools such as the formatter can preserve them.
+//! (So `A//2` is `A` and a comment; division needs a space: `A / 2`.)
+//!
+//! Mnemonics and directives may be written in any case. Their tokens hold
+//! the usual spelling (`LDA`, `.data`), so nothing after the lexer needs to
+//! care how they were written.
+
+use crate::cpu::instructions::Opcode;
+
+/// Every directive, as usually written
+const DIRECTIVES: [&str; 16] = [
+    ".data", ".code", ".equ", ".set", ".global", ".extern", ".macro", ".endm", ".include", ".byte", ".word",
+    ".ascii", ".asciz", ".space", ".fill", ".align",
+];
+
+/// Location of some text in a source file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Line number (starting at 1)
+    pub line: usize,
+    /// Byte offset from the start of the line
+    pub column: usize,
+    /// Length in bytes
+    pub length: usize,
+    /// Index of the file in `Ast::files` (0 for the main file)
+    pub file: usize,
+}
+
+impl Span {
+    /// Span in the main file
+    pub fn new(line: usize, column: usize, length: usize) -> Span {
+        Span {
+            line,
+            column,
+            length,
+            file: 0,
+        }
+    }
+
+    /// Byte offset just past the end of the span
+    pub fn end(&self) -> usize {
+        self.column + self.length
+    }
+
+    /// Span from the start of this one to the end of another on the same
+    /// line
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            length: other.end().saturating_sub(self.column),
+            ..*self
+        }
+    }
+
+    /// Check if a column on a line of the main file is inside the span (or
+    /// just after it)
+    pub fn contains(&self, line: usize, column: usize) -> bool {
+        self.file == 0 && self.line == line && (self.column..=self.end()).contains(&column)
+    }
+}
+
+/// Kind of token
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Label or mnemonic, e.g. `loop` or `LDA`
+    Identifier,
+    /// Word starting with a digit or `$`, e.g. `12`, `0x0C` or `$0C`
+    Number,
+    /// Character in single quotes, e.g. `'A'` or `'\n'`
+    Char,
+    /// Text in double quotes, e.g. `"hello\n"`
+    String,
+    /// `+ - * / % & | ^ << >> ( )`
+    Operator,
+    /// `,` between arguments
+    Comma,
+    /// `.` followed by a word, e.g. `.data`
+    Directive,
+    /// `;` or `//` to the end of the line
+    Comment,
+    /// `:` after a label
+    Colon,
+    /// End of a line
+    Newline,
+    /// A character that cannot start any other token
+    Unknown,
+}
+
+/// A piece of source text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    pub span: Span,
+}
+
+/// Split the text of the main file into tokens
+/// The last line always ends with a `Newline` token.
+pub fn lex(source: &str) -> Vec<Token<'_>> {
+    lex_file(source, 0)
+}
+
+/// Split the text of a file (an index into `Ast::files`) into tokens
+pub fn lex_file(source: &str, file: usize) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+
+    for (index, line) in source.split('\n').enumerate() {
+        lex_line(line, index + 1, &mut tokens);
+        tokens.push(Token {
+            kind: TokenKind::Newline,
+            text: "",
+            span: Span::new(index + 1, line.len(), 0),
+        });
+    }
+
+    for token in &mut tokens {
+        token.span.file = file;
+    }
+    tokens
+}
+
+/// Split one line (without its `\n`) into tokens, without a `Newline`
+pub fn lex_line<'a>(line: &'a str, number: usize, tokens: &mut Vec<Token<'a>>) {
+    let bytes = line.as_bytes();
+    let mut position = 0;
+
+    while position < line.len() {
+        let c = line[position..].chars().next().unwrap();
+        let start = position;
+
+        let kind = if c.is_whitespace() {
+            position += c.len_utf8();
+            continue;
+        } else if c == ';' || line[position..].starts_with("//") {
+            position = line.trim_end_matches('\r').len().max(position + 1);
+            TokenKind::Comment
+        } else if c == '.' && bytes.get(position + 1).is_some_and(|b| is_word_byte(*b)) {
+            position = word_end(bytes, position + 1);
+            TokenKind::Directive
+        } else if c == ':' {
+            position += 1;
+            TokenKind::Colon
+        } else if c.is_ascii_digit() {
+            position = word_end(bytes, position);
+            TokenKind::Number
+        } else if c == '$' && bytes.get(position + 1).is_some_and(|b| is_word_byte(*b)) {
+            position = word_end(bytes, position + 1);
+            TokenKind::Number
+        } else if c == '\'' {
+            position = char_end(line, position);
+            TokenKind::Char
+        } else if c == '"' {
+            position = string_end(line, position);
+            TokenKind::String
+        } else if line[position..].starts_with("<<") || line[position..].starts_with(">>") {
+            position += 2;
+            TokenKind::Operator
+        } else if "+-*/%&|^()".contains(c) {
+            position += 1;
+            TokenKind::Operator
+        } else if c == ',' {
+            position += 1;
+            TokenKind::Comma
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            position = word_end(bytes, position);
+            TokenKind::Identifier
+        } else {
+            position += c.len_utf8();
+            TokenKind::Unknown
+        };
+
+        let text = &line[start..position];
+        tokens.push(Token {
+            kind,
+            text: match kind {
+                TokenKind::Identifier | TokenKind::Directive => keyword(text).unwrap_or(text),
+                _ => text,
+            },
+            span: Span::new(number, start, position - start),
+        });
+    }
+}
+
+/// The usual spelling of a mnemonic or directive written in any case
+pub fn keyword(text: &str) -> Option<&'static str> {
+    match text.starts_with('.') {
+        true => DIRECTIVES.iter().copied().find(|directive| directive.eq_ignore_ascii_case(text)),
+        false => text.parse::<Opcode>().ok().map(|opcode| opcode.name()),
+    }
+}
+
+/// Check if a byte can be part of an identifier or number
+pub fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+/// Find the end of the word starting at a position
+fn word_end(bytes: &[u8], mut position: usize) -> usize {
+    while position < bytes.len() && is_word_byte(bytes[position]) {
+        position += 1;
+    }
+    position
+}
+
+/// Find the end of the character literal starting at a position
+/// (a literal without a closing quote ends after its first character)
+fn char_end(line: &str, start: usize) -> usize {
+    let mut chars = line[start + 1..].char_indices();
+    let first = match chars.next() {
+        Some((_, '\\')) => chars.next().map_or(line.len(), |(i, c)| start + 1 + i + c.len_utf8()),
+        Some((i, c)) => start + 1 + i + c.len_utf8(),
+        None => return line.len(),
+    };
+
+    // Take everything up to a closing quote in the same word, so `'ab'`
+    // is one (invalid) literal
+    let word = line[first..].find(char::is_whitespace).map_or(line.len(), |i| first + i);
+    match line[first..word].find('\'') {
+        Some(i) => first + i + 1,
+        None => first,
+    }
+}
+
+/// Find the end of the string literal starting at a position
+/// (a string without a closing quote runs to the end of the line)
+fn string_end(line: &str, start: usize) -> usize {
+    let mut chars = line[start + 1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            },
+            '"' => return start + 1 + i + 1,
+            _ => {},
+        }
+    }
+    line.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Kind and text of each token of a line
+    fn tokens(line: &str) -> Vec<(TokenKind, &str)> {
+        let mut tokens = Vec::new();
+        lex_line(line, 1, &mut tokens);
+        tokens.into_iter().map(|token| (token.kind, token.text)).collect()
+    }
+
+    #[test]
+    fn kinds() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens("loop: LDA A+1, 'x' \"a b\" $0F ; done"),
+            vec![
+                (Identifier, "loop"),
+                (Colon, ":"),
+                (Identifier, "LDA"),
+                (Identifier, "A"),
+                (Operator, "+"),
+                (Number, "1"),
+                (Comma, ","),
+                (Char, "'x'"),
+                (String, "\"a b\""),
+                (Number, "$0F"),
+                (Comment, "; done"),
+            ]
+        );
+        assert_eq!(tokens("A<<2 ?"), vec![(Identifier, "A"), (Operator, "<<"), (Number, "2"), (Unknown, "?")]);
+    }
+
+    #[test]
+    fn slashes_start_a_comment() {
+        assert_eq!(tokens("A//2"), vec![(TokenKind::Identifier, "A"), (TokenKind::Comment, "//2")]);
+        assert_eq!(tokens("A / 2").len(), 3);
+    }
+
+    #[test]
+    fn keywords_in_any_case() {
+        assert_eq!(tokens(".DATA lda Dat loop"), tokens(".data LDA DAT loop"));
+        assert_eq!(tokens("loop")[0].1, "loop");
+        assert_eq!(tokens(".Unknown")[0].1, ".Unknown");
+    }
+
+    #[test]
+    fn spans() {
+        let tokens = lex("  LDA A\nHLT");
+        let spans: Vec<(usize, usize, usize)> =
+            tokens.iter().map(|token| (token.span.line, token.span.column, token.span.length)).collect();
+        assert_eq!(spans, vec![(1, 2, 3), (1, 6, 1), (1, 7, 0), (2, 0, 3), (2, 3, 0)]);
+        assert_eq!(tokens.last().unwrap().kind, TokenKind::Newline);
+    }
+
+    #[test]
+    fn unterminated_literals_end_the_line() {
+        assert_eq!(tokens("\"abc"), vec![(TokenKind::String, "\"abc")]);
+        assert_eq!(tokens("'a b"), vec![(TokenKind::Char, "'a"), (TokenKind::Identifier, "b")]);
+    }
+}
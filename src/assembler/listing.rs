@@ -0,0 +1,274 @@
+//! Assembly listings
+//! A listing shows every line of the main file with the address it was
+//! placed at, the bytes it assembled to and, for code, the instruction
+//! they decode to. The lines a macro use or `.include` brought in follow
+//! it, marked with `+`:
+//! ```text
+//!  line  addr  bytes        instruction   source
+//!     1                                   .data
+//!     2  D:00  04                             A:  DAT 4
+//!     5                                   .code
+//!     6  C:00  06 00        LDA 0x00          LDA A
+//!     9                                       COUNTDOWN A
+//!        C:02  06 00        LDA 0x00      +       LDA var
+//! ```
+//! Addresses are in data (`D`) or instruction (`C`) memory. Problems are
+//! shown under the line they are on. At the end come the symbol table and
+//! a cross-reference of where each label and constant is defined and
+//! used.
+
+use super::ast::Linkage;
+use super::lexer::Span;
+use super::{Assembly, Emitted, Section};
+use crate::cpu::instructions::Instruction;
+
+/// Bytes shown on each row
+const ROW_BYTES: usize = 4;
+
+/// Make the listing of an assembled file from its source text
+pub fn listing(assembly: &Assembly, source: &str) -> String {
+    // Text of every file, reading included ones again
+    let files: Vec<Vec<String>> = assembly
+        .ast
+        .files
+        .iter()
+        .enumerate()
+        .map(|(index, name)| match index {
+            0 => source.to_string(),
+            _ => std::fs::read_to_string(name).unwrap_or_default(),
+        })
+        .map(|text| text.lines().map(str::to_string).collect())
+        .collect();
+    let text_of = |span: Span| {
+        let line = files.get(span.file).and_then(|lines| lines.get(span.line - 1));
+        line.map_or("", String::as_str)
+    };
+
+    let mut out = Vec::new();
+    out.push(format!("; listing of {}", assembly.file_name(Span::default())));
+    out.push(" line  addr  bytes        instruction   source".to_string());
+
+    for (index, text) in files[0].iter().enumerate() {
+        let line = index + 1;
+        let mut number = Some(line);
+        let mut own = false;
+
+        for emitted in assembly.emitted.iter().filter(|emitted| emitted.origin == line) {
+            // A line of the main file, or one it brought in
+            let here = emitted.span.file == 0 && emitted.span.line == line;
+            let source = match here {
+                true => text.clone(),
+                false => format!("+   {}", text_of(emitted.span)),
+            };
+            if !here && !own && number.is_some() {
+                out.push(row(number.take(), "", "", "", text));
+            }
+            own |= here;
+            emit(&mut out, assembly, emitted, number.take(), &source);
+        }
+        if number.is_some() {
+            out.push(row(number, "", "", "", text));
+        }
+
+        // Problems on the line, or in a file it included
+        for d in &assembly.diagnostics {
+            let on_line = match d.span.file {
+                0 => d.span.line == line,
+                file => assembly.ast.include_of(file).is_some_and(|include| include.line == line),
+            };
+            if on_line {
+                out.push(format!("*** {}:{}", assembly.file_name(d.span), d));
+            }
+        }
+    }
+
+    symbols(&mut out, assembly);
+    out.push(String::new());
+    out.join("\n")
+}
+
+/// Add the rows for the memory taken up by a line
+fn emit(out: &mut Vec<String>, assembly: &Assembly, emitted: &Emitted, number: Option<usize>, source: &str) {
+    let program = &assembly.program;
+    let memory = match emitted.section {
+        Section::Data => &program.data,
+        Section::Code => &program.code,
+    };
+    let start = emitted.address as usize;
+    let bytes = memory.get(start..start + emitted.size as usize).unwrap_or_default();
+
+    let instruction = match (emitted.section, bytes) {
+        (Section::Code, [opcode, operand]) => {
+            let word = u16::from_be_bytes([*opcode, *operand]);
+            Instruction::try_from_word(word).map_or("???".to_string(), |instruction| instruction.to_string())
+        },
+        _ => String::new(),
+    };
+
+    // Long data carries on over several rows
+    let mut chunks = bytes.chunks(ROW_BYTES);
+    let first = chunks.next().unwrap_or_default();
+    out.push(row(number, &address(emitted.section, start), &hex(first), &instruction, source));
+    for (index, chunk) in chunks.enumerate() {
+        let address = address(emitted.section, start + (index + 1) * ROW_BYTES);
+        out.push(row(None, &address, &hex(chunk), "", ""));
+    }
+}
+
+/// Add the symbol table and cross-reference
+fn symbols(out: &mut Vec<String>, assembly: &Assembly) {
+    let externs: Vec<&str> = assembly
+        .ast
+        .linkages()
+        .filter(|line| line.linkage == Linkage::Extern)
+        .flat_map(|line| line.names.iter().map(|name| name.name.as_str()))
+        .collect();
+    let mut labels: Vec<(&str, String)> = assembly
+        .program
+        .symbols
+        .iter()
+        .map(|symbol| (symbol.name.as_str(), address(symbol.section, symbol.address as usize)))
+        .collect();
+    labels.extend(externs.iter().map(|name| (*name, "ext".to_string())));
+
+    let constants: Vec<(&str, String)> = assembly
+        .constants
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.to_string()))
+        .collect();
+
+    let width = labels.iter().chain(&constants).map(|(name, _)| name.len()).max().unwrap_or(0).max(8);
+    for (title, value, entries) in [("symbol", "addr", labels), ("constant", "value", constants)] {
+        if entries.is_empty() {
+            continue;
+        }
+
+        out.push(String::new());
+        out.push(format!("{:<width$}  {:<5} {:<10} used", title, value, "defined", width = width));
+        for (name, value) in entries {
+            let sites = |definition: bool| -> Vec<String> {
+                let mut sites: Vec<String> = assembly
+                    .labels
+                    .iter()
+                    .filter(|site| site.name == name && site.definition == definition)
+                    .map(|site| match site.span.file {
+                        0 => site.span.line.to_string(),
+                        _ => format!("{}:{}", assembly.file_name(site.span), site.span.line),
+                    })
+                    .collect();
+                // Once per line, however often it is used there
+                sites.dedup();
+                sites
+            };
+            let defined = sites(true).join(", ");
+            let used = sites(false).join(", ");
+            let text = format!("{:<width$}  {:<5} {:<10} {}", name, value, defined, used, width = width);
+            out.push(text.trim_end().to_string());
+        }
+    }
+}
+
+/// Format a row of the listing
+fn row(number: Option<usize>, address: &str, bytes: &str, instruction: &str, source: &str) -> String {
+    let number = number.map_or(String::new(), |number| number.to_string());
+    let text = format!("{:>5}  {:<5} {:<12} {:<13} {}", number, address, bytes, instruction, source);
+    text.trim_end().to_string()
+}
+
+/// Format an address with the memory it is in
+fn address(section: Section, address: usize) -> String {
+    match section {
+        Section::Data => format!("D:{:02X}", address),
+        Section::Code => format!("C:{:02X}", address),
+    }
+}
+
+/// Format bytes as hex
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble_listing, assemble_with, Options};
+
+    const SOURCE: &str = ".equ START 3
+.macro DOWN var
+        LDA var
+        SUB ONE
+.endm
+.data
+A:      DAT START
+ONE:    DAT 1
+TEXT:   .ascii \"hello\"
+.code
+loop:   DOWN A
+        STA A
+        JNZ loop
+        FOO
+        HLT
+";
+
+    const LISTING: &str = "; listing of countdown.vnc
+ line  addr  bytes        instruction   source
+    1                                   .equ START 3
+    2                                   .macro DOWN var
+    3                                           LDA var
+    4                                           SUB ONE
+    5                                   .endm
+    6                                   .data
+    7  D:00  03                         A:      DAT START
+    8  D:01  01                         ONE:    DAT 1
+    9  D:02  68 65 6C 6C                TEXT:   .ascii \"hello\"
+       D:06  6F
+   10                                   .code
+   11                                   loop:   DOWN A
+       C:00  06 00        LDA 0x00      +           LDA var
+       C:02  02 01        SUB 0x01      +           SUB ONE
+   12  C:04  05 00        STA 0x00              STA A
+   13  C:06  0D 00        JNZ 0x00              JNZ loop
+   14                                           FOO
+*** countdown.vnc:14:9: error: Unknown instruction 'FOO' (add ':' to define a label)
+   15  C:08  0E 00        HLT                   HLT
+
+symbol    addr  defined    used
+A         D:00  7          11, 12
+ONE       D:01  8          4
+TEXT      D:02  9
+loop      C:00  11         13
+
+constant  value defined    used
+START     3     1          7
+";
+
+    #[test]
+    fn lists_every_line() {
+        let assembly = assemble_with(SOURCE, &Options::for_file("countdown.vnc"));
+        assert_eq!(listing(&assembly, SOURCE), LISTING);
+    }
+
+    #[test]
+    fn included_lines_follow_the_include() {
+        let directory = std::env::temp_dir().join(format!("vnc-listing-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let lib = directory.join("lib.vnc");
+        std::fs::write(&lib, "ONE:    DAT 1\nTWO:    DAT 2\n").unwrap();
+        let main = directory.join("main.vnc");
+        std::fs::write(&main, ".data\n.include \"lib.vnc\"\n.code\n        LDA TWO\n").unwrap();
+        let listing = assemble_listing(&main.display().to_string()).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[3], "    2                                   .include \"lib.vnc\"");
+        assert_eq!(lines[4], "       D:00  01                         +   ONE:    DAT 1");
+        assert_eq!(lines[5], "       D:01  02                         +   TWO:    DAT 2");
+        assert!(lines.contains(&format!("TWO       D:01  {}:2 4", lib.display()).as_str()), "{}", listing);
+    }
+
+    #[test]
+    fn unreadable_files_are_errors() {
+        let error = assemble_listing("no-such-file.vnc").unwrap_err();
+        assert!(error.starts_with("no-such-file.vnc: error: "), "{}", error);
+    }
+}
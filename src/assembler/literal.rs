@@ -0,0 +1,158 @@
+//! Numeric and character literals
+//! ```text
+//! 42  -42         decimal
+//! 0x2A  $2A       hexadecimal
+//! 0b101010        binary
+//! 0o52            octal
+//! 'A'  '\n'       ASCII character
+//! "hi\n"          ASCII string (for `.ascii` and `.asciz`)
+//! ```
+//! Characters and strings understand the escapes `\n \t \r \0 \\ \' \"`.
+//! A value must fit in a word: from -2^(WORD_BITS-1) (stored as two's
+//! complement) up to 2^WORD_BITS - 1.
+
+use super::WORD_BITS;
+
+/// Parse a number token
+pub fn parse_number(text: &str) -> Result<i64, String> {
+    let prefixes = [
+        ("0x", 16, "hexadecimal"),
+        ("0X", 16, "hexadecimal"),
+        ("$", 16, "hexadecimal"),
+        ("0b", 2, "binary"),
+        ("0B", 2, "binary"),
+        ("0o", 8, "octal"),
+        ("0O", 8, "octal"),
+    ];
+
+    let (digits, radix, name) = prefixes
+        .iter()
+        .find_map(|(prefix, radix, name)| text.strip_prefix(prefix).map(|digits| (digits, *radix, *name)))
+        .unwrap_or((text, 10, "decimal"));
+
+    if digits.is_empty() {
+        return Err(format!("Missing digits after '{}'", text));
+    }
+
+    match i64::from_str_radix(digits, radix) {
+        Ok(value) => Ok(value),
+        Err(e) if *e.kind() == std::num::IntErrorKind::PosOverflow => Err(format!("Number '{}' is too large", text)),
+        Err(_) => Err(format!("Invalid {} number '{}'", name, text)),
+    }
+}
+
+/// Parse a character literal token (including its quotes)
+pub fn parse_char(text: &str) -> Result<i64, String> {
+    let inner = text
+        .strip_prefix('\'')
+        .and_then(|t| t.strip_suffix('\''))
+        .filter(|t| !t.is_empty())
+        .ok_or_else(|| format!("Unterminated character literal {}", text))?;
+
+    let mut chars = inner.chars();
+    let c = match (chars.next(), chars.next(), chars.next()) {
+        (Some('\\'), Some(c), None) => escape(c).ok_or_else(|| format!("Unknown escape {}", text))?,
+        (Some(c), None, _) if c != '\\' => c,
+        _ => return Err(format!("Character literal {} holds more than one character", text)),
+    };
+
+    if !c.is_ascii() {
+        return Err(format!("Character {} is not ASCII", text));
+    }
+    Ok(c as i64)
+}
+
+/// Parse a string literal token (including its quotes) into its bytes
+pub fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .ok_or_else(|| format!("Unterminated string {}", text))?;
+
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some(escaped) => escape(escaped).ok_or_else(|| format!("Unknown escape \\{} in string", escaped))?,
+                None => return Err(format!("Unterminated string {}", text)),
+            },
+            c if !c.is_ascii() => return Err(format!("Character '{}' in string is not ASCII", c)),
+            c => c,
+        };
+        bytes.push(c as u8);
+    }
+    Ok(bytes)
+}
+
+/// Get the character for an escape (the character after a `\`)
+fn escape(c: char) -> Option<char> {
+    let escaped = match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        '\\' => '\\',
+        '\'' => '\'',
+        '"' => '"',
+        _ => return None,
+    };
+    Some(escaped)
+}
+
+/// Convert a value to a word, storing negative values as two's complement
+pub fn to_word(value: i64) -> Result<u8, String> {
+    fit(value, WORD_BITS).map(|value| value as u8)
+}
+
+/// Check that a value fits in a number of bits, storing negative values
+/// as two's complement
+pub fn fit(value: i64, bits: u32) -> Result<u64, String> {
+    let min = -(1i64 << (bits - 1));
+    let max = (1i64 << bits) - 1;
+
+    if value < min || value > max {
+        return Err(format!(
+            "Value {} does not fit in {} bits (expected {} to {})",
+            value, bits, min, max
+        ));
+    }
+    Ok(value as u64 & max as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        for text in ["42", "0x2A", "0X2a", "$2A", "0b101010", "0o52"] {
+            assert_eq!(parse_number(text), Ok(42), "{}", text);
+        }
+        assert_eq!(parse_number("0x"), Err("Missing digits after '0x'".to_string()));
+        assert_eq!(parse_number("0b102"), Err("Invalid binary number '0b102'".to_string()));
+        assert_eq!(parse_number("99999999999999999999"), Err("Number '99999999999999999999' is too large".to_string()));
+    }
+
+    #[test]
+    fn characters_and_strings() {
+        assert_eq!(parse_char("'A'"), Ok(65));
+        assert_eq!(parse_char("'\\n'"), Ok(10));
+        assert!(parse_char("'ab'").is_err());
+        assert!(parse_char("'é'").is_err());
+        assert!(parse_char("'\\q'").is_err());
+        assert_eq!(parse_string("\"hi\\0\""), Ok(vec![b'h', b'i', 0]));
+        assert!(parse_string("\"hi").is_err());
+        assert!(parse_string("\"\\\"").is_err());
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(to_word(255), Ok(255));
+        assert_eq!(to_word(-1), Ok(255));
+        assert_eq!(to_word(-128), Ok(128));
+        assert!(to_word(256).is_err());
+        assert!(to_word(-129).is_err());
+        assert_eq!(fit(-1, 16), Ok(0xFFFF));
+    }
+}
@@ -0,0 +1,1093 @@
+//! Assembles a source file into a binary file
+//! 0x01 and onwards are all instructions
+//! Data comes first, then code
+//! 
+//! Example source file:
+//! ```
+//! .data
+//!     A:  DAT 4
+//!     B:  DAT 2
+//! 
+//! .code
+//!     LDA A
+//!     ADD B
+//!     OUT
+//!     HLT
+//! ```
+//! 
+//! This will compile in the following format:
+//! "VNC" version data_length (value)* (opcode operand)*
+//! version: version of the format (1 byte, see `BINARY_VERSION`)
+//! data_length: number of data bytes (2 bytes, big endian)
+//! value: the value of the variable in binary
+//! opcode: the opcode in binary
+//! operand: the operand in binary (labels are resolved to addresses)
+//!
+//! Images without the "VNC" header are loaded the way they were before it
+//! existed: the whole image is code.
+//!
+//! Labels are defined with a trailing colon (`loop: LDA A`), or without
+//! one when followed by `DAT` or a mnemonic. A line may define several
+//! labels, and a line holding only labels names the next cell or
+//! instruction. Labels in `.data` resolve to their data memory address,
+//! labels in `.code` resolve to their instruction memory address (2 bytes
+//! per instruction).
+//!
+//! Values can be written in decimal (`42`, `-1`), hexadecimal (`0x2A`,
+//! `$2A`), binary (`0b101010`), octal (`0o52`) or as an ASCII character
+//! (`'*'`). Negative values are stored as two's complement. Values and
+//! operands can be expressions of these, labels and constants:
+//! ```text
+//! .equ SIZE 8
+//! .data
+//!     TABLE:  DAT SIZE*2
+//!     RANGE:  DAT 'Z'-'A'
+//! .code
+//!     LDA TABLE+2
+//! ```
+//!
+//! Besides `DAT`, the data section accepts directives that lay out
+//! several bytes under one label: `.byte 1, 2, 3`, `.word 1000` (2 bytes,
+//! high byte first), `.ascii "hello"`, `.asciz "hello"` (0 terminated),
+//! `.space 16`, `.fill 16, 0xFF` and `.align 4`.
+//!
+//! Repeated code can be written once as a macro and used like an
+//! instruction. Labels defined inside a macro are local to each use:
+//! ```text
+//! .macro COUNTDOWN var
+//! loop:
+//!     LDA var
+//!     SUB ONE
+//!     STA var
+//!     JNZ loop
+//! .endm
+//! .code
+//!     COUNTDOWN A
+//! ```
+//!
+//! Other files can be pulled in with `.include "lib.vnc"`. They are looked
+//! for next to the including file, then in the directories listed in the
+//! `VNC_INCLUDE` environment variable.
+//!
+//! A file can also be assembled on its own into a relocatable object (see
+//! `object`) and combined with others by the `linker`. Labels named with
+//! `.global` can be used by other objects, which name them with `.extern`:
+//! ```text
+//! .global print           .extern print
+//! .code                   .code
+//! print: OUT                  LDA A
+//!        HLT                  JMP print
+//! ```
+//!
+//! Assembling happens in stages:
+//! 1. `lexer` splits the text into tokens with their positions
+//! 2. `parser` builds an `ast::Ast` of sections and lines
+//! 3. Labels are given addresses
+//! 4. `eval` works out every expression and the bytes are emitted
+
+pub mod ast;
+pub mod eval;
+pub mod formats;
+pub mod formatter;
+pub mod lexer;
+pub mod listing;
+pub mod literal;
+pub mod object;
+pub mod parser;
+pub mod source_map;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::{fs::File, io::Read};
+use std::io::Write;
+
+use self::ast::{Ast, DataLine, DataValue, Item, Linkage, Placed};
+use self::eval::Scope;
+use self::lexer::Span;
+use self::object::{Object, ObjectSymbol, Relocation};
+use self::source_map::{SourceLocation, SourceMap};
+
+/// Number of bits in a data word
+pub const WORD_BITS: u32 = 8;
+
+/// Environment variable listing directories searched by `.include`
+/// (separated like `PATH`)
+pub const INCLUDE_PATH_VARIABLE: &str = "VNC_INCLUDE";
+
+/// Where source text comes from, where to look for files it includes and
+/// what to assemble it into
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Path of the source file, for diagnostics and finding included files
+    /// next to it
+    pub path: Option<PathBuf>,
+    /// Directories searched by `.include` after the including file's own
+    pub include_paths: Vec<PathBuf>,
+    /// Assemble a relocatable object for the linker instead of a program
+    pub relocatable: bool,
+}
+
+impl Options {
+    /// Options for a source file, searching the include paths listed in
+    /// `VNC_INCLUDE`
+    pub fn for_file(path: impl Into<PathBuf>) -> Options {
+        let include_paths = match std::env::var_os(INCLUDE_PATH_VARIABLE) {
+            Some(paths) => std::env::split_paths(&paths).collect(),
+            None => Vec::new(),
+        };
+        Options {
+            path: Some(path.into()),
+            include_paths,
+            relocatable: false,
+        }
+    }
+}
+
+/// First bytes of every binary image
+pub const BINARY_MAGIC: &[u8; 3] = b"VNC";
+
+/// Version of the binary image format written by `Program::to_binary`
+pub const BINARY_VERSION: u8 = 1;
+
+/// Bytes before the data section: magic, version and data length
+const HEADER_LENGTH: usize = BINARY_MAGIC.len() + 3;
+
+/// Save a binary to a file
+pub fn save_to_file(binary: Vec<u8>, filename: &str) -> std::io::Result<()> {
+    let mut file = File::create(filename)?;
+    file.write_all(&binary)
+}
+
+/// Load a binary from a file
+pub fn load_from_file(filename: &str) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(filename)?;
+    let mut binary = Vec::new();
+    
+    file.read_to_end(&mut binary)?;
+
+    Ok(binary)
+}
+
+/// How serious a diagnostic is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// The program cannot be assembled
+    Error,
+    /// The program assembles, but probably not as intended
+    Warning,
+}
+
+/// A problem found in a source file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+}
+
+/// Where a label is defined or used in a source file
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelSite {
+    pub name: String,
+    pub span: Span,
+    /// Whether this is the definition rather than a use
+    pub definition: bool,
+}
+
+/// Everything learnt from assembling source text
+#[derive(Clone, Debug, Default)]
+pub struct Assembly {
+    /// The program (only usable if there are no errors)
+    pub program: Program,
+    pub ast: Ast,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Every label and constant definition and use, in source order
+    pub labels: Vec<LabelSite>,
+    /// Value of every constant (its last definition for `.set`), by name
+    pub constants: Vec<(String, i64)>,
+    /// The relocatable object, if assembled with `Options::relocatable`
+    /// (only usable if there are no errors)
+    pub object: Option<Object>,
+    /// Memory taken up by each line, in source order
+    pub emitted: Vec<Emitted>,
+}
+
+/// Memory taken up by a data or code line
+#[derive(Clone, Debug, PartialEq)]
+pub struct Emitted {
+    /// Line of the main file it belongs to (that of the macro use or
+    /// `.include` it came from, if any)
+    pub origin: usize,
+    /// `DAT`, the data directive or the mnemonic
+    pub span: Span,
+    pub section: Section,
+    pub address: u32,
+    /// Number of bytes (including any padding before it)
+    pub size: u32,
+}
+
+impl Assembly {
+    /// Check if any diagnostic is an error
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|d| d.severity == Severity::Error)
+    }
+
+    /// Find the label definition or use covering a position in the main
+    /// file
+    pub fn label_at(&self, line: usize, column: usize) -> Option<&LabelSite> {
+        self.labels.iter().find(|site| site.span.contains(line, column))
+    }
+
+    /// Find where a label is defined
+    pub fn definition_of(&self, name: &str) -> Option<&LabelSite> {
+        self.labels.iter().find(|site| site.definition && site.name == name)
+    }
+
+    /// Name of the file a span is in
+    pub fn file_name(&self, span: Span) -> &str {
+        self.ast.files.get(span.file).map_or("", String::as_str)
+    }
+
+    fn warning(&mut self, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            span,
+            message,
+        });
+    }
+}
+
+/// Memory a symbol lives in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Section {
+    Data,
+    Code,
+}
+
+/// A resolved label
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub address: u32,
+}
+
+/// Every label defined by a program, in definition order
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    by_name: HashMap<String, usize>,
+}
+
+impl SymbolTable {
+    /// Create an empty symbol table
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    /// Define a label, panicking if it already exists
+    pub fn insert(&mut self, name: &str, section: Section, address: u32) {
+        if self.by_name.contains_key(name) {
+            panic!("Duplicate label: {}", name);
+        }
+
+        self.by_name.insert(name.to_string(), self.symbols.len());
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            section,
+            address,
+        });
+    }
+
+    /// Look up a label
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name).map(|i| &self.symbols[*i])
+    }
+
+    /// Look up the address of a label
+    pub fn address_of(&self, name: &str) -> Option<u32> {
+        self.get(name).map(|symbol| symbol.address)
+    }
+
+    /// Find the label defined at an address
+    pub fn label_at(&self, section: Section, address: u32) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.section == section && symbol.address == address)
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    /// Find the closest label at or before an address
+    /// (the first one defined if several share an address)
+    pub fn nearest(&self, section: Section, address: u32) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .rev()
+            .filter(|symbol| symbol.section == section && symbol.address <= address)
+            .max_by_key(|symbol| symbol.address)
+    }
+
+    /// Iterate over every symbol in definition order
+    pub fn iter(&self) -> std::slice::Iter<'_, Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Number of symbols
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Whether there are no symbols
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+/// An assembled program
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    /// Initial contents of data memory
+    pub data: Vec<u8>,
+    /// Contents of instruction memory
+    pub code: Vec<u8>,
+    /// Labels and their addresses
+    pub symbols: SymbolTable,
+    /// Source line of each instruction
+    pub code_lines: Vec<usize>,
+    /// File, line and text of each instruction and the labels of data
+    pub source_map: SourceMap,
+}
+
+impl Program {
+    /// Get the binary image of the program
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = Vec::with_capacity(HEADER_LENGTH + self.data.len() + self.code.len());
+        binary.extend_from_slice(BINARY_MAGIC);
+        binary.push(BINARY_VERSION);
+        binary.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        binary.extend_from_slice(&self.data);
+        binary.extend_from_slice(&self.code);
+        binary
+    }
+
+    /// Split a binary image back into its data and code
+    /// (symbols are not part of the image)
+    pub fn from_binary(binary: &[u8]) -> Result<Program, String> {
+        let header = match binary.strip_prefix(BINARY_MAGIC) {
+            Some(header) => header,
+            // Written before the header existed: nothing but code
+            None => {
+                return Ok(Program {
+                    code: binary.to_vec(),
+                    ..Program::default()
+                })
+            },
+        };
+
+        let (version, data_length, rest) = match header {
+            [version, high, low, rest @ ..] => (*version, u16::from_be_bytes([*high, *low]) as usize, rest),
+            _ => return Err("Binary is too short to contain a header".to_string()),
+        };
+        if version != BINARY_VERSION {
+            return Err(format!("Unsupported binary version {} (expected {})", version, BINARY_VERSION));
+        }
+        if rest.len() < data_length {
+            return Err(format!(
+                "Binary is shorter than its data section ({} bytes, header says {})",
+                rest.len(),
+                data_length
+            ));
+        }
+
+        Ok(Program {
+            data: rest[..data_length].to_vec(),
+            code: rest[data_length..].to_vec(),
+            ..Program::default()
+        })
+    }
+
+    /// Get the source line of the instruction at an address
+    pub fn line_of(&self, address: u32) -> Option<usize> {
+        self.code_lines.get(address as usize / 2).copied()
+    }
+
+    /// Get the address of the first instruction on a source line
+    pub fn address_of_line(&self, line: usize) -> Option<u32> {
+        self.code_lines
+            .iter()
+            .position(|l| *l == line)
+            .map(|index| index as u32 * 2)
+    }
+
+    /// Get the first instruction on or after a source line, with the
+    /// line it is on
+    pub fn next_code_line(&self, line: usize) -> Option<(u32, usize)> {
+        self.code_lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| **l >= line)
+            .min_by_key(|(_, l)| **l)
+            .map(|(index, l)| (index as u32 * 2, *l))
+    }
+}
+
+/// Assemble a source file into a binary file
+pub fn assemble(source_path: &str) -> Vec<u8> {
+    assemble_program(source_path).to_binary()
+}
+
+/// Assemble a source file into a program
+/// Panics with every error found if the source is invalid
+pub fn assemble_program(source_path: &str) -> Program {
+    try_assemble_program(source_path).unwrap_or_else(|errors| panic!("{}", errors))
+}
+
+/// Assemble a source file into a program, or return every error found
+/// (one per line, as `file:line:column: error: message`)
+pub fn try_assemble_program(source_path: &str) -> Result<Program, String> {
+    let source = std::fs::read_to_string(source_path).map_err(|e| format!("{}: error: {}", source_path, e))?;
+    let assembly = assemble_with(&source, &Options::for_file(source_path));
+    check_errors(&assembly)?;
+    Ok(assembly.program)
+}
+
+/// Assemble a source file into a relocatable object
+/// Panics with every error found if the source is invalid
+pub fn assemble_object(source_path: &str) -> Object {
+    try_assemble_object(source_path).unwrap_or_else(|errors| panic!("{}", errors))
+}
+
+/// Assemble a source file into a relocatable object, or return every
+/// error found
+pub fn try_assemble_object(source_path: &str) -> Result<Object, String> {
+    let source = std::fs::read_to_string(source_path).map_err(|e| format!("{}: error: {}", source_path, e))?;
+    let options = Options {
+        relocatable: true,
+        ..Options::for_file(source_path)
+    };
+    let assembly = assemble_with(&source, &options);
+    check_errors(&assembly)?;
+    Ok(assembly.object.unwrap())
+}
+
+/// Assemble a source file and make its listing (see `listing`), which
+/// shows any problems found
+/// Fails only if the file cannot be read
+pub fn assemble_listing(source_path: &str) -> Result<String, String> {
+    let source = std::fs::read_to_string(source_path).map_err(|e| format!("{}: error: {}", source_path, e))?;
+    let assembly = assemble_with(&source, &Options::for_file(source_path));
+    Ok(listing::listing(&assembly, &source))
+}
+
+/// Every error found while assembling, one per line, if there are any
+fn check_errors(assembly: &Assembly) -> Result<(), String> {
+    if assembly.has_errors() {
+        let errors: Vec<String> = assembly
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| format!("{}:{}", assembly.file_name(d.span), d))
+            .collect();
+        return Err(errors.join("\n"));
+    }
+    Ok(())
+}
+
+/// Assemble source text, collecting problems instead of stopping at the
+/// first one
+pub fn assemble_source(source: &str) -> Assembly {
+    assemble_with(source, &Options::default())
+}
+
+/// Assemble source text from a file, collecting problems instead of
+/// stopping at the first one
+pub fn assemble_with(source: &str, options: &Options) -> Assembly {
+    let (ast, diagnostics) = parser::parse_with(source, options);
+    let mut assembly = Assembly {
+        diagnostics,
+        ..Assembly::default()
+    };
+
+    // Every item with where it came from
+    let items = ast.placed();
+
+    // Record where labels and constants are defined and used
+    // (a `.set` of an existing `.set` constant counts as a use, and the
+    // labels a macro defines for itself are left out)
+    let mut redefinable = Vec::new();
+    for &Placed { item, expansion, .. } in &items {
+        let local = |name: &str| expansion.is_some_and(|expansion| expansion.locals.iter().any(|l| l == name));
+
+        for label in item.labels().iter().filter(|label| !local(&label.name)) {
+            assembly.labels.push(LabelSite {
+                name: label.name.clone(),
+                span: label.span,
+                definition: true,
+            });
+        }
+
+        let exprs = match item {
+            Item::Data(line) => line.value.exprs(),
+            Item::Code(line) => line.operand.iter().collect(),
+            Item::Constant(line) => {
+                let name = &line.name.name;
+                if !local(name) {
+                    assembly.labels.push(LabelSite {
+                        name: name.clone(),
+                        span: line.name.span,
+                        definition: !(line.redefinable && redefinable.contains(name)),
+                    });
+                }
+                if line.redefinable {
+                    redefinable.push(name.clone());
+                }
+                vec![&line.value]
+            },
+            // `.extern` stands in for the definition in another object
+            Item::Linkage(line) => {
+                for label in line.names.iter().filter(|label| !local(&label.name)) {
+                    assembly.labels.push(LabelSite {
+                        name: label.name.clone(),
+                        span: label.span,
+                        definition: line.linkage == Linkage::Extern,
+                    });
+                }
+                Vec::new()
+            },
+            _ => Vec::new(),
+        };
+        for expr in exprs {
+            expr.for_each_symbol(&mut |name, span| {
+                if !local(name) {
+                    assembly.labels.push(LabelSite {
+                        name: name.to_string(),
+                        span,
+                        definition: false,
+                    });
+                }
+            });
+        }
+    }
+    assembly.labels.sort_by_key(|site| (site.span.file, site.span.line, site.span.column));
+
+    // First pass: assign addresses to labels
+    // (labels on a line of their own name the next cell or instruction)
+    let mut symbols = SymbolTable::new();
+    let mut data_address = 0;
+    let mut code_index = 0;
+    let memory = 1 << WORD_BITS;
+
+    // Size of each data line, worked out with constants only
+    let constants = Scope::constants_only(&ast);
+    let mut data_sizes = Vec::new();
+    // Boundary every `.align` is a multiple of, for where an object's data
+    // can be placed
+    let mut data_alignment = 1;
+
+    for placed in &items {
+        let section = match placed.section {
+            Some(section) => section,
+            None => continue,
+        };
+        let mut address = match section {
+            Section::Data => data_address,
+            // 2 bytes per instruction
+            Section::Code => code_index * 2,
+        };
+
+        match placed.item {
+            Item::Data(line) => {
+                let size = data_size(line, placed.origin, &constants, data_address);
+                let size = size.unwrap_or_else(|diagnostic| {
+                    assembly.diagnostics.push(traced(diagnostic, placed));
+                    0
+                });
+                if data_address <= memory && data_address + size > memory {
+                    let message = format!("Data does not fit in data memory ({} bytes)", memory);
+                    assembly.diagnostics.push(traced(
+                        Diagnostic {
+                            severity: Severity::Error,
+                            span: line.keyword_span,
+                            message,
+                        },
+                        placed,
+                    ));
+                }
+
+                assembly.emitted.push(Emitted {
+                    origin: placed.origin,
+                    span: line.keyword_span,
+                    section,
+                    address: data_address,
+                    size,
+                });
+
+                // Labels name the first byte after any padding
+                if let DataValue::Align(boundary) = &line.value {
+                    address += size;
+                    if let Ok(boundary @ 1..) = constants.eval(boundary, placed.origin) {
+                        data_alignment = lcm(data_alignment, boundary as u32);
+                    }
+                }
+                data_sizes.push(size);
+                data_address += size;
+            },
+            Item::Code(line) => {
+                if address <= memory && address + 2 > memory {
+                    let message = format!("Code does not fit in instruction memory ({} bytes)", memory);
+                    assembly.diagnostics.push(traced(
+                        Diagnostic {
+                            severity: Severity::Error,
+                            span: line.opcode_span,
+                            message,
+                        },
+                        placed,
+                    ));
+                }
+
+                assembly.emitted.push(Emitted {
+                    origin: placed.origin,
+                    span: line.opcode_span,
+                    section,
+                    address,
+                    size: 2,
+                });
+                code_index += 1;
+            },
+            _ => {},
+        }
+
+        for label in placed.item.labels() {
+            if symbols.get(&label.name).is_none() {
+                symbols.insert(&label.name, section, address);
+            }
+        }
+    }
+
+    // Report duplicates (undefined labels are found while evaluating)
+    let mut defined: HashMap<&str, Span> = HashMap::new();
+    let mut problems = Vec::new();
+    for site in assembly.labels.iter().filter(|site| site.definition) {
+        if let Some(first) = defined.insert(&site.name, site.span) {
+            defined.insert(&site.name, first);
+            // The first definition may be in another (included) file
+            let place = match ast.files.get(first.file).map_or("", String::as_str) {
+                "" => format!("line {}", first.line),
+                file => format!("{}:{}", file, first.line),
+            };
+            problems.push((site.span, format!("Duplicate label '{}' (first defined on {})", site.name, place)));
+        }
+    }
+
+    // Only labels this file defines can be used by other objects
+    let mut globals = Vec::new();
+    let mut externs = Vec::new();
+    for line in ast.linkages() {
+        for label in &line.names {
+            match line.linkage {
+                Linkage::Global if symbols.get(&label.name).is_some() => globals.push(label.name.as_str()),
+                Linkage::Global if ast.constants().any(|constant| constant.name.name == label.name) => {
+                    problems.push((label.span, format!("'{}' is a constant, only labels can be .global", label.name)));
+                },
+                Linkage::Global => {
+                    problems.push((label.span, format!("'{}' is declared .global but never defined", label.name)));
+                },
+                Linkage::Extern if !externs.contains(&label.name) => externs.push(label.name.clone()),
+                Linkage::Extern => {},
+            }
+        }
+    }
+
+    for (span, message) in problems {
+        assembly.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            span,
+            message,
+        });
+    }
+
+    // Code lines and data lines with where they came from
+    let code_lines = items.iter().filter_map(|placed| match placed.item {
+        Item::Code(line) => Some((line, placed)),
+        _ => None,
+    });
+    let data_lines = items.iter().filter_map(|placed| match placed.item {
+        Item::Data(line) => Some((line, placed)),
+        _ => None,
+    });
+
+    // Operands that are missing or ignored
+    for (line, placed) in code_lines.clone() {
+        let trace = placed.trace();
+        match &line.operand {
+            Some(operand) if !line.opcode.has_operand() => {
+                assembly.warning(operand.span, format!("{} does not use an operand{}", line.opcode, trace));
+            },
+            None if line.opcode.has_operand() => {
+                assembly.warning(line.opcode_span, format!("{} expects an operand{}", line.opcode, trace));
+            },
+            _ => {},
+        }
+    }
+
+    // Second pass: evaluate expressions and emit bytes
+    // (each problem is reported once, however often a constant is used)
+    let scope = Scope::new(&symbols, &ast);
+    let mut errors = Vec::new();
+
+    // Constants that are never used still get checked
+    for placed in &items {
+        if let Item::Constant(constant) = placed.item {
+            if let Err(diagnostic) = scope.eval(&constant.value, constant.line) {
+                errors.push(traced(diagnostic, placed));
+            }
+        }
+    }
+
+    // Uses inside a macro or included file see constants as they are
+    // where the macro is used or the file included. In an object, a value
+    // using a label is left as 0 for the linker to fill in.
+    let mut relocations = Vec::new();
+    let mut value = |expr: Option<&ast::Expr>, placed: &Placed, bits: u32, section: Section, offset: usize| {
+        let expr = match expr {
+            Some(expr) => expr,
+            None => return 0,
+        };
+        let result = match options.relocatable {
+            false => scope.bits(expr, placed.origin, bits),
+            true => scope.relocatable(expr, placed.origin).and_then(|value| match value.symbol {
+                Some(symbol) => {
+                    relocations.push(Relocation {
+                        section,
+                        offset: offset as u32,
+                        bits,
+                        symbol,
+                        addend: value.addend,
+                    });
+                    Ok(0)
+                },
+                None => literal::fit(value.addend, bits).map_err(|message| Diagnostic {
+                    severity: Severity::Error,
+                    span: expr.span,
+                    message,
+                }),
+            }),
+        };
+        result.unwrap_or_else(|diagnostic| {
+            errors.push(traced(diagnostic, placed));
+            0
+        })
+    };
+
+    let program = &mut assembly.program;
+
+    // Add data section
+    // Format: (value)*
+    for ((line, placed), size) in data_lines.zip(data_sizes) {
+        match &line.value {
+            DataValue::Dat(expr) => {
+                let byte = value(expr.as_ref(), placed, WORD_BITS, Section::Data, program.data.len());
+                program.data.push(byte as u8);
+            },
+            DataValue::Bytes(exprs) => {
+                for expr in exprs {
+                    let byte = value(Some(expr), placed, WORD_BITS, Section::Data, program.data.len());
+                    program.data.push(byte as u8);
+                }
+            },
+            DataValue::Words(exprs) => {
+                for expr in exprs {
+                    let word = value(Some(expr), placed, 2 * WORD_BITS, Section::Data, program.data.len());
+                    program.data.extend_from_slice(&(word as u16).to_be_bytes());
+                }
+            },
+            DataValue::Ascii { bytes, zero_terminated, .. } => {
+                program.data.extend_from_slice(bytes);
+                if *zero_terminated {
+                    program.data.push(0);
+                }
+            },
+            DataValue::Fill { value: expr, .. } => {
+                for _ in 0..size {
+                    let byte = value(expr.as_ref(), placed, WORD_BITS, Section::Data, program.data.len());
+                    program.data.push(byte as u8);
+                }
+            },
+            DataValue::Align(_) => program.data.extend(std::iter::repeat_n(0, size as usize)),
+        }
+    }
+
+    // Add code section
+    // Format: (opcode operand)*
+    // (instructions from a macro or included file belong to the line using
+    // or including it)
+    for (line, placed) in code_lines {
+        program.code_lines.push(placed.origin);
+        program.source_map.code.push(SourceLocation {
+            address: program.code.len() as u32,
+            file: ast.files[line.opcode_span.file].clone(),
+            line: line.opcode_span.line,
+            column: line.opcode_span.column + 1,
+            text: match &line.operand {
+                Some(operand) => format!("{} {}", line.opcode, operand),
+                None => line.opcode.to_string(),
+            },
+        });
+
+        // Add opcode
+        program.code.push(line.opcode.to_bin());
+
+        // Add operand
+        let operand = value(line.operand.as_ref(), placed, WORD_BITS, Section::Code, program.code.len());
+        program.code.push(operand as u8);
+    }
+
+    let mut data_labels: Vec<(u32, String)> = symbols
+        .iter()
+        .filter(|symbol| symbol.section == Section::Data)
+        .map(|symbol| (symbol.address, symbol.name.clone()))
+        .collect();
+    data_labels.sort_by_key(|(address, _)| *address);
+    program.source_map.data = data_labels;
+
+    if options.relocatable {
+        assembly.object = Some(Object {
+            source: ast.files[0].clone(),
+            data: program.data.clone(),
+            code: program.code.clone(),
+            data_alignment,
+            symbols: symbols
+                .iter()
+                .map(|symbol| ObjectSymbol {
+                    symbol: symbol.clone(),
+                    global: globals.contains(&symbol.name.as_str()),
+                })
+                .collect(),
+            externs,
+            relocations,
+            lines: program.source_map.code.clone(),
+        });
+    }
+
+    assembly.constants = scope.constants();
+    for diagnostic in errors {
+        if !assembly.diagnostics.contains(&diagnostic) {
+            assembly.diagnostics.push(diagnostic);
+        }
+    }
+    assembly.diagnostics.sort_by_key(|d| (d.span.file, d.span.line, d.span.column));
+    program.symbols = symbols;
+
+    assembly.ast = ast;
+    assembly
+}
+
+/// Add where a diagnostic was expanded from to its message
+fn traced(mut diagnostic: Diagnostic, placed: &Placed) -> Diagnostic {
+    diagnostic.message.push_str(placed.trace());
+    diagnostic
+}
+
+/// Work out how many bytes a data line (used on a line) takes up at an
+/// address
+fn data_size(line: &DataLine, used_on: usize, constants: &Scope, address: u32) -> Result<u32, Diagnostic> {
+    let memory = 1 << WORD_BITS;
+    let count = |expr: &ast::Expr, what: &str| {
+        let value = constants.eval(expr, used_on)?;
+        if value < 0 || value > memory as i64 {
+            let message = format!("{} must be between 0 and {}, not {}", what, memory, value);
+            return Err(Diagnostic {
+                severity: Severity::Error,
+                span: expr.span,
+                message,
+            });
+        }
+        Ok(value as u32)
+    };
+
+    let size = match &line.value {
+        DataValue::Dat(_) => 1,
+        DataValue::Bytes(values) => values.len() as u32,
+        DataValue::Words(values) => 2 * values.len() as u32,
+        DataValue::Ascii { bytes, zero_terminated, .. } => bytes.len() as u32 + *zero_terminated as u32,
+        DataValue::Fill { count: expr, .. } => count(expr, "Count")?,
+        DataValue::Align(expr) => match count(expr, "Boundary")? {
+            0 => 0,
+            boundary => (boundary - address % boundary) % boundary,
+        },
+    };
+    Ok(size)
+}
+
+/// Least common multiple of two boundaries
+fn lcm(a: u32, b: u32) -> u32 {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    a / x * b
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: {}: {}", self.span.line, self.span.column + 1, self.severity, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Messages of the errors found assembling source
+    fn errors(source: &str) -> Vec<String> {
+        let assembly = assemble_with(source, &Options::default());
+        assembly
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn data_in_code() {
+        assert_eq!(errors(".code\nDAT 5\nHLT\n"), vec!["DAT can only be used in .data"]);
+        assert_eq!(errors(".code\nX: DAT\nHLT\n"), vec!["DAT can only be used in .data"]);
+        assert!(errors(".data\nX: DAT 5\n.code\nHLT\n").is_empty());
+    }
+
+    #[test]
+    fn code_must_fit_in_memory() {
+        let code = |count: usize| format!(".code\n{}", "        HLT\n".repeat(count));
+        assert!(errors(&code(128)).is_empty());
+        assert_eq!(errors(&code(130)), vec!["Code does not fit in instruction memory (256 bytes)"]);
+
+        let assembly = assemble_with(&code(129), &Options::default());
+        let error = assembly.diagnostics.iter().find(|d| d.severity == Severity::Error).unwrap();
+        assert_eq!(error.span.line, 130);
+    }
+
+    #[test]
+    fn data_directives() {
+        let source = ".data\nA: .byte 1, 2\n.word 0x1234\n.asciz \"hi\"\n.space 2\n.fill 2, 7\n.align 4\n.byte 9\n";
+        let assembly = assemble_source(source);
+        assert!(!assembly.has_errors(), "{:?}", assembly.diagnostics);
+        assert_eq!(assembly.program.data, vec![1, 2, 0x12, 0x34, b'h', b'i', 0, 0, 0, 7, 7, 0, 9]);
+    }
+
+    #[test]
+    fn labels_share_an_address() {
+        let source = ".code\n        HLT\nend:\ndone: stop: HLT\n";
+        assert!(errors(source).is_empty());
+        let assembly = assemble_source(source);
+        let symbols = &assembly.program.symbols;
+        let addresses: Vec<Option<u32>> = ["end", "done", "stop"].iter().map(|name| symbols.address_of(name)).collect();
+        assert_eq!(addresses, vec![Some(2), Some(2), Some(2)]);
+    }
+
+    #[test]
+    fn macro_labels_are_local() {
+        let source = ".data\nA: DAT 2\nB: DAT 1\nONE: DAT 1\n.macro countdown var\nloop:\n        LDA var\n        SUB ONE\n        STA var\n        JNZ loop\n.endm\n.code\n        countdown A\n        countdown B\n        HLT\n";
+        let assembly = assemble_source(source);
+        assert!(!assembly.has_errors(), "{:?}", assembly.diagnostics);
+        // Each use jumps back to its own loop
+        let code = &assembly.program.code;
+        assert_eq!((code[6], code[7]), (0x0D, 0));
+        assert_eq!((code[14], code[15]), (0x0D, 8));
+    }
+
+    #[test]
+    fn duplicate_labels_name_the_first_file() {
+        assert_eq!(
+            errors(".data\nA: DAT 1\nA: DAT 2\n.code\nHLT\n"),
+            vec!["Duplicate label 'A' (first defined on line 2)"]
+        );
+        assert_eq!(
+            errors(".code\nloop:\nloop: HLT\n"),
+            vec!["Duplicate label 'loop' (first defined on line 2)"]
+        );
+
+        let directory = std::env::temp_dir().join(format!("vnc-duplicate-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("lib.vnc"), "A: DAT 1\n").unwrap();
+        let main = directory.join("main.vnc");
+        std::fs::write(&main, ".data\n.include \"lib.vnc\"\nA: DAT 2\n.code\nHLT\n").unwrap();
+        let errors = try_assemble_program(&main.display().to_string()).unwrap_err();
+        std::fs::remove_dir_all(&directory).unwrap();
+        let expected = format!("lib.vnc:1:1: error: Duplicate label 'A' (first defined on {}:3)", main.display());
+        assert!(errors.contains(&expected), "{}", errors);
+    }
+
+    #[test]
+    fn includes() {
+        let directory = std::env::temp_dir().join(format!("vnc-include-{}", std::process::id()));
+        let library = directory.join("lib");
+        std::fs::create_dir_all(&library).unwrap();
+        std::fs::write(library.join("one.vnc"), "ONE: DAT 1\n").unwrap();
+        std::fs::write(directory.join("a.vnc"), ".include \"b.vnc\"\n").unwrap();
+        std::fs::write(directory.join("b.vnc"), ".include \"a.vnc\"\n").unwrap();
+        let main = directory.join("main.vnc");
+        let options = Options {
+            include_paths: vec![library],
+            ..Options::for_file(&main)
+        };
+
+        let assembly = assemble_with(".data\n.include \"one.vnc\"\n.code\nLDA ONE\nHLT\n", &options);
+        let cycle = assemble_with(".data\n.include \"a.vnc\"\n", &options);
+        let missing = assemble_with(".data\n.include \"none.vnc\"\n", &options);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(!assembly.has_errors(), "{:?}", assembly.diagnostics);
+        assert_eq!(assembly.program.data, vec![1]);
+        let message = |assembly: &Assembly| assembly.diagnostics[0].message.clone();
+        assert!(message(&cycle).starts_with("'a.vnc' includes itself"), "{}", message(&cycle));
+        assert!(message(&missing).starts_with("Cannot find 'none.vnc'"), "{}", message(&missing));
+    }
+
+    #[test]
+    fn errors_are_returned() {
+        let path = std::env::temp_dir().join(format!("vnc-assembler-errors-{}.vnc", std::process::id()));
+        std::fs::write(&path, ".code\nLDA A\nFOO\n").unwrap();
+        let errors = try_assemble_program(&path.display().to_string()).unwrap_err();
+        assert_eq!(errors.lines().count(), 2, "{}", errors);
+        assert!(errors.contains(":2:5: error: Undefined label 'A'"), "{}", errors);
+
+        let missing = try_assemble_program("no-such-file.vnc").unwrap_err();
+        assert!(missing.starts_with("no-such-file.vnc: error: "), "{}", missing);
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let program = assemble_with(".data\nA: DAT 4\n.code\nLDA A\nHLT\n", &Options::default()).program;
+        let binary = program.to_binary();
+        assert_eq!(binary, b"VNC\x01\x00\x01\x04\x06\x00\x0e\x00");
+
+        let loaded = Program::from_binary(&binary).unwrap();
+        assert_eq!((loaded.data, loaded.code), (program.data, program.code));
+    }
+
+    #[test]
+    fn bad_binaries_are_errors() {
+        assert!(Program::from_binary(b"VNC").unwrap_err().contains("too short"));
+        assert!(Program::from_binary(b"VNC\x01\x00").unwrap_err().contains("too short"));
+        assert!(Program::from_binary(b"VNC\x02\x00\x00").unwrap_err().contains("version 2"));
+        assert!(Program::from_binary(b"VNC\x01\x00\x03\x01\x02").unwrap_err().contains("data section"));
+    }
+
+    #[test]
+    fn headerless_binaries_are_code() {
+        let program = Program::from_binary(&[0x0E, 0x00]).unwrap();
+        assert!(program.data.is_empty());
+        assert_eq!(program.code, vec![0x0E, 0x00]);
+        assert!(Program::from_binary(&[]).unwrap().code.is_empty());
+    }
+}
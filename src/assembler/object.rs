@@ -0,0 +1,368 @@
+//! Relocatable object files
+//! An object is a file assembled on its own, to be combined with others
+//! by the `linker`. Its data and code start at address 0 and every place
+//! that holds the address of a label is listed as a relocation, which the
+//! linker fills in once it has decided where each object goes.
+//!
+//! Labels named by `.global` can be used by other objects, which name
+//! them with `.extern`. Other labels are local to their object.
+//!
+//! Objects are saved as a line based text file:
+//! ```text
+//! # virtual nanocomputer object
+//! format vnc-object
+//! version 1
+//! source lib.vnc
+//! data 3 align 1
+//! 0x0000: 03 04 00
+//! code 6
+//! 0x0000: 06 00 01 01 10 00
+//! symbol code 0x00 print global
+//! symbol data 0x00 A
+//! extern puts
+//! reloc code 0x01 8 puts 0
+//! line 0x00 lib.vnc:4:5 OUT
+//! end
+//! ```
+//! (`symbol section address name [global]`, `reloc section offset bits
+//! label addend` and `line` as in a `source_map`.) Rows that are all zero
+//! are left out. Relocations come after the sections they patch and are
+//! 8 or 16 bits wide.
+
+use super::source_map::{self, SourceLocation};
+use super::{Section, Symbol};
+
+/// Current object format version
+pub const OBJECT_VERSION: u32 = 1;
+
+/// Bytes per row in the file
+const ROW_SIZE: usize = 16;
+
+/// An assembled file waiting to be linked
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Object {
+    /// Name of the source file, for messages
+    pub source: String,
+    pub data: Vec<u8>,
+    pub code: Vec<u8>,
+    /// Boundary the data must start on for its `.align`s to hold
+    pub data_alignment: u32,
+    /// Every label defined by the object, relative to its section
+    pub symbols: Vec<ObjectSymbol>,
+    /// Labels the object expects another one to define
+    pub externs: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    /// Where each instruction was written, for the source map
+    pub lines: Vec<SourceLocation>,
+}
+
+/// A label defined by an object
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectSymbol {
+    pub symbol: Symbol,
+    /// Whether other objects can use it
+    pub global: bool,
+}
+
+/// A value in an object that depends on where a label ends up
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relocation {
+    /// Section holding the value
+    pub section: Section,
+    /// Offset of the value in its section
+    pub offset: u32,
+    /// Size of the value (a word, or two for `.word`)
+    pub bits: u32,
+    pub symbol: String,
+    /// Number added to the label's address
+    pub addend: i64,
+}
+
+impl Object {
+    /// Save the object to a file
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        std::fs::write(filename, self.to_string())
+    }
+
+    /// Load an object from a file
+    pub fn load(filename: &str) -> Result<Object, String> {
+        let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        Object::parse(&text).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    /// Find a label the object defines
+    pub fn symbol(&self, name: &str) -> Option<&ObjectSymbol> {
+        self.symbols.iter().find(|symbol| symbol.symbol.name == name)
+    }
+
+    /// Parse the text form of an object
+    pub fn parse(text: &str) -> Result<Object, String> {
+        let mut object = Object {
+            data_alignment: 1,
+            ..Object::default()
+        };
+
+        let mut version = 0;
+        let mut ended = false;
+        // Section currently receiving rows
+        let mut current: Option<Section> = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if ended {
+                return Err(format!("line {}: content after 'end'", line_number));
+            }
+
+            let error = |message: String| format!("line {}: {}", line_number, message);
+
+            // Section rows
+            if line.starts_with("0x") && line.contains(':') {
+                let bytes = match current {
+                    Some(Section::Data) => &mut object.data,
+                    Some(Section::Code) => &mut object.code,
+                    None => return Err(error("row outside of a section".to_string())),
+                };
+                parse_row(line, bytes).map_err(error)?;
+                continue;
+            }
+            current = None;
+
+            let mut parts = line.split_whitespace();
+            let key = parts.next().unwrap();
+            let values: Vec<&str> = parts.collect();
+            let value = |i: usize| values.get(i).copied().ok_or_else(|| error(format!("missing value for '{}'", key)));
+
+            match key {
+                "format" => {
+                    if value(0)? != "vnc-object" {
+                        return Err(error(format!("not an object (format {})", value(0)?)));
+                    }
+                },
+                "version" => {
+                    version = parse_number(value(0)?).map_err(error)? as u32;
+                    if version == 0 || version > OBJECT_VERSION {
+                        return Err(error(format!("unsupported object version {}", version)));
+                    }
+                },
+                "source" => object.source = values.join(" "),
+                "data" | "code" => {
+                    let size = parse_number(value(0)?).map_err(error)? as usize;
+                    let section = parse_section(key).map_err(error)?;
+                    match section {
+                        Section::Data => {
+                            object.data = vec![0; size];
+                            if value(1).is_ok() {
+                                if value(1)? != "align" {
+                                    return Err(error(format!("unexpected '{}'", value(1)?)));
+                                }
+                                object.data_alignment = parse_number(value(2)?).map_err(error)?.max(1) as u32;
+                            }
+                        },
+                        Section::Code => object.code = vec![0; size],
+                    }
+                    current = Some(section);
+                },
+                "symbol" => {
+                    let global = match values.get(3) {
+                        None => false,
+                        Some(&"global") => true,
+                        Some(other) => return Err(error(format!("unexpected '{}'", other))),
+                    };
+                    object.symbols.push(ObjectSymbol {
+                        symbol: Symbol {
+                            name: value(2)?.to_string(),
+                            section: parse_section(value(0)?).map_err(error)?,
+                            address: parse_number(value(1)?).map_err(error)? as u32,
+                        },
+                        global,
+                    });
+                },
+                "extern" => object.externs.push(value(0)?.to_string()),
+                "reloc" => {
+                    let addend = value(4)?;
+                    let relocation = Relocation {
+                        section: parse_section(value(0)?).map_err(error)?,
+                        offset: parse_number(value(1)?).map_err(error)? as u32,
+                        bits: parse_number(value(2)?).map_err(error)? as u32,
+                        symbol: value(3)?.to_string(),
+                        addend: addend.parse().map_err(|_| error(format!("invalid addend '{}'", addend)))?,
+                    };
+
+                    // The section it patches must already be declared
+                    let size = match relocation.section {
+                        Section::Data => object.data.len(),
+                        Section::Code => object.code.len(),
+                    };
+                    let width = match relocation.bits {
+                        8 => 1,
+                        16 => 2,
+                        bits => return Err(error(format!("relocation width {} is not 8 or 16", bits))),
+                    };
+                    if relocation.offset as usize + width > size {
+                        return Err(error(format!(
+                            "relocation at 0x{:02X} is outside the {} section",
+                            relocation.offset,
+                            section_name(relocation.section)
+                        )));
+                    }
+                    object.relocations.push(relocation);
+                },
+                "line" => object.lines.push(source_map::parse_location(&values.join(" ")).map_err(error)?),
+                "end" => ended = true,
+                _ => return Err(error(format!("unknown key '{}'", key))),
+            }
+        }
+
+        if version == 0 {
+            return Err("missing 'format' or 'version' header".to_string());
+        }
+        if !ended {
+            return Err("object is truncated (missing 'end')".to_string());
+        }
+
+        Ok(object)
+    }
+}
+
+/// Parse `data` or `code`
+fn parse_section(text: &str) -> Result<Section, String> {
+    match text {
+        "data" => Ok(Section::Data),
+        "code" => Ok(Section::Code),
+        _ => Err(format!("unknown section '{}'", text)),
+    }
+}
+
+/// Parse `0xADDR: XX XX ...` into a section
+fn parse_row(line: &str, bytes: &mut [u8]) -> Result<(), String> {
+    let (address, row) = line.split_once(':').unwrap();
+    let address = parse_number(address)? as usize;
+
+    for (i, byte) in row.split_whitespace().enumerate() {
+        let byte = u8::from_str_radix(byte, 16).map_err(|_| format!("invalid byte '{}'", byte))?;
+        let slot = bytes
+            .get_mut(address + i)
+            .ok_or_else(|| format!("offset 0x{:04X} is outside the section", address + i))?;
+        *slot = byte;
+    }
+    Ok(())
+}
+
+/// Parse a decimal or 0x hex number
+fn parse_number(text: &str) -> Result<u64, String> {
+    let result = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse::<u64>(),
+    };
+    result.map_err(|_| format!("invalid number '{}'", text))
+}
+
+/// Name of a section in the file
+fn section_name(section: Section) -> &'static str {
+    match section {
+        Section::Data => "data",
+        Section::Code => "code",
+    }
+}
+
+/// Write the rows of a section, skipping all-zero rows
+fn write_rows(f: &mut std::fmt::Formatter, bytes: &[u8]) -> std::fmt::Result {
+    for (row, bytes) in bytes.chunks(ROW_SIZE).enumerate() {
+        if bytes.iter().all(|byte| *byte == 0) {
+            continue;
+        }
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        writeln!(f, "0x{:04X}: {}", row * ROW_SIZE, bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "# virtual nanocomputer object")?;
+        writeln!(f, "format vnc-object")?;
+        writeln!(f, "version {}", OBJECT_VERSION)?;
+        if !self.source.is_empty() {
+            writeln!(f, "source {}", self.source)?;
+        }
+        writeln!(f, "data {} align {}", self.data.len(), self.data_alignment)?;
+        write_rows(f, &self.data)?;
+        writeln!(f, "code {}", self.code.len())?;
+        write_rows(f, &self.code)?;
+
+        for ObjectSymbol { symbol, global } in &self.symbols {
+            write!(f, "symbol {} 0x{:02X} {}", section_name(symbol.section), symbol.address, symbol.name)?;
+            match global {
+                true => writeln!(f, " global")?,
+                false => writeln!(f)?,
+            }
+        }
+        for name in &self.externs {
+            writeln!(f, "extern {}", name)?;
+        }
+        for relocation in &self.relocations {
+            writeln!(
+                f,
+                "reloc {} 0x{:02X} {} {} {}",
+                section_name(relocation.section),
+                relocation.offset,
+                relocation.bits,
+                relocation.symbol,
+                relocation.addend
+            )?;
+        }
+        for location in &self.lines {
+            writeln!(f, "line {}", location)?;
+        }
+
+        writeln!(f, "end")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble_with, Options};
+
+    /// Object text with one code relocation line
+    fn with_relocation(relocation: &str) -> String {
+        format!("format vnc-object\nversion 1\ndata 0 align 1\ncode 2\n0x0000: 06 00\n{}\nend\n", relocation)
+    }
+
+    #[test]
+    fn round_trip() {
+        let options = Options {
+            relocatable: true,
+            ..Options::default()
+        };
+        let source = ".global main\n.extern count\n.data\nA: DAT 3\n.code\nmain: LDA A\nSTA count\nHLT\n";
+        let object = assemble_with(source, &options).object.unwrap();
+        assert_eq!(object.relocations.len(), 2);
+        assert_eq!(Object::parse(&object.to_string()), Ok(object));
+    }
+
+    #[test]
+    fn relocation_in_section() {
+        assert!(Object::parse(&with_relocation("reloc code 0x01 8 print 0")).is_ok());
+        assert!(Object::parse(&with_relocation("reloc code 0x00 16 print 0")).is_ok());
+    }
+
+    #[test]
+    fn relocation_outside_section() {
+        let error = Object::parse(&with_relocation("reloc code 0x40 8 print 0")).unwrap_err();
+        assert!(error.contains("outside the code section"), "{}", error);
+        assert!(Object::parse(&with_relocation("reloc code 0x01 16 print 0")).is_err());
+        assert!(Object::parse(&with_relocation("reloc data 0x00 8 print 0")).is_err());
+    }
+
+    #[test]
+    fn relocation_width() {
+        let error = Object::parse(&with_relocation("reloc code 0x01 64 print 0")).unwrap_err();
+        assert!(error.contains("not 8 or 16"), "{}", error);
+        assert!(Object::parse(&with_relocation("reloc code 0x01 0 print 0")).is_err());
+    }
+}
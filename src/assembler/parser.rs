@@ -0,0 +1,1064 @@
+//! Builds the syntax tree from tokens
+//! Each line is one of
+//! ```text
+//! .data | .code                 section directive
+//! .equ NAME [,] VALUE           constant (`.set` may be redefined)
+//! .global NAME, ...             labels other objects may use
+//! .extern NAME, ...             labels defined in another object
+//! .macro NAME [PARAM, ...]      macro definition, up to `.endm`
+//! .include "PATH"               the lines of another file
+//! [LABEL:]* NAME [ARG, ...]     macro use
+//! [LABEL:]* DAT [VALUE]         in .data
+//! [LABEL:]* .byte VALUE, ...    in .data, also .word, .ascii "TEXT",
+//!                               .asciz, .space/.fill COUNT [, VALUE]
+//!                               and .align BOUNDARY
+//! [LABEL:]* OPCODE [OPERAND]    in .code
+//! [LABEL:]+                     labels for the next cell or instruction
+//! ```
+//! optionally followed by a comment. A `VALUE` or `OPERAND` is an
+//! expression of literals (see `literal`), labels and constants, joined
+//! with `+ - * / % & | ^ << >>` (C precedence) and grouped with
+//! parentheses, e.g. `TABLE+2` or `('Z'-'A') * 2`. A label may also be
+//! written without its colon when it is followed by `DAT` or a mnemonic
+//! (`loop LDA A`).
+//! A line with a problem is reported and left out of the tree, and
+//! parsing carries on with the next line.
+//!
+//! Macros are expanded as they are parsed, so a macro must be defined
+//! before it is used. In the body, each parameter is replaced by the
+//! tokens of its argument, and labels defined with a colon (or by `.equ`
+//! and `.set`) are renamed to `NAME@N` for the Nth expansion so every use
+//! gets its own. Problems inside an expansion say which use caused them.
+//!
+//! An included file is looked for next to the file including it, then in
+//! each include path. Its lines carry on in the section of the
+//! `.include`, and the section goes back to that one after it.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::ast::{
+    Ast, BinaryOp, CodeLine, Comment, ConstantLine, DataLine, DataValue, Expansion, Expr, ExprKind, Include, Item,
+    Label, LabelLine, Linkage, LinkageLine, MacroDef, SectionNode,
+};
+use super::lexer::{self, Span, Token, TokenKind};
+use super::{literal, Diagnostic, Options, Section, Severity};
+use crate::cpu::instructions::Opcode;
+
+/// Parse source text, returning the tree and every problem found
+pub fn parse(source: &str) -> (Ast, Vec<Diagnostic>) {
+    parse_with(source, &Options::default())
+}
+
+/// Parse source text (from the file named in the options, if any),
+/// following `.include`s
+pub fn parse_with(source: &str, options: &Options) -> (Ast, Vec<Diagnostic>) {
+    let path = options.path.clone().unwrap_or_default();
+    let name = path.display().to_string();
+
+    let mut parser = Parser {
+        ast: Ast::default(),
+        diagnostics: Vec::new(),
+        lines: Vec::new(),
+        section: None,
+        macros: HashMap::new(),
+        defining: None,
+        expanding: Vec::new(),
+        expansion_count: 0,
+        file: 0,
+        files: vec![(name.clone(), path.clone())],
+        including: Vec::new(),
+        include_paths: options.include_paths.clone(),
+    };
+    if options.path.is_some() {
+        parser.including.push((std::fs::canonicalize(&path).unwrap_or(path), name));
+    }
+
+    parser.file_contents(source);
+    parser.ast.files = parser.files.into_iter().map(|(name, _)| name).collect();
+
+    (parser.ast, parser.diagnostics)
+}
+
+/// Directives that go in `.data` in place of `DAT`
+const DATA_DIRECTIVES: [&str; 7] = [".byte", ".word", ".ascii", ".asciz", ".space", ".fill", ".align"];
+
+/// How deep macros can be used inside other macros
+const MAX_MACRO_DEPTH: usize = 16;
+
+struct Parser {
+    ast: Ast,
+    diagnostics: Vec<Diagnostic>,
+    /// Text of each line of the file being parsed
+    lines: Vec<String>,
+    /// Section the next line goes in
+    section: Option<Section>,
+    macros: HashMap<String, Rc<MacroDef>>,
+    /// Macro whose body is being recorded
+    defining: Option<MacroDef>,
+    /// Expansions being parsed, innermost last
+    expanding: Vec<Expansion>,
+    expansion_count: usize,
+    /// Index of the file being parsed in `files`
+    file: usize,
+    /// Name and path of every file read
+    files: Vec<(String, PathBuf)>,
+    /// Files being parsed, outermost first, as their full path and name
+    including: Vec<(PathBuf, String)>,
+    /// Directories searched by `.include`
+    include_paths: Vec<PathBuf>,
+}
+
+impl Parser {
+    /// Parse the text of the current file
+    fn file_contents(&mut self, source: &str) {
+        self.lines = source.split('\n').map(str::to_string).collect();
+
+        let tokens = lexer::lex_file(source, self.file);
+        let mut start = 0;
+        for (index, token) in tokens.iter().enumerate() {
+            if token.kind == TokenKind::Newline {
+                match self.defining {
+                    Some(_) => self.record(token.span.line, &tokens[start..index]),
+                    None => self.line(token.span.line, &tokens[start..index]),
+                }
+                start = index + 1;
+            }
+        }
+
+        if let Some(definition) = &self.defining {
+            let message = format!("Missing .endm for macro '{}'", definition.name.name);
+            self.error(definition.span, message);
+            self.define_macro();
+        }
+
+        // The lexer always ends with a newline, which leaves an empty last line
+        self.pop_trailing_blank(self.lines.len());
+    }
+
+    /// Parse the tokens of one line
+    fn line(&mut self, number: usize, tokens: &[Token]) {
+        // Split off a trailing comment
+        let (tokens, comment) = match tokens.split_last() {
+            Some((last, rest)) if last.kind == TokenKind::Comment => (
+                rest,
+                Some(Comment {
+                    text: last.text.to_string(),
+                    span: last.span,
+                }),
+            ),
+            _ => (tokens, None),
+        };
+
+        if let Some(unknown) = tokens.iter().find(|token| token.kind == TokenKind::Unknown) {
+            self.error(unknown.span, format!("Unexpected character '{}'", unknown.text));
+            return;
+        }
+
+        let first = match tokens.first() {
+            Some(first) => first,
+            None => {
+                // Blank or comment only
+                let item = match comment {
+                    Some(comment) => Item::Comment(comment),
+                    None => Item::Blank(number),
+                };
+                self.push(item);
+                return;
+            },
+        };
+
+        if first.kind == TokenKind::Directive && !DATA_DIRECTIVES.contains(&first.text) {
+            self.directive(tokens, comment);
+            return;
+        }
+
+        let section = match self.section {
+            Some(section) => section,
+            None => {
+                let span = first.span.to(tokens.last().unwrap().span);
+                self.error(span, "Expected .data or .code before this line".to_string());
+                return;
+            },
+        };
+
+        let (labels, rest) = match self.leading_labels(tokens) {
+            Some(found) => found,
+            None => return,
+        };
+
+        // A macro use (or a label without a colon and a macro use)
+        let is_macro = |token: &Token| token.kind == TokenKind::Identifier && self.macros.contains_key(token.text);
+        match rest {
+            [name, ..] if is_macro(name) => {
+                self.expand(number, labels, rest, comment);
+                return;
+            },
+            [label, name, ..] if is_macro(name) && !is_macro(label) => {
+                let mut labels = labels;
+                match self.label(label, false) {
+                    Some(label) => labels.push(label),
+                    None => return,
+                }
+                self.expand(number, labels, &rest[1..], comment);
+                return;
+            },
+            _ => {},
+        }
+
+        let item = if rest.is_empty() {
+            Some(Item::Labels(LabelLine {
+                line: number,
+                labels,
+                comment,
+            }))
+        } else {
+            match section {
+                Section::Data => self.data_line(labels, rest, comment).map(Item::Data),
+                Section::Code => self.code_line(labels, rest, comment).map(Item::Code),
+            }
+        };
+        if let Some(item) = item {
+            self.push(item);
+        }
+    }
+
+    /// Parse a directive that is not a data directive
+    fn directive(&mut self, tokens: &[Token], comment: Option<Comment>) {
+        let directive = &tokens[0];
+
+        let section = match directive.text {
+            ".data" | ".code" if !self.expanding.is_empty() => {
+                self.error(directive.span, format!("{} cannot be used inside a macro", directive.text));
+                return;
+            },
+            ".data" => Section::Data,
+            ".code" => Section::Code,
+            ".equ" | ".set" => {
+                if let Some(constant) = self.constant(tokens, comment) {
+                    self.push(Item::Constant(constant));
+                }
+                return;
+            },
+            ".global" | ".extern" => {
+                if let Some(linkage) = self.linkage(tokens, comment) {
+                    self.push(Item::Linkage(linkage));
+                }
+                return;
+            },
+            ".macro" => {
+                self.defining = self.macro_header(tokens, comment);
+                return;
+            },
+            ".endm" => {
+                self.error(directive.span, ".endm without .macro".to_string());
+                return;
+            },
+            ".include" => {
+                self.include(tokens, comment);
+                return;
+            },
+            other => {
+                self.error(directive.span, format!("Unknown directive '{}'", other));
+                return;
+            },
+        };
+
+        self.extra_tokens(&tokens[1..]);
+        self.section = Some(section);
+        self.ast.sections.push(SectionNode {
+            section,
+            span: directive.span,
+            comment,
+            items: Vec::new(),
+        });
+    }
+
+    /// Parse `.global NAME, ...` or `.extern NAME, ...`
+    fn linkage(&mut self, tokens: &[Token], comment: Option<Comment>) -> Option<LinkageLine> {
+        let directive = &tokens[0];
+        if tokens.len() == 1 {
+            self.error(directive.span, format!("Expected a label after {}", directive.text));
+            return None;
+        }
+
+        let mut names = Vec::new();
+        for (index, token) in tokens[1..].iter().enumerate() {
+            match (index % 2, token.kind) {
+                (0, _) => names.push(self.label(token, false)?),
+                (_, TokenKind::Comma) => {},
+                _ => {
+                    self.error(token.span, format!("Expected ',' between labels, found '{}'", token.text));
+                    return None;
+                },
+            }
+        }
+        if tokens.last().unwrap().kind == TokenKind::Comma {
+            self.error(tokens.last().unwrap().span, "Expected a label after ','".to_string());
+            return None;
+        }
+
+        Some(LinkageLine {
+            line: directive.span.line,
+            span: directive.span,
+            linkage: match directive.text {
+                ".global" => Linkage::Global,
+                _ => Linkage::Extern,
+            },
+            names,
+            comment,
+        })
+    }
+
+    /// Parse `.equ NAME [,] VALUE` or `.set NAME [,] VALUE`
+    fn constant(&mut self, tokens: &[Token], comment: Option<Comment>) -> Option<ConstantLine> {
+        let directive = &tokens[0];
+        let name = match tokens.get(1) {
+            Some(name) => self.label(name, false)?,
+            None => {
+                self.error(directive.span, format!("Expected a name after {}", directive.text));
+                return None;
+            },
+        };
+
+        let start = match tokens.get(2) {
+            Some(comma) if comma.kind == TokenKind::Comma => 3,
+            _ => 2,
+        };
+        if start >= tokens.len() {
+            let span = tokens[start - 1].span;
+            self.error(span, format!("Expected a value for '{}'", name.name));
+            return None;
+        }
+        let (value, used) = self.expression(&tokens[start..])?;
+        self.extra_tokens(&tokens[start + used..]);
+
+        Some(ConstantLine {
+            line: directive.span.line,
+            span: directive.span,
+            redefinable: directive.text == ".set",
+            name,
+            value,
+            comment,
+        })
+    }
+
+    /// Parse `.include "PATH"` and the file it names
+    fn include(&mut self, tokens: &[Token], comment: Option<Comment>) {
+        let directive = &tokens[0];
+        if !self.expanding.is_empty() {
+            self.error(directive.span, ".include cannot be used inside a macro".to_string());
+            return;
+        }
+
+        let (path, path_span) = match tokens.get(1) {
+            Some(token) if token.kind == TokenKind::String => match literal::parse_string(token.text) {
+                Ok(bytes) => (String::from_utf8(bytes).unwrap(), token.span),
+                Err(message) => {
+                    self.error(token.span, message);
+                    return;
+                },
+            },
+            Some(token) => {
+                self.error(token.span, format!("Expected a file name in quotes, found '{}'", token.text));
+                return;
+            },
+            None => {
+                self.error(directive.span, "Expected a file name in quotes after .include".to_string());
+                return;
+            },
+        };
+        self.extra_tokens(&tokens[2..]);
+
+        let mut include = Include {
+            line: directive.span.line,
+            span: directive.span,
+            path: path.clone(),
+            comment,
+            file: None,
+            ast: Ast::default(),
+        };
+
+        if let Some((found, full_path, source)) = self.find_include(&path, path_span) {
+            let name = found.display().to_string();
+            let file = self.files.len();
+            self.files.push((name.clone(), found));
+            self.including.push((full_path, name));
+
+            // Parse the file in its own tree, carrying on in this section
+            let outer_ast = std::mem::take(&mut self.ast);
+            let outer_lines = std::mem::take(&mut self.lines);
+            let outer_file = std::mem::replace(&mut self.file, file);
+            let outer_section = self.section;
+
+            self.file_contents(&source);
+
+            include.ast = std::mem::replace(&mut self.ast, outer_ast);
+            include.file = Some(file);
+            self.lines = outer_lines;
+            self.file = outer_file;
+            self.section = outer_section;
+            self.including.pop();
+        }
+
+        self.push(Item::Include(include));
+    }
+
+    /// Find and read an included file, returning the path it was found at,
+    /// its full path and its text
+    fn find_include(&mut self, path: &str, span: Span) -> Option<(PathBuf, PathBuf, String)> {
+        // Next to the including file, then in each include path
+        let here = self.files[self.file].1.parent().map(Path::to_path_buf).unwrap_or_default();
+        let directories: Vec<PathBuf> = std::iter::once(here).chain(self.include_paths.iter().cloned()).collect();
+
+        let found = match directories.iter().map(|directory| directory.join(path)).find(|found| found.is_file()) {
+            Some(found) => found,
+            None => {
+                let searched: Vec<String> = directories
+                    .iter()
+                    .map(|directory| match directory.as_os_str().is_empty() {
+                        true => ".".to_string(),
+                        false => directory.display().to_string(),
+                    })
+                    .collect();
+                self.error(span, format!("Cannot find '{}' (looked in {})", path, searched.join(", ")));
+                return None;
+            },
+        };
+
+        let full_path = std::fs::canonicalize(&found).unwrap_or_else(|_| found.clone());
+        if let Some(start) = self.including.iter().position(|(other, _)| *other == full_path) {
+            let mut cycle: Vec<&str> = self.including[start..].iter().map(|(_, name)| name.as_str()).collect();
+            let name = found.display().to_string();
+            cycle.push(&name);
+            let message = format!("'{}' includes itself ({})", path, cycle.join(" -> "));
+            self.error(span, message);
+            return None;
+        }
+
+        match std::fs::read_to_string(&found) {
+            Ok(source) => Some((found, full_path, source)),
+            Err(e) => {
+                self.error(span, format!("Cannot read '{}': {}", found.display(), e));
+                None
+            },
+        }
+    }
+
+    /// Parse `.macro NAME [PARAM, ...]`
+    fn macro_header(&mut self, tokens: &[Token], comment: Option<Comment>) -> Option<MacroDef> {
+        let directive = &tokens[0];
+        let name = match tokens.get(1) {
+            Some(name) => self.label(name, false)?,
+            None => {
+                self.error(directive.span, "Expected a name after .macro".to_string());
+                return None;
+            },
+        };
+
+        let mut params: Vec<Label> = Vec::new();
+        for token in tokens[2..].iter().filter(|token| token.kind != TokenKind::Comma) {
+            let param = self.label(token, false)?;
+            if params.iter().any(|other| other.name == param.name) {
+                self.error(param.span, format!("Duplicate parameter '{}'", param.name));
+                return None;
+            }
+            params.push(param);
+        }
+
+        Some(MacroDef {
+            line: directive.span.line,
+            span: directive.span,
+            name,
+            params,
+            comment,
+            body: Vec::new(),
+            end_line: None,
+            end_comment: None,
+        })
+    }
+
+    /// Record a line of the body of the macro being defined
+    fn record(&mut self, number: usize, tokens: &[Token]) {
+        match tokens.first() {
+            Some(token) if token.text == ".endm" => {
+                let (rest, comment) = match tokens.split_last() {
+                    Some((last, rest)) if last.kind == TokenKind::Comment => (
+                        rest,
+                        Some(Comment {
+                            text: last.text.to_string(),
+                            span: last.span,
+                        }),
+                    ),
+                    _ => (tokens, None),
+                };
+                self.extra_tokens(&rest[1..]);
+
+                let definition = self.defining.as_mut().unwrap();
+                definition.end_line = Some(number);
+                definition.end_comment = comment;
+                self.define_macro();
+            },
+            Some(token) if token.text == ".macro" => {
+                self.error(token.span, "Macros cannot be defined inside a macro".to_string());
+            },
+            _ => {
+                let text = self.lines[number - 1].clone();
+                self.defining.as_mut().unwrap().body.push(text);
+            },
+        }
+    }
+
+    /// Finish defining the macro being recorded
+    fn define_macro(&mut self) {
+        let definition = self.defining.take().unwrap();
+        let name = definition.name.clone();
+
+        match self.macros.get(&name.name) {
+            Some(first) => {
+                let message = format!("Duplicate macro '{}' (first defined on line {})", name.name, first.line);
+                self.error(name.span, message);
+            },
+            None => {
+                self.macros.insert(name.name.clone(), Rc::new(definition.clone()));
+            },
+        }
+        self.push(Item::Macro(definition));
+    }
+
+    /// Expand a use of a macro, `NAME [ARGUMENT, ...]`
+    fn expand(&mut self, number: usize, labels: Vec<Label>, tokens: &[Token], comment: Option<Comment>) {
+        let name = &tokens[0];
+        let definition = Rc::clone(&self.macros[name.text]);
+
+        // Reported once against the outermost use, as the full trace would
+        // be as deep as the limit
+        if self.expanding.len() >= MAX_MACRO_DEPTH {
+            let outer = &self.expanding[0];
+            let message = format!(
+                "Macros are used more than {} deep (does '{}' use itself?) (in expansion of {} at line {})",
+                MAX_MACRO_DEPTH, name.text, outer.name, outer.line
+            );
+            self.diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                span: name.span,
+                message,
+            });
+            return;
+        }
+
+        // Split the arguments on commas
+        let mut arguments: Vec<&[Token]> = Vec::new();
+        if tokens.len() > 1 {
+            for argument in tokens[1..].split(|token| token.kind == TokenKind::Comma) {
+                if argument.is_empty() {
+                    let comma = tokens[1..].iter().find(|token| token.kind == TokenKind::Comma).unwrap();
+                    self.error(comma.span, "Expected an argument".to_string());
+                    return;
+                }
+                arguments.push(argument);
+            }
+        }
+        if arguments.len() != definition.params.len() {
+            let message = format!(
+                "'{}' expects {} argument(s), found {} (separate arguments with ',')",
+                name.text,
+                definition.params.len(),
+                arguments.len()
+            );
+            self.error(name.span.to(tokens.last().unwrap().span), message);
+            return;
+        }
+
+        // Labels the body defines itself get a new name for each expansion
+        self.expansion_count += 1;
+        let mut renamed = HashMap::new();
+        for text in &definition.body {
+            let mut body_tokens = Vec::new();
+            lexer::lex_line(text, 0, &mut body_tokens);
+            let defined = match body_tokens.as_slice() {
+                [directive, name, ..] if directive.text == ".equ" || directive.text == ".set" => vec![name.text],
+                _ => body_tokens
+                    .chunks(2)
+                    .take_while(|pair| pair.len() == 2 && pair[1].kind == TokenKind::Colon)
+                    .map(|pair| pair[0].text)
+                    .collect(),
+            };
+            for local in defined {
+                if !definition.params.iter().any(|param| param.name == local) {
+                    renamed.insert(local.to_string(), format!("{}@{}", local, self.expansion_count));
+                }
+            }
+        }
+
+        let mut locals: Vec<String> = renamed.values().cloned().collect();
+        locals.sort();
+        self.expanding.push(Expansion {
+            line: number,
+            labels,
+            name: name.text.to_string(),
+            name_span: name.span,
+            arguments: arguments.iter().map(|argument| self.text_of(argument)).collect(),
+            comment,
+            items: Vec::new(),
+            locals,
+            trace: String::new(),
+        });
+        let trace: Vec<String> = self
+            .expanding
+            .iter()
+            .rev()
+            .map(|expansion| format!("in expansion of {} at line {}", expansion.name, expansion.line))
+            .collect();
+        self.expanding.last_mut().unwrap().trace = format!(" ({})", trace.join(", "));
+
+        // Parse the body with the arguments and new names substituted
+        for (index, text) in definition.body.iter().enumerate() {
+            let line = definition.line + 1 + index;
+            let mut body_tokens = Vec::new();
+            lexer::lex_line(text, line, &mut body_tokens);
+
+            let mut tokens = Vec::new();
+            for mut token in body_tokens {
+                token.span.file = definition.span.file;
+                let param = definition.params.iter().position(|param| param.name == token.text);
+                match (token.kind, param) {
+                    (TokenKind::Identifier, Some(param)) => tokens.extend_from_slice(arguments[param]),
+                    (TokenKind::Identifier, None) if renamed.contains_key(token.text) => tokens.push(Token {
+                        text: &renamed[token.text],
+                        ..token
+                    }),
+                    _ => tokens.push(token),
+                }
+            }
+            self.line(line, &tokens);
+        }
+
+        let expansion = self.expanding.pop().unwrap();
+        self.push(Item::Expansion(expansion));
+    }
+
+    /// Source text of some tokens
+    fn text_of(&self, tokens: &[Token]) -> String {
+        let (first, last) = (tokens[0].span, tokens[tokens.len() - 1].span);
+        let text = self.lines.get(first.line.wrapping_sub(1)).and_then(|line| line.get(first.column..last.end()));
+        match text {
+            Some(text) if first.file == self.file && first.line == last.line => text.to_string(),
+            _ => tokens.iter().map(|token| token.text).collect::<Vec<_>>().join(" "),
+        }
+    }
+
+    /// Take the `LABEL:` definitions from the start of a line, returning
+    /// them with the rest of the line
+    fn leading_labels<'t, 'k>(&mut self, tokens: &'t [Token<'k>]) -> Option<(Vec<Label>, &'t [Token<'k>])> {
+        let mut labels = Vec::new();
+        let mut rest = tokens;
+
+        while let [name, colon, tail @ ..] = rest {
+            if colon.kind != TokenKind::Colon {
+                break;
+            }
+            labels.push(self.label(name, true)?);
+            rest = tail;
+        }
+
+        if let Some(colon) = rest.iter().find(|token| token.kind == TokenKind::Colon) {
+            self.error(colon.span, "Unexpected ':' (labels go at the start of a line)".to_string());
+            return None;
+        }
+
+        Some((labels, rest))
+    }
+
+    /// Check that a token can be used as a label
+    fn label(&mut self, token: &Token, colon: bool) -> Option<Label> {
+        if token.kind != TokenKind::Identifier {
+            self.error(token.span, format!("Expected a label, found '{}'", token.text));
+            return None;
+        }
+        if token.text.parse::<Opcode>().is_ok() {
+            self.error(token.span, format!("'{}' is an instruction and cannot be used as a label", token.text));
+            return None;
+        }
+
+        Some(Label {
+            name: token.text.to_string(),
+            span: token.span,
+            colon,
+        })
+    }
+
+    /// Parse `DAT [VALUE]` or a data directive (either may follow a label
+    /// without a colon)
+    fn data_line(&mut self, mut labels: Vec<Label>, tokens: &[Token], comment: Option<Comment>) -> Option<DataLine> {
+        let is_keyword = |token: &Token| token.text == "DAT" || DATA_DIRECTIVES.contains(&token.text);
+
+        // A label without a colon
+        let tokens = match tokens {
+            [label, keyword, ..] if !is_keyword(label) && is_keyword(keyword) => {
+                labels.push(self.label(label, false)?);
+                &tokens[1..]
+            },
+            _ => tokens,
+        };
+
+        let keyword = &tokens[0];
+        if !is_keyword(keyword) {
+            let message = if keyword.kind == TokenKind::Directive {
+                format!("Unknown directive '{}'", keyword.text)
+            } else if tokens.len() == 1 {
+                format!("Expected DAT, found '{}' (add ':' to define a label)", keyword.text)
+            } else {
+                format!("Expected DAT, found '{}'", keyword.text)
+            };
+            self.error(keyword.span, message);
+            return None;
+        }
+
+        let arguments = &tokens[1..];
+        let (value, used) = match keyword.text {
+            "DAT" if arguments.is_empty() => (DataValue::Dat(None), 0),
+            "DAT" => {
+                let (value, used) = self.expression(arguments)?;
+                (DataValue::Dat(Some(value)), used)
+            },
+            ".byte" | ".word" => {
+                let (values, used) = self.expression_list(keyword, arguments)?;
+                match keyword.text {
+                    ".byte" => (DataValue::Bytes(values), used),
+                    _ => (DataValue::Words(values), used),
+                }
+            },
+            ".ascii" | ".asciz" => match arguments.first() {
+                Some(string) if string.kind == TokenKind::String => match literal::parse_string(string.text) {
+                    Ok(bytes) => {
+                        let value = DataValue::Ascii {
+                            bytes,
+                            text: string.text.to_string(),
+                            zero_terminated: keyword.text == ".asciz",
+                        };
+                        (value, 1)
+                    },
+                    Err(message) => {
+                        self.error(string.span, message);
+                        return None;
+                    },
+                },
+                Some(other) => {
+                    self.error(other.span, format!("Expected a string, found '{}'", other.text));
+                    return None;
+                },
+                None => {
+                    self.error(keyword.span, format!("Expected a string after {}", keyword.text));
+                    return None;
+                },
+            },
+            ".space" | ".fill" => {
+                let (values, used) = self.expression_list(keyword, arguments)?;
+                if let Some(extra) = values.get(2) {
+                    let message = format!("{} takes a count and an optional value", keyword.text);
+                    self.error(extra.span, message);
+                    return None;
+                }
+                let mut values = values.into_iter();
+                let count = values.next().unwrap();
+                (DataValue::Fill { count, value: values.next() }, used)
+            },
+            // .align
+            _ => {
+                if arguments.is_empty() {
+                    self.error(keyword.span, "Expected a boundary after .align".to_string());
+                    return None;
+                }
+                let (boundary, used) = self.expression(arguments)?;
+                (DataValue::Align(boundary), used)
+            },
+        };
+        self.extra_tokens(&arguments[used..]);
+
+        Some(DataLine {
+            line: keyword.span.line,
+            labels,
+            keyword_span: keyword.span,
+            value,
+            comment,
+        })
+    }
+
+    /// Parse `OPCODE [OPERAND]` (or `LABEL OPCODE [OPERAND]`)
+    fn code_line(&mut self, mut labels: Vec<Label>, tokens: &[Token], comment: Option<Comment>) -> Option<CodeLine> {
+        let is_opcode = |token: &Token| token.text.parse::<Opcode>().is_ok();
+
+        // A label without a colon is recognised by the mnemonic after it
+        let tokens = match tokens {
+            [label, opcode, ..] if !is_opcode(label) && is_opcode(opcode) => {
+                labels.push(self.label(label, false)?);
+                &tokens[1..]
+            },
+            _ => tokens,
+        };
+
+        let is_data = |token: &&Token| token.text == "DAT" || DATA_DIRECTIVES.contains(&token.text);
+        if let Some(directive) = tokens.iter().take(2).find(is_data) {
+            self.error(directive.span, format!("{} can only be used in .data", directive.text));
+            return None;
+        }
+
+        let opcode = match tokens[0].text.parse::<Opcode>() {
+            Ok(opcode) => opcode,
+            Err(_) => {
+                let message = match tokens.len() {
+                    1 => format!("Unknown instruction '{}' (add ':' to define a label)", tokens[0].text),
+                    // `LABEL XXX OPERAND` is more likely a misspelt mnemonic
+                    // after a label than the other way round
+                    3 => {
+                        self.error(tokens[1].span, format!("Unknown instruction '{}'", tokens[1].text));
+                        return None;
+                    },
+                    _ => format!("Unknown instruction '{}'", tokens[0].text),
+                };
+                self.error(tokens[0].span, message);
+                return None;
+            },
+        };
+
+        let (operand, used) = match tokens.len() {
+            1 => (None, 0),
+            _ => {
+                let (operand, used) = self.expression(&tokens[1..])?;
+                (Some(operand), used)
+            },
+        };
+        self.extra_tokens(&tokens[1 + used..]);
+
+        Some(CodeLine {
+            line: tokens[0].span.line,
+            labels,
+            opcode,
+            opcode_span: tokens[0].span,
+            operand,
+            comment,
+        })
+    }
+
+    /// Parse `VALUE, ...` after a directive, returning the values with the
+    /// number of tokens used
+    fn expression_list(&mut self, directive: &Token, tokens: &[Token]) -> Option<(Vec<Expr>, usize)> {
+        let mut values = Vec::new();
+        let mut position = 0;
+
+        loop {
+            if position == tokens.len() {
+                let after = tokens.get(position.wrapping_sub(1)).unwrap_or(directive);
+                self.error(after.span, format!("Expected a value after '{}'", after.text));
+                return None;
+            }
+
+            let (value, used) = self.expression(&tokens[position..])?;
+            values.push(value);
+            position += used;
+
+            match tokens.get(position) {
+                Some(comma) if comma.kind == TokenKind::Comma => position += 1,
+                _ => return Some((values, position)),
+            }
+        }
+    }
+
+    /// Parse an expression from the start of some tokens, returning it
+    /// with the number of tokens used
+    fn expression(&mut self, tokens: &[Token]) -> Option<(Expr, usize)> {
+        let mut position = 0;
+        let expr = self.binary(tokens, &mut position, 0)?;
+        Some((expr, position))
+    }
+
+    /// Parse operands joined by operators that bind at least as tightly
+    /// as `min_power`
+    fn binary(&mut self, tokens: &[Token], position: &mut usize, min_power: u8) -> Option<Expr> {
+        let mut left = self.unary(tokens, position)?;
+
+        while let Some(token) = tokens.get(*position).filter(|token| token.kind == TokenKind::Operator) {
+            let (op, power) = match BinaryOp::from_token(token.text) {
+                Some((op, power)) if power >= min_power => (op, power),
+                _ => break,
+            };
+            *position += 1;
+
+            // Operators of the same power group to the left
+            let right = self.binary(tokens, position, power + 1)?;
+            left = Expr {
+                span: left.span.to(right.span),
+                kind: ExprKind::Binary(op, Box::new(left), Box::new(right)),
+            };
+        }
+
+        Some(left)
+    }
+
+    /// Parse a value, a symbol, a negated operand or a group
+    fn unary(&mut self, tokens: &[Token], position: &mut usize) -> Option<Expr> {
+        let token = match tokens.get(*position) {
+            Some(token) => token,
+            None => {
+                let last = tokens[*position - 1].span;
+                self.error(last, format!("Expected a value after '{}'", tokens[*position - 1].text));
+                return None;
+            },
+        };
+        *position += 1;
+
+        let kind = match token.kind {
+            TokenKind::Number | TokenKind::Char => {
+                let value = match token.kind {
+                    TokenKind::Number => literal::parse_number(token.text),
+                    _ => literal::parse_char(token.text),
+                };
+                match value {
+                    Ok(value) => ExprKind::Number(value, token.text.to_string()),
+                    Err(message) => {
+                        self.error(token.span, message);
+                        return None;
+                    },
+                }
+            },
+            TokenKind::Identifier => ExprKind::Symbol(token.text.to_string()),
+            TokenKind::Operator if token.text == "-" => {
+                let inner = self.unary(tokens, position)?;
+                return Some(Expr {
+                    span: token.span.to(inner.span),
+                    kind: ExprKind::Negate(Box::new(inner)),
+                });
+            },
+            TokenKind::Operator if token.text == "(" => {
+                let inner = self.binary(tokens, position, 0)?;
+                match tokens.get(*position) {
+                    Some(close) if close.text == ")" => {
+                        *position += 1;
+                        return Some(Expr {
+                            span: token.span.to(close.span),
+                            kind: ExprKind::Group(Box::new(inner)),
+                        });
+                    },
+                    _ => {
+                        self.error(token.span, "Unclosed '('".to_string());
+                        return None;
+                    },
+                }
+            },
+            _ => {
+                self.error(token.span, format!("Expected a value, found '{}'", token.text));
+                return None;
+            },
+        };
+
+        Some(Expr { kind, span: token.span })
+    }
+
+    /// Add an item to the current expansion, section or the preamble
+    fn push(&mut self, item: Item) {
+        if let Some(expansion) = self.expanding.last_mut() {
+            expansion.items.push(item);
+            return;
+        }
+        match self.ast.sections.last_mut() {
+            Some(section) => section.items.push(item),
+            None => self.ast.preamble.push(item),
+        }
+    }
+
+    /// Remove the blank item for the empty text after a final newline
+    fn pop_trailing_blank(&mut self, last_line: usize) {
+        let items = match self.ast.sections.last_mut() {
+            Some(section) => &mut section.items,
+            None => &mut self.ast.preamble,
+        };
+        if items.last() == Some(&Item::Blank(last_line)) {
+            items.pop();
+        }
+    }
+
+    /// Warn about tokens after the end of a line
+    fn extra_tokens(&mut self, tokens: &[Token]) {
+        if let (Some(first), Some(last)) = (tokens.first(), tokens.last()) {
+            self.diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                span: first.span.to(last.span),
+                message: format!("Ignoring unexpected text{}", self.trace()),
+            });
+        }
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            span,
+            message: format!("{}{}", message, self.trace()),
+        });
+    }
+
+    /// Where the line being parsed was expanded from, if it is in a macro
+    fn trace(&self) -> &str {
+        self.expanding.last().map_or("", |expansion| expansion.trace.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each problem found parsing source, as `line:column: message`
+    fn problems(source: &str) -> Vec<String> {
+        let (_, diagnostics) = parse(source);
+        diagnostics.iter().map(|d| format!("{}:{}: {}", d.span.line, d.span.column + 1, d.message)).collect()
+    }
+
+    #[test]
+    fn code_lines() {
+        let (ast, diagnostics) = parse(".code\nstart: loop: lda A + 1 ; go\n        hlt\n");
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let lines: Vec<&CodeLine> = ast.code_lines().collect();
+        assert_eq!(lines.len(), 2);
+
+        let names: Vec<&str> = lines[0].labels.iter().map(|label| label.name.as_str()).collect();
+        assert_eq!(names, ["start", "loop"]);
+        assert_eq!(lines[0].opcode, Opcode::LDA);
+        let operand = lines[0].operand.as_ref().map(|operand| &operand.kind);
+        assert!(matches!(operand, Some(ExprKind::Binary(BinaryOp::Add, _, _))), "{:?}", operand);
+        assert_eq!(lines[0].comment.as_ref().map(|comment| comment.text.as_str()), Some("; go"));
+        assert_eq!((lines[1].line, lines[1].opcode), (3, Opcode::HLT));
+    }
+
+    #[test]
+    fn label_without_colon() {
+        let (ast, diagnostics) = parse(".data\nA DAT 1\n");
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let line = ast.data_lines().next().unwrap();
+        assert_eq!((line.labels[0].name.as_str(), line.labels[0].colon), ("A", false));
+    }
+
+    #[test]
+    fn problems_are_located() {
+        assert_eq!(problems("LDA A\n"), ["1:1: Expected .data or .code before this line"]);
+        assert_eq!(problems(".code\n  FOO\n"), ["2:3: Unknown instruction 'FOO' (add ':' to define a label)"]);
+        assert_eq!(problems(".code\nlda: HLT\n"), ["2:1: 'LDA' is an instruction and cannot be used as a label"]);
+        assert_eq!(problems(".code\nLDA (1\n"), ["2:5: Unclosed '('"]);
+        assert_eq!(problems(".bogus\n"), ["1:1: Unknown directive '.bogus'"]);
+        assert_eq!(problems(".data\nA: DAT 1 ? 2\n")[0], "2:10: Unexpected character '?'");
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        assert_eq!(problems(".code\nFOO\nBAR\nHLT\n").len(), 2);
+    }
+
+    #[test]
+    fn macros() {
+        let source = ".macro twice x\n        OUT\n        OUT\n.endm\n.code\n        twice 1\n        HLT\n";
+        let (ast, diagnostics) = parse(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(ast.code_lines().count(), 3);
+        assert_eq!(problems(".code\n.endm\n"), ["2:1: .endm without .macro"]);
+    }
+}
@@ -0,0 +1,258 @@
+//! Debug information linking a binary back to its source
+//! A source map gives the file, line and column of the instruction at
+//! each code address, and the label of each named data address, so that
+//! faults and traces can say `test.vnc:6: ADD B` instead of `0x02`.
+//!
+//! It is saved next to the binary (`test.bin` gets `test.map`) as a line
+//! based text file:
+//! ```text
+//! # virtual nanocomputer source map
+//! format vnc-map
+//! version 1
+//! code 0x00 test.vnc:6:5 LDA A
+//! code 0x02 test.vnc:7:5 ADD B
+//! data 0x00 A
+//! end
+//! ```
+//! (`code address file:line:column instruction` and `data address
+//! label`.) The instruction is written as assembled, so in a macro it
+//! shows the arguments rather than the parameters.
+
+use std::path::Path;
+
+/// Current source map format version
+pub const SOURCE_MAP_VERSION: u32 = 1;
+
+/// Where each part of a program came from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    /// Location of each instruction, by address
+    pub code: Vec<SourceLocation>,
+    /// Named data addresses, by address
+    pub data: Vec<(u32, String)>,
+}
+
+/// Where an instruction was written
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLocation {
+    pub address: u32,
+    pub file: String,
+    /// Line number (starting at 1)
+    pub line: usize,
+    /// Column of the mnemonic (starting at 1)
+    pub column: usize,
+    /// The instruction, e.g. `ADD B`
+    pub text: String,
+}
+
+impl SourceMap {
+    /// Path of the source map saved next to a binary
+    pub fn path_for(binary: &str) -> String {
+        Path::new(binary).with_extension("map").display().to_string()
+    }
+
+    /// Save the source map to a file
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        std::fs::write(filename, self.to_string())
+    }
+
+    /// Load a source map from a file
+    pub fn load(filename: &str) -> Result<SourceMap, String> {
+        let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        SourceMap::parse(&text).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    /// Find where the instruction at an address was written
+    pub fn location(&self, address: u32) -> Option<&SourceLocation> {
+        self.code.iter().find(|location| location.address == address)
+    }
+
+    /// Describe an instruction address as `file:line: instruction`, or
+    /// `0xNN` if it is not in the map
+    pub fn describe(&self, address: u32) -> String {
+        match self.location(address) {
+            Some(location) => format!("{}:{}: {}", location.file, location.line, location.text),
+            None => format!("0x{:02X}", address),
+        }
+    }
+
+    /// Name a data address by the closest label at or before it, e.g.
+    /// `TABLE+2`
+    pub fn data_label(&self, address: u32) -> Option<String> {
+        let (start, label) = self.data.iter().filter(|(start, _)| *start <= address).max_by_key(|(start, _)| *start)?;
+        match address - start {
+            0 => Some(label.clone()),
+            offset => Some(format!("{}+{}", label, offset)),
+        }
+    }
+
+    /// Move every address by an offset (for a program placed by the
+    /// linker)
+    pub fn shifted(&self, code_offset: u32, data_offset: u32) -> SourceMap {
+        SourceMap {
+            code: self
+                .code
+                .iter()
+                .map(|location| SourceLocation {
+                    address: location.address + code_offset,
+                    ..location.clone()
+                })
+                .collect(),
+            data: self.data.iter().map(|(address, label)| (address + data_offset, label.clone())).collect(),
+        }
+    }
+
+    /// Parse the text form of a source map
+    pub fn parse(text: &str) -> Result<SourceMap, String> {
+        let mut map = SourceMap::default();
+        let mut version = 0;
+        let mut ended = false;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if ended {
+                return Err(format!("line {}: content after 'end'", line_number));
+            }
+
+            let error = |message: String| format!("line {}: {}", line_number, message);
+            let (key, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "format" => {
+                    if rest != "vnc-map" {
+                        return Err(error(format!("not a source map (format {})", rest)));
+                    }
+                },
+                "version" => {
+                    version = parse_number(rest).map_err(error)? as u32;
+                    if version == 0 || version > SOURCE_MAP_VERSION {
+                        return Err(error(format!("unsupported source map version {}", version)));
+                    }
+                },
+                "code" => map.code.push(parse_location(rest).map_err(error)?),
+                "data" => {
+                    let (address, label) = rest.split_once(' ').ok_or_else(|| error("missing label".to_string()))?;
+                    map.data.push((parse_number(address).map_err(error)? as u32, label.to_string()));
+                },
+                "end" => ended = true,
+                _ => return Err(error(format!("unknown key '{}'", key))),
+            }
+        }
+
+        if version == 0 {
+            return Err("missing 'format' or 'version' header".to_string());
+        }
+        if !ended {
+            return Err("source map is truncated (missing 'end')".to_string());
+        }
+
+        Ok(map)
+    }
+}
+
+/// Parse `0xADDR file:line:column text`
+pub(crate) fn parse_location(text: &str) -> Result<SourceLocation, String> {
+    let (address, rest) = text.split_once(' ').ok_or("missing location")?;
+    let address = parse_number(address)? as u32;
+
+    // File names may hold spaces, so the location ends at the first space
+    // after something that reads as file:line:column
+    let ends = rest.match_indices(' ').map(|(index, _)| index).chain([rest.len()]);
+    let (file, line, column, end) = ends
+        .filter_map(|end| {
+            let mut parts = rest[..end].rsplitn(3, ':');
+            let (column, line, file) = (parts.next()?, parts.next()?, parts.next()?);
+            Some((file, parse_number(line).ok()?, parse_number(column).ok()?, end))
+        })
+        .next()
+        .ok_or_else(|| format!("invalid location '{}' (expected file:line:column)", rest))?;
+
+    Ok(SourceLocation {
+        address,
+        file: file.to_string(),
+        line: line as usize,
+        column: column as usize,
+        text: rest[end..].strip_prefix(' ').unwrap_or("").to_string(),
+    })
+}
+
+/// Parse a decimal or 0x hex number
+fn parse_number(text: &str) -> Result<u64, String> {
+    let result = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse::<u64>(),
+    };
+    result.map_err(|_| format!("invalid number '{}'", text))
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{:02X} {}:{}:{} {}", self.address, self.file, self.line, self.column, self.text)
+    }
+}
+
+impl std::fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "# virtual nanocomputer source map")?;
+        writeln!(f, "format vnc-map")?;
+        writeln!(f, "version {}", SOURCE_MAP_VERSION)?;
+        for location in &self.code {
+            writeln!(f, "code {}", location)?;
+        }
+        for (address, label) in &self.data {
+            writeln!(f, "data 0x{:02X} {}", address, label)?;
+        }
+        writeln!(f, "end")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble_with, Options};
+
+    const SOURCE: &str = ".data
+A:      DAT 4
+TABLE:  .byte 1, 2, 3
+.code
+        LDA A
+        ADD TABLE+2
+        HLT
+";
+
+    #[test]
+    fn round_trip() {
+        let map = assemble_with(SOURCE, &Options::for_file("my programs/C:sum.vnc")).program.source_map;
+        assert_eq!(map.code.len(), 3);
+        assert_eq!(map.code[1].to_string(), "0x02 my programs/C:sum.vnc:6:9 ADD TABLE + 2");
+        assert_eq!(map.data, vec![(0, "A".to_string()), (1, "TABLE".to_string())]);
+        assert_eq!(SourceMap::parse(&map.to_string()), Ok(map.clone()));
+
+        let path = std::env::temp_dir().join(format!("vnc-source-map-{}.map", std::process::id()));
+        let path = path.display().to_string();
+        map.save(&path).unwrap();
+        let loaded = SourceMap::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Ok(map));
+    }
+
+    #[test]
+    fn lookups() {
+        let map = assemble_with(SOURCE, &Options::for_file("sum.vnc")).program.source_map;
+        assert_eq!(map.describe(2), "sum.vnc:6: ADD TABLE + 2");
+        assert_eq!(map.describe(8), "0x08");
+        assert_eq!(map.data_label(3).as_deref(), Some("TABLE+2"));
+        assert_eq!(map.shifted(4, 1).location(6).map(|location| location.line), Some(6));
+    }
+
+    #[test]
+    fn bad_maps_are_errors() {
+        assert!(SourceMap::parse("format vnc-map\nversion 1\n").unwrap_err().contains("truncated"));
+        assert!(SourceMap::parse("format vnc-map\nversion 2\nend\n").unwrap_err().contains("version 2"));
+        assert!(SourceMap::parse("format vnc-map\nversion 1\ncode 0x00 sum.vnc LDA\nend\n").is_err());
+        assert!(SourceMap::load("no-such-file.map").unwrap_err().starts_with("no-such-file.map: "));
+    }
+}
@@ -0,0 +1,377 @@
+//! Control flow graph of a program
+//! The code is split into basic blocks: runs of instructions that always
+//! execute one after another, so are only entered at the first and only
+//! left after the last. A block ends after a jump, a branch or HLT, and
+//! one starts at every address something jumps to:
+//! ```text
+//!        .code
+//!        LDA A          block 0 -> block 1
+//! loop:  SUB ONE        block 1 -> block 1 (taken), block 2 (next)
+//!        JNZ loop
+//!        HLT            block 2
+//! ```
+//! Each edge says whether it is taken by carrying on to the next
+//! instruction, by a jump or by a branch whose condition holds. An edge
+//! may also lead nowhere: past the last instruction, or to an address
+//! that is not the start of an instruction.
+//!
+//! The graph only follows the bytes of the program, so it works on a
+//! loaded binary as well as on one just assembled.
+//!
+//! It can be drawn with Graphviz from its DOT form, which shows the labels
+//! and instructions of each block. Given the `Profile` of a run, each
+//! instruction and edge is marked with how often it ran, and blocks that
+//! never ran are greyed out:
+//! ```text
+//! digraph cfg {
+//!     b1 [label="loop:\l     3  0x02  SUB ONE\l     3  0x04  JNZ loop\l"];
+//!     b1 -> b1 [label="taken (2)"];
+//!     b1 -> b2 [label="not taken (1)"];
+//! }
+//! ```
+
+use crate::assembler::{Program, Section};
+use crate::cpu::instructions::{Instruction, Opcode};
+use crate::cpu::profile::Profile;
+
+/// Basic blocks of a program, in address order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+/// Instructions that execute one after another
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    /// Address of the first instruction
+    pub start: u32,
+    /// Each instruction with its address (`None` if the word is not an
+    /// instruction, which ends the block)
+    pub instructions: Vec<(u32, Option<Instruction>)>,
+    /// Where execution can go after the last instruction (nowhere after
+    /// HLT or a word that is not an instruction)
+    pub edges: Vec<Edge>,
+    /// Whether execution can get here from the start of the program
+    pub reachable: bool,
+}
+
+/// A way out of a block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: Target,
+}
+
+/// How an edge is taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Carrying on to the next instruction (also a branch not taken)
+    Next,
+    /// `JMP`
+    Jump,
+    /// A branch whose condition holds
+    Taken,
+}
+
+/// Where an edge leads
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// The block with this index
+    Block(usize),
+    /// Past the last instruction of the program
+    End,
+    /// An address that is not the start of an instruction
+    Outside(u32),
+}
+
+impl Cfg {
+    /// Build the graph of the code of a program
+    pub fn new(code: &[u8]) -> Cfg {
+        let words: Vec<Option<Instruction>> = code
+            .chunks_exact(2)
+            .map(|word| Instruction::try_from_word(u16::from_be_bytes([word[0], word[1]])))
+            .collect();
+        let count = words.len();
+        let index_of = |address: u32| match address.is_multiple_of(2) && ((address / 2) as usize) < count {
+            true => Some((address / 2) as usize),
+            false => None,
+        };
+
+        // Instructions that start a block
+        let mut leaders = vec![false; count];
+        if count > 0 {
+            leaders[0] = true;
+        }
+        for (index, word) in words.iter().enumerate() {
+            if ends_block(word) && index + 1 < count {
+                leaders[index + 1] = true;
+            }
+            if let Some(target) = jump_target(word).and_then(index_of) {
+                leaders[target] = true;
+            }
+        }
+
+        // Block each instruction is in
+        let mut block_of = Vec::with_capacity(count);
+        let mut blocks: Vec<Block> = Vec::new();
+        for (index, word) in words.iter().enumerate() {
+            let address = index as u32 * 2;
+            if leaders[index] {
+                blocks.push(Block {
+                    start: address,
+                    instructions: Vec::new(),
+                    edges: Vec::new(),
+                    reachable: false,
+                });
+            }
+            blocks.last_mut().unwrap().instructions.push((address, word.clone()));
+            block_of.push(blocks.len() - 1);
+        }
+
+        // Where each block goes
+        for block in &mut blocks {
+            let (address, last) = block.instructions.last().unwrap();
+            let index = (address / 2) as usize;
+            let next = match index + 1 < count {
+                true => Target::Block(block_of[index + 1]),
+                false => Target::End,
+            };
+            let target = |address: u32| index_of(address).map_or(Target::Outside(address), |index| Target::Block(block_of[index]));
+
+            let edge = |kind, target| Edge { kind, target };
+            block.edges = match last {
+                None => Vec::new(),
+                Some(instruction) => match instruction.opcode {
+                    Opcode::HLT | Opcode::DAT => Vec::new(),
+                    Opcode::JMP => vec![edge(EdgeKind::Jump, target(instruction.operand as u32))],
+                    opcode if opcode.is_branch() => vec![
+                        edge(EdgeKind::Taken, target(instruction.operand as u32)),
+                        edge(EdgeKind::Next, next),
+                    ],
+                    _ => vec![edge(EdgeKind::Next, next)],
+                },
+            };
+        }
+
+        // Blocks that can run, starting from address 0
+        let mut pending = match blocks.is_empty() {
+            true => Vec::new(),
+            false => vec![0],
+        };
+        while let Some(index) = pending.pop() {
+            if blocks[index].reachable {
+                continue;
+            }
+            blocks[index].reachable = true;
+            for edge in &blocks[index].edges {
+                if let Target::Block(target) = edge.target {
+                    pending.push(target);
+                }
+            }
+        }
+
+        Cfg { blocks }
+    }
+
+    /// Index of the block holding the instruction at an address
+    pub fn block_at(&self, address: u32) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| block.instructions.iter().any(|(start, _)| *start == address))
+    }
+
+    /// Write the graph in Graphviz DOT form, naming labels and source
+    /// lines from the program and marking counts from a profile if given
+    pub fn to_dot(&self, program: &Program, profile: Option<&Profile>) -> String {
+        let mut out = Vec::new();
+        out.push("digraph cfg {".to_string());
+        out.push("    node [shape=box, fontname=\"monospace\"];".to_string());
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for (address, word) in &block.instructions {
+                for symbol in program.symbols.iter() {
+                    if symbol.section == Section::Code && symbol.address == *address {
+                        label.push_str(&format!("{}:\\l", escape(&symbol.name)));
+                    }
+                }
+                let text = match (program.source_map.location(*address), word) {
+                    (Some(location), _) => location.text.clone(),
+                    (None, Some(instruction)) => instruction.to_string(),
+                    (None, None) => "???".to_string(),
+                };
+                if let Some(profile) = profile {
+                    label.push_str(&format!("{:>6}  ", profile.count(*address)));
+                }
+                label.push_str(&format!("0x{:02X}  {}\\l", address, escape(&text)));
+            }
+
+            let ran = profile.is_none_or(|profile| profile.count(block.start) > 0);
+            let style = match (block.reachable, ran) {
+                (false, _) => ", style=dashed",
+                (true, false) => ", color=gray, fontcolor=gray",
+                (true, true) => "",
+            };
+            out.push(format!("    b{} [label=\"{}\"{}];", index, label, style));
+        }
+
+        let mut ends = Vec::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            let (address, last) = block.instructions.last().unwrap();
+            let branch = last.as_ref().is_some_and(|instruction| instruction.opcode.is_branch());
+            for edge in &block.edges {
+                let target = match edge.target {
+                    Target::Block(target) => format!("b{}", target),
+                    Target::End => "end".to_string(),
+                    Target::Outside(address) => format!("x{:02X}", address),
+                };
+                if !matches!(edge.target, Target::Block(_)) && !ends.contains(&edge.target) {
+                    ends.push(edge.target);
+                }
+
+                // Executions of the edge, from those of the jump or branch
+                let count = profile.map(|profile| match (edge.kind, branch) {
+                    (EdgeKind::Taken, _) => profile.taken(*address),
+                    (EdgeKind::Next, true) => profile.count(*address).saturating_sub(profile.taken(*address)),
+                    _ => profile.count(*address),
+                });
+                let name = match (edge.kind, branch) {
+                    (EdgeKind::Taken, _) => "taken",
+                    (EdgeKind::Next, true) => "not taken",
+                    _ => "",
+                };
+                let label = match count {
+                    Some(count) if name.is_empty() => count.to_string(),
+                    Some(count) => format!("{} ({})", name, count),
+                    None => name.to_string(),
+                };
+                match label.is_empty() {
+                    true => out.push(format!("    b{} -> {};", index, target)),
+                    false => out.push(format!("    b{} -> {} [label=\"{}\"];", index, target, label)),
+                }
+            }
+        }
+
+        // Places outside the program that edges lead to
+        for target in ends {
+            match target {
+                Target::End => out.push("    end [shape=plaintext, label=\"end of program\"];".to_string()),
+                Target::Outside(address) => {
+                    out.push(format!("    x{:02X} [shape=plaintext, label=\"0x{:02X} (not an instruction)\"];", address, address));
+                },
+                Target::Block(_) => {},
+            }
+        }
+
+        out.push("}".to_string());
+        out.push(String::new());
+        out.join("\n")
+    }
+
+    /// Indexes of the blocks with an edge to a block
+    pub fn predecessors(&self, index: usize) -> Vec<usize> {
+        (0..self.blocks.len())
+            .filter(|other| {
+                self.blocks[*other]
+                    .edges
+                    .iter()
+                    .any(|edge| edge.target == Target::Block(index))
+            })
+            .collect()
+    }
+}
+
+/// Whether a word ends its block (anything that does not always carry on
+/// to the next instruction)
+fn ends_block(word: &Option<Instruction>) -> bool {
+    match word {
+        None => true,
+        Some(instruction) => matches!(instruction.opcode, Opcode::JMP | Opcode::HLT | Opcode::DAT) || instruction.opcode.is_branch(),
+    }
+}
+
+/// Address a word can jump to, if it is a jump or a branch
+fn jump_target(word: &Option<Instruction>) -> Option<u32> {
+    match word {
+        Some(instruction) if instruction.opcode == Opcode::JMP || instruction.opcode.is_branch() => {
+            Some(instruction.operand as u32)
+        },
+        _ => None,
+    }
+}
+
+/// Escape text for a DOT string
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+
+    const COUNTDOWN: &str = ".data
+A: DAT 3
+ONE: DAT 1
+.code
+        LDA A
+loop:   SUB ONE
+        JNZ loop
+        HLT
+";
+
+    fn edge(kind: EdgeKind, target: Target) -> Edge {
+        Edge { kind, target }
+    }
+
+    #[test]
+    fn blocks_end_at_jumps_and_start_at_targets() {
+        let program = assemble_source(COUNTDOWN).program;
+        let cfg = Cfg::new(&program.code);
+
+        let starts: Vec<_> = cfg.blocks.iter().map(|block| block.start).collect();
+        assert_eq!(starts, vec![0x00, 0x02, 0x06]);
+        assert_eq!(cfg.blocks[0].edges, vec![edge(EdgeKind::Next, Target::Block(1))]);
+        assert_eq!(
+            cfg.blocks[1].edges,
+            vec![edge(EdgeKind::Taken, Target::Block(1)), edge(EdgeKind::Next, Target::Block(2))]
+        );
+        assert!(cfg.blocks[2].edges.is_empty());
+        assert!(cfg.blocks.iter().all(|block| block.reachable));
+        assert_eq!(cfg.block_at(0x04), Some(1));
+        assert_eq!(cfg.predecessors(1), vec![0, 1]);
+    }
+
+    #[test]
+    fn edges_can_lead_out_of_the_program() {
+        let program = assemble_source(".code\nJNZ 0x03\nOUT\nHLT\nOUT\n").program;
+        let cfg = Cfg::new(&program.code);
+
+        assert_eq!(cfg.blocks[0].edges[0], edge(EdgeKind::Taken, Target::Outside(0x03)));
+        // After HLT nothing gets to the last block, which runs off the end
+        let last = cfg.blocks.last().unwrap();
+        assert!(!last.reachable);
+        assert_eq!(last.edges, vec![edge(EdgeKind::Next, Target::End)]);
+    }
+
+    #[test]
+    fn dot_shows_counts_from_a_profile() {
+        let program = assemble_source(COUNTDOWN).program;
+        let cfg = Cfg::new(&program.code);
+        let dot = cfg.to_dot(&program, None);
+        assert!(dot.contains("b1 [label=\"loop:\\l0x02  SUB ONE\\l0x04  JNZ loop\\l\"];"), "{}", dot);
+        assert!(dot.contains("b1 -> b1 [label=\"taken\"];"), "{}", dot);
+
+        // Stopped before the loop ran out
+        let mut profile = Profile::new();
+        profile.record(0x00, false);
+        for taken in [true, false] {
+            profile.record(0x02, false);
+            profile.record(0x04, taken);
+        }
+        let dot = cfg.to_dot(&program, Some(&profile));
+        assert!(dot.contains("b1 -> b1 [label=\"taken (1)\"];"), "{}", dot);
+        assert!(dot.contains("b1 -> b2 [label=\"not taken (1)\"];"), "{}", dot);
+        assert!(dot.contains("0x06  HLT\\l\", color=gray, fontcolor=gray];"), "{}", dot);
+    }
+}
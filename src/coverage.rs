@@ -0,0 +1,293 @@
+//! Code coverage of a program
+//! Works out, from the profiles of one or more runs (e.g. every test of a
+//! program), which source lines ran and which way each branch went, using
+//! the program's source map to find the line of each instruction.
+//!
+//! Coverage is written in the lcov tracefile format read by coverage
+//! viewers (`genhtml`, editor plugins, CI services). Each source file gets
+//! a record with:
+//! 1. `DA:line,count` for each line holding an instruction
+//! 2. `BRDA:line,block,branch,count` for each direction of each branch
+//!    (branch 0 is taken, 1 is not taken, and the count is `-` if the
+//!    line never ran)
+//! 3. `FN`/`FNDA` for each code label, counting runs of its instruction,
+//!    so the regions between labels show up as functions
+//!
+//! ```text
+//! TN:
+//! SF:loop.vnc
+//! FN:6,loop
+//! FNDA:3,loop
+//! DA:6,3
+//! DA:7,3
+//! BRDA:7,0,0,2
+//! BRDA:7,0,1,1
+//! end_of_record
+//! ```
+
+use std::collections::BTreeMap;
+
+use crate::assembler::{Program, Section};
+use crate::cpu::instructions::Instruction;
+use crate::cpu::profile::Profile;
+
+/// Coverage of each source file of a program, in the order they first
+/// appear in the code
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    pub files: Vec<FileCoverage>,
+}
+
+/// Coverage of one source file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileCoverage {
+    pub path: String,
+    /// Runs of each line holding an instruction (the most of any
+    /// instruction on it, for a line a macro expands to several)
+    pub lines: BTreeMap<usize, u64>,
+    /// Every branch instruction, in address order
+    pub branches: Vec<BranchCoverage>,
+    /// Every code label, in address order
+    pub functions: Vec<FunctionCoverage>,
+}
+
+/// Which ways a branch went
+#[derive(Clone, Debug, PartialEq)]
+pub struct BranchCoverage {
+    pub line: usize,
+    pub address: u32,
+    /// Times the branch was taken and not taken (`None` if it never ran)
+    pub counts: Option<(u64, u64)>,
+}
+
+/// Runs of the instruction a code label is on
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionCoverage {
+    pub name: String,
+    pub line: usize,
+    pub count: u64,
+}
+
+impl Coverage {
+    /// Work out the coverage of a program from the (merged) profile of
+    /// its runs. Instructions missing from the source map are left out.
+    pub fn new(program: &Program, profile: &Profile) -> Coverage {
+        let mut coverage = Coverage::default();
+
+        for location in &program.source_map.code {
+            let count = profile.count(location.address);
+            let file = coverage.file(&location.file);
+            let line = file.lines.entry(location.line).or_insert(0);
+            *line = (*line).max(count);
+
+            let instruction = program
+                .code
+                .get(location.address as usize..location.address as usize + 2)
+                .and_then(|word| Instruction::try_from_word(u16::from_be_bytes([word[0], word[1]])));
+            if instruction.is_some_and(|instruction| instruction.opcode.is_branch()) {
+                let taken = profile.taken(location.address);
+                file.branches.push(BranchCoverage {
+                    line: location.line,
+                    address: location.address,
+                    counts: (count > 0).then_some((taken, count.saturating_sub(taken))),
+                });
+            }
+        }
+
+        let labels = program.symbols.iter().filter(|symbol| symbol.section == Section::Code);
+        for symbol in labels {
+            if let Some(location) = program.source_map.location(symbol.address) {
+                coverage.file(&location.file).functions.push(FunctionCoverage {
+                    name: symbol.name.clone(),
+                    line: location.line,
+                    count: profile.count(symbol.address),
+                });
+            }
+        }
+
+        for file in &mut coverage.files {
+            file.branches.sort_by_key(|branch| branch.address);
+        }
+        coverage
+    }
+
+    /// Coverage of a file, added if it is not there yet
+    fn file(&mut self, path: &str) -> &mut FileCoverage {
+        let index = match self.files.iter().position(|file| file.path == path) {
+            Some(index) => index,
+            None => {
+                self.files.push(FileCoverage {
+                    path: path.to_string(),
+                    ..FileCoverage::default()
+                });
+                self.files.len() - 1
+            },
+        };
+        &mut self.files[index]
+    }
+
+    /// Write the coverage as an lcov tracefile
+    pub fn to_lcov(&self) -> String {
+        let mut out = Vec::new();
+        for file in &self.files {
+            out.push("TN:".to_string());
+            out.push(format!("SF:{}", file.path));
+
+            for function in &file.functions {
+                out.push(format!("FN:{},{}", function.line, function.name));
+            }
+            for function in &file.functions {
+                out.push(format!("FNDA:{},{}", function.count, function.name));
+            }
+            out.push(format!("FNF:{}", file.functions.len()));
+            out.push(format!("FNH:{}", file.functions.iter().filter(|function| function.count > 0).count()));
+
+            for (line, count) in &file.lines {
+                out.push(format!("DA:{},{}", line, count));
+            }
+
+            // Branches are numbered within their line
+            let mut blocks: BTreeMap<usize, usize> = BTreeMap::new();
+            for branch in &file.branches {
+                let block = blocks.entry(branch.line).or_insert(0);
+                let (taken, not_taken) = match branch.counts {
+                    Some((taken, not_taken)) => (taken.to_string(), not_taken.to_string()),
+                    None => ("-".to_string(), "-".to_string()),
+                };
+                out.push(format!("BRDA:{},{},0,{}", branch.line, block, taken));
+                out.push(format!("BRDA:{},{},1,{}", branch.line, block, not_taken));
+                *block += 1;
+            }
+            let (found, hit) = file.branch_totals();
+            out.push(format!("BRF:{}", found));
+            out.push(format!("BRH:{}", hit));
+
+            let (found, hit) = file.line_totals();
+            out.push(format!("LF:{}", found));
+            out.push(format!("LH:{}", hit));
+            out.push("end_of_record".to_string());
+        }
+        out.push(String::new());
+        out.join("\n")
+    }
+
+    /// Summarise the coverage, e.g. `lines: 5/6 (83.3%)`
+    pub fn summary(&self) -> String {
+        let total = |totals: fn(&FileCoverage) -> (usize, usize)| {
+            self.files
+                .iter()
+                .map(totals)
+                .fold((0, 0), |(found, hit), (file_found, file_hit)| (found + file_found, hit + file_hit))
+        };
+        let percent = |(found, hit): (usize, usize)| match found {
+            0 => format!("{}/{}", hit, found),
+            _ => format!("{}/{} ({:.1}%)", hit, found, 100.0 * hit as f64 / found as f64),
+        };
+        format!(
+            "lines: {}\nbranches: {}",
+            percent(total(FileCoverage::line_totals)),
+            percent(total(FileCoverage::branch_totals))
+        )
+    }
+}
+
+impl FileCoverage {
+    /// Lines found and lines that ran
+    pub fn line_totals(&self) -> (usize, usize) {
+        (self.lines.len(), self.lines.values().filter(|count| **count > 0).count())
+    }
+
+    /// Branch directions found and directions that were followed
+    pub fn branch_totals(&self) -> (usize, usize) {
+        let hit = self
+            .branches
+            .iter()
+            .filter_map(|branch| branch.counts)
+            .map(|(taken, not_taken)| (taken > 0) as usize + (not_taken > 0) as usize)
+            .sum();
+        (self.branches.len() * 2, hit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble_with, Options};
+
+    const COUNTDOWN: &str = ".data
+A: DAT 3
+ONE: DAT 1
+.code
+        LDA A
+loop:   SUB ONE
+        JNZ loop
+        HLT
+";
+
+    fn program() -> Program {
+        assemble_with(COUNTDOWN, &Options::for_file("loop.vnc")).program
+    }
+
+    /// Profile of the loop running `times` times before stopping
+    fn profile(times: u64) -> Profile {
+        let mut profile = Profile::new();
+        profile.record(0x00, false);
+        for time in 1..=times {
+            profile.record(0x02, false);
+            profile.record(0x04, time < times);
+        }
+        profile
+    }
+
+    #[test]
+    fn lines_branches_and_functions_are_counted() {
+        let coverage = Coverage::new(&program(), &profile(3));
+        assert_eq!(coverage.files.len(), 1);
+        let file = &coverage.files[0];
+        assert_eq!(file.path, "loop.vnc");
+        let lines: Vec<_> = file.lines.iter().map(|(line, count)| (*line, *count)).collect();
+        assert_eq!(lines, vec![(5, 1), (6, 3), (7, 3), (8, 0)]);
+        assert_eq!(file.branches, vec![BranchCoverage { line: 7, address: 0x04, counts: Some((2, 1)) }]);
+        assert_eq!(file.functions, vec![FunctionCoverage { name: "loop".to_string(), line: 6, count: 3 }]);
+        assert_eq!(coverage.summary(), "lines: 3/4 (75.0%)\nbranches: 2/2 (100.0%)");
+    }
+
+    #[test]
+    fn lines_that_never_ran_have_no_branch_counts() {
+        let coverage = Coverage::new(&program(), &Profile::new());
+        assert_eq!(coverage.files[0].branches[0].counts, None);
+        assert_eq!(coverage.summary(), "lines: 0/4 (0.0%)\nbranches: 0/2 (0.0%)");
+    }
+
+    #[test]
+    fn lcov() {
+        let expected = "TN:
+SF:loop.vnc
+FN:6,loop
+FNDA:1,loop
+FNF:1
+FNH:1
+DA:5,1
+DA:6,1
+DA:7,1
+DA:8,0
+BRDA:7,0,0,0
+BRDA:7,0,1,1
+BRF:2
+BRH:1
+LF:4
+LH:3
+end_of_record
+";
+        assert_eq!(Coverage::new(&program(), &profile(1)).to_lcov(), expected);
+    }
+
+    #[test]
+    fn programs_without_a_source_map_have_no_coverage() {
+        let mut program = program();
+        program.source_map = Default::default();
+        let coverage = Coverage::new(&program, &profile(3));
+        assert!(coverage.files.is_empty());
+        assert_eq!(coverage.summary(), "lines: 0/0\nbranches: 0/0");
+    }
+}
@@ -0,0 +1,727 @@
+//! Optional cache sitting between the CPU and data memory
+//! Supports the three classic organisations:
+//! 1. Direct mapped (one line per set)
+//! 2. N-way set associative
+//! 3. Fully associative (a single set holding every line)
+//!
+//! An address is split into tag | index | offset, e.g. for a 32 byte
+//! cache with 4 byte lines and 2 ways (4 sets):
+//! ```text
+//!  7   4 3   2 1    0
+//! | tag | index | offset |
+//! ```
+//!
+//! Every access is recorded as a `CacheEvent` so front-ends can visualise
+//! what the cache is doing, and counted in `CacheStats`. Only the most
+//! recent `EVENT_LIMIT` events are kept until they are taken.
+//!
+//! A configuration is written as its line size, capacity and optionally
+//! mapping, replacement and write policy (direct, lru and write-back when
+//! left out), e.g. `4,32,2-way,fifo,write-through`.
+
+use std::collections::VecDeque;
+
+use super::memory::Memory;
+
+/// Most events kept before the oldest are dropped
+pub const EVENT_LIMIT: usize = 1024;
+
+/// How addresses are mapped onto cache lines
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mapping {
+    /// Every address maps to exactly one line
+    DirectMapped,
+    /// Every address maps to one set of N lines
+    SetAssociative(u32),
+    /// Any address may live in any line
+    FullyAssociative,
+}
+
+/// Which line is evicted when a set is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Replacement {
+    /// Least recently used
+    Lru,
+    /// First in, first out
+    Fifo,
+    /// Pseudo-random (deterministic, seeded)
+    Random,
+}
+
+/// What happens to memory when the CPU writes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WritePolicy {
+    /// Memory is updated on every write, misses do not allocate a line
+    WriteThrough,
+    /// Only the line is updated and marked dirty, memory is updated on
+    /// eviction or flush; misses allocate a line
+    WriteBack,
+}
+
+/// Cache configuration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheConfig {
+    /// Bytes per line (power of two)
+    pub line_size: u32,
+    /// Total bytes held by the cache (multiple of line_size)
+    pub capacity: u32,
+    pub mapping: Mapping,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+}
+
+/// Kind of access made by the CPU
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A single thing that happened inside the cache
+#[derive(Clone, Debug, PartialEq)]
+pub enum CacheEvent {
+    /// Requested address was present
+    Hit { kind: AccessKind, address: u32, set: u32, way: u32 },
+    /// Requested address was not present
+    Miss { kind: AccessKind, address: u32, set: u32 },
+    /// A line was loaded from memory into a way
+    Fill { set: u32, way: u32, base: u32 },
+    /// A valid line was thrown out to make room
+    Evict { set: u32, way: u32, base: u32, dirty: bool },
+    /// A dirty line was written back to memory
+    WriteBack { set: u32, way: u32, base: u32 },
+}
+
+/// Hit/miss counters
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub write_backs: u64,
+}
+
+/// A single cache line
+#[derive(Clone, Debug)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    data: Vec<u8>,
+    /// Time of last access (LRU)
+    last_used: u64,
+    /// Time of fill (FIFO)
+    filled_at: u64,
+}
+
+/// Saved contents of a single line (see `Cache::save_state`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineState {
+    pub valid: bool,
+    pub dirty: bool,
+    pub tag: u32,
+    pub data: Vec<u8>,
+    pub last_used: u64,
+    pub filled_at: u64,
+}
+
+/// Everything needed to rebuild a cache exactly
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheState {
+    pub config: CacheConfig,
+    pub stats: CacheStats,
+    pub clock: u64,
+    pub seed: u32,
+    /// Every line, set by set
+    pub lines: Vec<LineState>,
+}
+
+/// The cache itself
+pub struct Cache {
+    config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    stats: CacheStats,
+    events: VecDeque<CacheEvent>,
+    /// Logical clock, incremented on every access
+    clock: u64,
+    /// xorshift state for random replacement
+    seed: u32,
+}
+
+impl CacheConfig {
+    /// Number of lines in the cache
+    pub fn lines(&self) -> u32 {
+        self.capacity / self.line_size
+    }
+
+    /// Number of lines per set
+    pub fn ways(&self) -> u32 {
+        match self.mapping {
+            Mapping::DirectMapped => 1,
+            Mapping::SetAssociative(ways) => ways,
+            Mapping::FullyAssociative => self.lines(),
+        }
+    }
+
+    /// Number of sets
+    pub fn sets(&self) -> u32 {
+        self.lines() / self.ways()
+    }
+
+    /// Check the configuration describes a buildable cache
+    pub fn validate(&self) -> Result<(), String> {
+        if self.line_size == 0 || !self.line_size.is_power_of_two() {
+            return Err(format!("line size {} is not a power of two", self.line_size));
+        }
+        if self.capacity == 0 || !self.capacity.is_multiple_of(self.line_size) {
+            return Err(format!("capacity {} is not a multiple of the line size {}", self.capacity, self.line_size));
+        }
+        let ways = self.ways();
+        if ways == 0 || !self.lines().is_multiple_of(ways) {
+            return Err(format!("{} lines cannot be split into {}-way sets", self.lines(), ways));
+        }
+        if !self.sets().is_power_of_two() {
+            return Err(format!("number of sets ({}) is not a power of two", self.sets()));
+        }
+        Ok(())
+    }
+}
+
+impl Cache {
+    /// Create a new, empty cache
+    pub fn new(config: CacheConfig) -> Result<Cache, String> {
+        config.validate()?;
+
+        let line = Line {
+            valid: false,
+            dirty: false,
+            tag: 0,
+            data: vec![0; config.line_size as usize],
+            last_used: 0,
+            filled_at: 0,
+        };
+
+        Ok(Cache {
+            config,
+            sets: vec![vec![line; config.ways() as usize]; config.sets() as usize],
+            stats: CacheStats::default(),
+            events: VecDeque::new(),
+            clock: 0,
+            seed: 0x2545_F491,
+        })
+    }
+
+    /// Save the full state of the cache
+    pub fn save_state(&self) -> CacheState {
+        CacheState {
+            config: self.config,
+            stats: self.stats,
+            clock: self.clock,
+            seed: self.seed,
+            lines: self.sets
+                .iter()
+                .flatten()
+                .map(|line| LineState {
+                    valid: line.valid,
+                    dirty: line.dirty,
+                    tag: line.tag,
+                    data: line.data.clone(),
+                    last_used: line.last_used,
+                    filled_at: line.filled_at,
+                })
+                .collect(),
+        }
+    }
+
+    /// Rebuild a cache from a saved state
+    pub fn from_state(state: &CacheState) -> Result<Cache, String> {
+        let mut cache = Cache::new(state.config)?;
+        if state.lines.len() != state.config.lines() as usize {
+            return Err(format!("expected {} cache lines, found {}", state.config.lines(), state.lines.len()));
+        }
+
+        for (line, saved) in cache.sets.iter_mut().flatten().zip(&state.lines) {
+            if saved.data.len() != state.config.line_size as usize {
+                return Err(format!("cache line holds {} bytes, expected {}", saved.data.len(), state.config.line_size));
+            }
+            line.valid = saved.valid;
+            line.dirty = saved.dirty;
+            line.tag = saved.tag;
+            line.data = saved.data.clone();
+            line.last_used = saved.last_used;
+            line.filled_at = saved.filled_at;
+        }
+
+        cache.stats = state.stats;
+        cache.clock = state.clock;
+        cache.seed = state.seed;
+        Ok(cache)
+    }
+
+    /// Get the configuration
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    /// Get the statistics gathered so far
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// Take every event recorded since the last call (at most the last
+    /// `EVENT_LIMIT`)
+    pub fn take_events(&mut self) -> Vec<CacheEvent> {
+        std::mem::take(&mut self.events).into()
+    }
+
+    /// Record an event, dropping the oldest if there are too many
+    fn record(&mut self, event: CacheEvent) {
+        if self.events.len() == EVENT_LIMIT {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Hit rate in the range 0.0 - 1.0
+    pub fn hit_rate(&self) -> f64 {
+        let accesses = self.stats.hits + self.stats.misses;
+        if accesses == 0 {
+            0.0
+        } else {
+            self.stats.hits as f64 / accesses as f64
+        }
+    }
+
+    /// Read a byte through the cache
+    pub fn read(&mut self, memory: &mut Memory, address: u32) -> u8 {
+        self.clock += 1;
+        self.stats.reads += 1;
+
+        let (tag, set, offset) = self.split(address);
+        let way = match self.lookup(set, tag) {
+            Some(way) => {
+                self.stats.hits += 1;
+                self.record(CacheEvent::Hit { kind: AccessKind::Read, address, set, way });
+                way
+            },
+            None => {
+                self.stats.misses += 1;
+                self.record(CacheEvent::Miss { kind: AccessKind::Read, address, set });
+                self.fill(memory, set, tag)
+            },
+        };
+
+        let line = &mut self.sets[set as usize][way as usize];
+        line.last_used = self.clock;
+        line.data[offset as usize]
+    }
+
+    /// Write a byte through the cache
+    pub fn write(&mut self, memory: &mut Memory, address: u32, value: u8) {
+        self.clock += 1;
+        self.stats.writes += 1;
+
+        let (tag, set, offset) = self.split(address);
+        let way = match self.lookup(set, tag) {
+            Some(way) => {
+                self.stats.hits += 1;
+                self.record(CacheEvent::Hit { kind: AccessKind::Write, address, set, way });
+                Some(way)
+            },
+            None => {
+                self.stats.misses += 1;
+                self.record(CacheEvent::Miss { kind: AccessKind::Write, address, set });
+                match self.config.write_policy {
+                    // no-write-allocate
+                    WritePolicy::WriteThrough => None,
+                    // write-allocate
+                    WritePolicy::WriteBack => Some(self.fill(memory, set, tag)),
+                }
+            },
+        };
+
+        if let Some(way) = way {
+            let line = &mut self.sets[set as usize][way as usize];
+            line.data[offset as usize] = value;
+            line.last_used = self.clock;
+            if self.config.write_policy == WritePolicy::WriteBack {
+                line.dirty = true;
+            }
+        }
+
+        if self.config.write_policy == WritePolicy::WriteThrough {
+            memory.write(address, value);
+        }
+    }
+
+    /// Get the value the CPU would see at an address without touching the
+    /// cache state or statistics
+    pub fn peek(&self, memory: &Memory, address: u32) -> u8 {
+        let (tag, set, offset) = self.split(address);
+        match self.lookup(set, tag) {
+            Some(way) => self.sets[set as usize][way as usize].data[offset as usize],
+            None => memory.read(address),
+        }
+    }
+
+    /// Write every dirty line back to memory
+    pub fn flush(&mut self, memory: &mut Memory) {
+        for set in 0..self.sets.len() {
+            for way in 0..self.sets[set].len() {
+                if self.sets[set][way].valid && self.sets[set][way].dirty {
+                    self.write_back(memory, set as u32, way as u32);
+                }
+            }
+        }
+    }
+
+    /// Drop every line without writing anything back
+    pub fn invalidate(&mut self) {
+        for line in self.sets.iter_mut().flatten() {
+            line.valid = false;
+            line.dirty = false;
+        }
+    }
+
+    /// Split an address into (tag, set index, offset)
+    fn split(&self, address: u32) -> (u32, u32, u32) {
+        let offset = address % self.config.line_size;
+        let block = address / self.config.line_size;
+        let sets = self.config.sets();
+        (block / sets, block % sets, offset)
+    }
+
+    /// First address held by a line
+    fn base(&self, set: u32, tag: u32) -> u32 {
+        (tag * self.config.sets() + set) * self.config.line_size
+    }
+
+    /// Find the way holding the tag in a set
+    fn lookup(&self, set: u32, tag: u32) -> Option<u32> {
+        self.sets[set as usize]
+            .iter()
+            .position(|line| line.valid && line.tag == tag)
+            .map(|way| way as u32)
+    }
+
+    /// Load the line for a tag into a set, evicting if needed
+    fn fill(&mut self, memory: &mut Memory, set: u32, tag: u32) -> u32 {
+        let way = self.victim(set);
+
+        let old = &self.sets[set as usize][way as usize];
+        if old.valid {
+            let (old_tag, dirty) = (old.tag, old.dirty);
+            self.stats.evictions += 1;
+            self.record(CacheEvent::Evict { set, way, base: self.base(set, old_tag), dirty });
+            if dirty {
+                self.write_back(memory, set, way);
+            }
+        }
+
+        let base = self.base(set, tag);
+        let line_size = self.config.line_size;
+        let line = &mut self.sets[set as usize][way as usize];
+        for i in 0..line_size {
+            // lines may hang off the end of small memories
+            line.data[i as usize] = if base + i < memory.size { memory.read(base + i) } else { 0 };
+        }
+        line.valid = true;
+        line.dirty = false;
+        line.tag = tag;
+        line.filled_at = self.clock;
+        line.last_used = self.clock;
+
+        self.record(CacheEvent::Fill { set, way, base });
+        way
+    }
+
+    /// Write a dirty line back to memory
+    fn write_back(&mut self, memory: &mut Memory, set: u32, way: u32) {
+        let base = self.base(set, self.sets[set as usize][way as usize].tag);
+        let line = &mut self.sets[set as usize][way as usize];
+        for (i, byte) in line.data.iter().enumerate() {
+            if base + (i as u32) < memory.size {
+                memory.write(base + i as u32, *byte);
+            }
+        }
+        line.dirty = false;
+
+        self.stats.write_backs += 1;
+        self.record(CacheEvent::WriteBack { set, way, base });
+    }
+
+    /// Pick the way to (re)use in a set
+    fn victim(&mut self, set: u32) -> u32 {
+        let lines = &self.sets[set as usize];
+
+        // Prefer an empty line
+        if let Some(way) = lines.iter().position(|line| !line.valid) {
+            return way as u32;
+        }
+
+        let way = match self.config.replacement {
+            Replacement::Lru => lines
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, line)| line.last_used)
+                .map(|(way, _)| way)
+                .unwrap(),
+            Replacement::Fifo => lines
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, line)| line.filled_at)
+                .map(|(way, _)| way)
+                .unwrap(),
+            Replacement::Random => {
+                // xorshift32
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 17;
+                self.seed ^= self.seed << 5;
+                self.seed as usize % lines.len()
+            },
+        };
+
+        way as u32
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let accesses = self.hits + self.misses;
+        let rate = if accesses == 0 { 0.0 } else { self.hits as f64 * 100.0 / accesses as f64 };
+        write!(
+            f,
+            "reads: {} | writes: {} | hits: {} | misses: {} | hit rate: {:.1}% | evictions: {} | write-backs: {}",
+            self.reads, self.writes, self.hits, self.misses, rate, self.evictions, self.write_backs
+        )
+    }
+}
+
+impl std::fmt::Display for CacheConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{}",
+            self.line_size, self.capacity, self.mapping, self.replacement, self.write_policy
+        )
+    }
+}
+
+impl std::str::FromStr for CacheConfig {
+    type Err = String;
+
+    /// Parse `LINE_SIZE,CAPACITY[,MAPPING[,REPLACEMENT[,POLICY]]]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        if parts.len() < 2 || parts.len() > 5 {
+            return Err(format!("invalid cache configuration: {}", s));
+        }
+        let size = |text: &str| text.parse::<u32>().map_err(|_| format!("invalid cache size: {}", text));
+
+        let config = CacheConfig {
+            line_size: size(parts[0])?,
+            capacity: size(parts[1])?,
+            mapping: parts.get(2).map_or(Ok(Mapping::DirectMapped), |part| part.parse())?,
+            replacement: parts.get(3).map_or(Ok(Replacement::Lru), |part| part.parse())?,
+            write_policy: parts.get(4).map_or(Ok(WritePolicy::WriteBack), |part| part.parse())?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl std::fmt::Display for Mapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Mapping::DirectMapped => write!(f, "direct"),
+            Mapping::SetAssociative(ways) => write!(f, "{}-way", ways),
+            Mapping::FullyAssociative => write!(f, "full"),
+        }
+    }
+}
+
+impl std::str::FromStr for Mapping {
+    type Err = String;
+
+    /// Parse `direct`, `N-way` or `full`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(Mapping::DirectMapped),
+            "full" => Ok(Mapping::FullyAssociative),
+            _ => s
+                .strip_suffix("-way")
+                .and_then(|ways| ways.parse::<u32>().ok())
+                .map(Mapping::SetAssociative)
+                .ok_or_else(|| format!("invalid cache mapping: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Replacement {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Replacement::Lru => write!(f, "lru"),
+            Replacement::Fifo => write!(f, "fifo"),
+            Replacement::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl std::str::FromStr for Replacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Replacement::Lru),
+            "fifo" => Ok(Replacement::Fifo),
+            "random" => Ok(Replacement::Random),
+            _ => Err(format!("invalid replacement policy: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for WritePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WritePolicy::WriteThrough => write!(f, "write-through"),
+            WritePolicy::WriteBack => write!(f, "write-back"),
+        }
+    }
+}
+
+impl std::str::FromStr for WritePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "write-through" => Ok(WritePolicy::WriteThrough),
+            "write-back" => Ok(WritePolicy::WriteBack),
+            _ => Err(format!("invalid write policy: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config: CacheConfig = "4,32,2-way,fifo,write-through".parse().unwrap();
+        assert_eq!(config.sets(), 4);
+        assert_eq!(config.to_string().parse::<CacheConfig>(), Ok(config));
+
+        let config: CacheConfig = "4,16".parse().unwrap();
+        assert_eq!(config.mapping, Mapping::DirectMapped);
+        assert_eq!(config.replacement, Replacement::Lru);
+        assert_eq!(config.write_policy, WritePolicy::WriteBack);
+    }
+
+    #[test]
+    fn bad_config_is_an_error() {
+        assert!("3,16".parse::<CacheConfig>().unwrap_err().contains("power of two"));
+        assert!("4".parse::<CacheConfig>().is_err());
+        assert!("4,16,3-way".parse::<CacheConfig>().is_err());
+
+        let mut config: CacheConfig = "4,16".parse().unwrap();
+        config.capacity = 10;
+        assert!(Cache::new(config).is_err());
+    }
+
+    #[test]
+    fn events_are_bounded() {
+        let mut cache = Cache::new("4,16".parse().unwrap()).unwrap();
+        let mut memory = Memory::new(256);
+        for _ in 0..EVENT_LIMIT {
+            cache.read(&mut memory, 0);
+        }
+        assert_eq!(cache.stats().hits, EVENT_LIMIT as u64 - 1);
+
+        let events = cache.take_events();
+        assert_eq!(events.len(), EVENT_LIMIT);
+        assert!(matches!(events[0], CacheEvent::Fill { .. }));
+        assert!(cache.take_events().is_empty());
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let mut cache = Cache::new("4,16".parse().unwrap()).unwrap();
+        let mut memory = Memory::new(256);
+        for address in 0..8 {
+            cache.read(&mut memory, address);
+        }
+        cache.write(&mut memory, 3, 1);
+
+        let stats = cache.stats();
+        assert_eq!((stats.reads, stats.writes), (8, 1));
+        assert_eq!((stats.hits, stats.misses), (7, 2));
+        assert_eq!(stats.evictions, 0);
+        assert_eq!(cache.hit_rate(), 7.0 / 9.0);
+    }
+
+    #[test]
+    fn lru_and_fifo_evict_different_lines() {
+        // Two lines, one set: touch A, B, A again, then bring in C
+        let evicted = |replacement: &str| {
+            let mut cache = Cache::new(format!("4,8,full,{}", replacement).parse().unwrap()).unwrap();
+            let mut memory = Memory::new(256);
+            for address in [0, 4, 0, 8] {
+                cache.read(&mut memory, address);
+            }
+            assert_eq!(cache.stats().evictions, 1);
+            cache
+                .take_events()
+                .into_iter()
+                .find_map(|event| match event {
+                    CacheEvent::Evict { base, .. } => Some(base),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        // LRU throws out B, the line used longest ago...
+        assert_eq!(evicted("lru"), 4);
+        // ...FIFO throws out A, the line filled first
+        assert_eq!(evicted("fifo"), 0);
+    }
+
+    #[test]
+    fn write_back_waits_for_eviction() {
+        let mut cache = Cache::new("4,8,direct,lru,write-back".parse().unwrap()).unwrap();
+        let mut memory = Memory::new(256);
+        cache.write(&mut memory, 1, 42);
+        assert_eq!(memory.read(1), 0);
+        assert_eq!(cache.peek(&memory, 1), 42);
+
+        // 9 maps to the same line as 1, so the dirty line is written back
+        cache.read(&mut memory, 9);
+        assert_eq!(memory.read(1), 42);
+        assert_eq!(cache.stats().write_backs, 1);
+
+        cache.write(&mut memory, 9, 7);
+        cache.flush(&mut memory);
+        assert_eq!(memory.read(9), 7);
+        assert_eq!(cache.stats().write_backs, 2);
+    }
+
+    #[test]
+    fn write_through_updates_memory_at_once() {
+        let mut cache = Cache::new("4,8,direct,lru,write-through".parse().unwrap()).unwrap();
+        let mut memory = Memory::new(256);
+        cache.write(&mut memory, 1, 42);
+        assert_eq!(memory.read(1), 42);
+
+        // Misses do not allocate
+        cache.read(&mut memory, 1);
+        assert_eq!((cache.stats().hits, cache.stats().misses), (0, 2));
+
+        // Hits update both the line and memory
+        cache.write(&mut memory, 2, 7);
+        assert_eq!(memory.read(2), 7);
+        assert_eq!(cache.peek(&memory, 2), 7);
+        cache.read(&mut memory, 9);
+        assert_eq!(cache.stats().write_backs, 0);
+    }
+}
@@ -0,0 +1,288 @@
+//! Breakpoints and watchpoints
+//! Breakpoints stop the CPU before the instruction at an address runs,
+//! optionally only when a condition holds (e.g. `acc == 5`).
+//! Watchpoints stop the CPU after an instruction reads or writes a data
+//! memory address.
+//!
+//! Conditions have the form `LHS OP RHS` where each side is one of
+//! - `acc` or `pc`
+//! - `[ADDRESS]` or `[LABEL]` (value in data memory)
+//! - a number or a label (its address)
+//!
+//! and OP is one of `==`, `!=`, `<`, `<=`, `>`, `>=`
+
+/// Why the CPU stopped before halting
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// Reached a breakpoint at an instruction address
+    Breakpoint(u32),
+    /// An instruction accessed a watched data address
+    Watchpoint { address: u32, access: Access, value: u8 },
+    /// Running backwards reached the oldest recorded cycle
+    StartOfHistory,
+}
+
+/// State of the CPU after running
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    /// Can keep running
+    Running,
+    /// Executed HLT
+    Halted,
+    /// Stopped by the debugger
+    Stopped(StopReason),
+    /// Stopped because the program did something invalid
+    Faulted(Fault),
+}
+
+/// Something the CPU cannot execute
+/// `address` is always the address of the faulting instruction
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// The opcode byte does not decode to an instruction
+    InvalidOpcode { address: u32, opcode: u8 },
+    /// The program counter ran off the end of instruction memory
+    InstructionOutOfRange { address: u32 },
+    /// The operand points past the end of data memory
+    DataOutOfRange { address: u32, data_address: u32 },
+    /// DIV by a cell holding zero
+    DivideByZero { address: u32 },
+    /// INP with no input left to read
+    InputExhausted { address: u32 },
+}
+
+impl Fault {
+    /// Address of the faulting instruction
+    pub fn address(&self) -> u32 {
+        match self {
+            Fault::InvalidOpcode { address, .. }
+            | Fault::InstructionOutOfRange { address }
+            | Fault::DataOutOfRange { address, .. }
+            | Fault::DivideByZero { address }
+            | Fault::InputExhausted { address } => *address,
+        }
+    }
+
+    /// Describe the fault, naming the faulting instruction with `at`
+    /// (e.g. its address or source line)
+    pub fn describe(&self, at: &str) -> String {
+        match self {
+            Fault::InvalidOpcode { opcode, .. } => format!("invalid opcode 0x{:02X} at {}", opcode, at),
+            Fault::InstructionOutOfRange { address } => {
+                format!("instruction address 0x{:02X} is out of range", address)
+            },
+            Fault::DataOutOfRange { data_address, .. } => {
+                format!("data address 0x{:02X} is out of range (instruction at {})", data_address, at)
+            },
+            Fault::DivideByZero { .. } => format!("division by zero at {}", at),
+            Fault::InputExhausted { .. } => format!("no input left for INP at {}", at),
+        }
+    }
+}
+
+/// Kind of data memory access
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Which accesses trigger a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// A value a condition can look at
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    Acc,
+    Pc,
+    /// Value in data memory at an address
+    Memory(u32),
+    /// Literal value
+    Value(u8),
+}
+
+/// Comparison operator
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Condition attached to a breakpoint
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub lhs: Operand,
+    pub comparison: Comparison,
+    pub rhs: Operand,
+}
+
+/// A breakpoint on an instruction address
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub address: u32,
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+    /// Number of times the breakpoint stopped the CPU
+    pub hits: u64,
+}
+
+/// A watchpoint on a data memory address
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub kind: WatchKind,
+    pub enabled: bool,
+    pub hits: u64,
+}
+
+impl WatchKind {
+    /// Check if an access triggers this kind of watchpoint
+    pub fn matches(&self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::ReadWrite, _) | (WatchKind::Read, Access::Read) | (WatchKind::Write, Access::Write)
+        )
+    }
+}
+
+impl Comparison {
+    /// Compare two values
+    pub fn apply(&self, lhs: u8, rhs: u8) -> bool {
+        match self {
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Lt => lhs < rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Gt => lhs > rhs,
+            Comparison::Ge => lhs >= rhs,
+        }
+    }
+}
+
+impl Operand {
+    /// Parse an operand, resolving labels with `resolve`
+    pub fn parse(text: &str, resolve: &dyn Fn(&str) -> Option<u32>) -> Result<Operand, String> {
+        let text = text.trim();
+        let lower = text.to_lowercase();
+
+        if lower == "acc" {
+            return Ok(Operand::Acc);
+        }
+        if lower == "pc" {
+            return Ok(Operand::Pc);
+        }
+
+        if let Some(inner) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            return parse_address(inner, resolve).map(Operand::Memory);
+        }
+
+        let address = parse_address(text, resolve)?;
+        if address > u8::MAX as u32 {
+            return Err(format!("Value out of range: {}", text));
+        }
+        Ok(Operand::Value(address as u8))
+    }
+}
+
+impl Condition {
+    /// Parse a condition such as `acc == 5` or `[COUNT] > 0`
+    pub fn parse(text: &str, resolve: &dyn Fn(&str) -> Option<u32>) -> Result<Condition, String> {
+        // two character operators must be tried first
+        let operators = [
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ];
+
+        for (symbol, comparison) in operators {
+            if let Some((lhs, rhs)) = text.split_once(symbol) {
+                return Ok(Condition {
+                    lhs: Operand::parse(lhs, resolve)?,
+                    comparison,
+                    rhs: Operand::parse(rhs, resolve)?,
+                });
+            }
+        }
+
+        Err(format!("Invalid condition: {}", text))
+    }
+}
+
+/// Parse a number (decimal or 0x hex) or a label
+pub fn parse_address(text: &str, resolve: &dyn Fn(&str) -> Option<u32>) -> Result<u32, String> {
+    let text = text.trim();
+
+    let number = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse::<u32>().ok(),
+    };
+
+    number
+        .or_else(|| resolve(text))
+        .ok_or_else(|| format!("Unknown address or label: {}", text))
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Operand::Acc => write!(f, "acc"),
+            Operand::Pc => write!(f, "pc"),
+            Operand::Memory(address) => write!(f, "[0x{:02X}]", address),
+            Operand::Value(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let symbol = match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.lhs, self.comparison, self.rhs)
+    }
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StopReason::Breakpoint(address) => write!(f, "breakpoint at 0x{:02X}", address),
+            StopReason::Watchpoint { address, access, value } => {
+                let access = match access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                };
+                write!(f, "watchpoint: {} of 0x{:02X} (value {})", access, address, value)
+            },
+            StopReason::StartOfHistory => write!(f, "reached the start of the recorded history"),
+        }
+    }
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.describe(&format!("0x{:02X}", self.address())))
+    }
+}
@@ -0,0 +1,208 @@
+//! Execution history for reverse debugging
+//! Two structures are kept:
+//! 1. An undo log with one record per executed instruction, holding the
+//!    registers before the instruction and the old value of every data
+//!    memory cell it wrote. Stepping back pops a record and restores it.
+//! 2. Checkpoints of the whole machine taken every `interval` cycles.
+//!    Cycles older than the undo log are reached by restoring the closest
+//!    checkpoint and executing forward again.
+//!
+//! Both are bounded so long runs do not use unbounded memory. Values read
+//! by INP are remembered so re-executing cycles that already ran (after
+//! going back) gives the same result. Changing the machine by hand
+//! changes the future, so everything recorded after the current cycle is
+//! dropped and the values INP read there are read again.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use super::instructions::Instruction;
+
+/// Every register of the CPU
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterState {
+    pub pc: u8,
+    pub acc: u8,
+    pub mdr: u16,
+    pub cir: Option<Instruction>,
+    pub instruction_address: u8,
+    pub running: bool,
+}
+
+/// Undo information for one executed instruction
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// Cycle the instruction started on
+    pub cycle: u64,
+    /// Registers before the instruction
+    pub registers: RegisterState,
+    /// (address, old value) of every data memory write
+    pub writes: Vec<(u32, u8)>,
+    /// Number of values written by OUT before the instruction
+    pub output_length: usize,
+}
+
+/// Full machine state at a cycle
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub cycle: u64,
+    pub registers: RegisterState,
+    pub data: Vec<u8>,
+    /// Number of values written by OUT at the checkpoint
+    pub output_length: usize,
+}
+
+/// Bounded undo log and checkpoints
+pub struct History {
+    records: VecDeque<Record>,
+    checkpoints: VecDeque<Checkpoint>,
+    /// Values read by INP, by cycle
+    inputs: BTreeMap<u64, u8>,
+    /// Maximum number of undo records
+    capacity: usize,
+    /// Cycles between checkpoints
+    interval: u64,
+    /// Maximum number of checkpoints
+    max_checkpoints: usize,
+    /// Cycle after the last one executed, before going back
+    end: u64,
+}
+
+impl History {
+    /// Create a history keeping `capacity` undo records and a checkpoint
+    /// every `interval` cycles (at least 1 of each)
+    pub fn new(capacity: usize, interval: u64) -> History {
+        let (capacity, interval) = (capacity.max(1), interval.max(1));
+
+        History {
+            records: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+            inputs: BTreeMap::new(),
+            capacity,
+            interval,
+            // Enough checkpoints to cover ten times the undo log
+            max_checkpoints: (capacity as u64 * 10 / interval).max(1) as usize + 1,
+            end: 0,
+        }
+    }
+
+    /// Check if a checkpoint is due on a cycle
+    pub fn checkpoint_due(&self, cycle: u64) -> bool {
+        cycle.is_multiple_of(self.interval) && self.checkpoints.back().is_none_or(|c| c.cycle < cycle)
+    }
+
+    /// Store a checkpoint
+    pub fn push_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.checkpoints.push_back(checkpoint);
+        if self.checkpoints.len() > self.max_checkpoints {
+            let dropped = self.checkpoints.pop_front().unwrap();
+            // Inputs before the oldest checkpoint can never be replayed
+            let oldest = self.checkpoints.front().map_or(dropped.cycle, |c| c.cycle);
+            self.inputs = self.inputs.split_off(&oldest);
+        }
+    }
+
+    /// Store an undo record
+    pub fn push(&mut self, record: Record) {
+        self.end = self.end.max(record.cycle + 1);
+        self.records.push_back(record);
+        if self.records.len() > self.capacity {
+            self.records.pop_front();
+        }
+    }
+
+    /// Remember the last undo record's data memory write
+    pub fn record_write(&mut self, address: u32, old: u8) {
+        if let Some(record) = self.records.back_mut() {
+            record.writes.push((address, old));
+        }
+    }
+
+    /// Remember a value read by INP
+    pub fn record_input(&mut self, cycle: u64, value: u8) {
+        self.inputs.insert(cycle, value);
+    }
+
+    /// Get the value INP read on a cycle, if it was recorded
+    pub fn input_at(&self, cycle: u64) -> Option<u8> {
+        self.inputs.get(&cycle).copied()
+    }
+
+    /// Take the most recent undo record
+    pub fn pop(&mut self) -> Option<Record> {
+        self.records.pop_back()
+    }
+
+    /// Get the most recent undo record
+    pub fn last(&self) -> Option<&Record> {
+        self.records.back()
+    }
+
+    /// Get the oldest undo record
+    pub fn first(&self) -> Option<&Record> {
+        self.records.front()
+    }
+
+    /// Whether a cycle has been executed before, so running it again
+    /// replays it
+    pub fn is_replay(&self, cycle: u64) -> bool {
+        cycle < self.end
+    }
+
+    /// Go back to a cycle: forget the undo records and checkpoints after
+    /// it, keeping the inputs so the cycles can be replayed
+    pub fn rewind(&mut self, cycle: u64) {
+        while self.records.back().is_some_and(|r| r.cycle >= cycle) {
+            self.records.pop_back();
+        }
+        while self.checkpoints.back().is_some_and(|c| c.cycle > cycle) {
+            self.checkpoints.pop_back();
+        }
+    }
+
+    /// Forget everything recorded on or after a cycle (the future has
+    /// been changed, so it is no longer valid)
+    /// Returns the values INP read from then on, in order
+    pub fn truncate(&mut self, cycle: u64) -> Vec<u8> {
+        self.rewind(cycle);
+        self.end = self.end.min(cycle);
+        self.inputs.split_off(&cycle).into_values().collect()
+    }
+
+    /// Forget everything
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.checkpoints.clear();
+        self.inputs.clear();
+        self.end = 0;
+    }
+
+    /// Latest checkpoint taken on or before a cycle
+    pub fn checkpoint_before(&self, cycle: u64) -> Option<&Checkpoint> {
+        self.checkpoints.iter().rev().find(|c| c.cycle <= cycle)
+    }
+
+    /// Earliest cycle that can be reached
+    pub fn earliest_cycle(&self) -> Option<u64> {
+        let record = self.records.front().map(|r| r.cycle);
+        let checkpoint = self.checkpoints.front().map(|c| c.cycle);
+        match (record, checkpoint) {
+            (Some(r), Some(c)) => Some(r.min(c)),
+            (r, c) => r.or(c),
+        }
+    }
+
+    /// Number of undo records held
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Whether there are no undo records
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Number of checkpoints held
+    pub fn checkpoints(&self) -> usize {
+        self.checkpoints.len()
+    }
+}
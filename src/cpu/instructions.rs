@@ -12,7 +12,7 @@
 //! 0x00 denotes data
 
 /// Instruction struct
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Instruction {
     /// Opcode
     pub opcode: Opcode,
@@ -21,7 +21,7 @@ pub struct Instruction {
 }
 
 /// All opcodes supported
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Opcode {
     ADD, // Add
     SUB, // Subtract
@@ -51,27 +51,56 @@ impl Instruction {
         }
     }
 
-    /// Create a new instruction from raw byte
-    pub fn from_byte(byte: u8) -> Instruction {
-        let opcode = Opcode::from_byte(byte & 0b0000_1111);
-        let operand = byte & 0b1111_0000;
+    /// Create a new instruction from a raw word
+    /// (opcode in the high byte, operand in the low byte)
+    pub fn from_word(word: u16) -> Instruction {
+        let opcode = Opcode::from_byte((word >> 8) as u8);
+        let operand = word as u8;
         Instruction::new(opcode, operand)
     }
 
+    /// Create a new instruction from a raw word, if the opcode is valid
+    pub fn try_from_word(word: u16) -> Option<Instruction> {
+        let opcode = Opcode::try_from_byte((word >> 8) as u8)?;
+        Some(Instruction::new(opcode, word as u8))
+    }
+
     /// Get binary representation of instruction
     pub fn to_bin(&self) -> Vec<u8> {
-        let mut bin = Vec::new();
-        bin.push(self.opcode.to_bin());
-        bin.push(self.operand);
-        bin
+        vec![self.opcode.to_bin(), self.operand]
     }
 }
 
 impl Opcode {
+    /// Every opcode, in encoding order
+    pub const ALL: [Opcode; 17] = [
+        Opcode::ADD,
+        Opcode::SUB,
+        Opcode::MUL,
+        Opcode::DIV,
+        Opcode::STA,
+        Opcode::LDA,
+        Opcode::JMP,
+        Opcode::JEQ,
+        Opcode::JNE,
+        Opcode::JGT,
+        Opcode::JLT,
+        Opcode::JZ,
+        Opcode::JNZ,
+        Opcode::HLT,
+        Opcode::INP,
+        Opcode::OUT,
+        Opcode::DAT,
+    ];
+
     /// Get the opcode from a byte
     pub fn from_byte(byte: u8) -> Opcode {
-        println!("convering {} to bytecode", byte);
-        match byte {
+        Opcode::try_from_byte(byte).expect("Invalid opcode")
+    }
+
+    /// Get the opcode from a byte, if it is valid
+    pub fn try_from_byte(byte: u8) -> Option<Opcode> {
+        let opcode = match byte {
             0x01 => Opcode::ADD, // 0000 0001 or 1
             0x02 => Opcode::SUB, // 0000 0010 or 2
             0x03 => Opcode::MUL, // 0000 0011 or 3
@@ -89,7 +118,73 @@ impl Opcode {
             0x0F => Opcode::INP, // 0000 1111 or 15
             0x10 => Opcode::OUT, // 0001 0000 or 16
             0x11 => Opcode::DAT, // 0001 0001 or 17
-            _ => panic!("Invalid opcode"),
+            _ => return None,
+        };
+
+        Some(opcode)
+    }
+
+    /// Check if the opcode is a conditional jump
+    pub fn is_branch(&self) -> bool {
+        matches!(self, Opcode::JEQ | Opcode::JNE | Opcode::JGT | Opcode::JLT | Opcode::JZ | Opcode::JNZ)
+    }
+
+    /// Check if the opcode uses its operand
+    pub fn has_operand(&self) -> bool {
+        !matches!(self, Opcode::HLT | Opcode::INP | Opcode::OUT)
+    }
+
+    /// Check if the opcode may change the program counter
+    pub fn is_jump(&self) -> bool {
+        matches!(
+            self,
+            Opcode::JMP | Opcode::JEQ | Opcode::JNE | Opcode::JGT | Opcode::JLT | Opcode::JZ | Opcode::JNZ
+        )
+    }
+
+    /// Describe what the opcode does
+    pub fn description(&self) -> &'static str {
+        match self {
+            Opcode::ADD => "Add the value at the operand's data address to acc",
+            Opcode::SUB => "Subtract the value at the operand's data address from acc",
+            Opcode::MUL => "Multiply acc by the value at the operand's data address",
+            Opcode::DIV => "Divide acc by the value at the operand's data address (faults on zero)",
+            Opcode::STA => "Store acc at the operand's data address",
+            Opcode::LDA => "Load the value at the operand's data address into acc",
+            Opcode::JMP => "Jump to the operand's instruction address",
+            Opcode::JEQ => "Jump if acc is zero (same as JZ)",
+            Opcode::JNE => "Jump if acc is not zero (same as JNZ)",
+            Opcode::JGT => "Jump if acc is greater than zero (signed)",
+            Opcode::JLT => "Jump if acc is less than zero (signed)",
+            Opcode::JZ => "Jump if acc is zero",
+            Opcode::JNZ => "Jump if acc is not zero",
+            Opcode::HLT => "Stop the program",
+            Opcode::INP => "Read an input value into acc",
+            Opcode::OUT => "Output acc",
+            Opcode::DAT => "Reserve a data memory cell holding the operand",
+        }
+    }
+
+    /// Get the mnemonic, as written in upper case
+    pub fn name(&self) -> &'static str {
+        match self {
+            Opcode::ADD => "ADD",
+            Opcode::SUB => "SUB",
+            Opcode::MUL => "MUL",
+            Opcode::DIV => "DIV",
+            Opcode::STA => "STA",
+            Opcode::LDA => "LDA",
+            Opcode::JMP => "JMP",
+            Opcode::JEQ => "JEQ",
+            Opcode::JNE => "JNE",
+            Opcode::JGT => "JGT",
+            Opcode::JLT => "JLT",
+            Opcode::JZ => "JZ",
+            Opcode::JNZ => "JNZ",
+            Opcode::HLT => "HLT",
+            Opcode::INP => "INP",
+            Opcode::OUT => "OUT",
+            Opcode::DAT => "DAT",
         }
     }
 
@@ -115,37 +210,30 @@ impl Opcode {
             Opcode::DAT => 0b0001_0001,
         }
     }
-
-    /// Generate from string
-    pub fn from_str(string: &str) -> Opcode {
-        match string {
-            "ADD" => Opcode::ADD,
-            "SUB" => Opcode::SUB,
-            "MUL" => Opcode::MUL,
-            "DIV" => Opcode::DIV,
-            "STA" => Opcode::STA,
-            "LDA" => Opcode::LDA,
-            "JMP" => Opcode::JMP,
-            "JEQ" => Opcode::JEQ,
-            "JNE" => Opcode::JNE,
-            "JGT" => Opcode::JGT,
-            "JLT" => Opcode::JLT,
-            "JZ" => Opcode::JZ,
-            "JNZ" => Opcode::JNZ,
-            "HLT" => Opcode::HLT,
-            "INP" => Opcode::INP,
-            "OUT" => Opcode::OUT,
-            "DAT" => Opcode::DAT,
-            _ => panic!("Invalid opcode"),
-        }
-    }
 }
 
 // FromStr for Opcode
 impl std::str::FromStr for Opcode {
     type Err = ();
 
+    /// Generate from string, in any case
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Opcode::from_str(s))
+        Opcode::ALL.iter().copied().find(|opcode| opcode.name().eq_ignore_ascii_case(s)).ok_or(())
     }
-}
\ No newline at end of file
+}
+
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.opcode.has_operand() {
+            write!(f, "{} 0x{:02X}", self.opcode, self.operand)
+        } else {
+            write!(f, "{}", self.opcode)
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Input and output device used by INP and OUT
+//! INP takes values from a queue first, then (if interactive) asks on
+//! stdin. Every value written by OUT is kept, and printed if `echo` is
+//! set, so programs can be run without a terminal (tests, editors).
+
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+
+/// Input and output device
+#[derive(Clone, Debug)]
+pub struct Io {
+    /// Values INP reads before falling back to stdin
+    pub input: VecDeque<u8>,
+    /// Every value written by OUT
+    pub output: Vec<u8>,
+    /// Ask on stdin when the input queue is empty
+    pub interactive: bool,
+    /// Print values written by OUT
+    pub echo: bool,
+}
+
+impl Io {
+    /// Device reading stdin and printing to stdout
+    pub fn console() -> Io {
+        Io {
+            input: VecDeque::new(),
+            output: Vec::new(),
+            interactive: true,
+            echo: true,
+        }
+    }
+
+    /// Device reading only from a fixed list of values, printing nothing
+    pub fn scripted(input: &[u8]) -> Io {
+        Io {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+            interactive: false,
+            echo: false,
+        }
+    }
+
+    /// Read a value for INP
+    /// Returns None when there is nothing left to read
+    pub fn read(&mut self) -> Option<u8> {
+        if let Some(value) = self.input.pop_front() {
+            return Some(value);
+        }
+
+        if !self.interactive {
+            return None;
+        }
+
+        loop {
+            print!("input: ");
+            std::io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if std::io::stdin().lock().read_line(&mut line).unwrap() == 0 {
+                // EOF
+                return None;
+            }
+
+            match line.trim().parse::<u8>() {
+                Ok(value) => return Some(value),
+                Err(_) => println!("Input must be a number between 0 and 255"),
+            }
+        }
+    }
+
+    /// Write a value for OUT
+    pub fn write(&mut self, value: u8, echo: bool) {
+        self.output.push(value);
+        if self.echo && echo {
+            println!("{}", value);
+        }
+    }
+}
+
+impl Default for Io {
+    fn default() -> Io {
+        Io::console()
+    }
+}
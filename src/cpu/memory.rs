@@ -71,7 +71,7 @@ impl std::fmt::Display for Memory {
             output.push_str(&format!("{:<8} | {:<8}", address, value));
             i += 1;
             if i % 2 == 0 {
-                output.push_str("\n");
+                output.push('\n');
             } else {
                 output.push_str(" | ");
             }
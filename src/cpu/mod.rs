@@ -5,15 +5,40 @@
 //! 2. Data memory
 //! 3. Instruction memory
 //! 4. Program counter
+//! 5. An optional cache in front of the data memory
+//! 6. Breakpoints and watchpoints
+//! 7. An optional execution history for stepping backwards
+//! 8. An input/output device for INP and OUT
+//! 9. An optional source map, so faults and traces can name source lines
+//! 10. An optional profile counting how often each instruction runs and
+//!     each data address is used
+//!
+//! The whole state can be saved to and restored from a `Snapshot`.
 
+pub mod cache;
+pub mod debug;
+pub mod history;
+pub mod snapshot;
+pub mod profile;
 pub mod instructions;
+pub mod io;
 pub mod memory;
 pub mod registers;
 
+use cache::{Cache, CacheConfig};
+use debug::{Access, Breakpoint, Condition, Fault, Operand, Status, StopReason, WatchKind, Watchpoint};
+use history::{Checkpoint, History, Record, RegisterState};
+use snapshot::{Snapshot, SNAPSHOT_VERSION};
+use profile::Profile;
+use io::Io;
 use memory::Memory;
 use instructions::{Instruction, Opcode};
 use registers::{Register, PC, MDR, CIR, ACC};
 
+use crate::assembler::formats::{self, Format};
+use crate::assembler::source_map::SourceMap;
+use crate::assembler::Program;
+
 /// Represents the CPU
 pub struct CPU {
     /// Registers
@@ -24,8 +49,48 @@ pub struct CPU {
     pub data_memory: Memory,
     pub instruction_memory: Memory,
 
-    /// Flag to indicate if the CPU is running
+    /// Cache between the CPU and the data memory (if any)
+    pub cache: Option<Cache>,
+
+    /// Device used by INP and OUT
+    pub io: Io,
+
+    /// Breakpoints on instruction addresses
+    pub breakpoints: Vec<Breakpoint>,
+    /// Watchpoints on data memory addresses
+    pub watchpoints: Vec<Watchpoint>,
+
+    /// Undo log for reverse execution (if enabled)
+    pub history: Option<History>,
+
+    /// Print every stage of the pipeline
+    pub trace: bool,
+
+    /// Where the loaded program came from (if known)
+    pub source_map: Option<SourceMap>,
+
+    /// Execution counts (if profiling)
+    pub profile: Option<Profile>,
+
+    /// Number of instructions executed
+    cycles: u64,
+    /// Re-executing a cycle that already ran (output is not echoed or
+    /// profiled again)
+    replaying: bool,
+
+    /// Flag to indicate if the CPU is running (cleared by HLT)
     running: bool,
+
+    /// Address of the instruction being (or last) executed
+    instruction_address: u8,
+
+    /// Watchpoint hit by the instruction being executed
+    pending_stop: Option<StopReason>,
+    /// Fault raised by the instruction being executed
+    pending_fault: Option<Fault>,
+    /// Address of the breakpoint the CPU last stopped at, so running
+    /// again does not immediately stop at the same place
+    stopped_at: Option<u32>,
 }
 
 impl CPU {
@@ -38,45 +103,573 @@ impl CPU {
             acc: ACC::new(),
             data_memory: Memory::new(data_memory_size),
             instruction_memory: Memory::new(instruction_memory_size),
-            running: false,
+            cache: None,
+            io: Io::console(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            history: None,
+            trace: false,
+            source_map: None,
+            profile: None,
+            cycles: 0,
+            replaying: false,
+            running: true,
+            instruction_address: 0,
+            pending_stop: None,
+            pending_fault: None,
+            stopped_at: None,
         }
     }
 
-    /// Load a program into the instruction memory
-    pub fn load_program(&mut self, program: Vec<u8>) {
-        for (i, byte) in program.iter().enumerate() {
+    /// Put a cache between the CPU and the data memory, replacing (and
+    /// flushing) any cache already there
+    pub fn attach_cache(&mut self, config: CacheConfig) -> Result<(), String> {
+        let cache = Cache::new(config)?;
+        self.detach_cache();
+        self.cache = Some(cache);
+        Ok(())
+    }
+
+    /// Remove the cache, writing any dirty lines back first
+    pub fn detach_cache(&mut self) -> Option<Cache> {
+        let mut cache = self.cache.take()?;
+        cache.flush(&mut self.data_memory);
+        Some(cache)
+    }
+
+    /// Load a program into memory
+    /// The data section goes to data memory and the code section to
+    /// instruction memory (see `assembler` for the binary format)
+    /// Fails if the image is malformed or does not fit in memory
+    pub fn load_program(&mut self, program: Vec<u8>) -> Result<(), String> {
+        let program = Program::from_binary(&program)?;
+        if program.data.len() > self.data_memory.size as usize {
+            return Err(format!(
+                "Data does not fit in data memory ({} bytes, {} available)",
+                program.data.len(),
+                self.data_memory.size
+            ));
+        }
+        if program.code.len() > self.instruction_memory.size as usize {
+            return Err(format!(
+                "Code does not fit in instruction memory ({} bytes, {} available)",
+                program.code.len(),
+                self.instruction_memory.size
+            ));
+        }
+
+        for (i, byte) in program.data.iter().enumerate() {
+            self.data_memory.write(i as u32, *byte);
+        }
+
+        for (i, byte) in program.code.iter().enumerate() {
             self.instruction_memory.write(i as u32, *byte);
         }
+        Ok(())
     }
 
-    /// Load a program from a file into the instruction memory
-    pub fn load_program_from_file(&mut self, filename: &str) {
-        let program = std::fs::read(filename).expect("Unable to read file");
-        self.load_program(program);
+    /// Load a program from a file into memory, in the format its
+    /// extension names (raw binary if it names none)
+    pub fn load_program_from_file(&mut self, filename: &str) -> Result<(), String> {
+        let format = Format::from_path(filename).unwrap_or(Format::Binary);
+        let program = formats::load(filename, format)?;
+        self.load_program(program.to_binary())
     }
 
-    /// Start the CPU
-    pub fn start(&mut self) {
+    /// Start the CPU and run until it halts or is stopped
+    pub fn start(&mut self) -> Status {
         self.running = true;
-        while self.running {
-            self.fetch();
+        self.run()
+    }
+
+    /// Run until the CPU halts or hits a breakpoint or watchpoint
+    pub fn run(&mut self) -> Status {
+        self.run_for(u64::MAX)
+    }
+
+    /// Run like `run`, but give up with `Status::Running` after at most
+    /// `limit` instructions
+    pub fn run_for(&mut self, limit: u64) -> Status {
+        for _ in 0..limit {
+            // Do not stop twice at the breakpoint we are resuming from
+            let resuming = self.stopped_at.take() == Some(self.pc.get() as u32);
+
+            if !resuming && self.running {
+                if let Some(address) = self.check_breakpoints() {
+                    self.stopped_at = Some(address);
+                    return Status::Stopped(StopReason::Breakpoint(address));
+                }
+            }
+
+            match self.step() {
+                Status::Running => {},
+                status => return status,
+            }
+        }
+
+        Status::Running
+    }
+
+    /// Execute a single instruction
+    pub fn step(&mut self) -> Status {
+        if !self.running {
+            return Status::Halted;
+        }
+
+        self.stopped_at = None;
+        self.replaying = self.history.as_ref().is_some_and(|history| history.is_replay(self.cycles));
+        self.begin_record();
+        self.fetch();
+        if self.pending_fault.is_none() {
             self.decode();
+        }
+        if self.pending_fault.is_none() {
             self.execute();
         }
+        if self.pending_fault.is_none() && !self.replaying {
+            self.profile_instruction();
+        }
+        self.cycles += 1;
+
+        if let Some(fault) = self.pending_fault.take() {
+            self.pending_stop = None;
+            self.running = false;
+            return Status::Faulted(fault);
+        }
+
+        if let Some(reason) = self.pending_stop.take() {
+            return Status::Stopped(reason);
+        }
+
+        if self.running {
+            Status::Running
+        } else {
+            Status::Halted
+        }
+    }
+
+    /// Describe an instruction address by its source line (e.g.
+    /// `test.vnc:6: ADD B`) if there is a source map, or else as `0xNN`
+    pub fn describe(&self, address: u32) -> String {
+        match &self.source_map {
+            Some(source_map) => source_map.describe(address),
+            None => format!("0x{:02X}", address),
+        }
+    }
+
+    /// Describe a fault, naming the source line of the faulting
+    /// instruction if there is a source map
+    pub fn describe_fault(&self, fault: &Fault) -> String {
+        fault.describe(&self.describe(fault.address()))
+    }
+
+    /// Start counting how often each instruction runs
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    /// Count the instruction just executed in the profile, if profiling
+    fn profile_instruction(&mut self) {
+        let address = self.instruction_address;
+        let taken = match (self.profile.as_ref(), self.cir.get_instruction()) {
+            (Some(_), Some(instruction)) => {
+                instruction.opcode.is_branch() && self.pc.get() != address.wrapping_add(2)
+            },
+            _ => return,
+        };
+        self.profile.as_mut().unwrap().record(address as u32, taken);
+    }
+
+    /// Check if the CPU has executed HLT (or faulted)
+    pub fn is_halted(&self) -> bool {
+        !self.running
+    }
+
+    /// Start recording history so execution can be reversed, keeping
+    /// `capacity` undo records and a checkpoint every `interval` cycles
+    pub fn enable_history(&mut self, capacity: usize, interval: u64) {
+        self.history = Some(History::new(capacity, interval));
+    }
+
+    /// Forget the history after the current cycle once the machine has
+    /// been changed by hand; values INP read there are read again
+    pub fn truncate_history(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            for value in history.truncate(self.cycles).into_iter().rev() {
+                self.io.input.push_front(value);
+            }
+        }
+    }
+
+    /// Number of instructions executed
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Undo the last instruction
+    /// Returns false if there is no history to go back to
+    pub fn step_back(&mut self) -> bool {
+        if self.undo().is_some() {
+            return true;
+        }
+
+        // The undo log is exhausted, fall back to the checkpoints
+        self.cycles > 0 && self.goto_cycle(self.cycles - 1).is_ok()
+    }
+
+    /// Run backwards until a breakpoint or a write to a watched address
+    /// is undone, or the history runs out
+    pub fn reverse_run(&mut self) -> Status {
+        loop {
+            let record = match self.undo() {
+                Some(record) => record,
+                None => return Status::Stopped(StopReason::StartOfHistory),
+            };
+
+            // Writes are undone last to first, report the earliest
+            for (address, old) in record.writes.iter().rev() {
+                let watched = self.watchpoints
+                    .iter()
+                    .any(|wp| wp.enabled && wp.address == *address && wp.kind.matches(Access::Write));
+                if watched {
+                    return Status::Stopped(StopReason::Watchpoint {
+                        address: *address,
+                        access: Access::Write,
+                        value: *old,
+                    });
+                }
+            }
+
+            if let Some(address) = self.check_breakpoints() {
+                self.stopped_at = Some(address);
+                return Status::Stopped(StopReason::Breakpoint(address));
+            }
+        }
+    }
+
+    /// Move execution to a cycle, forwards or backwards
+    /// Breakpoints and watchpoints are ignored on the way
+    pub fn goto_cycle(&mut self, cycle: u64) -> Result<Status, String> {
+        if self.history.is_none() {
+            return Err("History is not enabled".to_string());
+        }
+
+        if cycle < self.cycles {
+            let in_log = self.history
+                .as_ref()
+                .unwrap()
+                .first()
+                .is_some_and(|record| record.cycle <= cycle);
+
+            if in_log {
+                while self.cycles > cycle {
+                    if self.undo().is_none() {
+                        break;
+                    }
+                }
+            }
+
+            if self.cycles > cycle {
+                let checkpoint = self.history
+                    .as_ref()
+                    .unwrap()
+                    .checkpoint_before(cycle)
+                    .cloned()
+                    .ok_or_else(|| format!("Cycle {} is no longer in the history", cycle))?;
+                self.restore_checkpoint(checkpoint);
+            }
+        }
+
+        // Execute forward, replaying recorded input up to the cycle last
+        // reached and running new cycles after it
+        let mut status = if self.running { Status::Running } else { Status::Halted };
+        while self.cycles < cycle {
+            status = self.step();
+            if matches!(status, Status::Halted | Status::Faulted(_)) {
+                break;
+            }
+        }
+
+        if let Status::Stopped(_) = status {
+            status = Status::Running;
+        }
+        Ok(status)
+    }
+
+    /// Capture the full machine state
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            cycles: self.cycles,
+            registers: self.registers(),
+            input: self.io.input.iter().copied().collect(),
+            output: self.io.output.clone(),
+            data_memory: self.data_memory.data.clone(),
+            instruction_memory: self.instruction_memory.data.clone(),
+            cache: self.cache.as_ref().map(|cache| cache.save_state()),
+        }
+    }
+
+    /// Replace the full machine state with a snapshot
+    /// Breakpoints, watchpoints and how the I/O device reads and prints
+    /// are kept, the history is cleared
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        let cache = match &snapshot.cache {
+            Some(state) => Some(Cache::from_state(state)?),
+            None => None,
+        };
+
+        self.data_memory = Memory::new(snapshot.data_memory.len() as u32);
+        self.data_memory.data.copy_from_slice(&snapshot.data_memory);
+        self.instruction_memory = Memory::new(snapshot.instruction_memory.len() as u32);
+        self.instruction_memory.data.copy_from_slice(&snapshot.instruction_memory);
+        self.cache = cache;
+        self.io.input = snapshot.input.iter().copied().collect();
+        self.io.output = snapshot.output.clone();
+
+        self.restore_registers(snapshot.registers.clone());
+        self.cycles = snapshot.cycles;
+        self.pending_stop = None;
+        self.pending_fault = None;
+
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    /// Get every register
+    pub fn registers(&self) -> RegisterState {
+        RegisterState {
+            pc: self.pc.get(),
+            acc: self.acc.get(),
+            mdr: self.mdr.get(),
+            cir: self.cir.get_instruction(),
+            instruction_address: self.instruction_address,
+            running: self.running,
+        }
+    }
+
+    /// Set every register
+    pub fn restore_registers(&mut self, registers: RegisterState) {
+        self.pc.set(registers.pc);
+        self.acc.set(registers.acc);
+        self.mdr.set(registers.mdr);
+        self.cir.set_instruction(registers.cir);
+        self.instruction_address = registers.instruction_address;
+        self.running = registers.running;
+        self.stopped_at = None;
+    }
+
+    /// Record the state before an instruction runs
+    fn begin_record(&mut self) {
+        if self.history.is_none() {
+            return;
+        }
+
+        if self.history.as_ref().unwrap().checkpoint_due(self.cycles) {
+            let checkpoint = Checkpoint {
+                cycle: self.cycles,
+                registers: self.registers(),
+                data: (0..self.data_memory.size).map(|address| self.peek_data(address)).collect(),
+                output_length: self.io.output.len(),
+            };
+            self.history.as_mut().unwrap().push_checkpoint(checkpoint);
+        }
+
+        let record = Record {
+            cycle: self.cycles,
+            registers: self.registers(),
+            writes: Vec::new(),
+            output_length: self.io.output.len(),
+        };
+        self.history.as_mut().unwrap().push(record);
+    }
+
+    /// Undo the last recorded instruction
+    fn undo(&mut self) -> Option<Record> {
+        let record = self.history.as_mut()?.pop()?;
+
+        // Memory must hold what the CPU sees before it is rolled back
+        if let Some(cache) = self.cache.as_mut() {
+            cache.flush(&mut self.data_memory);
+            cache.invalidate();
+        }
+
+        for (address, old) in record.writes.iter().rev() {
+            self.data_memory.write(*address, *old);
+        }
+        self.restore_registers(record.registers.clone());
+        self.io.output.truncate(record.output_length);
+        self.cycles = record.cycle;
+        self.history.as_mut().unwrap().rewind(self.cycles);
+
+        Some(record)
+    }
+
+    /// Put the machine back into a checkpointed state
+    fn restore_checkpoint(&mut self, checkpoint: Checkpoint) {
+        // Cached values are newer than the checkpoint, drop them
+        if let Some(cache) = self.cache.as_mut() {
+            cache.invalidate();
+        }
+
+        for (address, value) in checkpoint.data.iter().enumerate() {
+            self.data_memory.write(address as u32, *value);
+        }
+        self.restore_registers(checkpoint.registers);
+        self.io.output.truncate(checkpoint.output_length);
+        self.cycles = checkpoint.cycle;
+        self.history.as_mut().unwrap().rewind(self.cycles);
+    }
+
+    /// Get the program counter
+    pub fn pc(&self) -> u8 {
+        self.pc.get()
+    }
+
+    /// Set the program counter
+    pub fn set_pc(&mut self, address: u8) {
+        self.pc.set(address);
+        self.stopped_at = None;
+    }
+
+    /// Get the memory data register
+    pub fn mdr(&self) -> u16 {
+        self.mdr.get()
+    }
+
+    /// Get the instruction in the current instruction register
+    pub fn cir(&self) -> Option<Instruction> {
+        self.cir.get_instruction()
+    }
+
+    /// Get the address of the instruction last executed
+    pub fn instruction_address(&self) -> u8 {
+        self.instruction_address
+    }
+
+    /// Decode the instruction at an address without executing it
+    pub fn instruction_at(&self, address: u32) -> Option<Instruction> {
+        if address >= self.instruction_memory.size.saturating_sub(1) {
+            return None;
+        }
+        Instruction::try_from_word(self.instruction_memory.read_word(address))
+    }
+
+    /// Add a breakpoint on an instruction address
+    pub fn add_breakpoint(&mut self, address: u32, condition: Option<Condition>) {
+        self.remove_breakpoint(address);
+        self.breakpoints.push(Breakpoint {
+            address,
+            condition,
+            enabled: true,
+            hits: 0,
+        });
+    }
+
+    /// Remove the breakpoint on an instruction address
+    /// Returns whether a breakpoint was removed
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|bp| bp.address != address);
+        self.breakpoints.len() != count
+    }
+
+    /// Add a watchpoint on a data memory address
+    pub fn add_watchpoint(&mut self, address: u32, kind: WatchKind) {
+        self.remove_watchpoint(address);
+        self.watchpoints.push(Watchpoint {
+            address,
+            kind,
+            enabled: true,
+            hits: 0,
+        });
+    }
+
+    /// Remove the watchpoint on a data memory address
+    /// Returns whether a watchpoint was removed
+    pub fn remove_watchpoint(&mut self, address: u32) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|wp| wp.address != address);
+        self.watchpoints.len() != count
+    }
+
+    /// Evaluate a breakpoint condition against the current state
+    pub fn evaluate(&self, condition: &Condition) -> bool {
+        let lhs = self.operand_value(&condition.lhs);
+        let rhs = self.operand_value(&condition.rhs);
+        condition.comparison.apply(lhs, rhs)
+    }
+
+    /// Get the value of a condition operand
+    pub fn operand_value(&self, operand: &Operand) -> u8 {
+        match operand {
+            Operand::Acc => self.acc.get(),
+            Operand::Pc => self.pc.get(),
+            Operand::Memory(address) => self.peek_data(*address),
+            Operand::Value(value) => *value,
+        }
+    }
+
+    /// Read data memory as the CPU sees it, without side effects
+    pub fn peek_data(&self, address: u32) -> u8 {
+        match self.cache.as_ref() {
+            Some(cache) => cache.peek(&self.data_memory, address),
+            None => self.data_memory.read(address),
+        }
+    }
+
+    /// Find an enabled breakpoint at the current address whose condition
+    /// holds, returning its address
+    fn check_breakpoints(&mut self) -> Option<u32> {
+        let address = self.pc.get() as u32;
+        let index = self.breakpoints.iter().position(|bp| {
+            bp.enabled
+                && bp.address == address
+                && bp.condition.as_ref().is_none_or(|condition| self.evaluate(condition))
+        })?;
+
+        self.breakpoints[index].hits += 1;
+        Some(address)
+    }
+
+    /// Record a watchpoint hit for a data memory access
+    fn check_watchpoints(&mut self, address: u32, access: Access, value: u8) {
+        if self.pending_stop.is_some() {
+            return;
+        }
+
+        if let Some(wp) = self.watchpoints
+            .iter_mut()
+            .find(|wp| wp.enabled && wp.address == address && wp.kind.matches(access))
+        {
+            wp.hits += 1;
+            self.pending_stop = Some(StopReason::Watchpoint { address, access, value });
+        }
     }
 
     /// Fetch the next instruction
     fn fetch(&mut self) {
         // Get the address of the next instruction
         let address = self.pc.get(); // MAR
-        
-        // Read the instruction from the instruction memory
-        let instruction = self.instruction_memory.read(address as u32); // MDR
+        self.instruction_address = address;
 
-        println!("Fetching instruction at address: {} -> {}", address, instruction);
+        // Both bytes of the instruction must be in memory
+        if address as u32 + 1 >= self.instruction_memory.size {
+            self.pending_fault = Some(Fault::InstructionOutOfRange { address: address as u32 });
+            return;
+        }
+
+        // Read the instruction (opcode and operand) from the instruction memory
+        let instruction = self.instruction_memory.read_word(address as u32); // MDR
+
+        if self.trace {
+            println!("Fetching instruction at {} -> {}", self.describe(address as u32), instruction);
+        }
  
         // Increment the program counter by 2 (2 bytes per instruction)
-        self.pc.set(address + 2);
+        self.pc.set(address.wrapping_add(2));
 
         // Set the MDR to the instruction
         self.mdr.set(instruction);
@@ -87,13 +680,25 @@ impl CPU {
         // Get the instruction from the MDR
         let instruction = self.mdr.get();
 
-        println!("Decoding instruction: {}", instruction);
+        if self.trace {
+            println!("Decoding instruction: {}", instruction);
+        }
 
         // Decode the instruction and set to CIR
         // Decoding handled by CIR
-        self.cir.set(instruction);
+        self.cir.set_word(instruction);
+
+        if self.cir.get_instruction().is_none() {
+            self.pending_fault = Some(Fault::InvalidOpcode {
+                address: self.instruction_address as u32,
+                opcode: (instruction >> 8) as u8,
+            });
+            return;
+        }
 
-        println!("Decoded instruction: {} -> {:?}", instruction, self.cir.get_instruction().unwrap());
+        if self.trace {
+            println!("Decoded instruction: {} -> {:?}", instruction, self.cir.get_instruction().unwrap());
+        }
     }
 
     /// Execute the current instruction
@@ -101,7 +706,9 @@ impl CPU {
         // Get the instruction from the CIR
         let instruction = self.cir.get_instruction();
 
-        println!("Executing instruction: {:?}", instruction);
+        if self.trace {
+            println!("Executing instruction: {:?}", instruction);
+        }
 
         // Execute the instruction
         match instruction {
@@ -113,10 +720,10 @@ impl CPU {
                 match instr.opcode {
                     Opcode::ADD => {
                         // Get the operand from the data memory
-                        let operand = self.data_memory.read(operand_addr as u32);
+                        let operand = self.read_data(operand_addr as u32);
 
                         // Add the operand to the accumulator
-                        let result = self.acc.get() + operand;
+                        let result = self.acc.get().wrapping_add(operand);
 
                         // Set the accumulator to the result
                         self.acc.set(result);
@@ -124,10 +731,10 @@ impl CPU {
 
                     Opcode::SUB => {
                         // Get the operand from the data memory
-                        let operand = self.data_memory.read(operand_addr as u32);
+                        let operand = self.read_data(operand_addr as u32);
 
                         // Subtract the operand from the accumulator
-                        let result = self.acc.get() - operand;
+                        let result = self.acc.get().wrapping_sub(operand);
 
                         // Set the accumulator to the result
                         self.acc.set(result);
@@ -135,10 +742,10 @@ impl CPU {
 
                     Opcode::MUL => {
                         // Get the operand from the data memory
-                        let operand = self.data_memory.read(operand_addr as u32);
+                        let operand = self.read_data(operand_addr as u32);
 
                         // Multiply the operand with the accumulator
-                        let result = self.acc.get() * operand;
+                        let result = self.acc.get().wrapping_mul(operand);
 
                         // Set the accumulator to the result
                         self.acc.set(result);
@@ -146,7 +753,14 @@ impl CPU {
 
                     Opcode::DIV => {
                         // Get the operand from the data memory
-                        let operand = self.data_memory.read(operand_addr as u32);
+                        let operand = self.read_data(operand_addr as u32);
+
+                        if operand == 0 {
+                            self.pending_fault = Some(Fault::DivideByZero {
+                                address: self.instruction_address as u32,
+                            });
+                            return;
+                        }
 
                         // Divide the accumulator by the operand
                         let result = self.acc.get() / operand;
@@ -160,12 +774,12 @@ impl CPU {
                         let acc = self.acc.get();
 
                         // Store the accumulator in the data memory
-                        self.data_memory.write(operand_addr as u32, acc);
+                        self.write_data(operand_addr as u32, acc);
                     },
 
                     Opcode::LDA => {
                         // Get the operand from the data memory
-                        let operand = self.data_memory.read(operand_addr as u32);
+                        let operand = self.read_data(operand_addr as u32);
 
                         // Set the accumulator to the operand
                         self.acc.set(operand);
@@ -206,7 +820,8 @@ impl CPU {
                         let acc = self.acc.get();
 
                         // Check if the accumulator is greater than zero
-                        if acc > 0 {
+                        // (the accumulator is treated as signed)
+                        if (acc as i8) > 0 {
                             // Set the program counter to the operand
                             self.pc.set(operand_addr);
                         }
@@ -217,7 +832,8 @@ impl CPU {
                         let acc = self.acc.get();
 
                         // Check if the accumulator is less than zero
-                        if acc < 0 {
+                        // (the accumulator is treated as signed)
+                        if (acc as i8) < 0 {
                             // Set the program counter to the operand
                             self.pc.set(operand_addr);
                         }
@@ -248,11 +864,19 @@ impl CPU {
                     Opcode::HLT => {
                         // Stop the CPU
                         self.running = false;
+
+                        // Make memory reflect the cached values
+                        if let Some(cache) = self.cache.as_mut() {
+                            cache.flush(&mut self.data_memory);
+                        }
                     },
 
                     Opcode::INP => {
                         // Get the input from the user
                         let input = self.get_input();
+                        if self.pending_fault.is_some() {
+                            return;
+                        }
 
                         // Set the accumulator to the input
                         self.acc.set(input);
@@ -267,20 +891,293 @@ impl CPU {
                     },
 
                     Opcode::DAT => {
-                        unreachable!("DAT should not be executed");
+                        // Data in the code, which is not an instruction
+                        self.pending_fault = Some(Fault::InvalidOpcode {
+                            address: self.instruction_address as u32,
+                            opcode: (self.mdr.get() >> 8) as u8,
+                        });
                     },
                 }
             }
         }
     }
 
+    /// Read a byte from data memory, going through the cache if present
+    fn read_data(&mut self, address: u32) -> u8 {
+        if !self.check_data_address(address) {
+            return 0;
+        }
+
+        let value = match self.cache.as_mut() {
+            Some(cache) => cache.read(&mut self.data_memory, address),
+            None => self.data_memory.read(address),
+        };
+        if let Some(profile) = self.profile.as_mut().filter(|_| !self.replaying) {
+            profile.record_read(address);
+        }
+
+        self.check_watchpoints(address, Access::Read, value);
+        value
+    }
+
+    /// Write a byte to data memory, going through the cache if present
+    fn write_data(&mut self, address: u32, value: u8) {
+        if !self.check_data_address(address) {
+            return;
+        }
+
+        let old = self.peek_data(address);
+        if let Some(history) = self.history.as_mut() {
+            history.record_write(address, old);
+        }
+
+        match self.cache.as_mut() {
+            Some(cache) => cache.write(&mut self.data_memory, address, value),
+            None => self.data_memory.write(address, value),
+        }
+        if let Some(profile) = self.profile.as_mut().filter(|_| !self.replaying) {
+            profile.record_write(address);
+        }
+
+        self.check_watchpoints(address, Access::Write, value);
+    }
+
+    /// Check a data address is in range, raising a fault if not
+    fn check_data_address(&mut self, address: u32) -> bool {
+        if address < self.data_memory.size {
+            return true;
+        }
+
+        self.pending_fault = Some(Fault::DataOutOfRange {
+            address: self.instruction_address as u32,
+            data_address: address,
+        });
+        false
+    }
+
     /// Get input from the user
+    /// (or the value read last time when replaying a cycle)
     fn get_input(&mut self) -> u8 {
-        unimplemented!()
+        if self.replaying {
+            if let Some(value) = self.history.as_ref().and_then(|h| h.input_at(self.cycles)) {
+                return value;
+            }
+        }
+
+        let value = match self.io.read() {
+            Some(value) => value,
+            None => {
+                self.pending_fault = Some(Fault::InputExhausted {
+                    address: self.instruction_address as u32,
+                });
+                return 0;
+            },
+        };
+
+        if let Some(history) = self.history.as_mut() {
+            history.record_input(self.cycles, value);
+        }
+        value
     }
 
     /// Output data
     fn output(&mut self, data: u8) {
-        unimplemented!()
+        // Values are not printed twice when re-executing
+        self.io.write(data, !self.replaying);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A = 3, then `LDA A`, `ADD A`, `STA 1`, `HLT`
+    const DOUBLE: &[u8] = b"VNC\x01\x00\x01\x03\x06\x00\x01\x00\x05\x01\x0e\x00";
+
+    /// A CPU with an image loaded, reading no input
+    fn cpu(image: &[u8]) -> CPU {
+        let mut cpu = CPU::new(256, 256);
+        cpu.load_program(image.to_vec()).unwrap();
+        cpu.io = Io::scripted(&[]);
+        cpu
+    }
+
+    #[test]
+    fn data_in_code_faults() {
+        let mut cpu = CPU::new(256, 256);
+        cpu.load_program(b"VNC\x01\x00\x00\x11\x00".to_vec()).unwrap();
+        assert_eq!(cpu.start(), Status::Faulted(Fault::InvalidOpcode { address: 0, opcode: 0x11 }));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn programs_must_fit_in_memory() {
+        let mut cpu = CPU::new(4, 4);
+        assert!(cpu.load_program(b"VNC\x01\x00\x02\x01\x02\x0e\x00".to_vec()).is_ok());
+        let error = cpu.load_program(b"VNC\x01\x00\x05\x01\x02\x03\x04\x05".to_vec()).unwrap_err();
+        assert!(error.starts_with("Data does not fit"), "{}", error);
+        let error = cpu.load_program(vec![0x0E; 6]).unwrap_err();
+        assert!(error.starts_with("Code does not fit"), "{}", error);
+        assert!(cpu.load_program(b"VNC\x01\x00\x09".to_vec()).is_err());
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction() {
+        let mut cpu = cpu(DOUBLE);
+        cpu.add_breakpoint(4, None);
+        assert_eq!(cpu.start(), Status::Stopped(StopReason::Breakpoint(4)));
+        assert_eq!(cpu.acc.get(), 6);
+        assert_eq!(cpu.data_memory.read(1), 0);
+
+        // Resuming does not stop at the same breakpoint again
+        assert_eq!(cpu.run(), Status::Halted);
+        assert_eq!(cpu.data_memory.read(1), 6);
+        assert_eq!(cpu.breakpoints[0].hits, 1);
+    }
+
+    #[test]
+    fn conditional_breakpoints() {
+        let labels = |name: &str| (name == "A").then_some(0);
+        let mut cpu = cpu(DOUBLE);
+        cpu.add_breakpoint(2, Some(Condition::parse("acc > 3", &labels).unwrap()));
+        cpu.add_breakpoint(4, Some(Condition::parse("[A] < acc", &labels).unwrap()));
+        assert_eq!(cpu.start(), Status::Stopped(StopReason::Breakpoint(4)));
+        assert_eq!(cpu.breakpoints[0].hits, 0);
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_access() {
+        let mut cpu = cpu(DOUBLE);
+        cpu.add_watchpoint(1, WatchKind::Read);
+        cpu.add_watchpoint(0, WatchKind::Read);
+        let read = Status::Stopped(StopReason::Watchpoint { address: 0, access: Access::Read, value: 3 });
+        assert_eq!(cpu.start(), read);
+        assert_eq!(cpu.acc.get(), 3);
+        assert_eq!(cpu.run(), read);
+        assert_eq!(cpu.acc.get(), 6);
+
+        // Reads do not trigger a write watchpoint
+        cpu.add_watchpoint(0, WatchKind::Write);
+        cpu.add_watchpoint(1, WatchKind::Write);
+        let written = Status::Stopped(StopReason::Watchpoint { address: 1, access: Access::Write, value: 6 });
+        assert_eq!(cpu.run(), written);
+        assert_eq!(cpu.run(), Status::Halted);
+    }
+
+    /// N = 3, then `loop: LDA N`, `OUT`, `SUB ONE`, `STA N`, `JNZ loop`, `HLT`
+    const COUNTDOWN: &[u8] = b"VNC\x01\x00\x02\x03\x01\x06\x00\x10\x00\x02\x01\x05\x00\x0d\x00\x0e\x00";
+
+    /// The countdown from 3, run to the end with history enabled
+    fn counted_down(capacity: usize, interval: u64) -> CPU {
+        let mut cpu = cpu(COUNTDOWN);
+        cpu.enable_history(capacity, interval);
+        assert_eq!(cpu.start(), Status::Halted);
+        assert_eq!(cpu.cycles(), 16);
+        cpu
+    }
+
+    #[test]
+    fn step_back_undoes_instructions() {
+        let mut cpu = counted_down(1000, 4);
+        assert!(cpu.step_back());
+        assert_eq!(cpu.cycles(), 15);
+        assert_eq!(cpu.pc(), 10);
+
+        // Undo the last JNZ, STA N, SUB ONE and OUT
+        for _ in 0..4 {
+            assert!(cpu.step_back());
+        }
+        assert_eq!(cpu.cycles(), 11);
+        assert_eq!(cpu.data_memory.read(0), 1);
+        assert_eq!(cpu.pc(), 2);
+    }
+
+    #[test]
+    fn goto_cycle_goes_both_ways() {
+        let mut cpu = counted_down(1000, 4);
+        assert_eq!(cpu.goto_cycle(1), Ok(Status::Running));
+        assert_eq!((cpu.acc.get(), cpu.data_memory.read(0)), (3, 3));
+        assert_eq!(cpu.goto_cycle(16), Ok(Status::Halted));
+        assert_eq!(cpu.data_memory.read(0), 0);
+    }
+
+    #[test]
+    fn goto_cycle_beyond_the_undo_log() {
+        let mut cpu = counted_down(3, 4);
+        assert_eq!(cpu.goto_cycle(5), Ok(Status::Running));
+        assert_eq!(cpu.cycles(), 5);
+        assert_eq!(cpu.data_memory.read(0), 2);
+        assert_eq!(cpu.goto_cycle(16), Ok(Status::Halted));
+        assert_eq!(cpu.data_memory.read(0), 0);
+    }
+
+    #[test]
+    fn cycles_past_the_history_are_new() {
+        let mut cpu = cpu(COUNTDOWN);
+        cpu.enable_history(1000, 4);
+        cpu.enable_profile();
+        assert_eq!(cpu.run_for(6), Status::Running);
+        assert!(cpu.step_back() && cpu.step_back());
+
+        // Cycles 4 and 5 are replayed, the rest are run (and counted) once
+        assert_eq!(cpu.goto_cycle(16), Ok(Status::Halted));
+        assert_eq!(cpu.profile.as_ref().unwrap().cycles, 16);
+        assert_eq!(cpu.io.output, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn changing_the_past_drops_the_future() {
+        let mut cpu = counted_down(1000, 4);
+        assert_eq!(cpu.goto_cycle(3), Ok(Status::Running));
+        cpu.acc.set(1);
+        cpu.truncate_history();
+
+        // One more time round the loop, instead of two
+        assert_eq!(cpu.goto_cycle(16), Ok(Status::Halted));
+        assert_eq!(cpu.cycles(), 11);
+        assert!(cpu.step_back());
+        assert_eq!(cpu.cycles(), 10);
+    }
+
+    #[test]
+    fn changing_the_past_reads_input_again() {
+        // `INP`, `OUT`, `HLT`
+        let mut cpu = cpu(b"VNC\x01\x00\x00\x0f\x00\x10\x00\x0e\x00");
+        cpu.io = Io::scripted(&[3]);
+        cpu.enable_history(1000, 4);
+        assert_eq!(cpu.start(), Status::Halted);
+        assert_eq!(cpu.goto_cycle(0), Ok(Status::Running));
+        assert!(cpu.io.input.is_empty());
+
+        cpu.truncate_history();
+        assert_eq!(cpu.io.input, vec![3]);
+        cpu.io.input = [2].into();
+        assert_eq!(cpu.run(), Status::Halted);
+        assert_eq!(cpu.io.output, vec![2]);
+    }
+
+    #[test]
+    fn history_of_nothing_keeps_one_cycle() {
+        let mut cpu = cpu(COUNTDOWN);
+        cpu.enable_history(0, 0);
+        assert_eq!(cpu.start(), Status::Halted);
+        assert!(cpu.step_back());
+        assert_eq!(cpu.cycles(), 15);
+    }
+
+    #[test]
+    fn reverse_run_stops_at_watched_writes() {
+        let mut cpu = counted_down(1000, 4);
+        cpu.add_watchpoint(0, WatchKind::Write);
+        assert!(matches!(cpu.reverse_run(), Status::Stopped(StopReason::Watchpoint { address: 0, .. })));
+        // Stopped before the write of 0 to N
+        assert_eq!(cpu.data_memory.read(0), 1);
+
+        cpu.watchpoints.clear();
+        cpu.add_breakpoint(2, None);
+        assert_eq!(cpu.reverse_run(), Status::Stopped(StopReason::Breakpoint(2)));
+        assert_eq!((cpu.acc.get(), cpu.data_memory.read(0)), (1, 1));
+
+        cpu.breakpoints.clear();
+        assert_eq!(cpu.reverse_run(), Status::Stopped(StopReason::StartOfHistory));
+        assert_eq!(cpu.cycles(), 0);
+    }
+}
@@ -0,0 +1,502 @@
+//! Execution profile of a run
+//! Counts how many times the instruction at each address was executed
+//! and, for branches, how many of those times the branch was taken, and
+//! how many times each data address was read and written. Only addresses
+//! that were used are kept.
+//!
+//! A profile is saved as a line based text file:
+//! ```text
+//! # virtual nanocomputer profile
+//! format vnc-profile
+//! version 2
+//! cycles 11
+//! count 0x00 1
+//! count 0x02 4 taken 3
+//! read 0x00 5
+//! write 0x00 4
+//! end
+//! ```
+//! (`count address executions [taken times]`, `read address times` and
+//! `write address times`.)
+//!
+//! With the program that ran, it makes a report of where the time went:
+//! by instruction, by opcode and by region (the instructions from one code
+//! label up to the next), with memory use and how often each branch was
+//! taken. The report is text for reading or JSON for other tools.
+
+use std::collections::BTreeMap;
+
+use super::instructions::{Instruction, Opcode};
+use crate::assembler::{Program, Section};
+use crate::json::Json;
+
+/// Current profile format version
+pub const PROFILE_VERSION: u32 = 2;
+
+/// Name of the region before the first code label
+const START_REGION: &str = "(start)";
+
+/// How often each instruction ran
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    /// Number of instructions executed
+    pub cycles: u64,
+    /// Executions of each instruction address
+    pub counts: BTreeMap<u32, u64>,
+    /// Times the branch at each address was taken
+    pub taken: BTreeMap<u32, u64>,
+    /// Reads of each data address
+    pub reads: BTreeMap<u32, u64>,
+    /// Writes of each data address
+    pub writes: BTreeMap<u32, u64>,
+}
+
+/// Executions of the instructions from a code label up to the next
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    /// The label (`(start)` before the first one)
+    pub label: String,
+    /// Address of the first instruction
+    pub start: u32,
+    pub count: u64,
+}
+
+impl Profile {
+    /// Empty profile
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    /// Count an executed instruction, and whether it was a branch taken
+    pub fn record(&mut self, address: u32, taken: bool) {
+        self.cycles += 1;
+        *self.counts.entry(address).or_insert(0) += 1;
+        if taken {
+            *self.taken.entry(address).or_insert(0) += 1;
+        }
+    }
+
+    /// Count a read of a data address
+    pub fn record_read(&mut self, address: u32) {
+        *self.reads.entry(address).or_insert(0) += 1;
+    }
+
+    /// Count a write of a data address
+    pub fn record_write(&mut self, address: u32) {
+        *self.writes.entry(address).or_insert(0) += 1;
+    }
+
+    /// Add the counts of another run of the same program
+    pub fn merge(&mut self, other: &Profile) {
+        self.cycles += other.cycles;
+        for (into, from) in [
+            (&mut self.counts, &other.counts),
+            (&mut self.taken, &other.taken),
+            (&mut self.reads, &other.reads),
+            (&mut self.writes, &other.writes),
+        ] {
+            for (address, count) in from {
+                *into.entry(*address).or_insert(0) += count;
+            }
+        }
+    }
+
+    /// Times the instruction at an address was executed
+    pub fn count(&self, address: u32) -> u64 {
+        self.counts.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Times the branch at an address was taken
+    pub fn taken(&self, address: u32) -> u64 {
+        self.taken.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Executions of each opcode, most executed first
+    pub fn by_opcode(&self, program: &Program) -> Vec<(Opcode, u64)> {
+        let mut counts: Vec<(Opcode, u64)> = Vec::new();
+        for (address, count) in &self.counts {
+            let opcode = match instruction_at(program, *address) {
+                Some(instruction) => instruction.opcode,
+                None => continue,
+            };
+            match counts.iter_mut().find(|(other, _)| *other == opcode) {
+                Some((_, total)) => *total += count,
+                None => counts.push((opcode, *count)),
+            }
+        }
+        counts.sort_by_key(|(opcode, count)| (std::cmp::Reverse(*count), Opcode::ALL.iter().position(|o| o == opcode)));
+        counts
+    }
+
+    /// Executions of each region between code labels, most executed
+    /// first
+    pub fn by_region(&self, program: &Program) -> Vec<Region> {
+        let mut labels: Vec<(u32, &str)> = program
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.section == Section::Code)
+            .map(|symbol| (symbol.address, symbol.name.as_str()))
+            .collect();
+        labels.sort_by_key(|(address, _)| *address);
+        // Several labels on one instruction make one region
+        labels.dedup_by_key(|(address, _)| *address);
+        if labels.first().is_none_or(|(address, _)| *address > 0) {
+            labels.insert(0, (0, START_REGION));
+        }
+
+        let mut regions: Vec<Region> = labels
+            .iter()
+            .enumerate()
+            .map(|(index, (start, label))| {
+                let end = labels.get(index + 1).map_or(u32::MAX, |(end, _)| *end);
+                Region {
+                    label: label.to_string(),
+                    start: *start,
+                    count: self.counts.range(*start..end).map(|(_, count)| count).sum(),
+                }
+            })
+            .filter(|region| region.count > 0)
+            .collect();
+        regions.sort_by_key(|region| (std::cmp::Reverse(region.count), region.start));
+        regions
+    }
+
+    /// Make a report for reading
+    pub fn report(&self, program: &Program) -> String {
+        let mut out = Vec::new();
+        let share = |count: u64| 100.0 * count as f64 / self.cycles.max(1) as f64;
+        let describe = |address: u32| program.source_map.describe(address);
+        let data_label = |address: u32| program.source_map.data_label(address).unwrap_or_default();
+        out.push(format!("instructions executed: {}", self.cycles));
+
+        // Hot spots first
+        let mut counts: Vec<(&u32, &u64)> = self.counts.iter().collect();
+        counts.sort_by_key(|(address, count)| (std::cmp::Reverse(**count), **address));
+        out.push(String::new());
+        out.push("   count       %  address  instruction".to_string());
+        for (address, count) in counts {
+            out.push(format!("{:>8}  {:>5.1}%  0x{:02X}     {}", count, share(*count), address, describe(*address)));
+        }
+
+        out.push(String::new());
+        out.push("   count       %  opcode".to_string());
+        for (opcode, count) in self.by_opcode(program) {
+            out.push(format!("{:>8}  {:>5.1}%  {}", count, share(count), opcode));
+        }
+
+        out.push(String::new());
+        out.push("   count       %  region".to_string());
+        for region in self.by_region(program) {
+            out.push(format!("{:>8}  {:>5.1}%  {} (0x{:02X})", region.count, share(region.count), region.label, region.start));
+        }
+
+        let addresses = self.data_addresses();
+        if !addresses.is_empty() {
+            out.push(String::new());
+            out.push("   reads  writes  address  label".to_string());
+            for address in addresses {
+                let (reads, writes) = (self.reads.get(&address), self.writes.get(&address));
+                let line = format!(
+                    "{:>8}  {:>6}  0x{:02X}     {}",
+                    reads.unwrap_or(&0),
+                    writes.unwrap_or(&0),
+                    address,
+                    data_label(address)
+                );
+                out.push(line.trim_end().to_string());
+            }
+        }
+
+        let branches = self.branches(program);
+        if !branches.is_empty() {
+            out.push(String::new());
+            out.push("   taken   not taken       %  instruction".to_string());
+            for (address, taken, count) in branches {
+                let ratio = 100.0 * taken as f64 / count.max(1) as f64;
+                out.push(format!("{:>8}  {:>10}  {:>5.1}%  {}", taken, count.saturating_sub(taken), ratio, describe(address)));
+            }
+        }
+
+        out.push(String::new());
+        out.join("\n")
+    }
+
+    /// Make a report for other tools
+    pub fn to_json(&self, program: &Program) -> Json {
+        let location = |address: u32| -> Json {
+            match program.source_map.location(address) {
+                Some(location) => Json::object(vec![
+                    ("file", location.file.clone().into()),
+                    ("line", location.line.into()),
+                    ("text", location.text.clone().into()),
+                ]),
+                None => Json::Null,
+            }
+        };
+
+        let instructions = self
+            .counts
+            .iter()
+            .map(|(address, count)| {
+                Json::object(vec![
+                    ("address", (*address).into()),
+                    ("count", (*count).into()),
+                    ("source", location(*address)),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let opcodes = self
+            .by_opcode(program)
+            .into_iter()
+            .map(|(opcode, count)| Json::object(vec![("opcode", opcode.to_string().into()), ("count", count.into())]))
+            .collect::<Vec<_>>();
+        let regions = self
+            .by_region(program)
+            .into_iter()
+            .map(|region| {
+                Json::object(vec![
+                    ("label", region.label.into()),
+                    ("start", region.start.into()),
+                    ("count", region.count.into()),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let memory = self
+            .data_addresses()
+            .into_iter()
+            .map(|address| {
+                Json::object(vec![
+                    ("address", address.into()),
+                    ("label", program.source_map.data_label(address).into()),
+                    ("reads", self.reads.get(&address).copied().unwrap_or(0).into()),
+                    ("writes", self.writes.get(&address).copied().unwrap_or(0).into()),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let branches = self
+            .branches(program)
+            .into_iter()
+            .map(|(address, taken, count)| {
+                Json::object(vec![
+                    ("address", address.into()),
+                    ("taken", taken.into()),
+                    ("not_taken", count.saturating_sub(taken).into()),
+                    ("taken_ratio", (taken as f64 / count.max(1) as f64).into()),
+                    ("source", location(address)),
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        Json::object(vec![
+            ("instructions_executed", self.cycles.into()),
+            ("instructions", instructions.into()),
+            ("opcodes", opcodes.into()),
+            ("regions", regions.into()),
+            ("memory", memory.into()),
+            ("branches", branches.into()),
+        ])
+    }
+
+    /// Every data address read or written, in order
+    fn data_addresses(&self) -> Vec<u32> {
+        let mut addresses: Vec<u32> = self.reads.keys().chain(self.writes.keys()).copied().collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+
+    /// Every branch executed, as (address, times taken, times executed)
+    fn branches(&self, program: &Program) -> Vec<(u32, u64, u64)> {
+        self.counts
+            .iter()
+            .filter(|(address, _)| instruction_at(program, **address).is_some_and(|instruction| instruction.opcode.is_branch()))
+            .map(|(address, count)| (*address, self.taken(*address), *count))
+            .collect()
+    }
+
+    /// Save the profile to a file
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        std::fs::write(filename, self.to_string())
+    }
+
+    /// Load a profile from a file
+    pub fn load(filename: &str) -> Result<Profile, String> {
+        let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        Profile::parse(&text).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    /// Parse the text form of a profile
+    pub fn parse(text: &str) -> Result<Profile, String> {
+        let mut profile = Profile::new();
+        let mut version = 0;
+        let mut ended = false;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if ended {
+                return Err(format!("line {}: content after 'end'", line_number));
+            }
+
+            let error = |message: String| format!("line {}: {}", line_number, message);
+            let values: Vec<&str> = line.split_whitespace().collect();
+            let value = |i: usize| values.get(i).copied().ok_or_else(|| error(format!("missing value for '{}'", values[0])));
+
+            match values[0] {
+                "format" => {
+                    if value(1)? != "vnc-profile" {
+                        return Err(error(format!("not a profile (format {})", value(1)?)));
+                    }
+                },
+                "version" => {
+                    version = parse_number(value(1)?).map_err(error)? as u32;
+                    if version == 0 || version > PROFILE_VERSION {
+                        return Err(error(format!("unsupported profile version {}", version)));
+                    }
+                },
+                "cycles" => profile.cycles = parse_number(value(1)?).map_err(error)?,
+                "count" => {
+                    let address = parse_number(value(1)?).map_err(error)? as u32;
+                    let count = parse_number(value(2)?).map_err(error)?;
+                    profile.counts.insert(address, count);
+                    match values.get(3) {
+                        None => {},
+                        Some(&"taken") => {
+                            let taken = parse_number(value(4)?).map_err(error)?;
+                            if taken > count {
+                                return Err(error(format!("taken {} times but executed {}", taken, count)));
+                            }
+                            profile.taken.insert(address, taken);
+                        },
+                        Some(other) => return Err(error(format!("unexpected '{}'", other))),
+                    }
+                },
+                "read" | "write" => {
+                    let address = parse_number(value(1)?).map_err(error)? as u32;
+                    let times = parse_number(value(2)?).map_err(error)?;
+                    match values[0] {
+                        "read" => profile.reads.insert(address, times),
+                        _ => profile.writes.insert(address, times),
+                    };
+                },
+                "end" => ended = true,
+                key => return Err(error(format!("unknown key '{}'", key))),
+            }
+        }
+
+        if version == 0 {
+            return Err("missing 'format' or 'version' header".to_string());
+        }
+        if !ended {
+            return Err("profile is truncated (missing 'end')".to_string());
+        }
+
+        Ok(profile)
+    }
+}
+
+/// The instruction at an address of a program
+fn instruction_at(program: &Program, address: u32) -> Option<Instruction> {
+    let word = program.code.get(address as usize..address as usize + 2)?;
+    Instruction::try_from_word(u16::from_be_bytes([word[0], word[1]]))
+}
+
+/// Parse a decimal or 0x hex number
+fn parse_number(text: &str) -> Result<u64, String> {
+    let result = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse::<u64>(),
+    };
+    result.map_err(|_| format!("invalid number '{}'", text))
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "# virtual nanocomputer profile")?;
+        writeln!(f, "format vnc-profile")?;
+        writeln!(f, "version {}", PROFILE_VERSION)?;
+        writeln!(f, "cycles {}", self.cycles)?;
+        for (address, count) in &self.counts {
+            write!(f, "count 0x{:02X} {}", address, count)?;
+            match self.taken.get(address) {
+                Some(taken) => writeln!(f, " taken {}", taken)?,
+                None => writeln!(f)?,
+            }
+        }
+        for (address, times) in &self.reads {
+            writeln!(f, "read 0x{:02X} {}", address, times)?;
+        }
+        for (address, times) in &self.writes {
+            writeln!(f, "write 0x{:02X} {}", address, times)?;
+        }
+        writeln!(f, "end")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble_with, Options};
+    use crate::cfg::Cfg;
+    use crate::coverage::Coverage;
+
+    const LOOP: &str = ".data\nA: DAT 3\nONE: DAT 1\n.code\nLDA A\nloop: SUB ONE\nJNZ loop\nHLT\n";
+
+    #[test]
+    fn round_trip() {
+        let mut profile = Profile::new();
+        profile.record(0, false);
+        profile.record(4, true);
+        profile.record(4, false);
+        profile.record_read(1);
+        profile.record_write(2);
+        assert_eq!(Profile::parse(&profile.to_string()), Ok(profile));
+    }
+
+    #[test]
+    fn version_1() {
+        let profile = Profile::parse("format vnc-profile\nversion 1\ncycles 3\ncount 0x04 3 taken 2\nend\n").unwrap();
+        assert_eq!((profile.count(4), profile.taken(4)), (3, 2));
+    }
+
+    #[test]
+    fn taken_more_than_executed() {
+        let error = Profile::parse("format vnc-profile\nversion 2\ncount 0x04 1 taken 5\nend\n").unwrap_err();
+        assert!(error.contains("line 3"), "{}", error);
+    }
+
+    #[test]
+    fn truncated() {
+        let error = Profile::parse("format vnc-profile\nversion 1\ncount 0x04 3 taken 2\n").unwrap_err();
+        assert!(error.contains("truncated"), "{}", error);
+    }
+
+    #[test]
+    fn inconsistent_counts_do_not_panic() {
+        let program = assemble_with(LOOP, &Options::default()).program;
+        let mut profile = Profile::new();
+        profile.counts.insert(4, 1);
+        profile.taken.insert(4, 5);
+
+        assert!(profile.report(&program).contains("JNZ loop"));
+        profile.to_json(&program);
+        Cfg::new(&program.code).to_dot(&program, Some(&profile));
+        Coverage::new(&program, &profile).to_lcov();
+    }
+
+    #[test]
+    fn by_region() {
+        let program = assemble_with(LOOP, &Options::default()).program;
+        let mut profile = Profile::new();
+        for (address, taken) in [(0, false), (2, false), (4, true), (2, false), (4, false), (6, false)] {
+            profile.record(address, taken);
+        }
+
+        let regions: Vec<(String, u64)> = profile.by_region(&program).into_iter().map(|region| (region.label, region.count)).collect();
+        assert_eq!(regions, vec![("loop".to_string(), 5), ("(start)".to_string(), 1)]);
+        assert_eq!(profile.by_opcode(&program)[0], (Opcode::SUB, 2));
+    }
+}
@@ -1,4 +1,4 @@
-use super::instructions::{Instruction};
+use super::instructions::Instruction;
 
 pub trait Register {
     /// Get the value of the register
@@ -8,6 +8,7 @@ pub trait Register {
 }
 
 /// Program Counter used to store the address of the next instruction
+#[derive(Default)]
 pub struct PC {
     /// Program Counter
     data: u8,
@@ -17,19 +18,24 @@ pub struct PC {
 /// location
 /// This is also used to store the data to be written to the memory location
 /// during a store instruction
+/// Holds a full word so a whole instruction (opcode and operand) can be
+/// fetched in one go
+#[derive(Default)]
 pub struct MDR {
     /// Memory Data Register
-    data: u8,
+    data: u16,
 }
 
 /// Current Instruction Register used to store the current instruction
 /// being executed, split into opcode and operand
+#[derive(Default)]
 pub struct CIR {
     /// Current Instruction Register
     data: Option<Instruction>,
 }
 
 /// Accumulator used to store the result of arithmetic and logical operations
+#[derive(Default)]
 pub struct ACC {
     /// Accumulator
     data: u8,
@@ -46,28 +52,6 @@ impl Register for PC {
     }
 }
 
-impl Register for MDR {
-    /// Get the value of the register
-    fn get(&self) -> u8 {
-        self.data
-    }
-    /// Set the value of the register
-    fn set(&mut self, value: u8) {
-        self.data = value;
-    }
-}
-
-impl Register for CIR {
-    /// Get the value of the register
-    fn get(&self) -> u8 {
-        unimplemented!("CIR::get() not implemented")
-    }
-    /// Set the value of the register
-    fn set(&mut self, value: u8) {
-        self.data = Some(Instruction::from_byte(value));
-    }
-}
-
 impl Register for ACC {
     /// Get the value of the register
     fn get(&self) -> u8 {
@@ -95,6 +79,16 @@ impl MDR {
             data: 0,
         }
     }
+
+    /// Get the value of the register
+    pub fn get(&self) -> u16 {
+        self.data
+    }
+
+    /// Set the value of the register
+    pub fn set(&mut self, value: u16) {
+        self.data = value;
+    }
 }
 
 impl CIR {
@@ -105,6 +99,17 @@ impl CIR {
         }
     }
 
+    /// Decode a raw instruction word into the register
+    /// (cleared if the word is not a valid instruction)
+    pub fn set_word(&mut self, word: u16) {
+        self.data = Instruction::try_from_word(word);
+    }
+
+    /// Set the decoded instruction directly
+    pub fn set_instruction(&mut self, instruction: Option<Instruction>) {
+        self.data = instruction;
+    }
+
     /// Get instruction
     pub fn get_instruction(&self) -> Option<Instruction> {
         self.data.clone()
@@ -0,0 +1,470 @@
+//! Saving and restoring the full machine state
+//! A snapshot is a versioned, line based text file so it can be read,
+//! diffed and attached to bug reports:
+//! ```text
+//! # virtual nanocomputer machine state
+//! format vnc-snapshot
+//! version 2
+//! cycles 14
+//! running true
+//! pc 0x0C
+//! acc 0x07
+//! mdr 0x0E00
+//! cir 0x0E00
+//! instruction_address 0x0A
+//! input 5 6
+//! output 7
+//! memory data 256
+//! 0x0000: 03 04 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+//! memory instruction 256
+//! 0x0000: 06 00 01 01 10 00 0E 00 00 00 00 00 00 00 00 00
+//! end
+//! ```
+//! `input` holds the values INP has still to read and `output` every value
+//! OUT has written (neither is in version 1 files, which restore with
+//! none). Memory rows that are all zero are left out. When a cache is attached
+//! its configuration, statistics and every line are saved too:
+//! ```text
+//! cache 4 16 direct lru write-back
+//! cache_stats 2 0 1 1 0 0
+//! cache_clock 2
+//! cache_seed 625341585
+//! cache_line 1 0 0 2 1 03040000
+//! ```
+//! (`cache_line valid dirty tag last_used filled_at data`, one per line
+//! in set order)
+
+use super::cache::{CacheConfig, CacheState, CacheStats, LineState};
+use super::history::RegisterState;
+use super::instructions::Instruction;
+
+/// Current snapshot format version
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// Largest memory a snapshot may hold (the CPU addresses at most 256 bytes)
+const MAX_MEMORY_SIZE: u64 = 256;
+
+/// Bytes per memory row in the file
+const ROW_SIZE: usize = 16;
+
+/// Full state of the machine
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub version: u32,
+    pub cycles: u64,
+    pub registers: RegisterState,
+    /// Values queued for INP
+    pub input: Vec<u8>,
+    /// Values written by OUT
+    pub output: Vec<u8>,
+    pub data_memory: Vec<u8>,
+    pub instruction_memory: Vec<u8>,
+    pub cache: Option<CacheState>,
+}
+
+impl Snapshot {
+    /// Save the snapshot to a file
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        std::fs::write(filename, self.to_string())
+    }
+
+    /// Load a snapshot from a file
+    pub fn load(filename: &str) -> Result<Snapshot, String> {
+        let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        Snapshot::parse(&text).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    /// Parse the text form of a snapshot
+    pub fn parse(text: &str) -> Result<Snapshot, String> {
+        let mut snapshot = Snapshot {
+            version: 0,
+            cycles: 0,
+            registers: RegisterState {
+                pc: 0,
+                acc: 0,
+                mdr: 0,
+                cir: None,
+                instruction_address: 0,
+                running: true,
+            },
+            input: Vec::new(),
+            output: Vec::new(),
+            data_memory: Vec::new(),
+            instruction_memory: Vec::new(),
+            cache: None,
+        };
+
+        let mut format_seen = false;
+        let mut ended = false;
+        // Memory currently receiving rows
+        let mut current_memory: Option<&str> = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if ended {
+                return Err(format!("line {}: content after 'end'", line_number));
+            }
+
+            let error = |message: String| format!("line {}: {}", line_number, message);
+
+            // Memory rows
+            if line.starts_with("0x") && line.contains(':') {
+                let memory = match current_memory {
+                    Some("data") => &mut snapshot.data_memory,
+                    Some("instruction") => &mut snapshot.instruction_memory,
+                    _ => return Err(error("memory row outside of a memory block".to_string())),
+                };
+                parse_row(line, memory).map_err(error)?;
+                continue;
+            }
+            current_memory = None;
+
+            let mut parts = line.split_whitespace();
+            let key = parts.next().unwrap();
+            let values: Vec<&str> = parts.collect();
+            let value = |i: usize| values.get(i).copied().ok_or_else(|| error(format!("missing value for '{}'", key)));
+
+            match key {
+                "format" => {
+                    if value(0)? != "vnc-snapshot" {
+                        return Err(error(format!("not a snapshot (format {})", value(0)?)));
+                    }
+                    format_seen = true;
+                },
+                "version" => {
+                    snapshot.version = parse_u32(value(0)?).map_err(error)?;
+                    if snapshot.version == 0 || snapshot.version > SNAPSHOT_VERSION {
+                        return Err(error(format!("unsupported snapshot version {}", snapshot.version)));
+                    }
+                },
+                "cycles" => snapshot.cycles = parse_number(value(0)?).map_err(error)?,
+                "running" => snapshot.registers.running = parse_bool(value(0)?).map_err(error)?,
+                "pc" => snapshot.registers.pc = parse_byte(value(0)?).map_err(error)?,
+                "acc" => snapshot.registers.acc = parse_byte(value(0)?).map_err(error)?,
+                "mdr" => snapshot.registers.mdr = parse_word(value(0)?).map_err(error)?,
+                "cir" => {
+                    snapshot.registers.cir = match value(0)? {
+                        "none" => None,
+                        word => {
+                            let word = parse_word(word).map_err(error)?;
+                            Some(Instruction::try_from_word(word).ok_or_else(|| error(format!("invalid instruction 0x{:04X}", word)))?)
+                        },
+                    }
+                },
+                "instruction_address" => {
+                    snapshot.registers.instruction_address = parse_byte(value(0)?).map_err(error)?
+                },
+                "input" | "output" => {
+                    let bytes = values.iter().map(|value| parse_byte(value)).collect::<Result<_, _>>().map_err(error)?;
+                    match key {
+                        "input" => snapshot.input = bytes,
+                        _ => snapshot.output = bytes,
+                    }
+                },
+                "memory" => {
+                    let size = parse_number(value(1)?).map_err(error)?;
+                    if size > MAX_MEMORY_SIZE {
+                        return Err(error(format!("memory size {} is larger than {}", size, MAX_MEMORY_SIZE)));
+                    }
+                    let memory = match value(0)? {
+                        "data" => &mut snapshot.data_memory,
+                        "instruction" => &mut snapshot.instruction_memory,
+                        other => return Err(error(format!("unknown memory '{}'", other))),
+                    };
+                    *memory = vec![0; size as usize];
+                    current_memory = Some(if value(0)? == "data" { "data" } else { "instruction" });
+                },
+                "cache" => {
+                    let config = CacheConfig {
+                        line_size: parse_u32(value(0)?).map_err(error)?,
+                        capacity: parse_u32(value(1)?).map_err(error)?,
+                        mapping: value(2)?.parse().map_err(error)?,
+                        replacement: value(3)?.parse().map_err(error)?,
+                        write_policy: value(4)?.parse().map_err(error)?,
+                    };
+                    config.validate().map_err(error)?;
+                    snapshot.cache = Some(CacheState {
+                        config,
+                        stats: CacheStats::default(),
+                        clock: 0,
+                        seed: 0,
+                        lines: Vec::new(),
+                    });
+                },
+                "cache_stats" | "cache_clock" | "cache_seed" | "cache_line" => {
+                    let cache = snapshot.cache.as_mut().ok_or_else(|| error(format!("'{}' before 'cache'", key)))?;
+                    match key {
+                        "cache_stats" => {
+                            let mut numbers = [0u64; 6];
+                            for (i, number) in numbers.iter_mut().enumerate() {
+                                *number = parse_number(value(i)?).map_err(error)?;
+                            }
+                            cache.stats = CacheStats {
+                                reads: numbers[0],
+                                writes: numbers[1],
+                                hits: numbers[2],
+                                misses: numbers[3],
+                                evictions: numbers[4],
+                                write_backs: numbers[5],
+                            };
+                        },
+                        "cache_clock" => cache.clock = parse_number(value(0)?).map_err(error)?,
+                        "cache_seed" => cache.seed = parse_u32(value(0)?).map_err(error)?,
+                        _ => cache.lines.push(LineState {
+                            valid: parse_bool(value(0)?).map_err(error)?,
+                            dirty: parse_bool(value(1)?).map_err(error)?,
+                            tag: parse_u32(value(2)?).map_err(error)?,
+                            last_used: parse_number(value(3)?).map_err(error)?,
+                            filled_at: parse_number(value(4)?).map_err(error)?,
+                            data: parse_hex_bytes(value(5)?).map_err(error)?,
+                        }),
+                    }
+                },
+                "end" => ended = true,
+                _ => return Err(error(format!("unknown key '{}'", key))),
+            }
+        }
+
+        if !format_seen || snapshot.version == 0 {
+            return Err("missing 'format' or 'version' header".to_string());
+        }
+        if !ended {
+            return Err("snapshot is truncated (missing 'end')".to_string());
+        }
+
+        Ok(snapshot)
+    }
+}
+
+/// Parse `0xADDR: XX XX ...` into a memory
+fn parse_row(line: &str, memory: &mut [u8]) -> Result<(), String> {
+    let (address, bytes) = line.split_once(':').unwrap();
+    let address = parse_number(address)?;
+
+    for (i, byte) in bytes.split_whitespace().enumerate() {
+        let byte = u8::from_str_radix(byte, 16).map_err(|_| format!("invalid byte '{}'", byte))?;
+        let slot = address
+            .checked_add(i as u64)
+            .and_then(|address| memory.get_mut(usize::try_from(address).ok()?))
+            .ok_or_else(|| format!("address 0x{:04X} is outside the memory", address.saturating_add(i as u64)))?;
+        *slot = byte;
+    }
+    Ok(())
+}
+
+/// Parse a decimal or 0x hex number
+fn parse_number(text: &str) -> Result<u64, String> {
+    let result = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse::<u64>(),
+    };
+    result.map_err(|_| format!("invalid number '{}'", text))
+}
+
+/// Parse a number that must fit in a byte
+fn parse_byte(text: &str) -> Result<u8, String> {
+    u8::try_from(parse_number(text)?).map_err(|_| format!("'{}' does not fit in a byte", text))
+}
+
+/// Parse a number that must fit in 16 bits
+fn parse_word(text: &str) -> Result<u16, String> {
+    u16::try_from(parse_number(text)?).map_err(|_| format!("'{}' does not fit in 16 bits", text))
+}
+
+/// Parse a number that must fit in 32 bits
+fn parse_u32(text: &str) -> Result<u32, String> {
+    u32::try_from(parse_number(text)?).map_err(|_| format!("'{}' does not fit in 32 bits", text))
+}
+
+/// Parse `true`/`false` or `1`/`0`
+fn parse_bool(text: &str) -> Result<bool, String> {
+    match text {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(format!("invalid boolean '{}'", text)),
+    }
+}
+
+/// Parse a run of hex digit pairs
+fn parse_hex_bytes(text: &str) -> Result<Vec<u8>, String> {
+    // Only then is every pair of bytes a pair of characters
+    if !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex '{}'", text));
+    }
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits in '{}'", text));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| format!("invalid hex '{}'", text)))
+        .collect()
+}
+
+/// Values as written after a key, each after a space
+fn values(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!(" {}", byte)).collect()
+}
+
+/// Write a memory block, skipping all-zero rows
+fn write_memory(f: &mut std::fmt::Formatter, name: &str, memory: &[u8]) -> std::fmt::Result {
+    writeln!(f, "memory {} {}", name, memory.len())?;
+    for (row, bytes) in memory.chunks(ROW_SIZE).enumerate() {
+        if bytes.iter().all(|byte| *byte == 0) {
+            continue;
+        }
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        writeln!(f, "0x{:04X}: {}", row * ROW_SIZE, bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "# virtual nanocomputer machine state")?;
+        writeln!(f, "format vnc-snapshot")?;
+        writeln!(f, "version {}", self.version)?;
+        writeln!(f, "cycles {}", self.cycles)?;
+        writeln!(f, "running {}", self.registers.running)?;
+        writeln!(f, "pc 0x{:02X}", self.registers.pc)?;
+        writeln!(f, "acc 0x{:02X}", self.registers.acc)?;
+        writeln!(f, "mdr 0x{:04X}", self.registers.mdr)?;
+        match &self.registers.cir {
+            Some(instruction) => {
+                let bin = instruction.to_bin();
+                writeln!(f, "cir 0x{:02X}{:02X}", bin[0], bin[1])?
+            },
+            None => writeln!(f, "cir none")?,
+        }
+        writeln!(f, "instruction_address 0x{:02X}", self.registers.instruction_address)?;
+        writeln!(f, "input{}", values(&self.input))?;
+        writeln!(f, "output{}", values(&self.output))?;
+        write_memory(f, "data", &self.data_memory)?;
+        write_memory(f, "instruction", &self.instruction_memory)?;
+
+        if let Some(cache) = &self.cache {
+            let config = &cache.config;
+            let stats = &cache.stats;
+            writeln!(
+                f,
+                "cache {} {} {} {} {}",
+                config.line_size, config.capacity, config.mapping, config.replacement, config.write_policy
+            )?;
+            writeln!(
+                f,
+                "cache_stats {} {} {} {} {} {}",
+                stats.reads, stats.writes, stats.hits, stats.misses, stats.evictions, stats.write_backs
+            )?;
+            writeln!(f, "cache_clock {}", cache.clock)?;
+            writeln!(f, "cache_seed {}", cache.seed)?;
+            for line in &cache.lines {
+                let data: String = line.data.iter().map(|byte| format!("{:02X}", byte)).collect();
+                writeln!(
+                    f,
+                    "cache_line {} {} {} {} {} {}",
+                    line.valid as u8, line.dirty as u8, line.tag, line.last_used, line.filled_at, data
+                )?;
+            }
+        }
+
+        writeln!(f, "end")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::cache::CacheConfig;
+    use crate::cpu::debug::Status;
+    use crate::cpu::io::Io;
+    use crate::cpu::CPU;
+
+    /// Outputs each of three inputs (`INP`, `STA A`, `OUT`, then `INP`,
+    /// `OUT` twice and `HLT`), stopping after the first
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new(256, 256);
+        let image = b"VNC\x01\x00\x01\x00\x0f\x00\x05\x00\x10\x00\x0f\x00\x10\x00\x0f\x00\x10\x00\x0e\x00";
+        cpu.load_program(image.to_vec()).unwrap();
+        cpu.io = Io::scripted(&[5, 6, 7]);
+        cpu.attach_cache("4,16".parse::<CacheConfig>().unwrap()).unwrap();
+        cpu.add_breakpoint(6, None);
+        cpu
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut cpu = cpu();
+        assert!(matches!(cpu.start(), Status::Stopped(_)));
+        let snapshot = cpu.snapshot();
+        assert_eq!(snapshot.input, vec![6, 7]);
+        assert_eq!(snapshot.output, vec![5]);
+        assert!(snapshot.cache.is_some());
+        assert_eq!(Snapshot::parse(&snapshot.to_string()), Ok(snapshot.clone()));
+
+        // A machine restored from it carries on as the original would
+        let mut restored = CPU::new(256, 256);
+        restored.io = Io::scripted(&[]);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.run(), Status::Halted);
+        assert_eq!(restored.io.output, vec![5, 6, 7]);
+        assert_eq!(restored.data_memory.read(0), 5);
+    }
+
+    #[test]
+    fn version_1() {
+        let mut cpu = cpu();
+        cpu.start();
+        let text = cpu.snapshot().to_string().replace("version 2", "version 1");
+        let text: String = text
+            .lines()
+            .filter(|line| !line.starts_with("input") && !line.starts_with("output"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let snapshot = Snapshot::parse(&text).unwrap();
+        assert!(snapshot.input.is_empty() && snapshot.output.is_empty());
+    }
+
+    #[test]
+    fn invalid_values() {
+        let mut cpu = cpu();
+        cpu.start();
+        let text = cpu.snapshot().to_string();
+
+        let line = text.lines().find(|line| line.starts_with("cache_line")).unwrap();
+        let (start, _) = line.rsplit_once(' ').unwrap();
+        for data in ["aé0", "0g", "123"] {
+            let broken = text.replace(line, &format!("{} {}", start, data));
+            assert!(Snapshot::parse(&broken).unwrap_err().contains("hex"), "{}", data);
+        }
+
+        let broken = text.replace("input 6 7", "input 6 256");
+        assert!(Snapshot::parse(&broken).unwrap_err().contains("does not fit in a byte"));
+    }
+
+    #[test]
+    fn numbers_must_fit() {
+        let mut cpu = cpu();
+        cpu.start();
+        let text = cpu.snapshot().to_string();
+
+        // Replace the values after a key
+        let with = |key: &str, values: &str| {
+            let line = text.lines().find(|line| line.split(' ').next() == Some(key)).unwrap();
+            text.replace(line, &format!("{} {}", key, values))
+        };
+        let error = |text: String| Snapshot::parse(&text).unwrap_err();
+
+        assert!(error(with("version", "4294967298")).contains("does not fit in 32 bits"));
+        assert!(error(with("mdr", "65536")).contains("does not fit in 16 bits"));
+        assert!(error(with("cir", "0x10000")).contains("does not fit in 16 bits"));
+        assert!(error(with("cache_seed", "4294967296")).contains("does not fit in 32 bits"));
+        assert!(error(with("memory", "data 1000000000000")).contains("larger than 256"));
+        for row in ["0x00FF: 01 02", "0xFFFFFFFFFFFFFFFF: 01 02"] {
+            let broken = text.replace("memory data 256\n", &format!("memory data 256\n{}\n", row));
+            assert!(error(broken).contains("outside the memory"), "{}", row);
+        }
+    }
+}
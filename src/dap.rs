@@ -0,0 +1,1060 @@
+//! Debug Adapter Protocol (DAP) server
+//! Started with `vnc dap`, speaks DAP over stdin/stdout so editors can
+//! debug `.vnc` programs. Stdout carries only protocol messages; values
+//! written by OUT are sent as `output` events.
+//!
+//! Launch arguments:
+//! ```text
+//! program       path of the source file
+//! stopOnEntry   stop before the first instruction (default false)
+//! input         values read by INP, e.g. [3, 4]
+//! ```
+//!
+//! The adapter works as follows:
+//! 1. `launch` assembles the program and loads it into a CPU with history
+//!    enabled, then sends `initialized`
+//! 2. Breakpoints are set by source line and moved to the first
+//!    instruction on or after that line. Conditions use the debugger's
+//!    syntax (e.g. `acc == 5`)
+//! 3. `configurationDone` starts the program (or stops on entry)
+//! 4. While running, instructions are executed in bursts so `pause` is
+//!    answered promptly
+//!
+//! There is a single thread with a single frame. The `Registers` scope
+//! shows pc, acc, mdr, cir and the cycle count and the `Data` scope shows
+//! every labelled data cell. Memory references use the same address space
+//! as the GDB server (data memory starts at `gdb::DATA_BASE`).
+
+use std::io::{BufRead, Write};
+use std::sync::mpsc::{self, TryRecvError};
+
+use crate::assembler::{self, Program, Section};
+use crate::cpu::debug::{parse_address, Condition, Operand, Status, StopReason, WatchKind};
+use crate::cpu::io::Io;
+use crate::cpu::registers::Register;
+use crate::cpu::CPU;
+use crate::gdb::DATA_BASE;
+use crate::json::Json;
+
+/// Size of both memories
+const MEMORY_SIZE: u32 = 256;
+
+/// Undo records kept for stepping backwards
+const HISTORY_CAPACITY: usize = 10_000;
+
+/// Cycles between full checkpoints
+const CHECKPOINT_INTERVAL: u64 = 256;
+
+/// Instructions executed between checks for new requests while running
+const BURST: u64 = 1000;
+
+/// Most instructions a single step request may execute
+const STEP_LIMIT: u32 = 100_000;
+
+/// Longest message body accepted (anything longer is skipped)
+const MAX_MESSAGE_LENGTH: usize = 1 << 24;
+
+/// The only thread
+const THREAD_ID: u32 = 1;
+
+/// Variable references of the two scopes
+const REGISTERS_REFERENCE: u32 = 1;
+const DATA_REFERENCE: u32 = 2;
+
+/// Debug adapter state
+pub struct DebugAdapter {
+    cpu: CPU,
+    program: Program,
+    /// Path of the source file
+    path: String,
+    /// Whether a program has been launched
+    launched: bool,
+    /// Stop before the first instruction once configuration is done
+    stop_on_entry: bool,
+    /// Whether `continue` is in progress
+    running: bool,
+    /// (id, address) of every breakpoint set by the client
+    breakpoint_ids: Vec<(u32, u32)>,
+    next_breakpoint_id: u32,
+    /// Number of OUT values already sent as output events
+    output_sent: usize,
+    /// Sequence number of the next message sent
+    seq: u64,
+    /// Events to send after the current response
+    events: Vec<Json>,
+}
+
+impl Default for DebugAdapter {
+    fn default() -> DebugAdapter {
+        DebugAdapter::new()
+    }
+}
+
+impl DebugAdapter {
+    /// Create an adapter with nothing launched
+    pub fn new() -> DebugAdapter {
+        DebugAdapter {
+            cpu: CPU::new(MEMORY_SIZE, MEMORY_SIZE),
+            program: Program::default(),
+            path: String::new(),
+            launched: false,
+            stop_on_entry: false,
+            running: false,
+            breakpoint_ids: Vec::new(),
+            next_breakpoint_id: 1,
+            output_sent: 0,
+            seq: 1,
+            events: Vec::new(),
+        }
+    }
+
+    /// Serve requests on stdin until the client disconnects
+    pub fn serve(&mut self) {
+        // Requests are read on another thread so a running program can be
+        // paused
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let stdin = std::io::stdin();
+            let mut reader = stdin.lock();
+            while let Some(message) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        loop {
+            let message = if self.running {
+                match receiver.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match receiver.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => break,
+                }
+            };
+
+            if let Some(message) = message {
+                if !self.dispatch(&message) {
+                    break;
+                }
+            }
+
+            if self.running {
+                let status = self.cpu.run_for(BURST);
+                if status != Status::Running {
+                    self.running = false;
+                    self.report(status, "step");
+                }
+                self.flush_events();
+            }
+        }
+    }
+
+    /// Answer a request, returning false when the session is over
+    fn dispatch(&mut self, message: &Json) -> bool {
+        if message.get("type").and_then(Json::as_str) != Some("request") {
+            return true;
+        }
+
+        let command = message.get("command").and_then(Json::as_str).unwrap_or("");
+        let empty = Json::Object(Vec::new());
+        let args = message.get("arguments").unwrap_or(&empty);
+
+        let result = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(Json::object(vec![("breakpoints", Json::Array(Vec::new()))])),
+            "configurationDone" => self.configuration_done(),
+            "threads" => Ok(Json::object(vec![(
+                "threads",
+                Json::Array(vec![Json::object(vec![("id", THREAD_ID.into()), ("name", "main".into())])]),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(scopes()),
+            "variables" => self.variables(args),
+            "setVariable" => self.set_variable(args),
+            "continue" => self.resume(),
+            "next" | "stepIn" | "stepOut" => self.next(),
+            "stepBack" => self.step_back(),
+            "reverseContinue" => self.reverse_continue(),
+            "pause" => self.pause(),
+            "readMemory" => self.read_memory(args),
+            "evaluate" => self.evaluate(args),
+            "dataBreakpointInfo" => self.data_breakpoint_info(args),
+            "setDataBreakpoints" => self.set_data_breakpoints(args),
+            "disconnect" | "terminate" => {
+                self.respond(message, Ok(Json::Null));
+                if command == "terminate" {
+                    self.event("terminated", Json::Null);
+                }
+                self.flush_events();
+                return false;
+            },
+            _ => Err(format!("Unsupported request: {}", command)),
+        };
+
+        self.respond(message, result);
+        self.flush_events();
+        true
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs a 'program' path")?
+            .to_string();
+
+        let input = match args.get("input").and_then(Json::as_array) {
+            Some(values) => values
+                .iter()
+                .map(|value| {
+                    value
+                        .as_u64()
+                        .and_then(|value| u8::try_from(value).ok())
+                        .ok_or_else(|| format!("Input values must be between 0 and 255: {}", value))
+                })
+                .collect::<Result<Vec<u8>, String>>()?,
+            None => Vec::new(),
+        };
+
+        let program =
+            assembler::try_assemble_program(&path).map_err(|errors| format!("Failed to assemble {}:\n{}", path, errors))?;
+
+        let mut cpu = CPU::new(MEMORY_SIZE, MEMORY_SIZE);
+        cpu.load_program(program.to_binary())?;
+        cpu.source_map = Some(program.source_map.clone());
+        cpu.enable_history(HISTORY_CAPACITY, CHECKPOINT_INTERVAL);
+        cpu.io = Io::scripted(&input);
+
+        self.cpu = cpu;
+        self.program = program;
+        self.path = path;
+        self.launched = true;
+        self.stop_on_entry = args.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+        self.output_sent = 0;
+        self.breakpoint_ids.clear();
+
+        self.event("initialized", Json::Null);
+        Ok(Json::Null)
+    }
+
+    fn configuration_done(&mut self) -> Result<Json, String> {
+        if !self.launched {
+            return Ok(Json::Null);
+        }
+
+        if self.stop_on_entry {
+            self.stopped("entry", None, Vec::new());
+        } else {
+            self.running = true;
+        }
+        Ok(Json::Null)
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args.get("source").and_then(|source| source.get("path")).and_then(Json::as_str);
+        let requested = args.get("breakpoints").and_then(Json::as_array).cloned().unwrap_or_default();
+
+        self.cpu.breakpoints.clear();
+        self.breakpoint_ids.clear();
+
+        let same_file = path.is_some_and(|path| same_file(path, &self.path));
+        let mut breakpoints = Vec::new();
+
+        for breakpoint in &requested {
+            let line = breakpoint.get("line").and_then(Json::as_u64).unwrap_or(0) as usize;
+            let id = self.next_breakpoint_id;
+            self.next_breakpoint_id += 1;
+
+            let location = if self.launched && same_file {
+                self.program.next_code_line(line)
+            } else {
+                None
+            };
+            let (address, line) = match location {
+                Some(location) => location,
+                None => {
+                    breakpoints.push(Json::object(vec![
+                        ("id", id.into()),
+                        ("verified", false.into()),
+                        ("line", line.into()),
+                        ("message", "No code on or after this line".into()),
+                    ]));
+                    continue;
+                },
+            };
+
+            let condition = match breakpoint.get("condition").and_then(Json::as_str) {
+                Some(text) if !text.trim().is_empty() => {
+                    match Condition::parse(text, &|name| self.program.symbols.address_of(name)) {
+                        Ok(condition) => Some(condition),
+                        Err(e) => {
+                            breakpoints.push(Json::object(vec![
+                                ("id", id.into()),
+                                ("verified", false.into()),
+                                ("line", line.into()),
+                                ("message", e.into()),
+                            ]));
+                            continue;
+                        },
+                    }
+                },
+                _ => None,
+            };
+
+            self.cpu.add_breakpoint(address, condition);
+            self.breakpoint_ids.push((id, address));
+            breakpoints.push(Json::object(vec![
+                ("id", id.into()),
+                ("verified", true.into()),
+                ("line", line.into()),
+                ("source", self.source()),
+                ("instructionReference", format!("0x{:02X}", address).into()),
+            ]));
+        }
+
+        Ok(Json::object(vec![("breakpoints", Json::Array(breakpoints))]))
+    }
+
+    fn stack_trace(&self) -> Result<Json, String> {
+        let pc = self.cpu.pc() as u32;
+
+        let mut frame = vec![
+            ("id", 0u32.into()),
+            ("name", self.describe_code(pc).into()),
+            ("instructionPointerReference", format!("0x{:02X}", pc).into()),
+        ];
+        match self.program.line_of(pc) {
+            Some(line) => {
+                frame.push(("source", self.source()));
+                frame.push(("line", line.into()));
+            },
+            None => frame.push(("line", 0u32.into())),
+        }
+        frame.push(("column", 1u32.into()));
+
+        Ok(Json::object(vec![
+            ("stackFrames", Json::Array(vec![Json::object(frame)])),
+            ("totalFrames", 1u32.into()),
+        ]))
+    }
+
+    fn variables(&self, args: &Json) -> Result<Json, String> {
+        let reference = args.get("variablesReference").and_then(Json::as_u64).unwrap_or(0);
+
+        let variables = match reference as u32 {
+            REGISTERS_REFERENCE => {
+                let cir = match self.cpu.cir() {
+                    Some(instruction) => instruction.to_string(),
+                    None => "-".to_string(),
+                };
+                vec![
+                    variable("pc", format!("0x{:02X}", self.cpu.pc()), None),
+                    variable("acc", format_byte(self.cpu.acc.get()), None),
+                    variable("mdr", format!("0x{:04X}", self.cpu.mdr()), None),
+                    variable("cir", cir, None),
+                    variable("cycles", self.cpu.cycles().to_string(), None),
+                ]
+            },
+            DATA_REFERENCE => self
+                .program
+                .symbols
+                .iter()
+                .filter(|symbol| symbol.section == Section::Data)
+                .map(|symbol| {
+                    let value = self.cpu.peek_data(symbol.address);
+                    variable(&symbol.name, format_byte(value), Some(DATA_BASE + symbol.address))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(Json::object(vec![("variables", Json::Array(variables))]))
+    }
+
+    fn set_variable(&mut self, args: &Json) -> Result<Json, String> {
+        let reference = args.get("variablesReference").and_then(Json::as_u64).unwrap_or(0) as u32;
+        let name = args.get("name").and_then(Json::as_str).unwrap_or("");
+        let text = args.get("value").and_then(Json::as_str).unwrap_or("");
+
+        let value = parse_address(text, &|name| self.program.symbols.address_of(name))?;
+        let value = u8::try_from(value).map_err(|_| format!("Value {} does not fit in a byte", value))?;
+
+        let shown = match (reference, name) {
+            (REGISTERS_REFERENCE, "acc") => {
+                self.cpu.acc.set(value);
+                format_byte(value)
+            },
+            (REGISTERS_REFERENCE, "pc") => {
+                self.cpu.set_pc(value);
+                format!("0x{:02X}", value)
+            },
+            (REGISTERS_REFERENCE, other) => return Err(format!("{} cannot be changed", other)),
+            (DATA_REFERENCE, label) => {
+                let address = match self.program.symbols.get(label) {
+                    Some(symbol) if symbol.section == Section::Data => symbol.address,
+                    _ => return Err(format!("Unknown data label: {}", label)),
+                };
+
+                // Keep any cached copy consistent with memory
+                if let Some(cache) = self.cpu.cache.as_mut() {
+                    cache.flush(&mut self.cpu.data_memory);
+                    cache.invalidate();
+                }
+                self.cpu.data_memory.write(address, value);
+                format_byte(value)
+            },
+            _ => return Err(format!("Unknown variable: {}", name)),
+        };
+        // Cycles recorded after this one ran with the old value
+        self.cpu.truncate_history();
+
+        Ok(Json::object(vec![("value", shown.into())]))
+    }
+
+    fn resume(&mut self) -> Result<Json, String> {
+        self.running = true;
+        Ok(Json::object(vec![("allThreadsContinued", true.into())]))
+    }
+
+    /// Step until the source line changes or a breakpoint is hit, giving
+    /// up after `STEP_LIMIT` instructions on one line
+    fn next(&mut self) -> Result<Json, String> {
+        let line = self.program.line_of(self.cpu.pc() as u32);
+
+        for count in 0..STEP_LIMIT {
+            // The first instruction runs even if it has a breakpoint
+            let status = match count {
+                0 => self.cpu.step(),
+                _ => self.cpu.run_for(1),
+            };
+            if status != Status::Running || self.program.line_of(self.cpu.pc() as u32) != line {
+                self.report(status, "step");
+                return Ok(Json::Null);
+            }
+        }
+
+        self.send_output();
+        let text = format!("Still on the same line after {} instructions", STEP_LIMIT);
+        self.stopped("step", Some(text), Vec::new());
+        Ok(Json::Null)
+    }
+
+    fn step_back(&mut self) -> Result<Json, String> {
+        if self.cpu.step_back() {
+            self.stopped("step", None, Vec::new());
+        } else {
+            self.report(Status::Stopped(StopReason::StartOfHistory), "step");
+        }
+        Ok(Json::Null)
+    }
+
+    fn reverse_continue(&mut self) -> Result<Json, String> {
+        let status = self.cpu.reverse_run();
+        self.report(status, "step");
+        Ok(Json::Null)
+    }
+
+    fn pause(&mut self) -> Result<Json, String> {
+        if self.running {
+            self.running = false;
+            self.stopped("pause", None, Vec::new());
+        }
+        Ok(Json::Null)
+    }
+
+    fn read_memory(&self, args: &Json) -> Result<Json, String> {
+        let reference = args.get("memoryReference").and_then(Json::as_str).unwrap_or("");
+        let base = parse_address(reference, &|name| self.program.symbols.address_of(name))? as i64;
+        let offset = args.get("offset").and_then(Json::as_i64).unwrap_or(0);
+        let count = args.get("count").and_then(Json::as_u64).unwrap_or(0);
+
+        let start = base.checked_add(offset).ok_or("readMemory offset is too large")?;
+        let end = (start.max(0) as u64).checked_add(count).ok_or("readMemory count is too large")?;
+        let mut bytes = Vec::new();
+        if start >= 0 {
+            for address in start as u64..end {
+                match self.read_byte(address) {
+                    Some(value) => bytes.push(value),
+                    None => break,
+                }
+            }
+        }
+
+        Ok(Json::object(vec![
+            ("address", format!("0x{:X}", start.max(0)).into()),
+            ("data", base64(&bytes).into()),
+            ("unreadableBytes", (count - bytes.len() as u64).into()),
+        ]))
+    }
+
+    /// Read a byte from the combined address space
+    fn read_byte(&self, address: u64) -> Option<u8> {
+        let address = u32::try_from(address).ok()?;
+        if address >= DATA_BASE {
+            let address = address - DATA_BASE;
+            (address < self.cpu.data_memory.size).then(|| self.cpu.peek_data(address))
+        } else {
+            (address < self.cpu.instruction_memory.size).then(|| self.cpu.instruction_memory.read(address))
+        }
+    }
+
+    fn evaluate(&self, args: &Json) -> Result<Json, String> {
+        let expression = args.get("expression").and_then(Json::as_str).unwrap_or("");
+
+        let result = match expression.trim() {
+            "cycles" => self.cpu.cycles().to_string(),
+            "mdr" => format!("0x{:04X}", self.cpu.mdr()),
+            "cir" => self.cpu.cir().map_or("-".to_string(), |instruction| instruction.to_string()),
+            text => {
+                // Data labels evaluate to their value rather than their address
+                let operand = match self.program.symbols.get(text) {
+                    Some(symbol) if symbol.section == Section::Data => Operand::Memory(symbol.address),
+                    _ => Operand::parse(text, &|name| self.program.symbols.address_of(name))?,
+                };
+                format_byte(self.cpu.operand_value(&operand))
+            },
+        };
+
+        Ok(Json::object(vec![("result", result.into()), ("variablesReference", 0u32.into())]))
+    }
+
+    fn data_breakpoint_info(&self, args: &Json) -> Result<Json, String> {
+        let name = args.get("name").and_then(Json::as_str).unwrap_or("");
+
+        let address = match self.program.symbols.get(name) {
+            Some(symbol) if symbol.section == Section::Data => Some(symbol.address),
+            _ => parse_address(name, &|_| None).ok().filter(|address| *address < self.cpu.data_memory.size),
+        };
+
+        Ok(match address {
+            Some(address) => Json::object(vec![
+                ("dataId", address.to_string().into()),
+                ("description", self.describe_data(address).into()),
+                (
+                    "accessTypes",
+                    Json::Array(vec!["read".into(), "write".into(), "readWrite".into()]),
+                ),
+                ("canPersist", false.into()),
+            ]),
+            None => Json::object(vec![
+                ("dataId", Json::Null),
+                ("description", format!("{} is not a data memory cell", name).into()),
+            ]),
+        })
+    }
+
+    fn set_data_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let requested = args.get("breakpoints").and_then(Json::as_array).cloned().unwrap_or_default();
+
+        self.cpu.watchpoints.clear();
+        let mut breakpoints = Vec::new();
+
+        for breakpoint in &requested {
+            let address = breakpoint
+                .get("dataId")
+                .and_then(Json::as_str)
+                .and_then(|id| id.parse::<u32>().ok())
+                .filter(|address| *address < self.cpu.data_memory.size);
+            let kind = match breakpoint.get("accessType").and_then(Json::as_str) {
+                Some("read") => WatchKind::Read,
+                Some("readWrite") => WatchKind::ReadWrite,
+                _ => WatchKind::Write,
+            };
+
+            match address {
+                Some(address) => {
+                    self.cpu.add_watchpoint(address, kind);
+                    breakpoints.push(Json::object(vec![("verified", true.into())]));
+                },
+                None => breakpoints.push(Json::object(vec![
+                    ("verified", false.into()),
+                    ("message", "Invalid data address".into()),
+                ])),
+            }
+        }
+
+        Ok(Json::object(vec![("breakpoints", Json::Array(breakpoints))]))
+    }
+
+    /// Send the events for a CPU status
+    fn report(&mut self, status: Status, step_reason: &str) {
+        self.send_output();
+
+        match status {
+            Status::Running => self.stopped(step_reason, None, Vec::new()),
+            Status::Halted => {
+                let acc = self.cpu.acc.get();
+                self.event("exited", Json::object(vec![("exitCode", acc.into())]));
+                self.event("terminated", Json::Null);
+            },
+            Status::Stopped(StopReason::Breakpoint(address)) => {
+                let ids = self
+                    .breakpoint_ids
+                    .iter()
+                    .filter(|(_, a)| *a == address)
+                    .map(|(id, _)| Json::from(*id))
+                    .collect();
+                self.stopped("breakpoint", None, ids);
+            },
+            Status::Stopped(reason @ StopReason::Watchpoint { .. }) => {
+                self.stopped("data breakpoint", Some(reason.to_string()), Vec::new());
+            },
+            Status::Stopped(reason @ StopReason::StartOfHistory) => {
+                self.stopped("step", Some(reason.to_string()), Vec::new());
+            },
+            Status::Faulted(fault) => {
+                let description = self.cpu.describe_fault(&fault);
+                self.stopped("exception", Some(description), Vec::new())
+            },
+        }
+    }
+
+    /// Queue a `stopped` event
+    fn stopped(&mut self, reason: &str, text: Option<String>, hit_breakpoint_ids: Vec<Json>) {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("description", text.clone().into()));
+            body.push(("text", text.into()));
+        }
+        if !hit_breakpoint_ids.is_empty() {
+            body.push(("hitBreakpointIds", Json::Array(hit_breakpoint_ids)));
+        }
+        self.event("stopped", Json::object(body));
+    }
+
+    /// Queue `output` events for values written by OUT since the last call
+    fn send_output(&mut self) {
+        // Stepping backwards forgets output
+        self.output_sent = self.output_sent.min(self.cpu.io.output.len());
+
+        let values: Vec<u8> = self.cpu.io.output[self.output_sent..].to_vec();
+        self.output_sent = self.cpu.io.output.len();
+        for value in values {
+            self.event(
+                "output",
+                Json::object(vec![("category", "stdout".into()), ("output", format!("{}\n", value).into())]),
+            );
+        }
+    }
+
+    /// Queue an event to send after the current response
+    fn event(&mut self, name: &str, body: Json) {
+        let mut event = Json::object(vec![("type", "event".into()), ("event", name.into())]);
+        if !body.is_null() {
+            event.set("body", body);
+        }
+        self.events.push(event);
+    }
+
+    /// Send the response to a request
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) {
+        let mut response = Json::object(vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+        ]);
+
+        match result {
+            Ok(body) => {
+                response.set("success", true.into());
+                if !body.is_null() {
+                    response.set("body", body);
+                }
+            },
+            Err(message) => {
+                response.set("success", false.into());
+                response.set("message", message.into());
+            },
+        }
+
+        self.send(response);
+    }
+
+    /// Send every queued event
+    fn flush_events(&mut self) {
+        for event in std::mem::take(&mut self.events) {
+            self.send(event);
+        }
+    }
+
+    /// Write a message to stdout
+    fn send(&mut self, mut message: Json) {
+        message.set("seq", self.seq.into());
+        self.seq += 1;
+
+        write_message(&message);
+    }
+
+    /// DAP source object for the program
+    fn source(&self) -> Json {
+        let name = std::path::Path::new(&self.path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.path.clone());
+        Json::object(vec![("name", name.into()), ("path", self.path.as_str().into())])
+    }
+
+    /// Describe an instruction address as `label+offset`
+    fn describe_code(&self, address: u32) -> String {
+        match self.program.symbols.nearest(Section::Code, address) {
+            Some(symbol) if symbol.address == address => symbol.name.clone(),
+            Some(symbol) => format!("{}+{}", symbol.name, address - symbol.address),
+            None => format!("0x{:02X}", address),
+        }
+    }
+
+    /// Describe a data address as `LABEL (0xNN)`
+    fn describe_data(&self, address: u32) -> String {
+        match self.program.symbols.label_at(Section::Data, address) {
+            Some(label) => format!("{} (0x{:02X})", label, address),
+            None => format!("0x{:02X}", address),
+        }
+    }
+}
+
+/// Capabilities sent in the `initialize` response
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsConditionalBreakpoints", true.into()),
+        ("supportsEvaluateForHovers", true.into()),
+        ("supportsStepBack", true.into()),
+        ("supportsSetVariable", true.into()),
+        ("supportsReadMemoryRequest", true.into()),
+        ("supportsDataBreakpoints", true.into()),
+        ("supportsTerminateRequest", true.into()),
+    ])
+}
+
+/// The two variable scopes
+fn scopes() -> Json {
+    Json::object(vec![(
+        "scopes",
+        Json::Array(vec![
+            Json::object(vec![
+                ("name", "Registers".into()),
+                ("presentationHint", "registers".into()),
+                ("variablesReference", REGISTERS_REFERENCE.into()),
+                ("expensive", false.into()),
+            ]),
+            Json::object(vec![
+                ("name", "Data".into()),
+                ("variablesReference", DATA_REFERENCE.into()),
+                ("expensive", false.into()),
+            ]),
+        ]),
+    )])
+}
+
+/// A variable with no children
+fn variable(name: &str, value: String, memory_reference: Option<u32>) -> Json {
+    let mut variable = Json::object(vec![
+        ("name", name.into()),
+        ("value", value.into()),
+        ("variablesReference", 0u32.into()),
+    ]);
+    if let Some(address) = memory_reference {
+        variable.set("memoryReference", format!("0x{:X}", address).into());
+    }
+    variable
+}
+
+/// Format a byte as `unsigned (0xNN)`, adding the signed value when negative
+fn format_byte(value: u8) -> String {
+    if (value as i8) < 0 {
+        format!("{} / {} (0x{:02X})", value, value as i8, value)
+    } else {
+        format!("{} (0x{:02X})", value, value)
+    }
+}
+
+/// Check if two paths name the same file
+fn same_file(a: &str, b: &str) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Read one `Content-Length` framed message (shared with the language
+/// server, which uses the same framing)
+/// Returns None at the end of the stream
+pub fn read_message(reader: &mut impl BufRead) -> Option<Json> {
+    loop {
+        let mut length = None;
+
+        // Headers end with an empty line
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+
+        let length = match length {
+            Some(length) if length > MAX_MESSAGE_LENGTH => {
+                eprintln!("Ignoring message of {} bytes (at most {} are accepted)", length, MAX_MESSAGE_LENGTH);
+                std::io::copy(&mut std::io::Read::take(&mut *reader, length as u64), &mut std::io::sink()).ok()?;
+                continue;
+            },
+            Some(length) => length,
+            None => continue,
+        };
+
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).ok()?;
+
+        match Json::parse(&String::from_utf8_lossy(&body)) {
+            Ok(message) => return Some(message),
+            Err(e) => eprintln!("Ignoring invalid message: {}", e),
+        }
+    }
+}
+
+/// Write a `Content-Length` framed message to stdout
+pub fn write_message(message: &Json) {
+    let text = message.to_string();
+    let mut stdout = std::io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{}", text.len(), text).unwrap();
+    stdout.flush().unwrap();
+}
+
+/// Encode bytes as base64
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(n >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame a message body as a client would
+    fn framed(body: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn launch_reports_assembly_errors() {
+        let path = std::env::temp_dir().join(format!("vnc-dap-{}.vnc", std::process::id()));
+        std::fs::write(&path, ".code\n        FOO\n").unwrap();
+        let args = Json::object(vec![("program", path.display().to_string().as_str().into())]);
+        let result = DebugAdapter::new().launch(&args);
+        std::fs::remove_file(&path).unwrap();
+
+        let message = result.unwrap_err();
+        assert!(message.starts_with("Failed to assemble"), "{}", message);
+        assert!(message.contains("2:9: error: Unknown instruction 'FOO'"), "{}", message);
+    }
+
+    #[test]
+    fn read_memory_out_of_range() {
+        let adapter = DebugAdapter::new();
+        let read = |reference: &str, offset: i64, count: u64| {
+            let args = Json::object(vec![
+                ("memoryReference", reference.into()),
+                ("offset", offset.into()),
+                ("count", count.into()),
+            ]);
+            adapter.read_memory(&args)
+        };
+
+        assert!(read("0x10", 0, u64::MAX).is_err());
+        assert!(read("0x100", i64::MAX, 1).is_err());
+        let body = read("0", 0, 4).unwrap();
+        assert_eq!(body.get("unreadableBytes").and_then(Json::as_u64), Some(0));
+        let body = read("0xFE", 0, 4).unwrap();
+        assert_eq!(body.get("unreadableBytes").and_then(Json::as_u64), Some(2));
+    }
+
+    #[test]
+    fn read_message_limits_its_length() {
+        let text = format!(
+            "Content-Length: {}\r\n\r\n{}{}",
+            MAX_MESSAGE_LENGTH + 1,
+            " ".repeat(MAX_MESSAGE_LENGTH + 1),
+            framed("{\"seq\":1}")
+        );
+        let mut reader = std::io::Cursor::new(text.into_bytes());
+        let message = read_message(&mut reader).unwrap();
+        assert_eq!(message.get("seq").and_then(Json::as_u64), Some(1));
+        assert!(read_message(&mut reader).is_none());
+
+        // A length with no body behind it ends the stream
+        let mut reader = std::io::Cursor::new(b"Content-Length: 99999999999\r\n\r\n".to_vec());
+        assert!(read_message(&mut reader).is_none());
+    }
+
+    /// An adapter with source saved to a temporary file launched in it
+    fn launched(name: &str, source: &str) -> DebugAdapter {
+        let path = std::env::temp_dir().join(format!("vnc-dap-{}-{}.vnc", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let mut adapter = DebugAdapter::new();
+        adapter
+            .launch(&Json::object(vec![("program", path.display().to_string().as_str().into())]))
+            .unwrap();
+        adapter.events.clear();
+        adapter
+    }
+
+    /// Body of the last stopped event sent
+    fn last_stop(adapter: &DebugAdapter) -> Json {
+        adapter
+            .events
+            .iter()
+            .rev()
+            .find(|event| event.get("event").and_then(Json::as_str) == Some("stopped"))
+            .and_then(|event| event.get("body"))
+            .cloned()
+            .unwrap()
+    }
+
+    const COUNTDOWN: &str = ".data
+A: DAT 3
+ONE: DAT 1
+.code
+loop:   LDA A
+        SUB ONE
+        STA A
+        JNZ loop
+        HLT
+";
+
+    #[test]
+    fn breakpoints_are_set_by_line() {
+        let mut adapter = launched("lines", COUNTDOWN);
+        let lines = [2u32, 7, 20].iter().map(|line| Json::object(vec![("line", (*line).into())])).collect();
+        let args = Json::object(vec![
+            ("source", Json::object(vec![("path", adapter.path.as_str().into())])),
+            ("breakpoints", Json::Array(lines)),
+        ]);
+        let body = adapter.set_breakpoints(&args).unwrap();
+
+        // Lines without code move to the next line with some
+        let breakpoints = body.get("breakpoints").and_then(Json::as_array).unwrap();
+        let verified: Vec<_> = breakpoints.iter().map(|bp| bp.get("verified").and_then(Json::as_bool)).collect();
+        assert_eq!(verified, vec![Some(true), Some(true), Some(false)]);
+        assert_eq!(breakpoints[0].get("line").and_then(Json::as_u64), Some(5));
+        assert_eq!(breakpoints[1].get("line").and_then(Json::as_u64), Some(7));
+        let addresses: Vec<u32> = adapter.cpu.breakpoints.iter().map(|bp| bp.address).collect();
+        assert_eq!(addresses, vec![0, 4]);
+
+        // Breakpoints for other files are not verified
+        let args = Json::object(vec![
+            ("source", Json::object(vec![("path", "other.vnc".into())])),
+            ("breakpoints", Json::Array(vec![Json::object(vec![("line", 7u32.into())])])),
+        ]);
+        let body = adapter.set_breakpoints(&args).unwrap();
+        let breakpoints = body.get("breakpoints").and_then(Json::as_array).unwrap();
+        assert_eq!(breakpoints[0].get("verified").and_then(Json::as_bool), Some(false));
+        assert!(adapter.cpu.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn next_steps_a_line() {
+        let mut adapter = launched("next", COUNTDOWN);
+        adapter.next().unwrap();
+        assert_eq!(adapter.cpu.pc(), 2);
+        assert_eq!(last_stop(&adapter).get("reason").and_then(Json::as_str), Some("step"));
+
+        // Back to the top of the loop, then to the end
+        for _ in 0..3 {
+            adapter.next().unwrap();
+        }
+        assert_eq!(adapter.cpu.pc(), 0);
+        adapter.step_back().unwrap();
+        assert_eq!(adapter.cpu.pc(), 6);
+    }
+
+    #[test]
+    fn next_stops_at_breakpoints_and_gives_up() {
+        // LDA, SUB and JNZ are all on the line calling the macro
+        let source = ".data\nA: DAT 3\nONE: DAT 1\n.macro count\nLDA A\nSUB ONE\nJNZ 2\n.endm\n.code\ncount\nHLT\n";
+        let mut adapter = launched("breakpoint", source);
+        adapter.cpu.add_breakpoint(2, None);
+        adapter.breakpoint_ids.push((7, 2));
+        adapter.next().unwrap();
+        assert_eq!(adapter.cpu.pc(), 2);
+        let stop = last_stop(&adapter);
+        assert_eq!(stop.get("reason").and_then(Json::as_str), Some("breakpoint"));
+        assert_eq!(stop.get("hitBreakpointIds").and_then(Json::as_array).map(Vec::len), Some(1));
+
+        let mut adapter = launched("endless", ".code\nloop: JMP loop\n");
+        adapter.next().unwrap();
+        let text = last_stop(&adapter).get("text").and_then(Json::as_str).map(str::to_string).unwrap();
+        assert!(text.starts_with("Still on the same line"), "{}", text);
+    }
+
+    #[test]
+    fn variables_show_registers_and_data() {
+        let mut adapter = launched("variables", COUNTDOWN);
+        adapter.next().unwrap();
+        adapter.next().unwrap();
+
+        let names_and_values = |adapter: &DebugAdapter, reference: u32| -> Vec<(String, String)> {
+            let body = adapter.variables(&Json::object(vec![("variablesReference", reference.into())])).unwrap();
+            body.get("variables")
+                .and_then(Json::as_array)
+                .unwrap()
+                .iter()
+                .map(|variable| {
+                    let text = |key: &str| variable.get(key).and_then(Json::as_str).unwrap().to_string();
+                    (text("name"), text("value"))
+                })
+                .collect()
+        };
+
+        let registers = names_and_values(&adapter, REGISTERS_REFERENCE);
+        assert!(registers.contains(&("pc".to_string(), "0x04".to_string())), "{:?}", registers);
+        assert!(registers.contains(&("acc".to_string(), "2 (0x02)".to_string())), "{:?}", registers);
+        assert!(registers.contains(&("cycles".to_string(), "2".to_string())), "{:?}", registers);
+
+        let data = names_and_values(&adapter, DATA_REFERENCE);
+        assert_eq!(data, vec![("A".to_string(), "3 (0x03)".to_string()), ("ONE".to_string(), "1 (0x01)".to_string())]);
+
+        // Changing a value shows up in the next request
+        let args = Json::object(vec![
+            ("variablesReference", DATA_REFERENCE.into()),
+            ("name", "A".into()),
+            ("value", "255".into()),
+        ]);
+        adapter.set_variable(&args).unwrap();
+        assert_eq!(names_and_values(&adapter, DATA_REFERENCE)[0].1, "255 / -1 (0xFF)");
+    }
+}
@@ -0,0 +1,776 @@
+//! Interactive debugger
+//! Started with `vnc debug <file>`, assembles the file, loads it into a
+//! fresh CPU and reads commands from stdin.
+//!
+//! Commands:
+//! ```text
+//! step [N]               execute N instructions (default 1)
+//! next                   step to the next source line (or a breakpoint)
+//! continue               run until a breakpoint, watchpoint or HLT
+//! back [N]               undo N instructions (default 1)
+//! reverse-continue       run backwards until a breakpoint or watched write
+//! goto CYCLE             jump to a cycle, forwards or backwards
+//! history                show how far back execution can go
+//! break LOC [if COND]    add a breakpoint (LOC = label, address or :line)
+//! delete [LOC]           remove a breakpoint (or all of them)
+//! watch ADDR [r|w|rw]    add a watchpoint on a data address or label
+//! unwatch ADDR           remove a watchpoint
+//! info                   list breakpoints and watchpoints
+//! regs                   show the registers
+//! mem [START[..END]]     show data memory
+//! set acc|pc VALUE       change a register
+//! poke ADDR VALUE        change a data memory cell
+//! disasm [ADDR [COUNT]]  disassemble instruction memory
+//! list                   show the source around the current line
+//! backtrace              show the current frame
+//! reset                  reload the program
+//! save FILE              save the machine state to a snapshot file
+//! load FILE              restore the machine state from a snapshot file
+//! quit                   leave the debugger
+//! ```
+//! An empty line repeats the last command.
+
+use std::io::{BufRead, Write};
+
+use crate::assembler::{self, Program, Section};
+use crate::cpu::debug::{parse_address, Condition, Status, WatchKind};
+use crate::cpu::snapshot::Snapshot;
+use crate::cpu::registers::Register;
+use crate::cpu::CPU;
+
+/// Size of both memories
+const MEMORY_SIZE: u32 = 256;
+
+/// Undo records kept for stepping backwards
+const HISTORY_CAPACITY: usize = 10_000;
+
+/// Cycles between full checkpoints
+const CHECKPOINT_INTERVAL: u64 = 256;
+
+/// Most instructions `next` may execute
+const STEP_LIMIT: u32 = 100_000;
+
+/// Interactive debugger state
+pub struct Debugger {
+    pub cpu: CPU,
+    pub program: Program,
+    /// Path of the source file
+    path: String,
+    /// Lines of the source file
+    source: Vec<String>,
+    /// Last command entered (repeated on an empty line)
+    last_command: String,
+}
+
+impl Debugger {
+    /// Assemble a source file and load it into a new CPU, or return the
+    /// errors found assembling it
+    pub fn new(source_path: &str) -> Result<Debugger, String> {
+        let program = assembler::try_assemble_program(source_path)?;
+        let source = std::fs::read_to_string(source_path)
+            .map_err(|e| format!("{}: {}", source_path, e))?
+            .lines()
+            .map(|line| line.to_string())
+            .collect();
+
+        let mut cpu = CPU::new(MEMORY_SIZE, MEMORY_SIZE);
+        cpu.load_program(program.to_binary())?;
+        cpu.source_map = Some(program.source_map.clone());
+        cpu.enable_history(HISTORY_CAPACITY, CHECKPOINT_INTERVAL);
+
+        Ok(Debugger {
+            cpu,
+            program,
+            path: source_path.to_string(),
+            source,
+            last_command: String::new(),
+        })
+    }
+
+    /// Read and execute commands until `quit` or EOF
+    pub fn repl(&mut self) {
+        println!("Debugging {} ({} instructions). Type 'help' for commands.", self.path, self.program.code.len() / 2);
+        self.print_location();
+
+        let stdin = std::io::stdin();
+        loop {
+            print!("(vnc) ");
+            std::io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+
+            if !self.execute(line.trim()) {
+                break;
+            }
+        }
+    }
+
+    /// Execute a single command, returning false to quit
+    pub fn execute(&mut self, line: &str) -> bool {
+        let line = if line.is_empty() {
+            self.last_command.clone()
+        } else {
+            self.last_command = line.to_string();
+            line.to_string()
+        };
+
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let command = parts.next().unwrap_or("");
+        let args = parts.next().unwrap_or("").trim();
+
+        let result = match command {
+            "" => Ok(()),
+            "help" | "h" => {
+                self.help();
+                Ok(())
+            },
+            "quit" | "q" | "exit" => return false,
+            "step" | "s" | "stepi" | "si" => self.step(args),
+            "next" | "n" => self.next(),
+            "continue" | "c" | "run" | "r" => self.resume(),
+            "back" | "step-back" | "rs" => self.step_back(args),
+            "reverse-continue" | "rc" => {
+                let status = self.cpu.reverse_run();
+                self.report(status);
+                Ok(())
+            },
+            "goto" => self.goto(args),
+            "history" => {
+                self.print_history();
+                Ok(())
+            },
+            "break" | "b" => self.add_breakpoint(args),
+            "delete" | "d" => self.delete_breakpoint(args),
+            "watch" | "w" => self.add_watchpoint(args),
+            "unwatch" => self.delete_watchpoint(args),
+            "info" | "i" => {
+                self.info();
+                Ok(())
+            },
+            "regs" | "registers" => {
+                self.print_registers();
+                Ok(())
+            },
+            "mem" | "x" => self.print_memory(args),
+            "set" => self.set_register(args),
+            "poke" => self.poke(args),
+            "disasm" | "disassemble" => self.disassemble(args),
+            "list" | "l" => {
+                self.list();
+                Ok(())
+            },
+            "backtrace" | "bt" | "where" => {
+                self.backtrace();
+                Ok(())
+            },
+            "reset" => {
+                self.reset();
+                Ok(())
+            },
+            "cache" => self.cache(args),
+            "save" => self.save_snapshot(args),
+            "load" => self.load_snapshot(args),
+            _ => Err(format!("Unknown command '{}'. Type 'help' for commands.", command)),
+        };
+
+        if let Err(e) = result {
+            println!("error: {}", e);
+        }
+
+        true
+    }
+
+    /// Resolve a label, number or `:line` to an instruction address
+    pub fn resolve_code_location(&self, text: &str) -> Result<u32, String> {
+        // FILE:LINE or :LINE
+        if let Some((_, line)) = text.rsplit_once(':') {
+            let line = line.trim().parse::<usize>().map_err(|_| format!("Invalid line number: {}", line))?;
+            return self
+                .program
+                .address_of_line(line)
+                .ok_or_else(|| format!("No code on line {}", line));
+        }
+
+        let address = parse_address(text, &|name| self.program.symbols.address_of(name))?;
+        if address >= self.cpu.instruction_memory.size {
+            return Err(format!("Address 0x{:02X} is out of range", address));
+        }
+        Ok(address)
+    }
+
+    /// Resolve a label or number to a data address
+    fn resolve_data_address(&self, text: &str) -> Result<u32, String> {
+        let address = parse_address(text, &|name| self.program.symbols.address_of(name))?;
+        if address >= self.cpu.data_memory.size {
+            return Err(format!("Address 0x{:02X} is out of range", address));
+        }
+        Ok(address)
+    }
+
+    /// Parse a value that must fit in a byte
+    fn parse_value(&self, text: &str) -> Result<u8, String> {
+        let value = parse_address(text, &|name| self.program.symbols.address_of(name))?;
+        u8::try_from(value).map_err(|_| format!("Value {} does not fit in a byte", value))
+    }
+
+    fn help(&self) {
+        println!("step [N]               execute N instructions (default 1)");
+        println!("next                   step to the next source line (or a breakpoint)");
+        println!("continue               run until a breakpoint, watchpoint or HLT");
+        println!("back [N]               undo N instructions (default 1)");
+        println!("reverse-continue       run backwards until a breakpoint or watched write");
+        println!("goto CYCLE             jump to a cycle, forwards or backwards");
+        println!("history                show how far back execution can go");
+        println!("break LOC [if COND]    add a breakpoint (LOC = label, address or :line)");
+        println!("delete [LOC]           remove a breakpoint (or all of them)");
+        println!("watch ADDR [r|w|rw]    add a watchpoint on a data address or label");
+        println!("unwatch ADDR           remove a watchpoint");
+        println!("info                   list breakpoints and watchpoints");
+        println!("regs                   show the registers");
+        println!("mem [START[..END]]     show data memory");
+        println!("set acc|pc VALUE       change a register");
+        println!("poke ADDR VALUE        change a data memory cell");
+        println!("disasm [ADDR [COUNT]]  disassemble instruction memory");
+        println!("list                   show the source around the current line");
+        println!("backtrace              show the current frame");
+        println!("reset                  reload the program");
+        println!("cache [CONFIG|off]     show cache stats, or attach (e.g. 4,32,2-way) or remove a cache");
+        println!("save FILE              save the machine state to a snapshot file");
+        println!("load FILE              restore the machine state from a snapshot file");
+        println!("quit                   leave the debugger");
+    }
+
+    fn step(&mut self, args: &str) -> Result<(), String> {
+        let count = if args.is_empty() {
+            1
+        } else {
+            args.parse::<u32>().map_err(|_| format!("Invalid step count: {}", args))?
+        };
+
+        for _ in 0..count {
+            let status = self.cpu.step();
+            if status != Status::Running {
+                self.report(status);
+                return Ok(());
+            }
+        }
+
+        self.print_location();
+        Ok(())
+    }
+
+    /// Step until the source line changes, stopping at a breakpoint or
+    /// after `STEP_LIMIT` instructions on one line
+    /// (there is no CALL instruction, so this never steps over anything)
+    fn next(&mut self) -> Result<(), String> {
+        let line = self.program.line_of(self.cpu.pc() as u32);
+
+        for count in 0..STEP_LIMIT {
+            // The first instruction runs even if it has a breakpoint
+            let status = match count {
+                0 => self.cpu.step(),
+                _ => self.cpu.run_for(1),
+            };
+            if status != Status::Running {
+                self.report(status);
+                return Ok(());
+            }
+
+            if self.program.line_of(self.cpu.pc() as u32) != line {
+                self.print_location();
+                return Ok(());
+            }
+        }
+
+        println!("Still on the same line after {} instructions", STEP_LIMIT);
+        self.print_location();
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), String> {
+        let status = self.cpu.run();
+        self.report(status);
+        Ok(())
+    }
+
+    fn step_back(&mut self, args: &str) -> Result<(), String> {
+        let count = if args.is_empty() {
+            1
+        } else {
+            args.parse::<u32>().map_err(|_| format!("Invalid step count: {}", args))?
+        };
+
+        for _ in 0..count {
+            if !self.cpu.step_back() {
+                println!("Reached the start of the recorded history");
+                break;
+            }
+        }
+
+        self.print_location();
+        Ok(())
+    }
+
+    fn goto(&mut self, args: &str) -> Result<(), String> {
+        let cycle = args.parse::<u64>().map_err(|_| format!("Invalid cycle: {}", args))?;
+        let status = self.cpu.goto_cycle(cycle)?;
+
+        if self.cpu.cycles() != cycle {
+            println!("Stopped at cycle {}", self.cpu.cycles());
+        }
+        match status {
+            Status::Running | Status::Stopped(_) => self.print_location(),
+            status => self.report(status),
+        }
+        Ok(())
+    }
+
+    fn print_history(&self) {
+        match self.cpu.history.as_ref() {
+            Some(history) => {
+                println!("cycle: {}", self.cpu.cycles());
+                println!("undo records: {}", history.len());
+                println!("checkpoints: {}", history.checkpoints());
+                if let Some(cycle) = history.earliest_cycle() {
+                    println!("earliest reachable cycle: {}", cycle);
+                }
+            },
+            None => println!("History is not enabled"),
+        }
+    }
+
+    fn add_breakpoint(&mut self, args: &str) -> Result<(), String> {
+        if args.is_empty() {
+            return Err("Usage: break LOC [if COND]".to_string());
+        }
+
+        let (location, condition) = match args.split_once(" if ") {
+            Some((location, condition)) => (location.trim(), Some(condition.trim())),
+            None => (args, None),
+        };
+
+        let address = self.resolve_code_location(location)?;
+        let condition = match condition {
+            Some(text) => Some(Condition::parse(text, &|name| self.program.symbols.address_of(name))?),
+            None => None,
+        };
+
+        match &condition {
+            Some(condition) => println!("Breakpoint at {} if {}", self.describe_code(address), condition),
+            None => println!("Breakpoint at {}", self.describe_code(address)),
+        }
+        self.cpu.add_breakpoint(address, condition);
+        Ok(())
+    }
+
+    fn delete_breakpoint(&mut self, args: &str) -> Result<(), String> {
+        if args.is_empty() {
+            println!("Deleted {} breakpoint(s)", self.cpu.breakpoints.len());
+            self.cpu.breakpoints.clear();
+            return Ok(());
+        }
+
+        let address = self.resolve_code_location(args)?;
+        if !self.cpu.remove_breakpoint(address) {
+            return Err(format!("No breakpoint at {}", self.describe_code(address)));
+        }
+        println!("Deleted breakpoint at {}", self.describe_code(address));
+        Ok(())
+    }
+
+    fn add_watchpoint(&mut self, args: &str) -> Result<(), String> {
+        let mut parts = args.split_whitespace();
+        let address = match parts.next() {
+            Some(text) => self.resolve_data_address(text)?,
+            None => return Err("Usage: watch ADDR [r|w|rw]".to_string()),
+        };
+
+        let kind = match parts.next() {
+            None | Some("w") => WatchKind::Write,
+            Some("r") => WatchKind::Read,
+            Some("rw") => WatchKind::ReadWrite,
+            Some(other) => return Err(format!("Invalid watch kind: {}", other)),
+        };
+
+        println!("Watchpoint ({:?}) on {}", kind, self.describe_data(address));
+        self.cpu.add_watchpoint(address, kind);
+        Ok(())
+    }
+
+    fn delete_watchpoint(&mut self, args: &str) -> Result<(), String> {
+        let address = self.resolve_data_address(args)?;
+        if !self.cpu.remove_watchpoint(address) {
+            return Err(format!("No watchpoint on {}", self.describe_data(address)));
+        }
+        println!("Deleted watchpoint on {}", self.describe_data(address));
+        Ok(())
+    }
+
+    fn info(&self) {
+        if self.cpu.breakpoints.is_empty() && self.cpu.watchpoints.is_empty() {
+            println!("No breakpoints or watchpoints");
+            return;
+        }
+
+        for bp in &self.cpu.breakpoints {
+            let condition = match &bp.condition {
+                Some(condition) => format!(" if {}", condition),
+                None => String::new(),
+            };
+            println!("break {}{} (hit {} times)", self.describe_code(bp.address), condition, bp.hits);
+        }
+
+        for wp in &self.cpu.watchpoints {
+            println!("watch {:?} {} (hit {} times)", wp.kind, self.describe_data(wp.address), wp.hits);
+        }
+    }
+
+    fn print_registers(&self) {
+        let acc = self.cpu.acc.get();
+        println!("pc   0x{:02X}", self.cpu.pc());
+        println!("acc  0x{:02X} ({} / {})", acc, acc, acc as i8);
+        println!("mdr  0x{:04X}", self.cpu.mdr());
+        match self.cpu.cir() {
+            Some(instruction) => println!("cir  {}", instruction),
+            None => println!("cir  -"),
+        }
+        println!("cycle: {}", self.cpu.cycles());
+        println!("halted: {}", self.cpu.is_halted());
+    }
+
+    fn print_memory(&self, args: &str) -> Result<(), String> {
+        let size = self.cpu.data_memory.size;
+
+        let (start, end) = if args.is_empty() {
+            // Everything the program defines, at least one row
+            (0, (self.program.data.len() as u32).max(8).min(size))
+        } else if let Some((start, end)) = args.split_once("..") {
+            let start = self.resolve_data_address(start)?;
+            let end = parse_address(end, &|name| self.program.symbols.address_of(name))?;
+            (start, end.min(size))
+        } else {
+            let mut parts = args.split_whitespace();
+            let start = self.resolve_data_address(parts.next().unwrap())?;
+            let count = match parts.next() {
+                Some(count) => count.parse::<u32>().map_err(|_| format!("Invalid count: {}", count))?,
+                None => 1,
+            };
+            (start, start.saturating_add(count).min(size))
+        };
+
+        for address in start..end {
+            let value = self.cpu.peek_data(address);
+            let label = self.program.symbols.label_at(Section::Data, address).unwrap_or("");
+            println!("0x{:02X}  {:<10} 0x{:02X}  {}", address, label, value, value);
+        }
+        Ok(())
+    }
+
+    fn set_register(&mut self, args: &str) -> Result<(), String> {
+        let mut parts = args.split_whitespace();
+        let (register, value) = match (parts.next(), parts.next()) {
+            (Some(register), Some(value)) => (register, value),
+            _ => return Err("Usage: set acc|pc VALUE".to_string()),
+        };
+
+        match register.to_lowercase().as_str() {
+            "acc" => {
+                let value = self.parse_value(value)?;
+                self.cpu.acc.set(value);
+            },
+            "pc" => {
+                let address = self.resolve_code_location(value)?;
+                let address = u8::try_from(address).map_err(|_| format!("Address {} is out of range", address))?;
+                self.cpu.set_pc(address);
+            },
+            other => return Err(format!("Unknown register: {}", other)),
+        }
+        // What was recorded after this point no longer follows from it
+        self.cpu.truncate_history();
+
+        self.print_registers();
+        Ok(())
+    }
+
+    fn poke(&mut self, args: &str) -> Result<(), String> {
+        let mut parts = args.split_whitespace();
+        let (address, value) = match (parts.next(), parts.next()) {
+            (Some(address), Some(value)) => (self.resolve_data_address(address)?, self.parse_value(value)?),
+            _ => return Err("Usage: poke ADDR VALUE".to_string()),
+        };
+
+        // Keep any cached copy consistent with memory
+        if let Some(cache) = self.cpu.cache.as_mut() {
+            cache.flush(&mut self.cpu.data_memory);
+            cache.invalidate();
+        }
+        self.cpu.data_memory.write(address, value);
+        self.cpu.truncate_history();
+        println!("{} = {}", self.describe_data(address), value);
+        Ok(())
+    }
+
+    fn disassemble(&self, args: &str) -> Result<(), String> {
+        let mut parts = args.split_whitespace();
+        let start = match parts.next() {
+            Some(location) => self.resolve_code_location(location)?,
+            None => self.cpu.pc().saturating_sub(4) as u32,
+        };
+        let count = match parts.next() {
+            Some(count) => count.parse::<u32>().map_err(|_| format!("Invalid count: {}", count))?,
+            None => 8,
+        };
+
+        // Only whole instructions
+        let end = self.cpu.instruction_memory.size.saturating_sub(1);
+        for address in (start..end).step_by(2).take(count as usize) {
+            let marker = if address == self.cpu.pc() as u32 { "=>" } else { "  " };
+            let breakpoint = if self.cpu.breakpoints.iter().any(|bp| bp.address == address) { "*" } else { " " };
+            println!("{}{} {}", marker, breakpoint, self.format_instruction(address));
+        }
+        Ok(())
+    }
+
+    fn list(&self) {
+        let line = match self.program.line_of(self.cpu.pc() as u32) {
+            Some(line) => line,
+            None => {
+                println!("No source for address 0x{:02X}", self.cpu.pc());
+                return;
+            },
+        };
+
+        let first = line.saturating_sub(5).max(1);
+        let last = (line + 5).min(self.source.len());
+        for number in first..=last {
+            let marker = if number == line { "=>" } else { "  " };
+            println!("{} {:>4} {}", marker, number, self.source[number - 1]);
+        }
+    }
+
+    fn backtrace(&self) {
+        // Without CALL there is only ever one frame
+        println!("#0 {}", self.format_instruction(self.cpu.pc() as u32));
+    }
+
+    /// Show the cache and its stats, attach a new one or remove it
+    fn cache(&mut self, args: &str) -> Result<(), String> {
+        match args {
+            "" => match self.cpu.cache.as_ref() {
+                Some(cache) => {
+                    println!("cache {}", cache.config());
+                    println!("{}", cache.stats());
+                },
+                None => println!("No cache"),
+            },
+            "off" => match self.cpu.detach_cache() {
+                Some(cache) => println!("Cache removed ({})", cache.stats()),
+                None => println!("No cache"),
+            },
+            _ => {
+                self.cpu.attach_cache(args.parse()?)?;
+                println!("Cache attached: {}", args);
+            },
+        }
+        Ok(())
+    }
+
+    fn save_snapshot(&self, args: &str) -> Result<(), String> {
+        if args.is_empty() {
+            return Err("Usage: save FILE".to_string());
+        }
+
+        self.cpu.snapshot().save(args).map_err(|e| format!("{}: {}", args, e))?;
+        println!("Saved machine state at cycle {} to {}", self.cpu.cycles(), args);
+        Ok(())
+    }
+
+    fn load_snapshot(&mut self, args: &str) -> Result<(), String> {
+        if args.is_empty() {
+            return Err("Usage: load FILE".to_string());
+        }
+
+        let snapshot = Snapshot::load(args)?;
+        self.cpu.restore(&snapshot)?;
+        println!("Restored machine state at cycle {} from {}", self.cpu.cycles(), args);
+        self.print_location();
+        Ok(())
+    }
+
+    /// Reload the program, keeping breakpoints and watchpoints
+    fn reset(&mut self) {
+        let mut cpu = CPU::new(MEMORY_SIZE, MEMORY_SIZE);
+        // The program was loaded once already when the debugger started
+        cpu.load_program(self.program.to_binary()).unwrap();
+        cpu.source_map = Some(self.program.source_map.clone());
+        cpu.enable_history(HISTORY_CAPACITY, CHECKPOINT_INTERVAL);
+        cpu.breakpoints = std::mem::take(&mut self.cpu.breakpoints);
+        cpu.watchpoints = std::mem::take(&mut self.cpu.watchpoints);
+        if let Some(cache) = self.cpu.cache.as_ref() {
+            // The configuration was valid when the cache was attached
+            cpu.attach_cache(*cache.config()).unwrap();
+        }
+        self.cpu = cpu;
+
+        println!("Program reset");
+        self.print_location();
+    }
+
+    /// Print why the CPU stopped and where
+    fn report(&self, status: Status) {
+        match status {
+            Status::Running => {},
+            Status::Halted => println!("Program halted (acc = {})", self.cpu.acc.get()),
+            Status::Stopped(reason) => println!("Stopped: {}", reason),
+            Status::Faulted(fault) => {
+                println!("Fault: {}", self.cpu.describe_fault(&fault));
+                println!("  at {}", self.format_instruction(fault.address()));
+                return;
+            },
+        }
+
+        if !self.cpu.is_halted() {
+            self.print_location();
+        }
+    }
+
+    /// Print the next instruction to execute with its source line
+    fn print_location(&self) {
+        println!("=> {}", self.format_instruction(self.cpu.pc() as u32));
+    }
+
+    /// Format an instruction address with its label, decoded instruction
+    /// and source line
+    fn format_instruction(&self, address: u32) -> String {
+        let instruction = match self.cpu.instruction_at(address) {
+            Some(instruction) => instruction.to_string(),
+            None => "???".to_string(),
+        };
+
+        let source = match self.program.line_of(address) {
+            Some(line) => format!(
+                "{}:{}: {}",
+                self.path,
+                line,
+                self.source.get(line - 1).map(|s| s.trim()).unwrap_or("")
+            ),
+            None => String::new(),
+        };
+
+        format!("{:<16} {:<10} {}", self.describe_code(address), instruction, source)
+    }
+
+    /// Describe an instruction address as `0xNN <label+offset>`
+    fn describe_code(&self, address: u32) -> String {
+        match self.program.symbols.nearest(Section::Code, address) {
+            Some(symbol) if symbol.address == address => format!("0x{:02X} <{}>", address, symbol.name),
+            Some(symbol) => format!("0x{:02X} <{}+{}>", address, symbol.name, address - symbol.address),
+            None => format!("0x{:02X}", address),
+        }
+    }
+
+    /// Describe a data address as `0xNN (LABEL)`
+    fn describe_data(&self, address: u32) -> String {
+        match self.program.symbols.label_at(Section::Data, address) {
+            Some(label) => format!("0x{:02X} ({})", address, label),
+            None => format!("0x{:02X}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::io::Io;
+
+    /// A debugger for source saved to a temporary file
+    fn debugger(name: &str, source: &str) -> Debugger {
+        let path = std::env::temp_dir().join(format!("vnc-debugger-{}-{}.vnc", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        Debugger::new(&path.display().to_string()).unwrap()
+    }
+
+    #[test]
+    fn next_gives_up_on_an_endless_line() {
+        let mut debugger = debugger("endless", ".code\nloop: JMP loop\n");
+        debugger.next().unwrap();
+        assert_eq!(debugger.cpu.pc(), 0);
+    }
+
+    #[test]
+    fn next_stops_at_a_breakpoint() {
+        // LDA, SUB and JNZ are all on the line calling the macro
+        let source = ".data\nA: DAT 3\nONE: DAT 1\n.macro count\nLDA A\nSUB ONE\nJNZ 2\n.endm\n.code\ncount\nHLT\n";
+        let mut debugger = debugger("breakpoint", source);
+        debugger.next().unwrap();
+        assert_eq!(debugger.cpu.pc(), 6);
+
+        debugger.reset();
+        debugger.cpu.add_breakpoint(2, None);
+        debugger.next().unwrap();
+        assert_eq!(debugger.cpu.pc(), 2);
+    }
+
+    #[test]
+    fn syntax_errors_are_returned() {
+        let path = std::env::temp_dir().join(format!("vnc-debugger-errors-{}.vnc", std::process::id()));
+        std::fs::write(&path, ".code\n    FOO\n").unwrap();
+        let errors = Debugger::new(&path.display().to_string()).err().unwrap();
+        assert!(errors.contains(":2:5: error: Unknown instruction 'FOO'"), "{}", errors);
+    }
+
+    #[test]
+    fn cache_command() {
+        let mut debugger = debugger("cache", ".data\nA: DAT 3\n.code\nLDA A\nADD A\nSTA A\nHLT\n");
+        assert!(debugger.cache("3,16").is_err());
+        assert!(debugger.cpu.cache.is_none());
+
+        debugger.cache("4,16").unwrap();
+        debugger.resume().unwrap();
+        assert_eq!(debugger.cpu.cache.as_ref().unwrap().stats().hits, 2);
+
+        // Kept over a reset, and memory is up to date once it is removed
+        debugger.reset();
+        assert_eq!(debugger.cpu.cache.as_ref().unwrap().stats().reads, 0);
+        debugger.resume().unwrap();
+        debugger.cache("off").unwrap();
+        assert!(debugger.cpu.cache.is_none());
+        assert_eq!(debugger.cpu.data_memory.read(0), 6);
+    }
+
+    #[test]
+    fn addresses_and_counts_do_not_overflow() {
+        let mut debugger = debugger("overflow", ".data\nA: DAT 1\n.code\nHLT\n");
+        assert!(debugger.resolve_code_location("0xFFFFFFFF").unwrap_err().contains("out of range"));
+        assert!(debugger.resolve_code_location("256").is_err());
+        assert_eq!(debugger.resolve_code_location("254"), Ok(254));
+        assert!(debugger.disassemble("0xFFFFFFFF").is_err());
+        assert!(debugger.disassemble("0 4294967295").is_ok());
+        assert!(debugger.print_memory("1 4294967295").is_ok());
+        assert!(debugger.execute("mem 0xFF 4294967295"));
+        assert_eq!(debugger.cpu.instruction_at(u32::MAX), None);
+        assert_eq!(debugger.cpu.instruction_at(255), None);
+    }
+
+    #[test]
+    fn changes_by_hand_drop_the_future() {
+        let mut debugger = debugger("poke", ".data\nA: DAT 0\n.code\nINP\nOUT\nHLT\n");
+        debugger.cpu.io = Io::scripted(&[7, 9]);
+        assert!(debugger.execute("step 2"));
+        assert!(debugger.execute("back 2"));
+        assert_eq!(debugger.cpu.io.input, vec![9]);
+
+        // The INP that ran is undone for good, so 7 is read again
+        assert!(debugger.execute("poke A 1"));
+        assert_eq!(debugger.cpu.io.input, vec![7, 9]);
+        assert!(debugger.execute("step 2"));
+        assert!(debugger.execute("back 2"));
+        assert!(debugger.execute("set acc 3"));
+        assert_eq!(debugger.cpu.io.input, vec![7, 9]);
+        assert!(debugger.execute("continue"));
+        assert_eq!(debugger.cpu.io.output, vec![7]);
+    }
+}
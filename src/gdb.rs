@@ -0,0 +1,583 @@
+//! GDB remote serial protocol (RSP) server
+//! Started with `vnc gdb <file> [port]`, listens on 127.0.0.1 and serves
+//! a single debugger connection, e.g.
+//! ```text
+//! (gdb) target remote :1234
+//! ```
+//!
+//! Registers (in `g`/`G` order, little endian):
+//! ```text
+//! pc  8 bits
+//! acc 8 bits
+//! mdr 16 bits
+//! cir 16 bits (0 when empty)
+//! ```
+//!
+//! The two memories are mapped into one address space:
+//! ```text
+//! 0x00000000  instruction memory
+//! 0x00010000  data memory
+//! ```
+//!
+//! Supported packets: `?`, `g`, `G`, `p`, `P`, `m`, `M`, `s`, `c`, `Z0`/`z0`
+//! (breakpoints), `Z2`-`Z4`/`z2`-`z4` (watchpoints on data memory), `k`,
+//! `D`, `qSupported`, `qXfer:features:read` (target description),
+//! `QStartNoAckMode` and the thread queries GDB needs to attach. A `0x03`
+//! byte interrupts a `c`.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::debug::{Access, Fault, Status, StopReason, WatchKind};
+use crate::cpu::registers::Register;
+use crate::cpu::CPU;
+
+/// Start of data memory in the GDB address space
+pub const DATA_BASE: u32 = 0x0001_0000;
+
+/// Instructions executed between checks for an interrupt during `c`
+const INTERRUPT_CHECK_INTERVAL: u64 = 1000;
+
+/// Target description sent to GDB
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.virtual-nanocomputer.core">
+    <reg name="pc" bitsize="8" type="code_ptr" regnum="0"/>
+    <reg name="acc" bitsize="8" type="int8" regnum="1"/>
+    <reg name="mdr" bitsize="16" type="uint16" regnum="2"/>
+    <reg name="cir" bitsize="16" type="uint16" regnum="3"/>
+  </feature>
+</target>
+"#;
+
+/// Signals reported to GDB
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// What the server should do after answering a packet
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Keep serving
+    Continue,
+    /// Close the connection
+    Disconnect,
+}
+
+/// Protocol state for one CPU
+pub struct GdbStub {
+    pub cpu: CPU,
+    /// Whether packets are acknowledged with `+`
+    ack_mode: bool,
+}
+
+impl GdbStub {
+    /// Create a stub for a CPU with a program loaded
+    pub fn new(cpu: CPU) -> GdbStub {
+        GdbStub { cpu, ack_mode: true }
+    }
+
+    /// Listen on a local port and serve one connection
+    pub fn listen(&mut self, port: u16) -> std::io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB on 127.0.0.1:{}", port);
+
+        let (stream, address) = listener.accept()?;
+        println!("GDB connected from {}", address);
+        self.serve(stream)
+    }
+
+    /// Serve packets on a connection until GDB detaches or disconnects
+    pub fn serve(&mut self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(true)?;
+
+        while let Some(packet) = read_packet(&mut stream)? {
+            let packet = match packet {
+                // Interrupt while already stopped
+                Incoming::Interrupt => continue,
+                Incoming::Packet(packet) => packet,
+            };
+
+            if self.ack_mode {
+                stream.write_all(b"+")?;
+            }
+
+            let (reply, action) = match packet.as_str() {
+                "c" => (self.resume(&mut stream)?, Action::Continue),
+                _ => self.handle(&packet),
+            };
+
+            if let Some(reply) = reply {
+                write_packet(&mut stream, &reply)?;
+                if self.ack_mode {
+                    // Wait for the acknowledgement
+                    let mut ack = [0u8; 1];
+                    stream.read_exact(&mut ack)?;
+                }
+            }
+
+            if packet == "QStartNoAckMode" {
+                self.ack_mode = false;
+            }
+
+            if action == Action::Disconnect {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer a packet (everything except `c`, which needs the connection
+    /// to listen for interrupts)
+    fn handle(&mut self, packet: &str) -> (Option<String>, Action) {
+        let reply = match packet.as_bytes().first() {
+            None => String::new(),
+            Some(b'?') => self.stop_reply(&self.last_status()),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b's') => {
+                let status = self.cpu.step();
+                self.stop_reply(&status)
+            },
+            Some(b'Z') => self.set_point(&packet[1..], true),
+            Some(b'z') => self.set_point(&packet[1..], false),
+            Some(b'H') => "OK".to_string(),
+            Some(b'T') => "OK".to_string(),
+            Some(b'k') => return (None, Action::Disconnect),
+            Some(b'D') => return (Some("OK".to_string()), Action::Disconnect),
+            Some(b'q') | Some(b'Q') => self.query(packet),
+            _ => String::new(),
+        };
+
+        (Some(reply), Action::Continue)
+    }
+
+    /// Run until a stop, checking the connection for an interrupt
+    fn resume(&mut self, stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+        loop {
+            // Run in bursts so an interrupt can get through
+            let status = self.cpu.run_for(INTERRUPT_CHECK_INTERVAL);
+            if status != Status::Running {
+                return Ok(Some(self.stop_reply(&status)));
+            }
+
+            if interrupted(stream)? {
+                return Ok(Some(format!("S{:02x}", SIGINT)));
+            }
+        }
+    }
+
+    /// Status to report for `?`
+    fn last_status(&self) -> Status {
+        if self.cpu.is_halted() {
+            Status::Halted
+        } else {
+            Status::Running
+        }
+    }
+
+    /// Build a stop reply packet for a status
+    fn stop_reply(&self, status: &Status) -> String {
+        match status {
+            // A completed step is a trap
+            Status::Running => format!("S{:02x}", SIGTRAP),
+            // Exit code is the accumulator
+            Status::Halted => format!("W{:02x}", self.cpu.acc.get()),
+            Status::Stopped(StopReason::Breakpoint(_)) => format!("T{:02x}swbreak:;", SIGTRAP),
+            Status::Stopped(StopReason::Watchpoint { address, access, .. }) => {
+                let kind = match access {
+                    Access::Read => "rwatch",
+                    Access::Write => "watch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, DATA_BASE + address)
+            },
+            Status::Stopped(StopReason::StartOfHistory) => format!("S{:02x}", SIGTRAP),
+            Status::Faulted(fault) => {
+                let signal = match fault {
+                    Fault::InvalidOpcode { .. } => SIGILL,
+                    Fault::DivideByZero { .. } => SIGFPE,
+                    Fault::InstructionOutOfRange { .. } | Fault::DataOutOfRange { .. } => SIGSEGV,
+                    Fault::InputExhausted { .. } => SIGABRT,
+                };
+                format!("S{:02x}", signal)
+            },
+        }
+    }
+
+    /// Registers as little endian byte strings, in `g` order
+    fn register_bytes(&self) -> [Vec<u8>; 4] {
+        let cir = match self.cpu.cir() {
+            Some(instruction) => {
+                let bin = instruction.to_bin();
+                u16::from_be_bytes([bin[0], bin[1]])
+            },
+            None => 0,
+        };
+
+        [
+            vec![self.cpu.pc()],
+            vec![self.cpu.acc.get()],
+            self.cpu.mdr().to_le_bytes().to_vec(),
+            cir.to_le_bytes().to_vec(),
+        ]
+    }
+
+    fn read_registers(&self) -> String {
+        self.register_bytes().iter().map(|bytes| to_hex(bytes)).collect()
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        let bytes = match from_hex(hex) {
+            Some(bytes) if bytes.len() >= 2 => bytes,
+            _ => return "E01".to_string(),
+        };
+
+        // Only pc and acc are writable, mdr and cir are pipeline state
+        self.cpu.set_pc(bytes[0]);
+        self.cpu.acc.set(bytes[1]);
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match usize::from_str_radix(args, 16) {
+            Ok(n) if n < 4 => to_hex(&self.register_bytes()[n]),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let (n, value) = match args.split_once('=') {
+            Some((n, value)) => (usize::from_str_radix(n, 16).ok(), from_hex(value)),
+            None => return "E01".to_string(),
+        };
+
+        match (n, value) {
+            (Some(0), Some(bytes)) if !bytes.is_empty() => self.cpu.set_pc(bytes[0]),
+            (Some(1), Some(bytes)) if !bytes.is_empty() => self.cpu.acc.set(bytes[0]),
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    /// Map a GDB address to (is data memory, address in that memory)
+    fn map_address(&self, address: u32) -> Option<(bool, u32)> {
+        if address >= DATA_BASE {
+            let address = address - DATA_BASE;
+            (address < self.cpu.data_memory.size).then_some((true, address))
+        } else {
+            (address < self.cpu.instruction_memory.size).then_some((false, address))
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (address, length) = match parse_address_length(args) {
+            Some(parsed) => parsed,
+            None => return "E01".to_string(),
+        };
+
+        let mut bytes = Vec::new();
+        for address in address..address.saturating_add(length) {
+            match self.map_address(address) {
+                Some((true, address)) => bytes.push(self.cpu.peek_data(address)),
+                Some((false, address)) => bytes.push(self.cpu.instruction_memory.read(address)),
+                // Partial reads are allowed, nothing readable is an error
+                None => break,
+            }
+        }
+
+        if bytes.is_empty() && length > 0 {
+            return "E14".to_string();
+        }
+        to_hex(&bytes)
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let (range, data) = match args.split_once(':') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let (address, length, bytes) = match (parse_address_length(range), from_hex(data)) {
+            (Some((address, length)), Some(bytes)) if bytes.len() == length as usize => (address, length, bytes),
+            _ => return "E01".to_string(),
+        };
+
+        // Check the whole range before writing anything
+        let end = match address.checked_add(length) {
+            Some(end) => end,
+            None => return "E14".to_string(),
+        };
+        if (address..end).any(|address| self.map_address(address).is_none()) {
+            return "E14".to_string();
+        }
+
+        // Keep the cache consistent with memory
+        if let Some(cache) = self.cpu.cache.as_mut() {
+            cache.flush(&mut self.cpu.data_memory);
+            cache.invalidate();
+        }
+
+        for (i, byte) in bytes.iter().enumerate() {
+            match self.map_address(address + i as u32).unwrap() {
+                (true, address) => self.cpu.data_memory.write(address, *byte),
+                (false, address) => self.cpu.instruction_memory.write(address, *byte),
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// Handle `Z`/`z` (insert/remove breakpoints and watchpoints)
+    fn set_point(&mut self, args: &str, insert: bool) -> String {
+        let mut parts = args.split(',');
+        let (kind, address) = match (parts.next(), parts.next().and_then(|a| u32::from_str_radix(a, 16).ok())) {
+            (Some(kind), Some(address)) => (kind, address),
+            _ => return "E01".to_string(),
+        };
+
+        match kind {
+            // Software and hardware breakpoints behave the same
+            "0" | "1" => {
+                if address >= DATA_BASE {
+                    return "E01".to_string();
+                }
+                if insert {
+                    self.cpu.add_breakpoint(address, None);
+                } else {
+                    self.cpu.remove_breakpoint(address);
+                }
+            },
+            "2" | "3" | "4" => {
+                let address = match self.map_address(address) {
+                    Some((true, address)) => address,
+                    _ => return "E01".to_string(),
+                };
+                let watch = match kind {
+                    "2" => WatchKind::Write,
+                    "3" => WatchKind::Read,
+                    _ => WatchKind::ReadWrite,
+                };
+                if insert {
+                    self.cpu.add_watchpoint(address, watch);
+                } else {
+                    self.cpu.remove_watchpoint(address);
+                }
+            },
+            _ => return String::new(),
+        }
+
+        "OK".to_string()
+    }
+
+    /// Handle `q`/`Q` general queries
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=1000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_string();
+        }
+
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_address_length(args) {
+                Some((offset, length)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + length as usize).min(TARGET_XML.len());
+                    let prefix = if end == TARGET_XML.len() { "l" } else { "m" };
+                    format!("{}{}", prefix, &TARGET_XML[offset..end])
+                },
+                None => "E01".to_string(),
+            };
+        }
+
+        match packet {
+            "QStartNoAckMode" => "OK".to_string(),
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qSymbol::" => "OK".to_string(),
+            "qOffsets" => "Text=0;Data=0;Bss=0".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Something read from the connection
+enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+/// Read the next packet, skipping acknowledgements
+/// Returns None when the connection is closed
+fn read_packet(stream: &mut TcpStream) -> std::io::Result<Option<Incoming>> {
+    let mut byte = [0u8; 1];
+
+    // Find the start of a packet
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            b'$' => break,
+            0x03 => return Ok(Some(Incoming::Interrupt)),
+            // '+' / '-' acknowledgements and noise
+            _ => {},
+        }
+    }
+
+    let mut data = Vec::new();
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            b'#' => break,
+            // Escaped byte
+            b'}' => {
+                if stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                data.push(byte[0] ^ 0x20);
+            },
+            b => data.push(b),
+        }
+    }
+
+    // Checksum (not verified, TCP is reliable)
+    let mut checksum = [0u8; 2];
+    stream.read_exact(&mut checksum)?;
+
+    Ok(Some(Incoming::Packet(String::from_utf8_lossy(&data).into_owned())))
+}
+
+/// Frame and send a packet
+fn write_packet(stream: &mut TcpStream, data: &str) -> std::io::Result<()> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data.bytes() {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.push(b'}');
+            escaped.push(byte ^ 0x20);
+        } else {
+            escaped.push(byte);
+        }
+    }
+
+    let checksum = escaped.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    stream.write_all(b"$")?;
+    stream.write_all(&escaped)?;
+    stream.write_all(format!("#{:02x}", checksum).as_bytes())?;
+    stream.flush()
+}
+
+/// Check for an interrupt byte without blocking
+fn interrupted(stream: &mut TcpStream) -> std::io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut byte = [0u8; 1];
+    let result = stream.read(&mut byte);
+    stream.set_nonblocking(false)?;
+
+    match result {
+        Ok(1) => Ok(byte[0] == 0x03),
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parse `ADDR,LENGTH` (both hex)
+fn parse_address_length(args: &str) -> Option<(u32, u32)> {
+    let (address, length) = args.split_once(',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(length, 16).ok()?))
+}
+
+/// Encode bytes as lowercase hex
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode hex into bytes
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+    use std::net::SocketAddr;
+
+    /// Serve a program on a loopback port, returning where to connect
+    fn serve(name: &str, source: &str) -> (SocketAddr, std::thread::JoinHandle<()>) {
+        let path = std::env::temp_dir().join(format!("vnc-gdb-{}-{}.vnc", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let program = assembler::assemble_program(&path.display().to_string());
+        let mut cpu = CPU::new(256, 256);
+        cpu.load_program(program.to_binary()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::new(cpu).serve(stream).unwrap();
+        });
+        (address, server)
+    }
+
+    /// Send a packet as GDB would and return the reply
+    fn request(stream: &mut TcpStream, packet: &str) -> String {
+        write_packet(stream, packet).unwrap();
+        let mut ack = [0u8; 1];
+        stream.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[0], b'+');
+        let reply = match read_packet(stream).unwrap() {
+            Some(Incoming::Packet(reply)) => reply,
+            _ => panic!("no reply to {}", packet),
+        };
+        stream.write_all(b"+").unwrap();
+        reply
+    }
+
+    #[test]
+    fn scripted_session() {
+        let (address, server) = serve("session", ".data\n    A DAT 3\n.code\n    LDA A\n    OUT\n    HLT\n");
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        assert_eq!(request(&mut stream, "?"), "S05");
+        assert_eq!(request(&mut stream, "g"), "000000000000");
+        assert_eq!(request(&mut stream, "m0,4"), "06001000");
+        assert_eq!(request(&mut stream, "m10000,1"), "03");
+        assert_eq!(request(&mut stream, "M10000,1:07"), "OK");
+        assert_eq!(request(&mut stream, "m10000,1"), "07");
+        assert_eq!(request(&mut stream, "Z0,2,2"), "OK");
+        assert_eq!(request(&mut stream, "c"), "T05swbreak:;");
+        assert_eq!(request(&mut stream, "s"), "S05");
+        assert_eq!(request(&mut stream, "p1"), "07");
+        assert_eq!(request(&mut stream, "c"), "W07");
+        assert_eq!(request(&mut stream, "D"), "OK");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn write_memory_out_of_range() {
+        let (address, server) = serve("range", ".code\n    HLT\n");
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        assert_eq!(request(&mut stream, "Mffffffff,2:0000"), "E14");
+        assert_eq!(request(&mut stream, "M100ff,2:0000"), "E14");
+        assert_eq!(request(&mut stream, "M100ff,1:01"), "OK");
+        assert_eq!(request(&mut stream, "D"), "OK");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+//! Combines relocatable objects into one program
+//! Each memory is laid out by placing the objects one after another, in
+//! the order given:
+//! ```text
+//! data memory                    instruction memory
+//! 0x00  main.vnc data            0x00  main.vnc code   <- execution starts
+//! 0x03  (padding for .align)     0x0A  lib.vnc code
+//! 0x04  lib.vnc data
+//! ```
+//! so the first object should hold the start of the program. Then every
+//! relocation is filled in with the final address of its label: one the
+//! object defines itself, or else a `.global` label of another object.
+//!
+//! Every problem is collected before giving up: `.global` labels defined
+//! by more than one object, `.extern` labels no object defines, values
+//! that no longer fit and objects that do not fit in memory.
+
+use std::collections::HashMap;
+
+use crate::assembler::object::{Object, ObjectSymbol};
+use crate::assembler::source_map::SourceMap;
+use crate::assembler::{literal, Program, Section, SymbolTable, WORD_BITS};
+
+/// Link objects into a program, or return every problem found
+pub fn link(objects: &[Object]) -> Result<Program, Vec<String>> {
+    let mut errors = Vec::new();
+    let memory = 1usize << WORD_BITS;
+    let name = |index: usize| match objects[index].source.as_str() {
+        "" => format!("object {}", index + 1),
+        source => source.to_string(),
+    };
+
+    // Where each object's data and code start
+    let mut data_bases = Vec::new();
+    let mut code_bases = Vec::new();
+    let (mut data_size, mut code_size) = (0usize, 0);
+    for object in objects {
+        let alignment = object.data_alignment.max(1) as usize;
+        data_size = data_size.div_ceil(alignment) * alignment;
+        data_bases.push(data_size);
+        code_bases.push(code_size);
+        data_size += object.data.len();
+        code_size += object.code.len();
+    }
+    if data_size > memory {
+        errors.push(format!("Data does not fit in data memory ({} of {} bytes)", data_size, memory));
+    }
+    if code_size > memory {
+        errors.push(format!("Code does not fit in instruction memory ({} of {} bytes)", code_size, memory));
+    }
+    let base = |section: Section, index: usize| match section {
+        Section::Data => data_bases[index],
+        Section::Code => code_bases[index],
+    };
+
+    // Labels every object can use
+    let mut globals: HashMap<&str, (usize, &ObjectSymbol)> = HashMap::new();
+    for (index, object) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+            let label = symbol.symbol.name.as_str();
+            match globals.get(label) {
+                Some((first, _)) => {
+                    errors.push(format!("'{}' is defined in both {} and {}", label, name(*first), name(index)));
+                },
+                None => {
+                    globals.insert(label, (index, symbol));
+                },
+            }
+        }
+    }
+
+    // Place the contents of each object
+    let mut data = vec![0; data_size];
+    let mut code = Vec::with_capacity(code_size);
+    for (index, object) in objects.iter().enumerate() {
+        data[data_bases[index]..data_bases[index] + object.data.len()].copy_from_slice(&object.data);
+        code.extend_from_slice(&object.code);
+    }
+
+    // Fill in the addresses of labels
+    for (index, object) in objects.iter().enumerate() {
+        for relocation in &object.relocations {
+            let label = &relocation.symbol;
+            let (owner, symbol) = match object.symbol(label) {
+                Some(symbol) => (index, symbol),
+                None => match globals.get(label.as_str()) {
+                    Some(&(owner, symbol)) if object.externs.contains(label) => (owner, symbol),
+                    _ => {
+                        let message = format!("{}: '{}' is not defined by any object (missing .global?)", name(index), label);
+                        if !errors.contains(&message) {
+                            errors.push(message);
+                        }
+                        continue;
+                    },
+                },
+            };
+
+            let address = base(symbol.symbol.section, owner) as i64 + symbol.symbol.address as i64;
+            let value = match literal::fit(address + relocation.addend, relocation.bits) {
+                Ok(value) => value,
+                Err(message) => {
+                    errors.push(format!("{}: value of '{}' {:+}: {}", name(index), label, relocation.addend, message));
+                    continue;
+                },
+            };
+
+            let (bytes, start) = match relocation.section {
+                Section::Data => (&mut data, data_bases[index]),
+                Section::Code => (&mut code, code_bases[index]),
+            };
+            let offset = start + relocation.offset as usize;
+            match relocation.bits > WORD_BITS {
+                true => bytes[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes()),
+                false => bytes[offset] = value as u8,
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // Global labels first, so a local label of the same name in another
+    // object does not hide one
+    let mut symbols = SymbolTable::new();
+    for global in [true, false] {
+        for (index, object) in objects.iter().enumerate() {
+            for ObjectSymbol { symbol, .. } in object.symbols.iter().filter(|symbol| symbol.global == global) {
+                if symbols.get(&symbol.name).is_none() {
+                    let address = base(symbol.section, index) as u32 + symbol.address;
+                    symbols.insert(&symbol.name, symbol.section, address);
+                }
+            }
+        }
+    }
+
+    // Where each instruction came from, and the labels of data
+    let mut source_map = SourceMap::default();
+    for (index, object) in objects.iter().enumerate() {
+        let lines = SourceMap {
+            code: object.lines.clone(),
+            data: Vec::new(),
+        };
+        source_map.code.extend(lines.shifted(code_bases[index] as u32, 0).code);
+    }
+    source_map.data = symbols
+        .iter()
+        .filter(|symbol| symbol.section == Section::Data)
+        .map(|symbol| (symbol.address, symbol.name.clone()))
+        .collect();
+    source_map.data.sort_by_key(|(address, _)| *address);
+
+    Ok(Program {
+        data,
+        code,
+        symbols,
+        source_map,
+        ..Program::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble_with, Options};
+
+    /// Assemble source into a relocatable object
+    fn object(source: &str, name: &str) -> Object {
+        let options = Options {
+            relocatable: true,
+            ..Options::default()
+        };
+        let assembly = assemble_with(source, &options);
+        assert!(!assembly.has_errors(), "{:?}", assembly.diagnostics);
+        Object {
+            source: name.to_string(),
+            ..assembly.object.unwrap()
+        }
+    }
+
+    #[test]
+    fn links_objects_in_order() {
+        let main = object(".extern sum\n.data\nA: DAT 3\n.code\nLDA A\nJMP sum\n", "main.vnc");
+        let lib = object(".global sum\n.data\nB: DAT 4\n.code\nsum: ADD B\nOUT\nHLT\n", "lib.vnc");
+        let program = link(&[main, lib]).unwrap();
+
+        assert_eq!(program.data, vec![3, 4]);
+        // JMP sum and ADD B are filled in with addresses in the second object
+        assert_eq!(program.code, vec![0x06, 0x00, 0x07, 0x04, 0x01, 0x01, 0x10, 0x00, 0x0E, 0x00]);
+        assert_eq!(program.symbols.get("sum").map(|symbol| symbol.address), Some(4));
+        assert_eq!(program.symbols.get("B").map(|symbol| symbol.address), Some(1));
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let main = object(".extern missing\n.global twice\n.code\ntwice: JMP missing\n", "main.vnc");
+        let other = object(".global twice\n.code\ntwice: HLT\n", "other.vnc");
+        let errors = link(&[main, other]).unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                "'twice' is defined in both main.vnc and other.vnc",
+                "main.vnc: 'missing' is not defined by any object (missing .global?)",
+            ]
+        );
+    }
+
+    #[test]
+    fn objects_must_fit_in_memory() {
+        let big = object(".data\n.space 200\n.code\nHLT\n", "");
+        let errors = link(&[big.clone(), big]).unwrap_err();
+        assert_eq!(errors, ["Data does not fit in data memory (400 of 256 bytes)"]);
+    }
+}
@@ -0,0 +1,359 @@
+//! Finds likely bugs in a program without running it
+//! Works on the control flow graph (see `cfg`) of an assembled program
+//! and reports, as warnings on the lines they are about:
+//! 1. Code that can never run (e.g. after `HLT` or `JMP`)
+//! 2. Paths that carry on past the last instruction without `HLT`
+//! 3. Jumps to an address that is not an instruction, or to a data label
+//! 4. Reading a cell declared without a value (`DAT` or `.space`) before
+//!    anything is stored in it
+//! 5. `DIV` by a cell that is always zero at that point
+//! 6. Labels that are never used
+//!
+//! Cells are followed through the program by working out, at the start of
+//! each block, which may still be unwritten and which hold a value known
+//! whatever path was taken to get there. Branch conditions are not
+//! followed, so every edge is assumed to be possible.
+
+use crate::assembler::ast::{CodeLine, DataValue, Item, Placed};
+use crate::assembler::{Assembly, Diagnostic, Section, Severity};
+use crate::cfg::{Cfg, EdgeKind, Target};
+use crate::cpu::instructions::{Instruction, Opcode};
+
+/// Number of cells in data memory
+const DATA_SIZE: usize = 256;
+
+/// What is known about the machine at a point in the program
+#[derive(Clone, Debug, PartialEq)]
+struct State {
+    /// Value of the accumulator, if known
+    acc: Option<u8>,
+    /// Value of each cell, if known
+    cells: Vec<Option<u8>>,
+    /// Cells that may not have been written yet
+    unset: Vec<bool>,
+}
+
+/// Check an assembled program, returning a warning for each problem
+/// found (nothing if it did not assemble)
+pub fn lint(assembly: &Assembly) -> Vec<Diagnostic> {
+    if assembly.has_errors() {
+        return Vec::new();
+    }
+
+    let program = &assembly.program;
+    let cfg = Cfg::new(&program.code);
+    let items = assembly.ast.placed();
+    // The line each instruction came from, by address / 2
+    let code_lines: Vec<(&CodeLine, &Placed)> = items
+        .iter()
+        .filter_map(|placed| match placed.item {
+            Item::Code(line) => Some((line, placed)),
+            _ => None,
+        })
+        .collect();
+
+    let mut warnings = Vec::new();
+    let mut warn = |address: u32, operand: bool, message: String| {
+        if let Some((line, placed)) = code_lines.get(address as usize / 2) {
+            let span = match (&line.operand, operand) {
+                (Some(expr), true) => expr.span,
+                _ => line.opcode_span,
+            };
+            warnings.push(Diagnostic {
+                severity: Severity::Warning,
+                span,
+                message: format!("{}{}", message, placed.trace()),
+            });
+        }
+    };
+
+    // Code that never runs, once for each stretch of it
+    for (index, block) in cfg.blocks.iter().enumerate() {
+        if block.reachable || (index > 0 && !cfg.blocks[index - 1].reachable) {
+            continue;
+        }
+        let after = match index.checked_sub(1).and_then(|before| cfg.blocks[before].instructions.last()) {
+            Some((_, Some(Instruction { opcode, .. }))) if matches!(opcode, Opcode::HLT | Opcode::JMP) => {
+                format!(" after {}", opcode)
+            },
+            _ => String::new(),
+        };
+        warn(block.start, false, format!("Unreachable code{}", after));
+    }
+
+    // Where execution goes
+    for block in cfg.blocks.iter().filter(|block| block.reachable) {
+        let (address, last) = block.instructions.last().unwrap();
+        let instruction = match last {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+
+        for edge in &block.edges {
+            match (edge.kind, edge.target) {
+                (EdgeKind::Next, Target::End) => {
+                    warn(*address, false, "Execution can carry on past the last instruction (missing HLT?)".to_string());
+                },
+                (EdgeKind::Next, _) => {},
+                // Pointing at a data label is the more likely mistake
+                (_, target) => {
+                    let line = code_lines.get(*address as usize / 2).map(|(line, _)| *line);
+                    let message = match (data_label_in(assembly, line), target) {
+                        (Some(label), _) => format!("{} jumps to '{}', which is a data label", instruction.opcode, label),
+                        (None, Target::Outside(target)) if target.is_multiple_of(2) => {
+                            format!("{} jumps to 0x{:02X}, which is past the last instruction", instruction.opcode, target)
+                        },
+                        (None, Target::Outside(target)) => {
+                            format!("{} jumps to 0x{:02X}, which is not the start of an instruction", instruction.opcode, target)
+                        },
+                        _ => continue,
+                    };
+                    warn(*address, true, message);
+                },
+            }
+        }
+    }
+
+    // Cells read before being written, and division by zero
+    let states = flow(assembly, &cfg);
+    for (block, state) in cfg.blocks.iter().zip(states) {
+        let mut state = match state {
+            Some(state) => state,
+            None => continue,
+        };
+        for (address, instruction) in &block.instructions {
+            let instruction = match instruction {
+                Some(instruction) => instruction,
+                None => break,
+            };
+            let cell = instruction.operand as usize;
+            let name = program
+                .source_map
+                .data_label(cell as u32)
+                .map_or(format!("0x{:02X}", cell), |label| format!("'{}'", label));
+
+            if reads(instruction.opcode) && state.unset[cell] {
+                warn(*address, true, format!("{} may be read before anything is stored in it", name));
+                // Once is enough
+                state.unset[cell] = false;
+            }
+            if instruction.opcode == Opcode::DIV && state.cells[cell] == Some(0) {
+                warn(*address, true, format!("DIV by {}, which is always 0 here", name));
+            }
+            step(&mut state, instruction);
+        }
+    }
+
+    // Labels that are never used
+    for symbol in program.symbols.iter() {
+        let used = assembly.labels.iter().any(|site| site.name == symbol.name && !site.definition);
+        if let Some(definition) = assembly.definition_of(&symbol.name).filter(|_| !used) {
+            warnings.push(Diagnostic {
+                severity: Severity::Warning,
+                span: definition.span,
+                message: format!("Label '{}' is never used", symbol.name),
+            });
+        }
+    }
+
+    warnings.sort_by_key(|d| (d.span.file, d.span.line, d.span.column));
+    warnings
+}
+
+/// The first label in an instruction's operand that is a data label
+fn data_label_in(assembly: &Assembly, line: Option<&CodeLine>) -> Option<String> {
+    let mut found = None;
+    line?.operand.as_ref()?.for_each_symbol(&mut |name, _| {
+        let symbol = assembly.program.symbols.get(name);
+        if found.is_none() && symbol.is_some_and(|symbol| symbol.section == Section::Data) {
+            found = Some(name.to_string());
+        }
+    });
+    found
+}
+
+/// Work out the state at the start of every block that can run
+fn flow(assembly: &Assembly, cfg: &Cfg) -> Vec<Option<State>> {
+    let program = &assembly.program;
+    let mut cells = vec![Some(0); DATA_SIZE];
+    for (cell, byte) in cells.iter_mut().zip(&program.data) {
+        *cell = Some(*byte);
+    }
+    let entry = State {
+        acc: Some(0),
+        cells,
+        unset: unset_cells(assembly),
+    };
+
+    let mut states: Vec<Option<State>> = vec![None; cfg.blocks.len()];
+    if cfg.blocks.is_empty() {
+        return states;
+    }
+    states[0] = Some(entry);
+    let mut pending = vec![0];
+    while let Some(index) = pending.pop() {
+        let mut state = states[index].clone().unwrap();
+        for (_, instruction) in &cfg.blocks[index].instructions {
+            match instruction {
+                Some(instruction) => step(&mut state, instruction),
+                None => break,
+            }
+        }
+
+        for edge in &cfg.blocks[index].edges {
+            let target = match edge.target {
+                Target::Block(target) => target,
+                _ => continue,
+            };
+            let merged = match &states[target] {
+                Some(old) => merge(old, &state),
+                None => state.clone(),
+            };
+            if states[target].as_ref() != Some(&merged) {
+                states[target] = Some(merged);
+                pending.push(target);
+            }
+        }
+    }
+    states
+}
+
+/// Cells declared without a value
+fn unset_cells(assembly: &Assembly) -> Vec<bool> {
+    let mut unset = vec![false; DATA_SIZE];
+    let lines = assembly.ast.placed().into_iter().filter_map(|placed| match placed.item {
+        Item::Data(line) => Some(line),
+        _ => None,
+    });
+    let emitted = assembly.emitted.iter().filter(|emitted| emitted.section == Section::Data);
+
+    for (line, emitted) in lines.zip(emitted) {
+        let declared = match line.value {
+            DataValue::Dat(None) | DataValue::Fill { value: None, .. } => emitted.address..emitted.address + emitted.size,
+            _ => continue,
+        };
+        for cell in declared.filter(|cell| (*cell as usize) < DATA_SIZE) {
+            unset[cell as usize] = true;
+        }
+    }
+    unset
+}
+
+/// Whether an instruction reads the cell its operand names
+fn reads(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::LDA)
+}
+
+/// Update the state for an instruction running
+fn step(state: &mut State, instruction: &Instruction) {
+    let cell = instruction.operand as usize;
+    let value = state.cells[cell];
+    let both = |op: fn(u8, u8) -> Option<u8>| match (state.acc, value) {
+        (Some(acc), Some(value)) => op(acc, value),
+        _ => None,
+    };
+
+    state.acc = match instruction.opcode {
+        Opcode::ADD => both(|a, b| Some(a.wrapping_add(b))),
+        Opcode::SUB => both(|a, b| Some(a.wrapping_sub(b))),
+        Opcode::MUL => both(|a, b| Some(a.wrapping_mul(b))),
+        Opcode::DIV => both(|a, b| a.checked_div(b)),
+        Opcode::LDA => value,
+        Opcode::INP => None,
+        Opcode::STA => {
+            state.cells[cell] = state.acc;
+            state.unset[cell] = false;
+            state.acc
+        },
+        _ => state.acc,
+    };
+}
+
+/// What is known whichever of two states execution arrives in
+fn merge(a: &State, b: &State) -> State {
+    let same = |a: Option<u8>, b: Option<u8>| if a == b { a } else { None };
+    State {
+        acc: same(a.acc, b.acc),
+        cells: a.cells.iter().zip(&b.cells).map(|(a, b)| same(*a, *b)).collect(),
+        unset: a.unset.iter().zip(&b.unset).map(|(a, b)| *a || *b).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_source;
+
+    /// Messages of the warnings for a program
+    fn warnings(source: &str) -> Vec<String> {
+        lint(&assemble_source(source)).into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn clean_programs_have_no_warnings() {
+        let source = ".data\nA: DAT 3\nONE: DAT 1\n.code\nloop: LDA A\nSUB ONE\nSTA A\nJNZ loop\nHLT\n";
+        assert!(warnings(source).is_empty(), "{:?}", warnings(source));
+    }
+
+    #[test]
+    fn programs_with_errors_are_not_linted() {
+        assert!(warnings(".code\nLDA MISSING\n").is_empty());
+    }
+
+    #[test]
+    fn unreachable_code() {
+        assert_eq!(warnings(".code\nHLT\nHLT\n"), vec!["Unreachable code after HLT"]);
+        assert_eq!(warnings(".code\nend: JMP end\nHLT\n"), vec!["Unreachable code after JMP"]);
+        assert!(warnings(".code\nLDA 0\nJNZ end\nOUT\nend: HLT\n").is_empty());
+    }
+
+    #[test]
+    fn missing_halt() {
+        assert_eq!(
+            warnings(".code\nOUT\n"),
+            vec!["Execution can carry on past the last instruction (missing HLT?)"]
+        );
+        assert!(warnings(".code\nOUT\nHLT\n").is_empty());
+    }
+
+    #[test]
+    fn jumps_to_data_or_outside_the_code() {
+        assert_eq!(
+            warnings(".data\nA: DAT 1\n.code\nJMP A\nHLT\n"),
+            vec!["JMP jumps to 'A', which is a data label", "Unreachable code after JMP"]
+        );
+        assert_eq!(
+            warnings(".code\nJMP 0x10\n"),
+            vec!["JMP jumps to 0x10, which is past the last instruction"]
+        );
+        assert_eq!(
+            warnings(".code\nJMP 0x01\nHLT\n"),
+            vec!["JMP jumps to 0x01, which is not the start of an instruction", "Unreachable code after JMP"]
+        );
+        assert!(warnings(".code\nJMP end\nend: HLT\n").is_empty());
+    }
+
+    #[test]
+    fn reading_unset_cells() {
+        assert_eq!(
+            warnings(".data\nA: .space 1\n.code\nLDA A\nOUT\nHLT\n"),
+            vec!["'A' may be read before anything is stored in it"]
+        );
+        assert!(warnings(".data\nA: .space 1\n.code\nINP\nSTA A\nLDA A\nOUT\nHLT\n").is_empty());
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_eq!(
+            warnings(".data\nA: DAT 6\nZERO: DAT 0\n.code\nLDA A\nDIV ZERO\nOUT\nHLT\n"),
+            vec!["DIV by 'ZERO', which is always 0 here"]
+        );
+        assert!(warnings(".data\nA: DAT 6\nTWO: DAT 2\n.code\nLDA A\nDIV TWO\nOUT\nHLT\n").is_empty());
+    }
+
+    #[test]
+    fn unused_labels() {
+        assert_eq!(warnings(".data\nA: DAT 1\n.code\nHLT\n"), vec!["Label 'A' is never used"]);
+        assert!(warnings(".data\nA: DAT 1\n.code\nLDA A\nOUT\nHLT\n").is_empty());
+    }
+}
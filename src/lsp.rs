@@ -0,0 +1,711 @@
+//! Language Server Protocol (LSP) server
+//! Started with `vnc lsp`, speaks LSP over stdin/stdout so editors give
+//! feedback while a `.vnc` file is being written.
+//!
+//! Every change re-assembles the whole document with
+//! `assembler::assemble_with` (finding included files next to it), which
+//! gives:
+//! 1. Diagnostics (errors and warnings), published after each change
+//! 2. Label definitions and uses, for go-to-definition, find-references
+//!    and hover
+//! 3. Section directives, for document symbols
+//!
+//! Hovering over a mnemonic shows its description and encoding.
+//! Completion offers every mnemonic, label and section directive.
+
+use std::collections::HashMap;
+
+use crate::assembler::lexer::{is_word_byte, Span};
+use crate::assembler::{self, Assembly, LabelSite, Options, Section, Severity};
+use crate::cpu::instructions::Opcode;
+use crate::dap::{read_message, write_message};
+use crate::json::Json;
+
+/// JSON-RPC error codes
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_REQUEST: i32 = -32600;
+
+/// LSP kinds used in responses
+const SYMBOL_KIND_NAMESPACE: u32 = 3;
+const SYMBOL_KIND_FUNCTION: u32 = 12;
+const SYMBOL_KIND_VARIABLE: u32 = 13;
+const COMPLETION_KIND_FUNCTION: u32 = 3;
+const COMPLETION_KIND_VARIABLE: u32 = 6;
+const COMPLETION_KIND_KEYWORD: u32 = 14;
+const COMPLETION_KIND_CONSTANT: u32 = 21;
+
+/// An open document
+struct Document {
+    text: String,
+    assembly: Assembly,
+}
+
+impl Document {
+    fn new(uri: &str, text: String) -> Document {
+        // Files it includes are found next to it on disk
+        let options = match uri_to_path(uri) {
+            Some(path) => Options::for_file(&path),
+            None => Options::default(),
+        };
+        let assembly = assembler::assemble_with(&text, &options);
+        Document { text, assembly }
+    }
+
+    /// Get a line of the document (lines start at 1)
+    fn line(&self, line: usize) -> &str {
+        self.text.split('\n').nth(line - 1).unwrap_or("").trim_end_matches('\r')
+    }
+
+    /// Convert an LSP position to a (line, byte column) pair
+    fn to_line_column(&self, position: &Json) -> (usize, usize) {
+        let line = position.get("line").and_then(Json::as_u64).unwrap_or(0) as usize + 1;
+        let character = position.get("character").and_then(Json::as_u64).unwrap_or(0) as usize;
+
+        // LSP counts UTF-16 code units
+        let text = self.line(line);
+        let mut units = 0;
+        for (index, c) in text.char_indices() {
+            if units >= character {
+                return (line, index);
+            }
+            units += c.len_utf16();
+        }
+        (line, text.len())
+    }
+
+    /// Convert a line and byte column to an LSP position
+    fn position(&self, line: usize, column: usize) -> Json {
+        let text = self.line(line);
+        let character = text.get(..column.min(text.len())).unwrap_or(text).encode_utf16().count();
+        Json::object(vec![("line", (line - 1).into()), ("character", character.into())])
+    }
+
+    /// LSP range covering a span
+    fn range(&self, span: Span) -> Json {
+        Json::object(vec![
+            ("start", self.position(span.line, span.column)),
+            ("end", self.position(span.line, span.end())),
+        ])
+    }
+
+    /// Get the identifier (mnemonic, label or constant) at a position
+    fn word_at(&self, line: usize, column: usize) -> Option<&str> {
+        let text = self.line(line);
+        let column = column.min(text.len());
+        let start = text[..column]
+            .bytes()
+            .rposition(|byte| !is_word_byte(byte))
+            .map_or(0, |index| index + 1);
+        let end = text[column..]
+            .bytes()
+            .position(|byte| !is_word_byte(byte))
+            .map_or(text.len(), |index| column + index);
+        let word = &text[start..end];
+        (!word.is_empty()).then_some(word)
+    }
+}
+
+/// Language server state
+#[derive(Default)]
+pub struct LanguageServer {
+    /// Open documents by URI
+    documents: HashMap<String, Document>,
+    /// Whether `shutdown` has been received
+    shutting_down: bool,
+}
+
+impl LanguageServer {
+    /// Create a server with no open documents
+    pub fn new() -> LanguageServer {
+        LanguageServer::default()
+    }
+
+    /// Serve requests on stdin until `exit`
+    pub fn serve(&mut self) {
+        let stdin = std::io::stdin();
+        let mut reader = stdin.lock();
+
+        while let Some(message) = read_message(&mut reader) {
+            if !self.dispatch(&message) {
+                break;
+            }
+        }
+    }
+
+    /// Handle a request or notification, returning false on `exit`
+    fn dispatch(&mut self, message: &Json) -> bool {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => {
+                // Notifications get no response
+                match method {
+                    "exit" => return false,
+                    "textDocument/didOpen" => self.did_open(&params),
+                    "textDocument/didChange" => self.did_change(&params),
+                    "textDocument/didClose" => self.did_close(&params),
+                    _ => {},
+                }
+                return true;
+            },
+        };
+
+        if self.shutting_down {
+            respond(id, Err((INVALID_REQUEST, "Server is shutting down".to_string())));
+            return true;
+        }
+
+        let result = match method {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                self.shutting_down = true;
+                Ok(Json::Null)
+            },
+            "textDocument/definition" => Ok(self.definition(&params)),
+            "textDocument/references" => Ok(self.references(&params)),
+            "textDocument/hover" => Ok(self.hover(&params)),
+            "textDocument/completion" => Ok(self.completion(&params)),
+            "textDocument/documentSymbol" => Ok(self.document_symbols(&params)),
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported method: {}", method))),
+        };
+
+        respond(id, result);
+        true
+    }
+
+    fn did_open(&mut self, params: &Json) {
+        let document = params.get("textDocument");
+        let uri = document.and_then(|d| d.get("uri")).and_then(Json::as_str);
+        let text = document.and_then(|d| d.get("text")).and_then(Json::as_str);
+
+        if let (Some(uri), Some(text)) = (uri, text) {
+            self.documents.insert(uri.to_string(), Document::new(uri, text.to_string()));
+            self.publish_diagnostics(uri);
+        }
+    }
+
+    fn did_change(&mut self, params: &Json) {
+        let uri = params.get("textDocument").and_then(|d| d.get("uri")).and_then(Json::as_str);
+
+        // Only full document sync is offered, so the last change is the
+        // whole text
+        let text = params
+            .get("contentChanges")
+            .and_then(Json::as_array)
+            .and_then(|changes| changes.last())
+            .and_then(|change| change.get("text"))
+            .and_then(Json::as_str);
+
+        if let (Some(uri), Some(text)) = (uri, text) {
+            self.documents.insert(uri.to_string(), Document::new(uri, text.to_string()));
+            self.publish_diagnostics(uri);
+        }
+    }
+
+    fn did_close(&mut self, params: &Json) {
+        if let Some(uri) = params.get("textDocument").and_then(|d| d.get("uri")).and_then(Json::as_str) {
+            self.documents.remove(uri);
+            // Clear the problems shown for the file
+            notify(
+                "textDocument/publishDiagnostics",
+                Json::object(vec![("uri", uri.into()), ("diagnostics", Json::Array(Vec::new()))]),
+            );
+        }
+    }
+
+    fn publish_diagnostics(&self, uri: &str) {
+        notify(
+            "textDocument/publishDiagnostics",
+            Json::object(vec![("uri", uri.into()), ("diagnostics", self.diagnostics(uri))]),
+        );
+    }
+
+    /// Diagnostics of an open document
+    fn diagnostics(&self, uri: &str) -> Json {
+        let document = &self.documents[uri];
+
+        let diagnostics = document
+            .assembly
+            .diagnostics
+            .iter()
+            .map(|d| {
+                let severity: u32 = match d.severity {
+                    Severity::Error => 1,
+                    Severity::Warning => 2,
+                };
+
+                // Problems in included files are shown on the `.include`
+                let (span, message) = match document.assembly.ast.include_of(d.span.file) {
+                    Some(include) if d.span.file != 0 => {
+                        let name = document.assembly.file_name(d.span);
+                        (include.span, format!("{}:{}", name, d))
+                    },
+                    _ => (d.span, d.message.clone()),
+                };
+                Json::object(vec![
+                    ("range", document.range(span)),
+                    ("severity", severity.into()),
+                    ("source", "vnc".into()),
+                    ("message", message.into()),
+                ])
+            })
+            .collect();
+        Json::Array(diagnostics)
+    }
+
+    /// Find the document and label under the cursor of a request
+    fn label_at<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a Document, &'a LabelSite)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let document = self.documents.get(uri)?;
+        let (line, column) = document.to_line_column(params.get("position")?);
+        let site = document.assembly.label_at(line, column)?;
+        Some((uri, document, site))
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        let (uri, document, site) = match self.label_at(params) {
+            Some(found) => found,
+            None => return Json::Null,
+        };
+
+        match document.assembly.definition_of(&site.name) {
+            Some(definition) => location(uri, document, definition),
+            None => Json::Null,
+        }
+    }
+
+    fn references(&self, params: &Json) -> Json {
+        let (uri, document, site) = match self.label_at(params) {
+            Some(found) => found,
+            None => return Json::Null,
+        };
+
+        let include_declaration = params
+            .get("context")
+            .and_then(|context| context.get("includeDeclaration"))
+            .and_then(Json::as_bool)
+            .unwrap_or(true);
+
+        let locations = document
+            .assembly
+            .labels
+            .iter()
+            .filter(|other| other.name == site.name && (include_declaration || !other.definition))
+            .map(|other| location(uri, document, other))
+            .collect();
+        Json::Array(locations)
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let document = match params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .and_then(|uri| self.documents.get(uri))
+        {
+            Some(document) => document,
+            None => return Json::Null,
+        };
+        let (line, column) = document.to_line_column(params.get("position").unwrap_or(&Json::Null));
+
+        let word = match document.word_at(line, column) {
+            Some(word) => word,
+            None => return Json::Null,
+        };
+
+        let text = if let Ok(opcode) = word.parse::<Opcode>() {
+            let encoding = if opcode.has_operand() {
+                format!("0x{:02X} <operand>", opcode.to_bin())
+            } else {
+                format!("0x{:02X} 0x00", opcode.to_bin())
+            };
+            format!("**{}**: {}\n\nEncoding: `{}`", opcode, opcode.description(), encoding)
+        } else if let Some(symbol) = document.assembly.program.symbols.get(word) {
+            match symbol.section {
+                Section::Data => {
+                    let value = document.assembly.program.data.get(symbol.address as usize).copied().unwrap_or(0);
+                    format!("**{}**: data address 0x{:02X}, initial value {}", symbol.name, symbol.address, value)
+                },
+                Section::Code => {
+                    let line = document.assembly.program.line_of(symbol.address).unwrap_or(0);
+                    format!("**{}**: instruction address 0x{:02X} (line {})", symbol.name, symbol.address, line)
+                },
+            }
+        } else if let Some((name, value)) = document.assembly.constants.iter().find(|(name, _)| name == word) {
+            format!("**{}**: constant {}", name, value)
+        } else {
+            return Json::Null;
+        };
+
+        Json::object(vec![(
+            "contents",
+            Json::object(vec![("kind", "markdown".into()), ("value", text.into())]),
+        )])
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let mut items: Vec<Json> = Opcode::ALL
+            .iter()
+            .map(|opcode| {
+                Json::object(vec![
+                    ("label", opcode.to_string().into()),
+                    ("kind", COMPLETION_KIND_KEYWORD.into()),
+                    ("detail", opcode.description().into()),
+                ])
+            })
+            .collect();
+
+        let directives = [
+            ".data", ".code", ".equ", ".set", ".byte", ".word", ".ascii", ".asciz", ".space", ".fill", ".align",
+            ".macro", ".endm", ".include", ".global", ".extern",
+        ];
+        for directive in directives {
+            items.push(Json::object(vec![
+                ("label", directive.into()),
+                ("kind", COMPLETION_KIND_KEYWORD.into()),
+            ]));
+        }
+
+        let document = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .and_then(|uri| self.documents.get(uri));
+
+        if let Some(document) = document {
+            for symbol in document.assembly.program.symbols.iter() {
+                let (kind, detail) = match symbol.section {
+                    Section::Data => (COMPLETION_KIND_VARIABLE, format!("data 0x{:02X}", symbol.address)),
+                    Section::Code => (COMPLETION_KIND_FUNCTION, format!("code 0x{:02X}", symbol.address)),
+                };
+                items.push(Json::object(vec![
+                    ("label", symbol.name.as_str().into()),
+                    ("kind", kind.into()),
+                    ("detail", detail.into()),
+                ]));
+            }
+            for (name, value) in &document.assembly.constants {
+                items.push(Json::object(vec![
+                    ("label", name.as_str().into()),
+                    ("kind", COMPLETION_KIND_CONSTANT.into()),
+                    ("detail", format!("constant {}", value).into()),
+                ]));
+            }
+        }
+
+        Json::Array(items)
+    }
+
+    fn document_symbols(&self, params: &Json) -> Json {
+        let document = match params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .and_then(|uri| self.documents.get(uri))
+        {
+            Some(document) => document,
+            None => return Json::Null,
+        };
+
+        let assembly = &document.assembly;
+
+        let symbols = assembly
+            .ast
+            .sections
+            .iter()
+            .map(|node| {
+                let (start, end) = (node.span.line, node.last_line());
+
+                let children = assembly
+                    .labels
+                    .iter()
+                    // Labels from included files have lines of their own
+                    .filter(|site| site.definition && site.span.file == 0 && (start..=end).contains(&site.span.line))
+                    .map(|site| {
+                        let kind = match node.section {
+                            Section::Data => SYMBOL_KIND_VARIABLE,
+                            Section::Code => SYMBOL_KIND_FUNCTION,
+                        };
+                        let range = document.range(site.span);
+                        Json::object(vec![
+                            ("name", site.name.as_str().into()),
+                            ("kind", kind.into()),
+                            ("range", range.clone()),
+                            ("selectionRange", range),
+                        ])
+                    })
+                    .collect();
+
+                Json::object(vec![
+                    ("name", document.line(start)[node.span.column..node.span.end()].into()),
+                    ("kind", SYMBOL_KIND_NAMESPACE.into()),
+                    (
+                        "range",
+                        Json::object(vec![
+                            ("start", document.position(start, 0)),
+                            ("end", document.position(end, document.line(end).len())),
+                        ]),
+                    ),
+                    ("selectionRange", document.range(node.span)),
+                    ("children", Json::Array(children)),
+                ])
+            })
+            .collect();
+
+        Json::Array(symbols)
+    }
+}
+
+/// Capabilities sent in the `initialize` response
+fn capabilities() -> Json {
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                // Full document sync
+                ("textDocumentSync", 1u32.into()),
+                ("definitionProvider", true.into()),
+                ("referencesProvider", true.into()),
+                ("hoverProvider", true.into()),
+                ("completionProvider", Json::Object(Vec::new())),
+                ("documentSymbolProvider", true.into()),
+            ]),
+        ),
+        ("serverInfo", Json::object(vec![("name", "vnc".into())])),
+    ])
+}
+
+/// LSP location of a label
+fn location(uri: &str, document: &Document, site: &LabelSite) -> Json {
+    if site.span.file == 0 {
+        return Json::object(vec![("uri", uri.into()), ("range", document.range(site.span))]);
+    }
+
+    // In an included file, which is not open, so columns are left as bytes
+    let path = document.assembly.file_name(site.span);
+    let path = std::fs::canonicalize(path).map_or(path.to_string(), |path| path.display().to_string());
+    let position = |column: usize| {
+        Json::object(vec![("line", (site.span.line - 1).into()), ("character", column.into())])
+    };
+    Json::object(vec![
+        ("uri", path_to_uri(&path).into()),
+        (
+            "range",
+            Json::object(vec![("start", position(site.span.column)), ("end", position(site.span.end()))]),
+        ),
+    ])
+}
+
+/// Path of a `file://` URI, with `%XX` escapes decoded
+fn uri_to_path(uri: &str) -> Option<String> {
+    let mut rest = uri.strip_prefix("file://")?.as_bytes();
+    let mut path = Vec::with_capacity(rest.len());
+    while let Some((&byte, tail)) = rest.split_first() {
+        match tail {
+            [high, low, ..] if byte == b'%' && high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                // Two hex digits are valid UTF-8 and always parse
+                let hex = std::str::from_utf8(&tail[..2]).unwrap();
+                path.push(u8::from_str_radix(hex, 16).unwrap());
+                rest = &tail[2..];
+            },
+            _ => {
+                path.push(byte);
+                rest = tail;
+            },
+        }
+    }
+    Some(String::from_utf8_lossy(&path).into_owned())
+}
+
+/// `file://` URI of a path, escaping every byte that may not appear in one
+fn path_to_uri(path: &str) -> String {
+    let mut uri = "file://".to_string();
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+/// Send the response to a request
+fn respond(id: Json, result: Result<Json, (i32, String)>) {
+    let mut response = Json::object(vec![("jsonrpc", "2.0".into()), ("id", id)]);
+    match result {
+        Ok(result) => response.set("result", result),
+        Err((code, message)) => response.set(
+            "error",
+            Json::object(vec![("code", code.into()), ("message", message.into())]),
+        ),
+    }
+    write_message(&response);
+}
+
+/// Send a notification
+fn notify(method: &str, params: Json) {
+    write_message(&Json::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("method", method.into()),
+        ("params", params),
+    ]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols_leave_out_included_labels() {
+        let directory = std::env::temp_dir().join(format!("vnc-lsp-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("lib.vnc"), "ONE:    DAT 1\nTWO:    DAT 2\n").unwrap();
+        let main = directory.join("main.vnc");
+        let text = ".data\n.include \"lib.vnc\"\nA:      DAT 3\n\n.code\n        LDA A\n        HLT\n";
+
+        let uri = format!("file://{}", main.display());
+        let mut server = LanguageServer::new();
+        server.documents.insert(uri.clone(), Document::new(&uri, text.to_string()));
+        let params = Json::object(vec![("textDocument", Json::object(vec![("uri", uri.as_str().into())]))]);
+        let symbols = server.document_symbols(&params);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let data = symbols.as_array().unwrap()[0].get("children").unwrap().as_array().unwrap();
+        let names: Vec<&str> = data.iter().filter_map(|symbol| symbol.get("name")?.as_str()).collect();
+        assert_eq!(names, ["A"]);
+    }
+
+    const SOURCE: &str = ".equ SIZE 2
+.data
+TABLE:  DAT 1
+        DAT 2
+COUNT:  DAT SIZE
+.code
+loop:   LDA TABLE+1
+        SUB COUNT
+        JNZ loop
+        HLT
+";
+
+    /// A server with one document open
+    fn open(text: &str) -> (LanguageServer, String) {
+        let uri = "file:///vnc-lsp-test/main.vnc".to_string();
+        let mut server = LanguageServer::new();
+        server.documents.insert(uri.clone(), Document::new(&uri, text.to_string()));
+        (server, uri)
+    }
+
+    /// Parameters of a request at a position (lines and characters from 0)
+    fn at(uri: &str, line: u32, character: u32) -> Json {
+        Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", uri.into())])),
+            ("position", Json::object(vec![("line", line.into()), ("character", character.into())])),
+        ])
+    }
+
+    /// (line, character) a location starts at
+    fn start(location: &Json) -> (u64, u64) {
+        let start = location.get("range").and_then(|range| range.get("start")).unwrap();
+        (start.get("line").and_then(Json::as_u64).unwrap(), start.get("character").and_then(Json::as_u64).unwrap())
+    }
+
+    /// Text shown when hovering at a position
+    fn hover(server: &LanguageServer, uri: &str, line: u32, character: u32) -> Option<String> {
+        let hover = server.hover(&at(uri, line, character));
+        let value = hover.get("contents")?.get("value")?.as_str()?;
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn diagnostics() {
+        let (server, uri) = open(SOURCE);
+        assert_eq!(server.diagnostics(&uri).as_array().map(Vec::len), Some(0));
+
+        let (server, uri) = open(".code\n        FOO\n        LDA X\n");
+        let diagnostics = server.diagnostics(&uri);
+        let diagnostics = diagnostics.as_array().unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(start(&diagnostics[0]), (1, 8));
+        assert_eq!(diagnostics[0].get("severity").and_then(Json::as_u64), Some(1));
+        let message = diagnostics[0].get("message").and_then(Json::as_str).unwrap();
+        assert!(message.contains("Unknown instruction 'FOO'"), "{}", message);
+        assert_eq!(start(&diagnostics[1]), (2, 12));
+    }
+
+    #[test]
+    fn definition() {
+        let (server, uri) = open(SOURCE);
+        let location = server.definition(&at(&uri, 8, 13));
+        assert_eq!(location.get("uri").and_then(Json::as_str), Some(uri.as_str()));
+        assert_eq!(start(&location), (6, 0));
+        assert_eq!(start(&server.definition(&at(&uri, 6, 14))), (2, 0));
+
+        // Not on a label
+        assert!(server.definition(&at(&uri, 6, 9)).is_null());
+    }
+
+    #[test]
+    fn references() {
+        let (server, uri) = open(SOURCE);
+        let references = server.references(&at(&uri, 6, 2));
+        let starts: Vec<_> = references.as_array().unwrap().iter().map(start).collect();
+        assert_eq!(starts, vec![(6, 0), (8, 12)]);
+
+        let mut params = at(&uri, 7, 12);
+        params.set("context", Json::object(vec![("includeDeclaration", false.into())]));
+        let references = server.references(&params);
+        let starts: Vec<_> = references.as_array().unwrap().iter().map(start).collect();
+        assert_eq!(starts, vec![(7, 12)]);
+    }
+
+    #[test]
+    fn hover_finds_the_word_under_the_cursor() {
+        let (server, uri) = open(SOURCE);
+        assert!(hover(&server, &uri, 6, 9).unwrap().starts_with("**LDA**: "));
+
+        // Words end at colons and operators, not only at spaces
+        let on_label = hover(&server, &uri, 6, 4).unwrap();
+        assert!(on_label.contains("instruction address 0x00"), "{}", on_label);
+        let on_operand = hover(&server, &uri, 6, 16).unwrap();
+        assert!(on_operand.contains("data address 0x00, initial value 1"), "{}", on_operand);
+        assert_eq!(hover(&server, &uri, 4, 13).unwrap(), "**SIZE**: constant 2");
+
+        assert_eq!(hover(&server, &uri, 6, 18), None);
+        assert_eq!(hover(&server, &uri, 1, 0), None);
+    }
+
+    #[test]
+    fn completion() {
+        let (server, uri) = open(SOURCE);
+        let items = server.completion(&at(&uri, 9, 8));
+        let labels: Vec<&str> = items
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|item| item.get("label")?.as_str())
+            .collect();
+        for expected in ["LDA", "HLT", ".data", ".include", "TABLE", "COUNT", "loop", "SIZE"] {
+            assert!(labels.contains(&expected), "{} in {:?}", expected, labels);
+        }
+    }
+
+    #[test]
+    fn uris_are_percent_decoded() {
+        assert_eq!(uri_to_path("file:///a%20b/c%2Bd.vnc").as_deref(), Some("/a b/c+d.vnc"));
+        assert_eq!(uri_to_path("file:///100%/x%4").as_deref(), Some("/100%/x%4"));
+        assert_eq!(uri_to_path("untitled:1"), None);
+        assert_eq!(path_to_uri("/a b/c+d.vnc"), "file:///a%20b/c%2Bd.vnc");
+
+        // Included files are found next to a file with spaces in its path
+        let directory = std::env::temp_dir().join(format!("vnc lsp {}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("lib.vnc"), "ONE:    DAT 1\n").unwrap();
+        let uri = path_to_uri(&directory.join("main.vnc").display().to_string());
+        let document = Document::new(&uri, ".data\n.include \"lib.vnc\"\n.code\n        LDA ONE\n".to_string());
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(document.assembly.diagnostics.is_empty(), "{:?}", document.assembly.diagnostics);
+    }
+}
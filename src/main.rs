@@ -1,22 +1,571 @@
 pub mod cpu;
 pub mod assembler;
+pub mod debugger;
+pub mod gdb;
+pub mod json;
+pub mod dap;
+pub mod lsp;
+pub mod linker;
+pub mod cfg;
+pub mod lint;
+pub mod coverage;
+pub mod testing;
 
-use cpu::instructions::{Instruction, Opcode};
+use crate::assembler::formats::{self, Format};
+use crate::assembler::source_map::SourceMap;
+use crate::cpu::cache::CacheConfig;
 use crate::cpu::registers::Register;
 
+/// Command line usage
+const USAGE: &str = "Usage:
+    vnc                 assemble and run test.vnc
+    vnc run <file> [--trace] [--profile <output>] [--cache <config>]
+                        assemble and run a program (or run a saved one),
+                        optionally printing every step, saving how
+                        often each instruction ran or putting a cache
+                        in front of data memory and printing its stats
+    vnc profile <file> [output] [--json]
+                        run a program and report how often each
+                        instruction, opcode and labelled region ran, how
+                        memory was used and how often branches were taken
+                        (as text, or as JSON for other tools)
+    vnc test [path]... [--coverage <output>]
+                        run the test files (.vnct) at each path (default
+                        the current directory) and report each case,
+                        optionally saving the coverage as an lcov file
+    vnc coverage <file> <output> <profile>...
+                        save the coverage of the runs that saved the
+                        profiles as an lcov tracefile
+    vnc cfg <file> [output] [--profile <profile>]
+                        print (or save) the control flow graph as DOT,
+                        with the counts of a saved profile
+    vnc assemble <file> <output> [format]
+                        save a program as bin, ihex, srec, text or logisim
+                        (by default, the format the extension names)
+    vnc fmt [--check] <file>...
+                        format source files in place (or, with --check,
+                        list those that are not formatted and fail)
+    vnc lint <file>     warn about likely bugs found without running
+    vnc listing <file> [output]
+                        print (or save) the assembly listing of a file
+    vnc object <file> [output]
+                        assemble a relocatable object (default <file>.o)
+    vnc link <output> <object>...
+                        link objects into a binary (and its .map)
+    vnc debug <file>    debug a program interactively
+    vnc resume <state>  continue running a saved machine state
+    vnc gdb <file> [port]
+                        serve a program to GDB (default port 1234)
+    vnc dap             serve the Debug Adapter Protocol on stdin/stdout
+    vnc lsp             serve the Language Server Protocol on stdin/stdout
+
+.include looks next to the including file, then in the directories
+listed in VNC_INCLUDE (separated like PATH). A saved program's source
+map (test.bin -> test.map) is used to name source lines when present.
+Saved programs are read in the format their extension names: .bin,
+.hex/.ihex, .srec/.mot, .txt or .lgs (data in NAME.data.lgs and code in
+NAME.code.lgs).
+A cache is configured as LINE_SIZE,CAPACITY[,MAPPING[,REPLACEMENT[,POLICY]]]
+with MAPPING direct, N-way or full, REPLACEMENT lru, fifo or random and
+POLICY write-back or write-through, e.g. 4,32,2-way,fifo.";
+
 fn main() {
-    // First assemble source code
-    let binary = assembler::assemble("test.vnc");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(|s| s.as_str()) {
+        None => {
+            // First assemble source code
+            let program = load("test.vnc");
+
+            // Save binary and source map to files
+            if let Err(e) = assembler::save_to_file(program.to_binary(), "test.bin") {
+                fail(&format!("error: test.bin: {}", e));
+            }
+            if let Err(e) = program.source_map.save("test.map") {
+                fail(&format!("error: test.map: {}", e));
+            }
+
+            let binary =
+                assembler::load_from_file("test.bin").unwrap_or_else(|e| fail(&format!("error: test.bin: {}", e)));
+            run(binary, Some(program.source_map), false, None, None);
+        },
+        Some("run") => {
+            let trace = args.iter().any(|arg| arg == "--trace");
+            let profile = option(&args, "--profile");
+            let cache = option(&args, "--cache").map(|config| match config.parse() {
+                Ok(config) => config,
+                Err(e) => fail(&format!("error: {}", e)),
+            });
+            match args.get(1).map(|path| load(path)) {
+                Some(program) => run(program.to_binary(), Some(program.source_map), trace, profile, cache),
+                None => println!("{}", USAGE),
+            }
+        },
+        Some("profile") => match args.get(1).filter(|path| !path.starts_with("--")) {
+            Some(path) => {
+                let output = args.get(2).filter(|output| !output.starts_with("--"));
+                profile(path, output, args.iter().any(|arg| arg == "--json"));
+            },
+            None => println!("{}", USAGE),
+        },
+        Some("test") => {
+            let coverage = option(&args, "--coverage");
+            let mut paths: Vec<&String> = Vec::new();
+            let mut rest = args[1..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--coverage" => {
+                        rest.next();
+                    },
+                    _ => paths.push(arg),
+                }
+            }
+            test(&paths, coverage);
+        },
+        Some("coverage") if args.len() > 3 => coverage(&args[1], &args[2], &args[3..]),
+        Some("cfg") => match args.get(1).filter(|path| !path.starts_with("--")) {
+            Some(path) => {
+                let output = args.get(2).filter(|output| !output.starts_with("--"));
+                cfg(path, output, option(&args, "--profile"));
+            },
+            None => println!("{}", USAGE),
+        },
+        Some("assemble") => match (args.get(1), args.get(2)) {
+            (Some(path), Some(output)) => assemble(path, output, args.get(3)),
+            _ => println!("{}", USAGE),
+        },
+        Some("fmt") => {
+            let check = args.iter().any(|arg| arg == "--check");
+            let paths: Vec<&String> = args[1..].iter().filter(|arg| *arg != "--check").collect();
+            match paths.is_empty() {
+                true => println!("{}", USAGE),
+                false => fmt(&paths, check),
+            }
+        },
+        Some("lint") => match args.get(1) {
+            Some(path) => lint(path),
+            None => println!("{}", USAGE),
+        },
+        Some("listing") => match args.get(1) {
+            Some(path) => {
+                let listing = assembler::assemble_listing(path).unwrap_or_else(|e| fail(&e));
+                match args.get(2) {
+                    Some(output) => {
+                        if let Err(e) = assembler::save_to_file(listing.into_bytes(), output) {
+                            fail(&format!("error: {}: {}", output, e));
+                        }
+                    },
+                    None => print!("{}", listing),
+                }
+            },
+            None => println!("{}", USAGE),
+        },
+        Some("object") => match args.get(1) {
+            Some(path) => {
+                let output = match args.get(2) {
+                    Some(output) => output.clone(),
+                    None => std::path::Path::new(path).with_extension("o").display().to_string(),
+                };
+                let object = assembler::try_assemble_object(path).unwrap_or_else(|errors| fail(&errors));
+                if let Err(e) = object.save(&output) {
+                    println!("error: {}: {}", output, e);
+                    std::process::exit(1);
+                }
+            },
+            None => println!("{}", USAGE),
+        },
+        Some("link") if args.len() > 2 => link(&args[1], &args[2..]),
+        Some("resume") => match args.get(1) {
+            Some(path) => resume(path),
+            None => println!("{}", USAGE),
+        },
+        Some("gdb") => match args.get(1) {
+            Some(path) => {
+                let port = match args.get(2).map(|port| port.parse::<u16>()) {
+                    None => 1234,
+                    Some(Ok(port)) => port,
+                    Some(Err(_)) => {
+                        println!("{}", USAGE);
+                        return;
+                    },
+                };
+
+                let mut cpu = cpu::CPU::new(256, 256);
+                if let Err(e) = cpu.load_program(load(path).to_binary()) {
+                    fail(&format!("error: {}", e));
+                }
+                if let Err(e) = gdb::GdbStub::new(cpu).listen(port) {
+                    println!("error: {}", e);
+                }
+            },
+            None => println!("{}", USAGE),
+        },
+        Some("debug") => match args.get(1) {
+            Some(path) => debugger::Debugger::new(path).unwrap_or_else(|errors| fail(&errors)).repl(),
+            None => println!("{}", USAGE),
+        },
+        Some("dap") => dap::DebugAdapter::new().serve(),
+        Some("lsp") => lsp::LanguageServer::new().serve(),
+        Some(_) => println!("{}", USAGE),
+    }
+}
 
-    // Save binary to file
-    assembler::save_to_file(binary, "test.bin");
+/// Value given after a flag, e.g. `--profile out.prof`
+fn option<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1))
+}
+
+/// Load a saved program (with its source map, if there is one) or
+/// assemble a source file, exiting with the errors if it cannot
+fn load(path: &str) -> assembler::Program {
+    let format = match Format::from_path(path) {
+        Some(format) => format,
+        None => return assembler::try_assemble_program(path).unwrap_or_else(|errors| fail(&errors)),
+    };
+    match formats::load(path, format) {
+        Ok(mut program) => {
+            program.source_map = SourceMap::load(&SourceMap::path_for(path)).unwrap_or_default();
+            program
+        },
+        Err(e) => fail(&format!("error: {}", e)),
+    }
+}
 
+/// Print errors (each already saying `error:`) and exit with an error
+fn fail(errors: &str) -> ! {
+    eprintln!("{}", errors);
+    std::process::exit(1);
+}
+
+/// Run an assembled binary and print the final state, saving its profile
+/// if given a file for it and printing the stats of the cache if given one
+fn run(
+    binary: Vec<u8>,
+    source_map: Option<SourceMap>,
+    trace: bool,
+    profile: Option<&String>,
+    cache: Option<CacheConfig>,
+) {
     // Initialise CPU and load program
     let mut cpu = cpu::CPU::new(256, 256);
-    cpu.load_program_from_file("test.bin");
+    if let Err(e) = cpu.load_program(binary) {
+        fail(&format!("error: {}", e));
+    }
+    cpu.source_map = source_map;
+    cpu.trace = trace;
+    if profile.is_some() {
+        cpu.enable_profile();
+    }
+    if let Some(config) = cache {
+        // Already validated when parsed
+        cpu.attach_cache(config).unwrap();
+    }
 
     // Start CPU
-    cpu.start();
+    let status = cpu.start();
+    // Write any dirty lines back so memory is printed as the program left it
+    let cache = cpu.detach_cache();
+    print_state(&cpu, status);
+
+    if let Some(cache) = cache {
+        println!("cache {}", cache.config());
+        println!("{}", cache.stats());
+    }
+
+    if let (Some(path), Some(profile)) = (profile, &cpu.profile) {
+        if let Err(e) = profile.save(path) {
+            println!("error: {}: {}", path, e);
+        }
+    }
+}
+
+/// Run a program with profiling and print (or save) the report. JSON
+/// printed to stdout is all that is printed, so it can be piped.
+fn profile(path: &str, output: Option<&String>, json: bool) {
+    let program = load(path);
+
+    let mut cpu = cpu::CPU::new(256, 256);
+    if let Err(e) = cpu.load_program(program.to_binary()) {
+        fail(&format!("error: {}", e));
+    }
+    cpu.source_map = Some(program.source_map.clone());
+    cpu.enable_profile();
+    let quiet = json && output.is_none();
+    cpu.io.echo = !quiet;
+
+    let status = cpu.start();
+    if !quiet {
+        print_state(&cpu, status);
+    }
+
+    let profile = cpu.profile.as_ref().unwrap();
+    let report = match json {
+        true => format!("{}\n", profile.to_json(&program)),
+        false => profile.report(&program),
+    };
+    match output {
+        Some(output) => {
+            if let Err(e) = std::fs::write(output, report) {
+                println!("error: {}: {}", output, e);
+            }
+        },
+        None if quiet => print!("{}", report),
+        None => print!("\n{}", report),
+    }
+}
+
+/// Run the test files at some paths, printing each case and a summary,
+/// and exit 1 if any did not pass
+fn test(paths: &[&String], coverage: Option<&String>) {
+    let here = ".".to_string();
+    let paths = match paths.is_empty() {
+        true => vec![&here],
+        false => paths.to_vec(),
+    };
+
+    let mut files = Vec::new();
+    for path in paths {
+        match testing::find(path) {
+            Ok(found) => files.extend(found),
+            Err(e) => {
+                println!("error: {}", e);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    let (mut passed, mut failed, mut broken) = (0, 0, 0);
+    let mut lcov = String::new();
+    for file in &files {
+        let file = file.display().to_string();
+        println!("{}", file);
+        // Errors running a program already say `error:`
+        let result = match testing::TestFile::load(&file) {
+            Ok(test) => test.run(),
+            Err(e) => Err(format!("error: {}", e)),
+        };
+        let (program, outcomes) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                println!("{}", e);
+                broken += 1;
+                continue;
+            },
+        };
+
+        let mut merged = cpu::profile::Profile::new();
+        for outcome in &outcomes {
+            let result = match outcome.passed() {
+                true => "ok",
+                false => "FAIL",
+            };
+            println!("    {:<4}  {} ({} cycles)", result, outcome.name, outcome.cycles);
+            for failure in &outcome.failures {
+                println!("          {}", failure);
+            }
+            match outcome.passed() {
+                true => passed += 1,
+                false => failed += 1,
+            }
+            merged.merge(&outcome.profile);
+        }
+        lcov.push_str(&coverage::Coverage::new(&program, &merged).to_lcov());
+    }
+
+    println!();
+    match broken {
+        0 => println!("{} passed, {} failed", passed, failed),
+        _ => println!("{} passed, {} failed, {} could not run", passed, failed, broken),
+    }
+    if let Some(output) = coverage {
+        if let Err(e) = std::fs::write(output, lcov) {
+            println!("error: {}: {}", output, e);
+            std::process::exit(1);
+        }
+    }
+    if failed > 0 || broken > 0 || files.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Save the coverage of the runs that saved some profiles as an lcov
+/// tracefile, printing a summary
+fn coverage(path: &str, output: &str, profiles: &[String]) {
+    let program = load(path);
+    if program.source_map.code.is_empty() {
+        fail(&format!("error: {}: no source map to find source lines in", path));
+    }
+
+    let mut merged = cpu::profile::Profile::new();
+    for profile in profiles {
+        match cpu::profile::Profile::load(profile) {
+            Ok(profile) => merged.merge(&profile),
+            Err(e) => fail(&format!("error: {}", e)),
+        }
+    }
+
+    let coverage = coverage::Coverage::new(&program, &merged);
+    if let Err(e) = std::fs::write(output, coverage.to_lcov()) {
+        fail(&format!("error: {}: {}", output, e));
+    }
+    println!("{}", coverage.summary());
+}
+
+/// Print or save the control flow graph of a program in DOT form
+fn cfg(path: &str, output: Option<&String>, profile: Option<&String>) {
+    let program = load(path);
+    let profile = match profile.map(|path| cpu::profile::Profile::load(path)) {
+        None => None,
+        Some(Ok(profile)) => Some(profile),
+        Some(Err(e)) => fail(&format!("error: {}", e)),
+    };
+
+    let dot = cfg::Cfg::new(&program.code).to_dot(&program, profile.as_ref());
+    match output {
+        Some(output) => {
+            if let Err(e) = std::fs::write(output, dot) {
+                fail(&format!("error: {}: {}", output, e));
+            }
+        },
+        None => print!("{}", dot),
+    }
+}
+
+/// Assemble a source file and save it in a format (by name, or else the
+/// one the output's extension names), with its source map
+fn assemble(path: &str, output: &str, format: Option<&String>) {
+    let format = match format.map(|name| name.parse::<Format>()) {
+        Some(Ok(format)) => format,
+        Some(Err(e)) => fail(&format!("error: {}", e)),
+        None => Format::from_path(output).unwrap_or(Format::Binary),
+    };
+
+    let program = assembler::try_assemble_program(path).unwrap_or_else(|errors| fail(&errors));
+    let saved = formats::save(&program, output, format).and_then(|_| {
+        program.source_map.save(&SourceMap::path_for(output))
+    });
+    if let Err(e) = saved {
+        fail(&format!("error: {}: {}", output, e));
+    }
+}
+
+/// Format source files in place, or with `check` only list the ones that
+/// are not formatted, exiting with an error if there are any
+fn fmt(paths: &[&String], check: bool) {
+    let mut failed = false;
+    for path in paths {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                println!("error: {}: {}", path, e);
+                failed = true;
+                continue;
+            },
+        };
+        let formatted = match assembler::formatter::format(&source, &assembler::Options::for_file(path.as_str())) {
+            Ok(formatted) => formatted,
+            Err(errors) => {
+                for e in errors {
+                    println!("error: {}", e);
+                }
+                failed = true;
+                continue;
+            },
+        };
+
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", path);
+            failed = true;
+        } else if let Err(e) = std::fs::write(path, formatted) {
+            println!("error: {}: {}", path, e);
+            failed = true;
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Print the problems found in a source file by the assembler and the
+/// linter, exiting with an error if there are any
+fn lint(path: &str) {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            println!("error: {}: {}", path, e);
+            std::process::exit(1);
+        },
+    };
+    let assembly = assembler::assemble_with(&source, &assembler::Options::for_file(path));
+
+    let mut diagnostics = assembly.diagnostics.clone();
+    diagnostics.extend(lint::lint(&assembly));
+    for d in &diagnostics {
+        println!("{}:{}", assembly.file_name(d.span), d);
+    }
+    if !diagnostics.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Link object files into a binary file
+fn link(output: &str, paths: &[String]) {
+    let mut objects = Vec::new();
+    for path in paths {
+        match assembler::object::Object::load(path) {
+            Ok(object) => objects.push(object),
+            Err(e) => {
+                println!("error: {}", e);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    match linker::link(&objects) {
+        Ok(program) => {
+            if let Err(e) = assembler::save_to_file(program.to_binary(), output) {
+                fail(&format!("error: {}: {}", output, e));
+            }
+            let map = SourceMap::path_for(output);
+            if let Err(e) = program.source_map.save(&map) {
+                fail(&format!("error: {}: {}", map, e));
+            }
+        },
+        Err(errors) => {
+            for e in errors {
+                println!("error: {}", e);
+            }
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Continue running a machine state saved with the debugger
+fn resume(snapshot_path: &str) {
+    let snapshot = match cpu::snapshot::Snapshot::load(snapshot_path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => fail(&format!("error: {}", e)),
+    };
+
+    let mut cpu = cpu::CPU::new(256, 256);
+    if let Err(e) = cpu.restore(&snapshot) {
+        fail(&format!("error: {}", e));
+    }
+
+    let status = cpu.run();
+    print_state(&cpu, status);
+}
+
+/// Print the final state of a CPU
+fn print_state(cpu: &cpu::CPU, status: cpu::debug::Status) {
+    println!("status: {:?}", status);
+    if let cpu::debug::Status::Faulted(fault) = &status {
+        println!("fault: {}", cpu.describe_fault(fault));
+    }
 
     // Print acc
     println!("acc: {}", cpu.acc.get());
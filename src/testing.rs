@@ -0,0 +1,418 @@
+//! Tests for programs
+//! A test file (`.vnct`) names a program and describes cases to run it
+//! with: the values `INP` reads, and what is expected of the run. Each
+//! case runs the program from the start on a fresh machine and passes if
+//! it halts within its cycle budget with everything expected:
+//! ```text
+//! # Tests for countdown.vnc
+//! program countdown.vnc
+//! cycles 1000
+//!
+//! case counts down from 3
+//! input 3
+//! output 3 2 1 0
+//! acc 0
+//! memory COUNT 0
+//!
+//! case nothing to count
+//! input 0
+//! output 0
+//! cycles 10
+//! ```
+//! 1. `program` is the source file or saved program to run, relative to
+//!    the test file
+//! 2. `case` starts a case and names it
+//! 3. `input` gives the values INP reads, in order (a case fails if INP
+//!    runs out)
+//! 4. `output` gives every value OUT must write, in order
+//! 5. `acc` gives the accumulator when the program halts
+//! 6. `memory` gives a data cell (label, `label+offset` or address) and
+//!    its value when the program halts
+//! 7. `cycles` gives the most instructions the program may execute;
+//!    before the first case it is the budget for every case (otherwise
+//!    1,000,000)
+//!
+//! Numbers are decimal or `0x` hex. Anything not given is not checked.
+
+use std::path::{Path, PathBuf};
+
+use crate::assembler::formats::{self, Format};
+use crate::assembler::source_map::SourceMap;
+use crate::assembler::{self, Program, Section};
+use crate::cpu::debug::Status;
+use crate::cpu::io::Io;
+use crate::cpu::profile::Profile;
+use crate::cpu::registers::Register;
+use crate::cpu::CPU;
+
+/// Extension of test files
+pub const EXTENSION: &str = "vnct";
+
+/// Cycle budget of a case when none is given
+pub const DEFAULT_CYCLES: u64 = 1_000_000;
+
+/// A program and the cases to run it with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestFile {
+    /// Path of the program (relative to the current directory)
+    pub program: String,
+    pub cases: Vec<Case>,
+}
+
+/// One run of a program and what is expected of it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Case {
+    pub name: String,
+    /// Line of the test file the case starts on
+    pub line: usize,
+    pub input: Vec<u8>,
+    pub output: Option<Vec<u8>>,
+    pub acc: Option<u8>,
+    /// Each cell, as written, with its value
+    pub memory: Vec<(String, u8)>,
+    pub cycles: u64,
+}
+
+/// What happened when a case ran
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outcome {
+    pub name: String,
+    /// Instructions executed
+    pub cycles: u64,
+    /// Each way the run differed from what was expected
+    pub failures: Vec<String>,
+    /// How often each instruction ran (for coverage)
+    pub profile: Profile,
+}
+
+impl TestFile {
+    /// Load a test file
+    pub fn load(filename: &str) -> Result<TestFile, String> {
+        let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        let directory = Path::new(filename).parent().unwrap_or(Path::new(""));
+        let mut test = TestFile::parse(&text).map_err(|e| format!("{}: {}", filename, e))?;
+        test.program = directory.join(&test.program).display().to_string();
+        Ok(test)
+    }
+
+    /// Parse the text of a test file, with the program path as written
+    pub fn parse(text: &str) -> Result<TestFile, String> {
+        let mut test = TestFile::default();
+        let mut budget = DEFAULT_CYCLES;
+
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let error = |message: String| format!("line {}: {}", number, message);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            if key == "program" || key == "case" {
+                if rest.is_empty() {
+                    return Err(error(format!("'{}' needs a name", key)));
+                }
+                match key {
+                    "program" => test.program = rest.to_string(),
+                    _ => test.cases.push(Case {
+                        name: rest.to_string(),
+                        line: number,
+                        cycles: budget,
+                        ..Case::default()
+                    }),
+                }
+                continue;
+            }
+
+            let values: Vec<&str> = rest.split_whitespace().collect();
+            if key == "cycles" && test.cases.is_empty() {
+                budget = match values[..] {
+                    [value] => parse_number(value).map_err(error)?,
+                    _ => return Err(error("'cycles' takes a number".to_string())),
+                };
+                continue;
+            }
+            let case = match test.cases.last_mut() {
+                Some(case) => case,
+                None => return Err(error(format!("'{}' before the first case", key))),
+            };
+
+            match (key, &values[..]) {
+                ("input", values) => case.input.extend(parse_bytes(values).map_err(error)?),
+                ("output", values) => case.output = Some(parse_bytes(values).map_err(error)?),
+                ("acc", [value]) => case.acc = Some(parse_byte(value).map_err(error)?),
+                ("memory", [cell, value]) => case.memory.push((cell.to_string(), parse_byte(value).map_err(error)?)),
+                ("cycles", [value]) => case.cycles = parse_number(value).map_err(error)?,
+                ("acc" | "memory" | "cycles", _) => {
+                    return Err(error(format!("wrong number of values for '{}'", key)));
+                },
+                _ => return Err(error(format!("unknown key '{}'", key))),
+            }
+        }
+
+        if test.program.is_empty() {
+            return Err("missing 'program'".to_string());
+        }
+        Ok(test)
+    }
+
+    /// Load the program and run every case
+    pub fn run(&self) -> Result<(Program, Vec<Outcome>), String> {
+        let program = load_program(&self.program)?;
+        let outcomes = self.cases.iter().map(|case| case.run(&program)).collect();
+        Ok((program, outcomes))
+    }
+}
+
+impl Case {
+    /// Run a program and check it against the case
+    pub fn run(&self, program: &Program) -> Outcome {
+        let mut cpu = CPU::new(256, 256);
+        if let Err(e) = cpu.load_program(program.to_binary()) {
+            return Outcome {
+                name: self.name.clone(),
+                cycles: 0,
+                failures: vec![e],
+                profile: Profile::default(),
+            };
+        }
+        cpu.source_map = Some(program.source_map.clone());
+        cpu.io = Io::scripted(&self.input);
+        cpu.enable_profile();
+
+        let status = cpu.run_for(self.cycles);
+        let mut failures = Vec::new();
+        match &status {
+            Status::Halted => {},
+            Status::Running => failures.push(format!("did not halt within {} cycles", self.cycles)),
+            Status::Faulted(fault) => failures.push(format!("faulted: {}", cpu.describe_fault(fault))),
+            Status::Stopped(reason) => failures.push(format!("stopped: {:?}", reason)),
+        }
+
+        if let Some(expected) = self.output.as_ref().filter(|expected| **expected != cpu.io.output) {
+            failures.push(format!("output was {}, expected {}", list(&cpu.io.output), list(expected)));
+        }
+        if let Some(expected) = self.acc.filter(|expected| *expected != cpu.acc.get()) {
+            failures.push(format!("acc was {}, expected {}", cpu.acc.get(), expected));
+        }
+        for (cell, expected) in &self.memory {
+            match cell_address(program, cell) {
+                Some(address) if address < 256 => {
+                    let value = cpu.data_memory.read(address);
+                    let name = match parse_number(cell) {
+                        Ok(_) => format!("0x{:02X}", address),
+                        Err(_) => format!("{} (0x{:02X})", cell, address),
+                    };
+                    if value != *expected {
+                        failures.push(format!("{} was {}, expected {}", name, value, expected));
+                    }
+                },
+                _ => failures.push(format!("no data cell '{}'", cell)),
+            }
+        }
+
+        let profile = cpu.profile.take().unwrap();
+        Outcome {
+            name: self.name.clone(),
+            cycles: profile.cycles,
+            failures,
+            profile,
+        }
+    }
+}
+
+impl Outcome {
+    /// Whether the run was as expected
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Every test file at a path: the file itself, or those in a directory
+/// and the directories in it, in name order
+pub fn find(path: &str) -> Result<Vec<PathBuf>, String> {
+    let path = Path::new(path);
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut found = Vec::new();
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        let entries = std::fs::read_dir(&directory).map_err(|e| format!("{}: {}", directory.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("{}: {}", directory.display(), e))?.path();
+            if entry.is_dir() {
+                pending.push(entry);
+            } else if entry.extension().is_some_and(|extension| extension == EXTENSION) {
+                found.push(entry);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Load a saved program (with its source map, if there is one) or
+/// assemble a source file, without panicking on errors
+fn load_program(path: &str) -> Result<Program, String> {
+    if let Some(format) = Format::from_path(path) {
+        let mut program = formats::load(path, format).map_err(|e| format!("error: {}", e))?;
+        program.source_map = SourceMap::load(&SourceMap::path_for(path)).unwrap_or_default();
+        return Ok(program);
+    }
+
+    assembler::try_assemble_program(path)
+}
+
+/// Address of a data cell named by a label, `label+offset` or a number
+fn cell_address(program: &Program, cell: &str) -> Option<u32> {
+    if let Ok(address) = parse_number(cell) {
+        return u32::try_from(address).ok();
+    }
+
+    let (label, offset) = match cell.split_once('+') {
+        Some((label, offset)) => (label.trim(), parse_number(offset.trim()).ok()? as u32),
+        None => (cell, 0),
+    };
+    let address = match program.symbols.get(label) {
+        Some(symbol) if symbol.section == Section::Data => symbol.address,
+        Some(_) => return None,
+        // A saved program only has the labels in its source map
+        None => program.source_map.data.iter().find(|(_, name)| name == label)?.0,
+    };
+    Some(address + offset)
+}
+
+/// Values as written in a test file, e.g. `3 2 1`
+fn list(values: &[u8]) -> String {
+    match values.is_empty() {
+        true => "nothing".to_string(),
+        false => values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(" "),
+    }
+}
+
+/// Parse a list of byte values
+fn parse_bytes(values: &[&str]) -> Result<Vec<u8>, String> {
+    values.iter().map(|value| parse_byte(value)).collect()
+}
+
+/// Parse a value that fits in a byte
+fn parse_byte(text: &str) -> Result<u8, String> {
+    u8::try_from(parse_number(text)?).map_err(|_| format!("'{}' does not fit in a byte", text))
+}
+
+/// Parse a decimal or 0x hex number
+fn parse_number(text: &str) -> Result<u64, String> {
+    let result = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse::<u64>(),
+    };
+    result.map_err(|_| format!("invalid number '{}'", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble_with, Options};
+
+    const COUNTDOWN: &str = ".data
+COUNT: DAT 0
+ONE: DAT 1
+.code
+        INP
+        STA COUNT
+loop:   LDA COUNT
+        OUT
+        SUB ONE
+        STA COUNT
+        JNZ loop
+        HLT
+";
+
+    #[test]
+    fn parse() {
+        let text = "# counts
+program countdown.vnc
+cycles 100
+
+case three
+input 3
+output 3 2 1
+acc 0
+memory COUNT 0
+
+case none
+cycles 0x10
+";
+        let test = TestFile::parse(text).unwrap();
+        assert_eq!(test.program, "countdown.vnc");
+        assert_eq!(
+            test.cases,
+            vec![
+                Case {
+                    name: "three".to_string(),
+                    line: 5,
+                    input: vec![3],
+                    output: Some(vec![3, 2, 1]),
+                    acc: Some(0),
+                    memory: vec![("COUNT".to_string(), 0)],
+                    cycles: 100,
+                },
+                Case {
+                    name: "none".to_string(),
+                    line: 11,
+                    cycles: 16,
+                    ..Case::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| TestFile::parse(text).unwrap_err();
+        assert_eq!(error("case a\n"), "missing 'program'");
+        assert_eq!(error("program a.vnc\ninput 1\n"), "line 2: 'input' before the first case");
+        assert_eq!(error("program a.vnc\ncase a\nacc 256\n"), "line 3: '256' does not fit in a byte");
+        assert_eq!(error("program a.vnc\ncase a\nacc 1 2\n"), "line 3: wrong number of values for 'acc'");
+        assert_eq!(error("program a.vnc\ncase a\nspeed 2\n"), "line 3: unknown key 'speed'");
+        assert_eq!(error("program\n"), "line 1: 'program' needs a name");
+    }
+
+    #[test]
+    fn cases_pass_and_fail() {
+        let program = assemble_with(COUNTDOWN, &Options::default()).program;
+        let text = "program countdown.vnc
+case passes
+input 3
+output 3 2 1
+memory COUNT 0
+memory ONE+0 1
+cycles 18
+case fails
+input 2
+output 2
+acc 1
+memory 0x00 5
+case out of input
+case too slow
+input 9
+cycles 5
+";
+        let test = TestFile::parse(text).unwrap();
+        let outcomes: Vec<Outcome> = test.cases.iter().map(|case| case.run(&program)).collect();
+
+        assert!(outcomes[0].passed(), "{:?}", outcomes[0].failures);
+        assert_eq!(outcomes[0].cycles, 18);
+        assert_eq!(
+            outcomes[1].failures,
+            ["output was 2 1, expected 2", "acc was 0, expected 1", "0x00 was 0, expected 5"]
+        );
+        assert_eq!(outcomes[2].failures.len(), 1);
+        assert!(outcomes[2].failures[0].starts_with("faulted: no input left for INP"), "{:?}", outcomes[2].failures);
+        assert_eq!(outcomes[3].failures, ["did not halt within 5 cycles"]);
+    }
+}